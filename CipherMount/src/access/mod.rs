@@ -0,0 +1,142 @@
+//! Per-access audit trail for incident response (`--panic`).
+//!
+//! Every access is appended as one line: when, which uid, what was done and
+//! to which path relative to the vault root. Unlike the regular log, paths
+//! are always written in the clear — recording who touched what is the
+//! point — so the log belongs outside the vault and away from untrusted
+//! readers.
+//!
+//! An optional per-uid rate limit caps how fast any one user can read,
+//! slowing bulk copying while the vault stays available for investigation.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Where access lines go, and the rate limit applied to them.
+pub struct AccessLog {
+    out: Mutex<Box<dyn Write + Send>>,
+    /// Accesses per second allowed to each uid, if limited.
+    rate: Option<u32>,
+    /// Token bucket per uid: tokens left and when it was last refilled.
+    buckets: Mutex<HashMap<u32, (f64, Instant)>>,
+}
+
+impl AccessLog {
+    /// Append to the log file at `path`, creating it readable by its owner
+    /// only.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("Cannot open access log {:?}", path))?;
+        Ok(Self::new(Box::new(file)))
+    }
+
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self {
+            out: Mutex::new(out),
+            rate: None,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Allow each uid at most `per_sec` accesses per second, in bursts of up
+    /// to `per_sec`.
+    pub fn with_rate_limit(mut self, per_sec: u32) -> Self {
+        self.rate = Some(per_sec);
+        self
+    }
+
+    /// Record that `uid` did `op` on `path`. Returns `false` if the access is
+    /// over the uid's rate limit and must be refused; it is still logged,
+    /// marked `denied`.
+    pub fn record(&self, uid: u32, op: &str, path: &Path) -> bool {
+        let allowed = self.take_token(uid);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!(
+            "{}.{:03} uid={} {}{} {:?}\n",
+            now.as_secs(),
+            now.subsec_millis(),
+            uid,
+            op,
+            if allowed { "" } else { " denied" },
+            path
+        );
+        let mut out = self.out.lock().unwrap();
+        // Losing the audit trail must not go unnoticed, but the access itself
+        // is still served: the mount is meant to stay usable.
+        if let Err(e) = out.write_all(line.as_bytes()).and_then(|_| out.flush()) {
+            log::error!("Cannot write to the access log: {}", e);
+        }
+        allowed
+    }
+
+    fn take_token(&self, uid: u32) -> bool {
+        let Some(rate) = self.rate else {
+            return true;
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let (tokens, last) = buckets.entry(uid).or_insert((rate as f64, now));
+        let refill = now.duration_since(*last).as_secs_f64() * rate as f64;
+        *tokens = (*tokens + refill).min(rate as f64);
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// A writer whose output can still be inspected after it is handed off.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rate_limit_is_per_uid_and_denials_are_logged() {
+        let out = Shared::default();
+        let log = AccessLog::new(Box::new(out.clone())).with_rate_limit(2);
+        let path = Path::new("docs/a.txt");
+        assert!(log.record(1000, "read", path));
+        assert!(log.record(1000, "read", path));
+        assert!(!log.record(1000, "read", path));
+        assert!(log.record(1001, "read", path));
+
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(
+            lines[0].ends_with(" uid=1000 read \"docs/a.txt\""),
+            "{}",
+            lines[0]
+        );
+        assert!(lines[2].ends_with(" uid=1000 read denied \"docs/a.txt\""));
+        assert!(lines[3].contains(" uid=1001 read "));
+    }
+}
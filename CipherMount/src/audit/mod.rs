@@ -0,0 +1,169 @@
+//! Offline diagnostics for space wasted by a vault's files.
+//!
+//! A backing file can end up much bigger than its contents justify: trailing
+//! bytes left behind after a crash, or — far more often — a write past the
+//! end of a file zero-filling the gap, which then gets encrypted and stored
+//! in full. `audit` reports such files so they can be rewritten or cleaned.
+
+use crate::{crypto, dedup, format, layout, rewrite, walk};
+use anyhow::Result;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Files with fewer zero-filled bytes than this are never reported, however
+/// the zeros are spread; small runs of zeros are ordinary file contents.
+pub const BALLOON_MIN_BYTES: u64 = 64 * 1024;
+
+/// Granularity at which zero runs are counted in legacy files, which have
+/// no block size of their own.
+const LEGACY_SCAN_BLOCK: usize = 4096;
+
+/// Something wrong with a single file.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Issue {
+    /// The backing file is longer than its header accounts for.
+    TrailingBytes { extra: u64 },
+    /// Most of the file is whole blocks of zeros, as left by writes past the
+    /// end of the file.
+    ZeroFilled { zero_bytes: u64, logical_len: u64 },
+    /// The file could not be decrypted at all.
+    Unreadable(String),
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::TrailingBytes { extra } => {
+                write!(f, "{} trailing byte(s) beyond the last block", extra)
+            }
+            Issue::ZeroFilled {
+                zero_bytes,
+                logical_len,
+            } => write!(
+                f,
+                "{} of {} bytes are zero-filled blocks (sparse write ballooned)",
+                zero_bytes, logical_len
+            ),
+            Issue::Unreadable(e) => write!(f, "unreadable: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Finding {
+    pub path: PathBuf,
+    pub issue: Issue,
+}
+
+/// Bytes of `plaintext` lying in blocks of `block_size` that are entirely zero.
+fn zero_block_bytes(plaintext: &[u8], block_size: usize) -> u64 {
+    plaintext
+        .chunks(block_size)
+        .filter(|block| block.iter().all(|&b| b == 0))
+        .map(|block| block.len() as u64)
+        .sum()
+}
+
+/// Check one backing file.
+pub fn audit_file(key: &[u8; 32], path: &Path) -> Result<Vec<Issue>> {
+    let raw = layout::read(path)?;
+    if raw.len() < crypto::HEADER_LEN + 16 {
+        return Ok(vec![]);
+    }
+    let mut issues = vec![];
+    let mut scan_block = LEGACY_SCAN_BLOCK;
+    if let Some(header) = format::Header::decode(&raw) {
+        if (raw.len() as u128) > header.file_len() {
+            issues.push(Issue::TrailingBytes {
+                extra: (raw.len() as u128 - header.file_len()) as u64,
+            });
+            // Decrypt what the header describes so the rest can be checked.
+            let plaintext = dedup::decrypt_file(key, path, &raw[..header.file_len() as usize]);
+            return Ok(match plaintext {
+                Ok(plaintext) => {
+                    issues.extend(balloon(&plaintext, header.block_size as usize));
+                    issues
+                }
+                Err(e) => vec![Issue::Unreadable(e.to_string())],
+            });
+        }
+        scan_block = header.block_size as usize;
+    }
+    match dedup::decrypt_file(key, path, &raw) {
+        Ok(plaintext) => issues.extend(balloon(&plaintext, scan_block)),
+        Err(e) => issues.push(Issue::Unreadable(e.to_string())),
+    }
+    Ok(issues)
+}
+
+fn balloon(plaintext: &[u8], block_size: usize) -> Option<Issue> {
+    let zero_bytes = zero_block_bytes(plaintext, block_size);
+    let logical_len = plaintext.len() as u64;
+    (zero_bytes >= BALLOON_MIN_BYTES && zero_bytes * 2 >= logical_len).then_some(
+        Issue::ZeroFilled {
+            zero_bytes,
+            logical_len,
+        },
+    )
+}
+
+/// Check every file in the vault at `root`.
+pub fn audit(root: &Path, key: &[u8; 32], options: walk::Options) -> Result<Vec<Finding>> {
+    let mut findings = vec![];
+    for path in rewrite::files(root, options)? {
+        for issue in audit_file(key, &path)? {
+            findings.push(Finding {
+                path: path.clone(),
+                issue,
+            });
+        }
+    }
+    Ok(findings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const KEY: [u8; 32] = [0x42u8; 32];
+
+    #[test]
+    fn zero_ballooned_and_padded_files_are_flagged() {
+        let dir = tempfile::tempdir().unwrap();
+        // A one-byte write at 1 MiB into an empty file.
+        let mut ballooned = vec![0u8; 1 << 20];
+        ballooned.push(b'x');
+        let healthy: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8 | 1).collect();
+        let small_zeros = vec![0u8; 8192];
+        for (name, plaintext) in [
+            ("ballooned", &ballooned),
+            ("healthy", &healthy),
+            ("small", &small_zeros),
+        ] {
+            let raw = format::encrypt_file(&KEY, plaintext, 4096).unwrap();
+            fs::write(dir.path().join(name), raw).unwrap();
+        }
+        let mut padded = format::encrypt_file(&KEY, b"contents", 4096).unwrap();
+        padded.extend_from_slice(&[0u8; 100]);
+        fs::write(dir.path().join("padded"), padded).unwrap();
+
+        let findings = audit(dir.path(), &KEY, walk::Options::default()).unwrap();
+        assert_eq!(
+            findings,
+            [
+                Finding {
+                    path: dir.path().join("ballooned"),
+                    issue: Issue::ZeroFilled {
+                        zero_bytes: 1 << 20,
+                        logical_len: (1 << 20) + 1,
+                    },
+                },
+                Finding {
+                    path: dir.path().join("padded"),
+                    issue: Issue::TrailingBytes { extra: 100 },
+                },
+            ]
+        );
+    }
+}
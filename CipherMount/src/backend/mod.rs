@@ -0,0 +1,716 @@
+//! Storage backends: where encrypted file contents are read from and written
+//! to. `CipherFS` only ever sees ciphertext through this trait, so backends
+//! can be swapped (or wrapped) without touching the crypto layer.
+
+use crate::logging::detail;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Backing store for encrypted file contents.
+pub trait Backend: Send + Sync {
+    /// Read the whole backing file at `path`.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Read up to `len` bytes at `offset`; shorter only at end of file.
+    fn read_at(&self, path: &Path, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let all = self.read(path)?;
+        let start = (offset as usize).min(all.len());
+        let end = start.saturating_add(len).min(all.len());
+        Ok(all[start..end].to_vec())
+    }
+
+    /// Replace the backing file at `path` with `data`. Either all of it
+    /// is written or an error is returned; a store that may take less at
+    /// once loops with `write_fully`.
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()>;
+
+    /// Overwrite bytes at `offset` in the existing backing file at `path`,
+    /// extending it if the write runs past the end. All or nothing, like
+    /// `write`.
+    fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut all = self.read(path)?;
+        let start = offset as usize;
+        if all.len() < start + data.len() {
+            all.resize(start + data.len(), 0);
+        }
+        all[start..start + data.len()].copy_from_slice(data);
+        self.write(path, &all)
+    }
+
+    /// Flush the backing file at `path` to stable storage: file data only if
+    /// `data_only`, otherwise data and metadata.
+    fn sync(&self, path: &Path, data_only: bool) -> io::Result<()>;
+}
+
+/// Write all of `data` at `offset` through `write`, a `pwrite`-like call
+/// that may take only part of what it is given and returns how much it
+/// took. Loops until everything is in, retrying after EINTR; a call that
+/// takes nothing fails with `WriteZero` rather than spinning.
+pub fn write_fully(
+    mut write: impl FnMut(u64, &[u8]) -> io::Result<usize>,
+    offset: u64,
+    data: &[u8],
+) -> io::Result<()> {
+    let mut done = 0;
+    while done < data.len() {
+        match write(offset + done as u64, &data[done..]) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "backing store accepted none of a write",
+                ))
+            }
+            Ok(n) => done += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// How hard each write is pushed to stable storage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SyncPolicy {
+    /// Rely on the OS to write back in its own time.
+    None,
+    /// fdatasync after every write: contents are durable, metadata such as
+    /// mtime may lag.
+    #[default]
+    Data,
+    /// fsync after every write: contents and metadata are durable.
+    Full,
+}
+
+/// Whether closing a file syncs it, on top of `SyncPolicy`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CloseSync {
+    /// fsync on every close.
+    Always,
+    /// Leave it to the write policy and explicit fsyncs.
+    #[default]
+    Never,
+    /// fsync on close if the handle wrote since it was last synced.
+    Dirty,
+}
+
+/// Backend storing each file as a regular file on the local filesystem.
+#[derive(Debug, Default)]
+pub struct LocalBackend;
+
+impl Backend for LocalBackend {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn read_at(&self, path: &Path, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let file = fs::File::open(path)?;
+        let mut buf = vec![0u8; len];
+        let mut filled = 0;
+        while filled < len {
+            match file.read_at(&mut buf[filled..], offset + filled as u64) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        buf.truncate(filled);
+        Ok(buf)
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let file = fs::File::create(path)?;
+        write_fully(|offset, chunk| file.write_at(chunk, offset), 0, data)
+    }
+
+    fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+        let file = fs::OpenOptions::new().write(true).open(path)?;
+        write_fully(|offset, chunk| file.write_at(chunk, offset), offset, data)
+    }
+
+    fn sync(&self, path: &Path, data_only: bool) -> io::Result<()> {
+        let file = fs::File::open(path)?;
+        if data_only {
+            file.sync_data()
+        } else {
+            file.sync_all()
+        }
+    }
+}
+
+/// Wraps a backend so no single operation blocks for longer than `timeout`.
+///
+/// Operations on a path run one at a time, in order, on a worker thread of
+/// that path's own, which stays around for a while in case more follow. If
+/// one hasn't finished in time the caller gets `ErrorKind::TimedOut`. A
+/// blocked syscall can't be cancelled, so the worker is left to finish (or
+/// stay stuck) on its own — it only holds its own copies of the path and
+/// data, never filesystem locks. A write that timed out may still land, so
+/// until it returns every operation on its path fails with EIO instead of
+/// queueing: a newer write must not go in first only to be overwritten.
+pub struct TimeoutBackend {
+    inner: Arc<dyn Backend>,
+    timeout: Duration,
+    lanes: Lanes,
+}
+
+/// How long a path's worker waits for another operation before exiting.
+const LANE_IDLE: Duration = Duration::from_secs(5);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The worker of each path with operations queued, running or recent.
+type Lanes = Arc<Mutex<HashMap<PathBuf, Lane>>>;
+
+struct Lane {
+    queue: mpsc::Sender<Job>,
+    /// A write that timed out is still running.
+    stalled: bool,
+}
+
+impl TimeoutBackend {
+    pub fn new(inner: Arc<dyn Backend>, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            lanes: Arc::default(),
+        }
+    }
+
+    /// Run `f` on the worker of `path`, behind anything already queued
+    /// there. `writes` says whether it changes the file, so that timing out
+    /// stalls the path.
+    fn run<T: Send + 'static>(
+        &self,
+        op: &str,
+        path: &Path,
+        writes: bool,
+        f: impl FnOnce(&dyn Backend) -> io::Result<T> + Send + 'static,
+    ) -> io::Result<T> {
+        let (tx, rx) = mpsc::channel();
+        let abandoned = Arc::new(AtomicBool::new(false));
+        let job: Job = {
+            let (inner, lanes) = (Arc::clone(&self.inner), Arc::clone(&self.lanes));
+            let (owned, abandoned) = (PathBuf::from(path), Arc::clone(&abandoned));
+            Box::new(move || {
+                // The receiver is gone if we already timed out.
+                let _ = tx.send(f(inner.as_ref()));
+                let mut lanes = lanes.lock().unwrap();
+                if abandoned.load(Ordering::Relaxed) {
+                    if let Some(lane) = lanes.get_mut(&owned) {
+                        lane.stalled = false;
+                    }
+                }
+            })
+        };
+        {
+            let mut lanes = self.lanes.lock().unwrap();
+            match lanes.get(path) {
+                Some(lane) if lane.stalled => {
+                    log::error!(
+                        "Backing {} of {} refused: an earlier write timed out and is still running",
+                        op,
+                        detail(path)
+                    );
+                    return Err(io::Error::from_raw_os_error(libc::EIO));
+                }
+                // A worker only exits once its lane is gone, so the send
+                // can't fail.
+                Some(lane) => {
+                    let _ = lane.queue.send(job);
+                }
+                None => {
+                    let queue = spawn_lane(&self.lanes, path.to_path_buf());
+                    let _ = queue.send(job);
+                    lanes.insert(
+                        path.to_path_buf(),
+                        Lane {
+                            queue,
+                            stalled: false,
+                        },
+                    );
+                }
+            }
+        }
+        if let Ok(result) = rx.recv_timeout(self.timeout) {
+            return result;
+        }
+        let mut lanes = self.lanes.lock().unwrap();
+        // Finished just now, before the lane could be marked.
+        if let Ok(result) = rx.try_recv() {
+            return result;
+        }
+        if writes {
+            abandoned.store(true, Ordering::Relaxed);
+            if let Some(lane) = lanes.get_mut(path) {
+                lane.stalled = true;
+            }
+        }
+        log::error!(
+            "Backing {} of {} exceeded {:?}",
+            op,
+            detail(path),
+            self.timeout
+        );
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "backing I/O timed out",
+        ))
+    }
+}
+
+/// Start the worker for `path`, returning its queue. It runs jobs in order
+/// until none arrives for `LANE_IDLE`, then removes its lane and exits.
+fn spawn_lane(lanes: &Lanes, path: PathBuf) -> mpsc::Sender<Job> {
+    let (tx, rx) = mpsc::channel::<Job>();
+    let lanes = Arc::clone(lanes);
+    thread::spawn(move || loop {
+        if let Ok(job) = rx.recv_timeout(LANE_IDLE) {
+            job();
+            continue;
+        }
+        // Jobs are only queued with the lanes locked.
+        let mut locked = lanes.lock().unwrap();
+        match rx.try_recv() {
+            Ok(job) => {
+                drop(locked);
+                job();
+            }
+            Err(_) => {
+                locked.remove(&path);
+                return;
+            }
+        }
+    });
+    tx
+}
+
+impl Backend for TimeoutBackend {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let owned = PathBuf::from(path);
+        self.run("read", path, false, move |b| b.read(&owned))
+    }
+
+    fn read_at(&self, path: &Path, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let owned = PathBuf::from(path);
+        self.run("read", path, false, move |b| b.read_at(&owned, offset, len))
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let (owned, data) = (PathBuf::from(path), data.to_vec());
+        self.run("write", path, true, move |b| b.write(&owned, &data))
+    }
+
+    fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+        let (owned, data) = (PathBuf::from(path), data.to_vec());
+        self.run("write", path, true, move |b| {
+            b.write_at(&owned, offset, &data)
+        })
+    }
+
+    fn sync(&self, path: &Path, data_only: bool) -> io::Result<()> {
+        let owned = PathBuf::from(path);
+        self.run("sync", path, false, move |b| b.sync(&owned, data_only))
+    }
+}
+
+/// Wait before the first retry; each further retry waits twice as long,
+/// up to `MAX_RETRY_DELAY`.
+pub const FIRST_RETRY_DELAY: Duration = Duration::from_millis(10);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Errors retried on request on top of EINTR and EAGAIN, which always are.
+/// They say nothing about the file itself; errors that do (ENOENT, EACCES,
+/// ...) are never retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Transient {
+    /// The operation timed out, including under `--op-timeout`.
+    TimedOut,
+    /// The connection to a network source dropped.
+    Connection,
+    /// EIO: often a network source going away, but also a failing disk.
+    Io,
+}
+
+impl Transient {
+    fn matches(self, e: &io::Error) -> bool {
+        use io::ErrorKind::*;
+        match self {
+            Transient::TimedOut => e.kind() == TimedOut,
+            Transient::Connection => matches!(
+                e.kind(),
+                ConnectionReset | ConnectionAborted | NotConnected | BrokenPipe
+            ),
+            Transient::Io => e.raw_os_error() == Some(libc::EIO),
+        }
+    }
+}
+
+/// Wraps a backend so operations failing with a transient error are retried
+/// with exponential backoff before the error is passed on.
+///
+/// Reads and syncs can be repeated freely. A write that may half have
+/// happened is repeated whole, the same bytes to the same place, which is
+/// only safe because nothing else writes the file in between: the mount
+/// holds the file's content lock across the whole call, and a write that
+/// timed out under `TimeoutBackend` is never still running when its retry
+/// lands, since until it returns the path fails with EIO instead (retried
+/// only with `Transient::Io`).
+pub struct RetryBackend {
+    inner: Arc<dyn Backend>,
+    retries: u32,
+    first_delay: Duration,
+    transient: Vec<Transient>,
+}
+
+impl RetryBackend {
+    /// Retry up to `retries` times on EINTR, EAGAIN and `transient`.
+    pub fn new(inner: Arc<dyn Backend>, retries: u32, transient: &[Transient]) -> Self {
+        Self {
+            inner,
+            retries,
+            first_delay: FIRST_RETRY_DELAY,
+            transient: transient.to_vec(),
+        }
+    }
+
+    /// Wait `first_delay` before the first retry instead of the default.
+    pub fn with_first_delay(mut self, first_delay: Duration) -> Self {
+        self.first_delay = first_delay;
+        self
+    }
+
+    fn is_transient(&self, e: &io::Error) -> bool {
+        matches!(
+            e.kind(),
+            io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+        ) || self.transient.iter().any(|t| t.matches(e))
+    }
+
+    fn run<T>(
+        &self,
+        op: &str,
+        path: &Path,
+        f: impl Fn(&dyn Backend) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut delay = self.first_delay;
+        let mut attempt = 0;
+        loop {
+            match f(self.inner.as_ref()) {
+                Err(e) if attempt < self.retries && self.is_transient(&e) => {
+                    attempt += 1;
+                    log::warn!(
+                        "Backing {} of {} failed ({}); retry {} of {} in {:?}",
+                        op,
+                        detail(path),
+                        e,
+                        attempt,
+                        self.retries,
+                        delay
+                    );
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Backend for RetryBackend {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.run("read", path, |b| b.read(path))
+    }
+
+    fn read_at(&self, path: &Path, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        self.run("read", path, |b| b.read_at(path, offset, len))
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        self.run("write", path, |b| b.write(path, data))
+    }
+
+    fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.run("write", path, |b| b.write_at(path, offset, data))
+    }
+
+    fn sync(&self, path: &Path, data_only: bool) -> io::Result<()> {
+        self.run("sync", path, |b| b.sync(path, data_only))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    struct SlowBackend(Duration);
+
+    impl Backend for SlowBackend {
+        fn read(&self, _path: &Path) -> io::Result<Vec<u8>> {
+            thread::sleep(self.0);
+            Ok(vec![1, 2, 3])
+        }
+
+        fn write(&self, _path: &Path, _data: &[u8]) -> io::Result<()> {
+            thread::sleep(self.0);
+            Ok(())
+        }
+
+        fn sync(&self, _path: &Path, _data_only: bool) -> io::Result<()> {
+            thread::sleep(self.0);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn slow_operation_times_out() {
+        let slow = Arc::new(SlowBackend(Duration::from_secs(5)));
+        let backend = TimeoutBackend::new(slow, Duration::from_millis(50));
+
+        let start = Instant::now();
+        let err = backend.read(Path::new("x")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let err = backend.write(Path::new("x"), b"data").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    /// In-memory store whose first write waits for `release`.
+    struct Gated {
+        files: Mutex<HashMap<PathBuf, Vec<u8>>>,
+        gate: Mutex<Option<mpsc::Receiver<()>>>,
+    }
+
+    impl Backend for Gated {
+        fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+            let files = self.files.lock().unwrap();
+            files
+                .get(path)
+                .cloned()
+                .ok_or(io::ErrorKind::NotFound.into())
+        }
+
+        fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+            let gate = self.gate.lock().unwrap().take();
+            if let Some(gate) = gate {
+                gate.recv().unwrap();
+            }
+            self.files
+                .lock()
+                .unwrap()
+                .insert(path.into(), data.to_vec());
+            Ok(())
+        }
+
+        fn sync(&self, _path: &Path, _data_only: bool) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn timed_out_write_fails_its_path_until_it_lands() {
+        let (release, gate) = mpsc::channel();
+        let inner = Arc::new(Gated {
+            files: Mutex::default(),
+            gate: Mutex::new(Some(gate)),
+        });
+        let backend = TimeoutBackend::new(inner, Duration::from_millis(50));
+        let path = Path::new("f");
+        let err = backend.write(path, b"old").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        // Queued behind it, this would be overwritten once it lands.
+        let err = backend.write(path, b"new").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        let err = backend.read(path).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        backend.write(Path::new("other"), b"fine").unwrap();
+
+        release.send(()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while backend.read(path).is_err() {
+            assert!(Instant::now() < deadline, "the late write never landed");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(backend.read(path).unwrap(), b"old");
+        backend.write(path, b"new").unwrap();
+        assert_eq!(backend.read(path).unwrap(), b"new");
+    }
+
+    #[test]
+    fn local_read_at_stops_at_end_of_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f");
+        fs::write(&path, b"0123456789").unwrap();
+        assert_eq!(LocalBackend.read_at(&path, 3, 4).unwrap(), b"3456");
+        assert_eq!(LocalBackend.read_at(&path, 8, 4).unwrap(), b"89");
+        assert_eq!(LocalBackend.read_at(&path, 20, 4).unwrap(), b"");
+    }
+
+    #[test]
+    fn local_write_at_overwrites_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f");
+        fs::write(&path, b"0123456789").unwrap();
+        LocalBackend.write_at(&path, 2, b"ab").unwrap();
+        LocalBackend.write_at(&path, 9, b"XY").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"01ab45678XY");
+    }
+
+    /// Fails reads with `error` until `failures` runs out.
+    struct Flaky {
+        failures: std::sync::atomic::AtomicU32,
+        calls: std::sync::atomic::AtomicU32,
+        error: fn() -> io::Error,
+    }
+
+    impl Flaky {
+        fn new(failures: u32, error: fn() -> io::Error) -> Arc<Self> {
+            Arc::new(Self {
+                failures: failures.into(),
+                calls: std::sync::atomic::AtomicU32::new(0),
+                error,
+            })
+        }
+    }
+
+    impl Backend for Flaky {
+        fn read(&self, _path: &Path) -> io::Result<Vec<u8>> {
+            use std::sync::atomic::Ordering::SeqCst;
+            self.calls.fetch_add(1, SeqCst);
+            match self
+                .failures
+                .fetch_update(SeqCst, SeqCst, |n| n.checked_sub(1))
+            {
+                Ok(_) => Err((self.error)()),
+                Err(_) => Ok(vec![1, 2, 3]),
+            }
+        }
+
+        fn write(&self, _path: &Path, _data: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn sync(&self, _path: &Path, _data_only: bool) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn transient_errors_are_retried_within_budget() {
+        use std::sync::atomic::Ordering::SeqCst;
+        let eagain = || io::Error::from_raw_os_error(libc::EAGAIN);
+        let retrying = |inner: Arc<Flaky>, transient: &[Transient]| {
+            RetryBackend::new(inner, 3, transient).with_first_delay(Duration::from_millis(1))
+        };
+
+        let flaky = Flaky::new(3, eagain);
+        let backend = retrying(flaky.clone(), &[]);
+        assert_eq!(backend.read(Path::new("x")).unwrap(), vec![1, 2, 3]);
+        assert_eq!(flaky.calls.load(SeqCst), 4);
+
+        let flaky = Flaky::new(4, eagain);
+        assert!(retrying(flaky.clone(), &[]).read(Path::new("x")).is_err());
+        assert_eq!(flaky.calls.load(SeqCst), 4);
+
+        // EIO only when asked for; ENOENT never.
+        let eio = || io::Error::from_raw_os_error(libc::EIO);
+        assert!(retrying(Flaky::new(1, eio), &[])
+            .read(Path::new("x"))
+            .is_err());
+        let flaky = Flaky::new(1, eio);
+        assert!(retrying(flaky, &[Transient::Io])
+            .read(Path::new("x"))
+            .is_ok());
+        let flaky = Flaky::new(1, || io::Error::from(io::ErrorKind::NotFound));
+        let all = [Transient::TimedOut, Transient::Connection, Transient::Io];
+        assert!(retrying(flaky.clone(), &all).read(Path::new("x")).is_err());
+        assert_eq!(flaky.calls.load(SeqCst), 1);
+    }
+
+    /// In-memory store whose transport takes at most `limit` bytes a call.
+    struct ShortWrites {
+        files: std::sync::Mutex<std::collections::HashMap<PathBuf, Vec<u8>>>,
+        limit: usize,
+    }
+
+    impl ShortWrites {
+        fn pwrite(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<usize> {
+            let n = data.len().min(self.limit);
+            let mut files = self.files.lock().unwrap();
+            let file = files.entry(path.to_path_buf()).or_default();
+            let (start, end) = (offset as usize, offset as usize + n);
+            if file.len() < end {
+                file.resize(end, 0);
+            }
+            file[start..end].copy_from_slice(&data[..n]);
+            Ok(n)
+        }
+    }
+
+    impl Backend for ShortWrites {
+        fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+            let files = self.files.lock().unwrap();
+            files
+                .get(path)
+                .cloned()
+                .ok_or(io::ErrorKind::NotFound.into())
+        }
+
+        fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+            self.files.lock().unwrap().insert(path.into(), vec![]);
+            write_fully(|offset, chunk| self.pwrite(path, offset, chunk), 0, data)
+        }
+
+        fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+            write_fully(
+                |offset, chunk| self.pwrite(path, offset, chunk),
+                offset,
+                data,
+            )
+        }
+
+        fn sync(&self, _path: &Path, _data_only: bool) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn short_writes_are_continued_until_complete() {
+        let backend = ShortWrites {
+            files: Default::default(),
+            limit: 7,
+        };
+        let path = Path::new("f");
+        let ciphertext = crate::format::encrypt_file(&[9u8; 32], &[5u8; 3000], 256).unwrap();
+        backend.write(path, &ciphertext).unwrap();
+        assert_eq!(backend.read(path).unwrap(), ciphertext);
+        backend.write_at(path, 100, &[0xee; 50]).unwrap();
+        assert_eq!(backend.read(path).unwrap()[100..150], [0xee; 50]);
+
+        let mut interrupted = false;
+        let flaky = |_, chunk: &[u8]| -> io::Result<usize> {
+            if !std::mem::replace(&mut interrupted, true) {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            Ok(chunk.len())
+        };
+        assert!(write_fully(flaky, 0, b"data").is_ok());
+        let stalled = write_fully(|_, _| Ok(0), 0, b"data").unwrap_err();
+        assert_eq!(stalled.kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn fast_operation_passes_through() {
+        let fast = Arc::new(SlowBackend(Duration::ZERO));
+        let backend = TimeoutBackend::new(fast, Duration::from_secs(5));
+        assert_eq!(backend.read(Path::new("x")).unwrap(), vec![1, 2, 3]);
+    }
+}
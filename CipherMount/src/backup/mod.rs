@@ -0,0 +1,181 @@
+//! Checking that a backup copy of a vault is a faithful replica.
+//!
+//! `validate` compares an original vault with a copy file by file: every
+//! file must be present in both, with identical ciphertext and metadata
+//! sidecar, and every file in the copy must authenticate under the key. A
+//! byte-identical copy of a damaged original is still reported, since it
+//! can't be restored from. The vault's own canary and `vault.meta` must
+//! match too.
+
+use crate::{layout, meta, scrub, vault, walk};
+use anyhow::Result;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Something wrong with one file of a backup.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// In the original but not the backup.
+    Missing,
+    /// In the backup but not the original.
+    Extra,
+    /// Present in both, but the ciphertext or metadata differs.
+    Differs,
+    /// The backup's copy fails authentication.
+    Unauthentic(String),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Missing => f.write_str("missing from the backup"),
+            Problem::Extra => f.write_str("only in the backup"),
+            Problem::Differs => f.write_str("differs from the original"),
+            Problem::Unauthentic(e) => write!(f, "fails authentication: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Finding {
+    /// Path relative to the vault root.
+    pub path: PathBuf,
+    pub problem: Problem,
+}
+
+/// Relative paths of the regular files under `root`.
+fn files(root: &Path, options: walk::Options) -> Result<BTreeSet<PathBuf>> {
+    let mut out = BTreeSet::new();
+    walk::walk(root, options, |entry| {
+        if entry.file_type.is_file() {
+            out.insert(entry.path.strip_prefix(root)?.to_path_buf());
+        }
+        Ok(())
+    })?;
+    Ok(out)
+}
+
+/// Contents of the file at `path`, or `None` if it doesn't exist.
+fn read_optional(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Compare the backup at `backup` with the vault at `source`. Returns every
+/// problem found, vault files first and then in path order; an empty list
+/// means the backup is good.
+pub fn validate(
+    source: &Path,
+    backup: &Path,
+    key: &[u8; 32],
+    options: walk::Options,
+) -> Result<Vec<Finding>> {
+    let original = files(source, options)?;
+    let copy = files(backup, options)?;
+    let mut findings = vec![];
+    for name in [vault::CANARY_FILE, vault::VAULT_META_FILE] {
+        let (a, b) = (
+            read_optional(&source.join(name))?,
+            read_optional(&backup.join(name))?,
+        );
+        let problem = match (&a, &b) {
+            (Some(_), None) => Problem::Missing,
+            _ if a != b => Problem::Differs,
+            _ => continue,
+        };
+        findings.push(Finding {
+            path: name.into(),
+            problem,
+        });
+    }
+    for path in original.union(&copy) {
+        let problem = if !copy.contains(path) {
+            Some(Problem::Missing)
+        } else if !original.contains(path) {
+            Some(Problem::Extra)
+        } else if let Err(e) = scrub::verify(key, &backup.join(path)) {
+            Some(Problem::Unauthentic(e.to_string()))
+        } else {
+            let (a, b) = (source.join(path), backup.join(path));
+            let same = layout::read(&a)? == layout::read(&b)?
+                && read_optional(&meta::sidecar_path(&a))?
+                    == read_optional(&meta::sidecar_path(&b))?;
+            (!same).then_some(Problem::Differs)
+        };
+        if let Some(problem) = problem {
+            findings.push(Finding {
+                path: path.clone(),
+                problem,
+            });
+        }
+    }
+    Ok(findings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{format, vault};
+
+    const KEY: [u8; 32] = [0x42u8; 32];
+
+    fn copy_tree(from: &Path, to: &Path) {
+        for entry in fs::read_dir(from).unwrap().flatten() {
+            let target = to.join(entry.file_name());
+            if entry.file_type().unwrap().is_dir() {
+                fs::create_dir(&target).unwrap();
+                copy_tree(&entry.path(), &target);
+            } else {
+                fs::copy(entry.path(), target).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn complete_backup_passes_and_gaps_are_flagged() {
+        let source = tempfile::tempdir().unwrap();
+        vault::init(source.path(), &KEY).unwrap();
+        fs::create_dir(source.path().join("docs")).unwrap();
+        for (name, data) in [("a", &b"alpha"[..]), ("docs/b", b"bravo"), ("docs/c", b"")] {
+            let raw = format::encrypt_file(&KEY, data, 64).unwrap();
+            fs::write(source.path().join(name), raw).unwrap();
+        }
+        meta::FileMeta {
+            expires_at: Some(99),
+            ..meta::FileMeta::default()
+        }
+        .store(&KEY, source.path(), &source.path().join("a"))
+        .unwrap();
+
+        let backup = tempfile::tempdir().unwrap();
+        copy_tree(source.path(), backup.path());
+        let check = || validate(source.path(), backup.path(), &KEY, walk::Options::default());
+        assert_eq!(check().unwrap(), vec![]);
+
+        fs::remove_file(backup.path().join("docs/b")).unwrap();
+        fs::remove_file(meta::sidecar_path(&backup.path().join("a"))).unwrap();
+        let mut raw = fs::read(backup.path().join("docs/c")).unwrap();
+        *raw.last_mut().unwrap() ^= 1;
+        fs::write(backup.path().join("docs/c"), raw).unwrap();
+        fs::write(backup.path().join("stray"), b"").unwrap();
+        fs::remove_file(backup.path().join(vault::CANARY_FILE)).unwrap();
+
+        let problems: Vec<_> = check()
+            .unwrap()
+            .into_iter()
+            .map(|f| (f.path.to_string_lossy().into_owned(), f.problem))
+            .collect();
+        assert_eq!(problems.len(), 5);
+        assert_eq!(problems[0], (vault::CANARY_FILE.into(), Problem::Missing));
+        assert_eq!(problems[1], ("a".into(), Problem::Differs));
+        assert_eq!(problems[2], ("docs/b".into(), Problem::Missing));
+        assert!(matches!(&problems[3], (p, Problem::Unauthentic(_)) if p == "docs/c"));
+        assert_eq!(problems[4], ("stray".into(), Problem::Extra));
+    }
+}
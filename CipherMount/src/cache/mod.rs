@@ -0,0 +1,253 @@
+//! Cache of decrypted blocks, keyed by (inode, block index).
+//!
+//! Entries are evicted least-recently-used once `capacity` blocks are held.
+//! When the mount re-authenticates cached data on every read, each entry
+//! also keeps the sealed block it was decrypted from. A locked cache keeps
+//! its plaintext out of swap with `mlock` for as long as it is cached.
+//!
+//! FUSE doesn't pass `posix_fadvise` on to the filesystem, so applications
+//! announce their access pattern through `ADVICE_XATTR` instead, with the
+//! same names; the mount then preloads, reads ahead or drops blocks.
+
+use crate::memlock;
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// Extended attribute a process sets on a file to advise how it will read
+/// it: `normal`, `sequential`, `random`, `willneed` or `dontneed`.
+pub const ADVICE_XATTR: &str = "user.ciphermount.advice";
+
+/// Access pattern announced for a file, after `posix_fadvise`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advice {
+    /// No particular pattern: stop reading ahead.
+    Normal,
+    /// Read front to back: each read also loads the blocks that follow.
+    Sequential,
+    /// Read in no order: stop reading ahead.
+    Random,
+    /// About to be read: load the file's blocks now.
+    WillNeed,
+    /// Not read again soon: drop the file's blocks.
+    DontNeed,
+}
+
+impl FromStr for Advice {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        Ok(match name {
+            "normal" => Advice::Normal,
+            "sequential" => Advice::Sequential,
+            "random" => Advice::Random,
+            "willneed" => Advice::WillNeed,
+            "dontneed" => Advice::DontNeed,
+            _ => bail!("Unknown advice {:?}", name),
+        })
+    }
+}
+
+/// A cached block.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub plaintext: Arc<Vec<u8>>,
+    /// The sealed block, kept only when every hit must be re-authenticated.
+    pub sealed: Option<Arc<Vec<u8>>>,
+}
+
+struct Slot {
+    entry: Entry,
+    last_used: u64,
+    _locked: Option<memlock::Locked>,
+}
+
+#[derive(Default)]
+struct Inner {
+    slots: HashMap<(u64, u64), Slot>,
+    tick: u64,
+}
+
+pub struct BlockCache {
+    capacity: usize,
+    lock_memory: bool,
+    inner: Mutex<Inner>,
+}
+
+impl BlockCache {
+    /// A cache holding at most `capacity` blocks (0 disables caching).
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lock_memory: false,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Like `new`, but cached plaintext is locked in memory.
+    pub fn locked(capacity: usize) -> Self {
+        Self {
+            lock_memory: true,
+            ..Self::new(capacity)
+        }
+    }
+
+    /// Most blocks the cache holds.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Whether block `index` of `ino` is cached, without counting as a use.
+    pub fn contains(&self, ino: u64, index: u64) -> bool {
+        self.inner.lock().unwrap().slots.contains_key(&(ino, index))
+    }
+
+    pub fn get(&self, ino: u64, index: u64) -> Option<Entry> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let slot = inner.slots.get_mut(&(ino, index))?;
+        slot.last_used = tick;
+        Some(slot.entry.clone())
+    }
+
+    pub fn insert(&self, ino: u64, index: u64, entry: Entry) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if inner.slots.len() >= self.capacity && !inner.slots.contains_key(&(ino, index)) {
+            let oldest = inner
+                .slots
+                .iter()
+                .min_by_key(|(_, slot)| slot.last_used)
+                .map(|(k, _)| *k);
+            if let Some(oldest) = oldest {
+                inner.slots.remove(&oldest);
+            }
+        }
+        inner.tick += 1;
+        let last_used = inner.tick;
+        let locked = if self.lock_memory {
+            memlock::lock(&entry.plaintext)
+        } else {
+            None
+        };
+        let slot = Slot {
+            entry,
+            last_used,
+            _locked: locked,
+        };
+        inner.slots.insert((ino, index), slot);
+    }
+
+    /// Drop every cached block of `ino`.
+    pub fn invalidate(&self, ino: u64) {
+        self.inner
+            .lock()
+            .unwrap()
+            .slots
+            .retain(|(i, _), _| *i != ino);
+    }
+
+    /// Drop a single cached block.
+    pub fn remove(&self, ino: u64, index: u64) {
+        self.inner.lock().unwrap().slots.remove(&(ino, index));
+    }
+
+    /// Cached blocks of `ino` and their plaintext bytes.
+    pub fn usage(&self, ino: u64) -> (usize, u64) {
+        self.inner
+            .lock()
+            .unwrap()
+            .slots
+            .iter()
+            .filter(|((i, _), _)| *i == ino)
+            .fold((0, 0), |(blocks, bytes), (_, slot)| {
+                (blocks + 1, bytes + slot.entry.plaintext.len() as u64)
+            })
+    }
+
+    /// Number of cached blocks.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(byte: u8) -> Entry {
+        Entry {
+            plaintext: Arc::new(vec![byte]),
+            sealed: None,
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = BlockCache::new(2);
+        cache.insert(1, 0, entry(0));
+        cache.insert(1, 1, entry(1));
+        cache.get(1, 0);
+        cache.insert(1, 2, entry(2));
+
+        assert!(cache.get(1, 0).is_some());
+        assert!(cache.get(1, 1).is_none());
+        assert!(cache.get(1, 2).is_some());
+    }
+
+    /// Locked memory of this process, from /proc/self/status.
+    fn vm_locked_kib() -> u64 {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap();
+        status
+            .lines()
+            .find_map(|l| l.strip_prefix("VmLck:"))
+            .and_then(|v| v.trim().trim_end_matches("kB").trim().parse().ok())
+            .unwrap()
+    }
+
+    #[test]
+    fn locked_cache_pins_plaintext_until_evicted() {
+        const BLOCK: usize = 256 * 1024;
+        if memlock::limit()
+            .unwrap()
+            .is_some_and(|l| l < 2 * BLOCK as u64)
+        {
+            eprintln!("RLIMIT_MEMLOCK too low to test locking; skipped");
+            return;
+        }
+        let cache = BlockCache::locked(1);
+        let before = vm_locked_kib();
+        let plaintext = Arc::new(vec![7u8; BLOCK]);
+        cache.insert(
+            1,
+            0,
+            Entry {
+                plaintext,
+                sealed: None,
+            },
+        );
+        assert!(vm_locked_kib() >= before + BLOCK as u64 / 1024);
+
+        // Evicted by the next block, which locks a page at most.
+        cache.insert(1, 1, entry(1));
+        assert!(vm_locked_kib() < before + BLOCK as u64 / 1024);
+    }
+
+    #[test]
+    fn invalidate_drops_only_that_inode() {
+        let cache = BlockCache::new(8);
+        cache.insert(1, 0, entry(0));
+        cache.insert(2, 0, entry(0));
+        cache.invalidate(1);
+        assert!(cache.get(1, 0).is_none());
+        assert!(cache.get(2, 0).is_some());
+    }
+}
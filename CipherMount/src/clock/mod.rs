@@ -0,0 +1,99 @@
+//! Wall-clock time that never runs backwards.
+//!
+//! Expiry and sealed mtimes are wall-clock times, so they must survive the
+//! system clock being stepped back (an NTP correction, a restored VM
+//! snapshot). Read naively, a backward step would bring expired files back
+//! until the clock caught up again. A `Clock` remembers the latest time it
+//! has handed out and holds there while the system clock is behind it, so
+//! an expired file stays expired and a new mtime is never older than one
+//! already stamped. A forward step is taken as is: it can't be told apart
+//! from time genuinely passing.
+//!
+//! Nonces don't depend on the clock at all: random ones come from the RNG
+//! and counter ones from the persisted counter.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Nanoseconds in a second.
+const NANOS: u64 = 1_000_000_000;
+
+/// A monotonic view of a wall clock.
+pub struct Clock {
+    source: Box<dyn Fn() -> u64 + Send + Sync>,
+    /// Latest Unix time (nanoseconds) handed out.
+    latest: AtomicU64,
+    /// The source is currently behind `latest`, already reported.
+    behind: AtomicBool,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::system()
+    }
+}
+
+impl Clock {
+    /// The system clock.
+    pub fn system() -> Self {
+        Self::with_source(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64
+        })
+    }
+
+    /// A clock reading Unix time in nanoseconds from `source`.
+    pub fn with_source(source: impl Fn() -> u64 + Send + Sync + 'static) -> Self {
+        Self {
+            source: Box::new(source),
+            latest: AtomicU64::new(0),
+            behind: AtomicBool::new(false),
+        }
+    }
+
+    /// Current Unix time in nanoseconds, never less than any earlier reading.
+    pub fn now_ns(&self) -> u64 {
+        let now = (self.source)();
+        let latest = self.latest.fetch_max(now, Ordering::SeqCst);
+        if now >= latest {
+            if self.behind.swap(false, Ordering::Relaxed) {
+                log::info!("System clock caught up again");
+            }
+            return now;
+        }
+        if !self.behind.swap(true, Ordering::Relaxed) {
+            log::warn!(
+                "System clock went back {} ms; holding time until it catches up",
+                (latest - now) / 1_000_000
+            );
+        }
+        latest
+    }
+
+    /// Current Unix time in seconds, never less than any earlier reading.
+    pub fn now(&self) -> u64 {
+        self.now_ns() / NANOS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn holds_time_while_the_source_is_behind() {
+        let source = Arc::new(AtomicU64::new(100 * NANOS));
+        let clock = Clock::with_source({
+            let source = Arc::clone(&source);
+            move || source.load(Ordering::SeqCst)
+        });
+        assert_eq!(clock.now(), 100);
+        source.store(40 * NANOS, Ordering::SeqCst);
+        assert_eq!(clock.now(), 100);
+        source.store(130 * NANOS, Ordering::SeqCst);
+        assert_eq!(clock.now(), 130);
+    }
+}
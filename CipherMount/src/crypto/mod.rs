@@ -4,12 +4,13 @@
 ///   [ 12-byte nonce ][ ciphertext + 16-byte GCM tag ]
 ///
 /// The nonce is randomly generated on every write so that encrypting the
-/// same plaintext twice produces different ciphertext.
+/// same plaintext twice produces different ciphertext. Callers that need a
+/// different guarantee pass their own `NonceSource` (see `nonce`).
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use ring::aead::{
-    Aad, BoundKey, Nonce, NonceSequence, OpeningKey, SealingKey, UnboundKey, AES_256_GCM,
-    NONCE_LEN,
+    Aad, Algorithm, BoundKey, Nonce, NonceSequence, OpeningKey, SealingKey, UnboundKey,
+    AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN,
 };
 use ring::error::Unspecified;
 use ring::rand::{SecureRandom, SystemRandom};
@@ -25,19 +26,103 @@ impl NonceSequence for SingleNonce {
     }
 }
 
+/// AEAD used to seal data. Both take a 32-byte key and a 12-byte nonce and
+/// add a 16-byte tag, so blobs have the same layout and size under either.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Cipher {
+    #[default]
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// Name used on the command line and in extended attributes.
+    pub fn name(self) -> &'static str {
+        match self {
+            Cipher::Aes256Gcm => "aes-256-gcm",
+            Cipher::ChaCha20Poly1305 => "chacha20-poly1305",
+        }
+    }
+
+    fn algorithm(self) -> &'static Algorithm {
+        match self {
+            Cipher::Aes256Gcm => &AES_256_GCM,
+            Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+        }
+    }
+}
+
+impl std::str::FromStr for Cipher {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "aes-256-gcm" => Ok(Cipher::Aes256Gcm),
+            "chacha20-poly1305" => Ok(Cipher::ChaCha20Poly1305),
+            _ => bail!(
+                "Unknown cipher {:?} (expected aes-256-gcm or chacha20-poly1305)",
+                name
+            ),
+        }
+    }
+}
+
+/// Where the nonces for `encrypt_with_nonces` come from. Every nonce must
+/// be unique under the key it is used with.
+pub trait NonceSource: Send + Sync {
+    fn next_nonce(&self) -> Result<[u8; NONCE_LEN]>;
+}
+
+/// Fresh random nonces from the system RNG.
+#[derive(Debug, Default)]
+pub struct RandomNonces;
+
+impl NonceSource for RandomNonces {
+    fn next_nonce(&self) -> Result<[u8; NONCE_LEN]> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).map_err(|_| anyhow!("RNG failure"))?;
+        Ok(nonce)
+    }
+}
+
 /// Encrypt `plaintext` with AES-256-GCM.
 /// Returns `nonce || ciphertext || tag`.
 pub fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>> {
-    let rng = SystemRandom::new();
-    let mut nonce_bytes = [0u8; NONCE_LEN];
-    rng.fill(&mut nonce_bytes).map_err(|_| anyhow!("RNG failure"))?;
+    encrypt_with_aad(key, plaintext, &[])
+}
 
-    let unbound = UnboundKey::new(&AES_256_GCM, key).map_err(|_| anyhow!("Bad key"))?;
+/// Like `encrypt`, but also authenticates `aad` (which is not stored).
+/// The same `aad` must be supplied to `decrypt_with_aad`.
+pub fn encrypt_with_aad(key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    encrypt_with_nonces(key, &RandomNonces, plaintext, aad)
+}
+
+/// Like `encrypt_with_aad`, with the nonce taken from `nonces`.
+pub fn encrypt_with_nonces(
+    key: &[u8; 32],
+    nonces: &dyn NonceSource,
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    encrypt_with_cipher(Cipher::Aes256Gcm, key, nonces, plaintext, aad)
+}
+
+/// Like `encrypt_with_nonces`, sealing with `cipher`.
+pub fn encrypt_with_cipher(
+    cipher: Cipher,
+    key: &[u8; 32],
+    nonces: &dyn NonceSource,
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    let nonce_bytes = nonces.next_nonce()?;
+
+    let unbound = UnboundKey::new(cipher.algorithm(), key).map_err(|_| anyhow!("Bad key"))?;
     let mut sealing = SealingKey::new(unbound, SingleNonce(nonce_bytes));
 
     let mut buf = plaintext.to_vec();
     sealing
-        .seal_in_place_append_tag(Aad::empty(), &mut buf)
+        .seal_in_place_append_tag(Aad::from(aad), &mut buf)
         .map_err(|_| anyhow!("Encryption failed"))?;
 
     let mut out = Vec::with_capacity(NONCE_LEN + buf.len());
@@ -49,6 +134,22 @@ pub fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>> {
 /// Decrypt a blob produced by `encrypt`.
 /// Input must be at least `HEADER_LEN + 16` bytes (nonce + GCM tag).
 pub fn decrypt(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>> {
+    decrypt_with_aad(key, data, &[])
+}
+
+/// Decrypt a blob produced by `encrypt_with_aad` with the same `aad`.
+pub fn decrypt_with_aad(key: &[u8; 32], data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    decrypt_with_cipher(Cipher::Aes256Gcm, key, data, aad)
+}
+
+/// Decrypt a blob produced by `encrypt_with_cipher` with the same `cipher`
+/// and `aad`.
+pub fn decrypt_with_cipher(
+    cipher: Cipher,
+    key: &[u8; 32],
+    data: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
     if data.len() < HEADER_LEN + 16 {
         return Err(anyhow!("Ciphertext too short"));
     }
@@ -56,17 +157,72 @@ pub fn decrypt(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>> {
     let (nonce_bytes, ciphertext) = data.split_at(HEADER_LEN);
     let nonce: [u8; NONCE_LEN] = nonce_bytes.try_into().unwrap();
 
-    let unbound = UnboundKey::new(&AES_256_GCM, key).map_err(|_| anyhow!("Bad key"))?;
+    let unbound = UnboundKey::new(cipher.algorithm(), key).map_err(|_| anyhow!("Bad key"))?;
     let mut opening = OpeningKey::new(unbound, SingleNonce(nonce));
 
     let mut buf = ciphertext.to_vec();
     let plaintext = opening
-        .open_in_place(Aad::empty(), &mut buf)
+        .open_in_place(Aad::from(aad), &mut buf)
         .map_err(|_| anyhow!("Decryption failed (wrong key or corrupted data)"))?;
 
     Ok(plaintext.to_vec())
 }
 
+/// What the GCM tag of an authenticate-only blob covers: `aad` (length
+/// prefixed, so it can't run into the data) followed by the data itself.
+fn authenticated_aad(aad: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut out = (aad.len() as u64).to_le_bytes().to_vec();
+    out.extend_from_slice(aad);
+    out.extend_from_slice(data);
+    out
+}
+
+/// Authenticate `data` and `aad` without encrypting anything. Returns
+/// `nonce || data || tag` — the `encrypt` layout with `data` left readable —
+/// where the tag is AES-GCM's over both as associated data (GMAC).
+pub fn authenticate_with_nonces(
+    key: &[u8; 32],
+    nonces: &dyn NonceSource,
+    data: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    let nonce_bytes = nonces.next_nonce()?;
+
+    let unbound = UnboundKey::new(&AES_256_GCM, key).map_err(|_| anyhow!("Bad key"))?;
+    let mut sealing = SealingKey::new(unbound, SingleNonce(nonce_bytes));
+    let tag = sealing
+        .seal_in_place_separate_tag(Aad::from(authenticated_aad(aad, data)), &mut [])
+        .map_err(|_| anyhow!("Authentication failed"))?;
+
+    let mut out = Vec::with_capacity(NONCE_LEN + data.len() + 16);
+    out.extend_from_slice(&nonce_bytes);
+    out.extend_from_slice(data);
+    out.extend_from_slice(tag.as_ref());
+    Ok(out)
+}
+
+/// Check a blob produced by `authenticate_with_nonces` with the same `aad`
+/// and return its data.
+pub fn verify_with_aad(key: &[u8; 32], blob: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if blob.len() < HEADER_LEN + 16 {
+        return Err(anyhow!("Authenticated data too short"));
+    }
+
+    let (nonce_bytes, rest) = blob.split_at(HEADER_LEN);
+    let (data, tag) = rest.split_at(rest.len() - 16);
+    let nonce: [u8; NONCE_LEN] = nonce_bytes.try_into().unwrap();
+
+    let unbound = UnboundKey::new(&AES_256_GCM, key).map_err(|_| anyhow!("Bad key"))?;
+    let mut opening = OpeningKey::new(unbound, SingleNonce(nonce));
+
+    let mut buf = tag.to_vec();
+    opening
+        .open_in_place(Aad::from(authenticated_aad(aad, data)), &mut buf)
+        .map_err(|_| anyhow!("Verification failed (wrong key or modified data)"))?;
+
+    Ok(data.to_vec())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -96,4 +252,41 @@ mod tests {
         let ct2 = encrypt(&key, pt).unwrap();
         assert_ne!(ct1, ct2); // different nonces → different ciphertext
     }
+
+    #[test]
+    fn authenticated_data_stays_readable_but_not_editable() {
+        let key = [0x42u8; 32];
+        let blob = authenticate_with_nonces(&key, &RandomNonces, b"public", b"a/b").unwrap();
+        assert_eq!(&blob[HEADER_LEN..HEADER_LEN + 6], b"public");
+        assert_eq!(verify_with_aad(&key, &blob, b"a/b").unwrap(), b"public");
+        assert!(verify_with_aad(&key, &blob, b"a/c").is_err());
+        let mut edited = blob.clone();
+        edited[HEADER_LEN] ^= 1;
+        assert!(verify_with_aad(&key, &edited, b"a/b").is_err());
+    }
+
+    #[test]
+    fn ciphers_are_not_interchangeable() {
+        let key = [0x42u8; 32];
+        let cipher: Cipher = "chacha20-poly1305".parse().unwrap();
+        let ct = encrypt_with_cipher(cipher, &key, &RandomNonces, b"meta", b"a/b").unwrap();
+        assert_eq!(
+            ct.len(),
+            encrypt_with_aad(&key, b"meta", b"a/b").unwrap().len()
+        );
+        assert_eq!(
+            decrypt_with_cipher(cipher, &key, &ct, b"a/b").unwrap(),
+            b"meta"
+        );
+        assert!(decrypt_with_aad(&key, &ct, b"a/b").is_err());
+        assert!("rot13".parse::<Cipher>().is_err());
+    }
+
+    #[test]
+    fn aad_mismatch_fails() {
+        let key = [0x42u8; 32];
+        let ct = encrypt_with_aad(&key, b"meta", b"a/b").unwrap();
+        assert_eq!(decrypt_with_aad(&key, &ct, b"a/b").unwrap(), b"meta");
+        assert!(decrypt_with_aad(&key, &ct, b"a/c").is_err());
+    }
 }
@@ -0,0 +1,317 @@
+//! Deduplicated block storage (`--block-layout dedup`).
+//!
+//! Blocks are sealed convergently: a block's key is an HMAC of its
+//! plaintext under the vault key, and it is sealed under that key with a
+//! fixed nonce (safe, since each key only ever seals the one plaintext it
+//! was derived from). Identical blocks therefore produce identical
+//! ciphertext, which is stored once in the vault's block store,
+//! `.cmstore/<SHA-256 of the ciphertext>`. The file itself holds each
+//! block's key, sealed to its position (see `format`).
+//!
+//! Reference counting is left to the filesystem: every block of a file is a
+//! hard link `.cmblocks.<name>/<i>` to its store entry, so an entry's link
+//! count is one more than the number of blocks referencing it. Dropping the
+//! last reference removes the entry, and `collect_garbage` sweeps entries
+//! left unreferenced by a crash.
+//!
+//! The trade-off is the one every convergent scheme makes. Anyone who can
+//! see the backing store learns which blocks are identical, within and
+//! across files, and how often each occurs. Because the block key depends
+//! on the vault key, confirming that a vault holds a guessed file still
+//! requires the key — but it is no longer the only thing hiding that two
+//! files share content. Use it only where that is acceptable.
+
+use crate::crypto::{self, NonceSource};
+use crate::{format, layout};
+use anyhow::{anyhow, ensure, Result};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use ring::{digest, hmac};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Block store directory, relative to the vault root.
+pub const STORE_DIR: &str = ".cmstore";
+
+/// Prefix of entries being written to the store.
+const TMP_PREFIX: &str = ".tmp-";
+
+/// Key of the block with contents `plaintext`.
+pub fn content_key(key: &[u8; 32], plaintext: &[u8]) -> [u8; format::REF_LEN] {
+    let dedup_key = hmac::sign(
+        &hmac::Key::new(hmac::HMAC_SHA256, key),
+        b"CipherMount dedup key v1",
+    );
+    let tag = hmac::sign(
+        &hmac::Key::new(hmac::HMAC_SHA256, dedup_key.as_ref()),
+        plaintext,
+    );
+    tag.as_ref().try_into().unwrap()
+}
+
+/// Seal `plaintext` under its content key. The result has the
+/// `crypto::encrypt` layout, with an all-zero nonce.
+pub fn seal(content_key: &[u8; format::REF_LEN], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let key = UnboundKey::new(&AES_256_GCM, content_key).map_err(|_| anyhow!("Bad key"))?;
+    let nonce = [0u8; crypto::HEADER_LEN];
+    let mut buf = plaintext.to_vec();
+    LessSafeKey::new(key)
+        .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut buf)
+        .map_err(|_| anyhow!("Encryption failed"))?;
+    let mut out = nonce.to_vec();
+    out.extend_from_slice(&buf);
+    Ok(out)
+}
+
+/// Authenticate and decrypt stored block `index` of a file described by
+/// `header`, given the reference sealed in the file.
+pub fn open_block(
+    content_key: &[u8],
+    header: &format::Header,
+    index: u64,
+    sealed: &[u8],
+) -> Result<Vec<u8>> {
+    let content_key: &[u8; 32] = content_key
+        .try_into()
+        .map_err(|_| anyhow!("Block {} has a malformed reference", index))?;
+    let plaintext = crypto::decrypt(content_key, sealed)
+        .map_err(|_| anyhow!("Stored block {} failed authentication", index))?;
+    ensure!(
+        plaintext.len() == header.block_len(index),
+        "Stored block {} has the wrong length",
+        index
+    );
+    Ok(plaintext)
+}
+
+/// Encrypt `plaintext` as a deduplicated file at `path` with logical mtime
+/// `mtime_ns`, putting its blocks in `store` and sealing the references
+/// with nonces from `nonces`. Of `flags`, only `FLAG_EXPIRY` applies.
+/// Returns what goes in the backing file itself: the header and the sealed
+/// references.
+#[allow(clippy::too_many_arguments)]
+pub fn encrypt_file(
+    store: &Store,
+    key: &[u8; 32],
+    nonces: &dyn NonceSource,
+    path: &Path,
+    plaintext: &[u8],
+    block_size: u32,
+    flags: u8,
+    mtime_ns: u64,
+) -> Result<Vec<u8>> {
+    ensure!(block_size > 0, "Block size must be non-zero");
+    let flags = format::FLAG_DEDUP_BLOCKS | (flags & format::FLAG_EXPIRY);
+    let len = plaintext.len() as u64;
+    let header = format::new_header(key, nonces, block_size, len, flags, mtime_ns)?;
+    let mut out = Vec::with_capacity(header.file_len() as usize);
+    out.extend_from_slice(&header.encode());
+    for index in 0..header.block_count() {
+        let start = (index * block_size as u64) as usize;
+        let block = &plaintext[start..start + header.block_len(index)];
+        let content_key = store.put(key, path, index, block)?;
+        out.extend_from_slice(&format::seal_block(
+            key,
+            nonces,
+            &header,
+            index,
+            &content_key,
+        )?);
+    }
+    Ok(out)
+}
+
+/// Decrypt the backing file at `path`, with contents `raw`, in any format,
+/// reading its blocks from the store if it is deduplicated.
+pub fn decrypt_file(key: &[u8; 32], path: &Path, raw: &[u8]) -> Result<Vec<u8>> {
+    let Some(header) = format::Header::decode(raw).filter(|h| h.dedup_blocks()) else {
+        return format::decrypt_file(key, raw);
+    };
+    format::check_len(raw, &header)?;
+    let mut out = Vec::with_capacity(header.plaintext_len as usize);
+    for index in 0..header.block_count() {
+        let (start, len) = header.block_span(index);
+        let sealed_ref = &raw[start as usize..start as usize + len];
+        let content_key = format::open_sealed_block(key, &header, index, sealed_ref)?;
+        let stored = fs::read(layout::block_file(path, index))?;
+        out.extend_from_slice(&open_block(&content_key, &header, index, &stored)?);
+    }
+    Ok(out)
+}
+
+/// The shared block store of one vault.
+#[derive(Debug, Clone)]
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    /// The store of the vault at `source`. It is created on first use.
+    pub fn new(source: &Path) -> Self {
+        Self {
+            dir: source.join(STORE_DIR),
+        }
+    }
+
+    /// Store entry for the sealed block `sealed`.
+    fn entry(&self, sealed: &[u8]) -> PathBuf {
+        self.dir
+            .join(hex::encode(digest::digest(&digest::SHA256, sealed)))
+    }
+
+    /// Make `plaintext` block `index` of the file at `path`: store it unless
+    /// an identical block already is, and link the file to it. Returns the
+    /// content key to seal into the file.
+    pub fn put(
+        &self,
+        key: &[u8; 32],
+        path: &Path,
+        index: u64,
+        plaintext: &[u8],
+    ) -> Result<[u8; format::REF_LEN]> {
+        let content_key = content_key(key, plaintext);
+        let sealed = seal(&content_key, plaintext)?;
+        let entry = self.entry(&sealed);
+        let link = layout::block_file(path, index);
+        if same_file(&entry, &link) {
+            return Ok(content_key);
+        }
+        self.unlink(&link)?;
+        fs::create_dir_all(layout::block_dir(path))?;
+        self.store(&entry, &sealed)?;
+        if let Err(e) = fs::hard_link(&entry, &link) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+            // Collected between storing and linking: store it again.
+            self.store(&entry, &sealed)?;
+            fs::hard_link(&entry, &link)?;
+        }
+        Ok(content_key)
+    }
+
+    /// Write `sealed` to `entry` unless it is already there. Entries only
+    /// appear complete.
+    fn store(&self, entry: &Path, sealed: &[u8]) -> io::Result<()> {
+        if entry.exists() {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir)?;
+        let id: [u8; 8] = rand::random();
+        let tmp = self.dir.join(format!("{}{}", TMP_PREFIX, hex::encode(id)));
+        fs::write(&tmp, sealed)?;
+        let linked = match fs::hard_link(&tmp, entry) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+            other => other,
+        };
+        fs::remove_file(&tmp)?;
+        linked
+    }
+
+    /// Remove the block link `link`, and the store entry with it if nothing
+    /// else references the block. Anything that isn't a store link (such as
+    /// a separately stored block) is simply removed.
+    pub fn unlink(&self, link: &Path) -> io::Result<()> {
+        let meta = match fs::symlink_metadata(link) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            other => other?,
+        };
+        if let Some(entry) = self.sole_entry(link, &meta)? {
+            match fs::remove_file(&entry) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        fs::remove_file(link)
+    }
+
+    /// Whether `link` is the only block linked to its store entry, so the
+    /// entry goes when it does.
+    pub fn is_only_link(&self, link: &Path) -> io::Result<bool> {
+        let meta = fs::symlink_metadata(link)?;
+        Ok(self.sole_entry(link, &meta)?.is_some())
+    }
+
+    /// The store entry `link` (with metadata `meta`) is linked to, if only
+    /// the store and this link are left.
+    fn sole_entry(&self, link: &Path, meta: &fs::Metadata) -> io::Result<Option<PathBuf>> {
+        if !meta.is_file() || meta.nlink() != 2 {
+            return Ok(None);
+        }
+        let entry = self.entry(&fs::read(link)?);
+        Ok(same_file(&entry, link).then_some(entry))
+    }
+
+    /// Drop the blocks of `path` from index `keep` on, like `layout::prune`.
+    pub fn prune(&self, path: &Path, keep: u64) -> io::Result<()> {
+        layout::prune_with(path, keep, |block| self.unlink(block))
+    }
+
+    /// Drop every block of `path`, like `layout::remove`.
+    pub fn remove(&self, path: &Path) -> io::Result<()> {
+        self.prune(path, 0)?;
+        match fs::remove_dir(layout::block_dir(path)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            other => other,
+        }
+    }
+
+    /// Remove store entries no file references, and entries a crash left
+    /// half-written. Returns how many were removed. Must not run while the
+    /// store is being written to.
+    pub fn collect_garbage(&self) -> io::Result<usize> {
+        let entries = match fs::read_dir(&self.dir) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            other => other?,
+        };
+        let mut removed = 0;
+        for entry in entries {
+            let entry = entry?;
+            if entry.metadata()?.nlink() == 1 {
+                fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Whether `a` and `b` both exist and are the same file.
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::RandomNonces;
+
+    const KEY: [u8; 32] = [0x42u8; 32];
+
+    #[test]
+    fn identical_blocks_are_stored_once_and_collected() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(dir.path());
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        let content = [[1u8; 64], [2u8; 64], [1u8; 64]].concat();
+        let raw_a = encrypt_file(&store, &KEY, &RandomNonces, &a, &content, 64, 0, 0).unwrap();
+        let raw_b = encrypt_file(&store, &KEY, &RandomNonces, &b, &content, 64, 0, 0).unwrap();
+        fs::write(&a, &raw_a).unwrap();
+        fs::write(&b, &raw_b).unwrap();
+        assert_eq!(fs::read_dir(&store.dir).unwrap().count(), 2);
+        assert_eq!(decrypt_file(&KEY, &b, &raw_b).unwrap(), content);
+
+        // A block left unreferenced by a crash is swept.
+        fs::write(store.dir.join("stray"), b"x").unwrap();
+        assert_eq!(store.collect_garbage().unwrap(), 1);
+
+        store.remove(&a).unwrap();
+        assert_eq!(decrypt_file(&KEY, &b, &raw_b).unwrap(), content);
+        store.remove(&b).unwrap();
+        assert_eq!(fs::read_dir(&store.dir).unwrap().count(), 0);
+    }
+}
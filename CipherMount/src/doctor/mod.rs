@@ -0,0 +1,384 @@
+//! Diagnosing common vault and mount problems (`doctor`).
+//!
+//! Most failed mounts come down to a handful of causes: the wrong key, a
+//! backing filesystem that can't do what the mount needs, control files
+//! others can write, leftovers of a mount that crashed, FUSE not being set
+//! up, or a kernel RNG that isn't seeded yet. `diagnose` runs one check for
+//! each and says what to do about the ones that don't pass. Nothing is
+//! mounted and nothing in the vault is changed, beyond a probe file that is
+//! removed again.
+
+use crate::backend::Backend;
+use crate::{nonce, scrub, vault};
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// Probe file written to the vault root to try out the backing store.
+pub const PROBE_FILE: &str = ".ciphermount-doctor";
+
+/// Longest file name the mount expects the backing store to take.
+const NAME_MAX: u64 = 255;
+
+/// Outcome of a check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    /// Mounting works, but something is likely to go wrong later.
+    Warn,
+    /// Mounting fails, or is unsafe.
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        })
+    }
+}
+
+/// One line of the report.
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    /// What to do about it, for checks that didn't pass.
+    pub hint: Option<&'static str>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            name,
+            status: Status::Warn,
+            detail: detail.into(),
+            hint: Some(hint),
+        }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            name,
+            status: Status::Fail,
+            detail: detail.into(),
+            hint: Some(hint),
+        }
+    }
+}
+
+/// Run every check against the vault at `source`, whose files are written
+/// through `backend`. The key check is skipped without a key.
+pub fn diagnose(source: &Path, key: Option<&[u8; 32]>, backend: &dyn Backend) -> Vec<Check> {
+    let mut checks = vec![];
+    if source.is_dir() {
+        checks.push(check_key(source, key));
+        checks.push(check_backing_store(source, backend));
+        checks.push(check_control_files(source));
+        checks.push(check_leftovers(source));
+    } else {
+        checks.push(Check::fail(
+            "vault",
+            format!("{:?} is not a directory", source),
+            "Check --source; `ciphermount init` creates a new vault",
+        ));
+    }
+    checks.push(check_fuse());
+    checks.push(check_fuse_conf());
+    checks.push(check_rng());
+    checks
+}
+
+fn check_key(source: &Path, key: Option<&[u8; 32]>) -> Check {
+    const NAME: &str = "key";
+    const HINT: &str = "Check which key is in use (--key, CIPHER_KEY, --key-credential): \
+                        `ciphermount key fingerprint` prints its fingerprint, to compare \
+                        with the one in .ciphermount-vault.meta";
+    let Some(key) = key else {
+        return Check::warn(
+            NAME,
+            "not checked: no key given",
+            "Pass the key as you would to mount",
+        );
+    };
+    let has_fingerprint = match vault::check_fingerprint(source, key) {
+        Ok(found) => found,
+        Err(e) => return Check::fail(NAME, e.to_string(), HINT),
+    };
+    match vault::verify_canary(source, key) {
+        Err(e) => Check::fail(NAME, e.to_string(), HINT),
+        Ok(vault::Canary::Missing) if !has_fingerprint => Check::warn(
+            NAME,
+            "the vault has no canary or fingerprint, so the key can't be checked",
+            "Vaults created with `ciphermount init` can be checked",
+        ),
+        Ok(_) => Check::pass(
+            NAME,
+            format!(
+                "matches the vault (fingerprint {})",
+                vault::fingerprint(key)
+            ),
+        ),
+    }
+}
+
+fn check_backing_store(source: &Path, backend: &dyn Backend) -> Check {
+    const NAME: &str = "backing store";
+    if let Err(problem) = probe(source, backend) {
+        return Check::fail(
+            NAME,
+            problem,
+            "Keep the vault on a local POSIX filesystem such as ext4, XFS or btrfs",
+        );
+    }
+    match name_max(source) {
+        Ok(max) if max < NAME_MAX => Check::warn(
+            NAME,
+            format!("file names are limited to {} bytes", max),
+            "Files with longer names can't be created in this vault",
+        ),
+        Ok(_) => Check::pass(NAME, "writes, syncs, renames and names behave"),
+        Err(e) => Check::warn(
+            NAME,
+            format!("cannot read filesystem limits: {}", e),
+            "Keep the vault on a local POSIX filesystem such as ext4, XFS or btrfs",
+        ),
+    }
+}
+
+/// Put a probe file through what a mount does to backing files, and say
+/// what went wrong, if anything. The probe is removed either way.
+fn probe(source: &Path, backend: &dyn Backend) -> Result<(), String> {
+    let path = source.join(PROBE_FILE);
+    let moved = source.join(format!("{}-moved", PROBE_FILE));
+    let step = |what: &str, e: io::Error| format!("{} a probe file failed: {}", what, e);
+    let result = (|| -> Result<(), String> {
+        backend
+            .write(&path, b"probe")
+            .map_err(|e| step("Writing", e))?;
+        backend
+            .write_at(&path, 5, b" data")
+            .map_err(|e| step("Writing into", e))?;
+        if backend.read(&path).map_err(|e| step("Reading", e))? != b"probe data" {
+            return Err("A probe file read back differently from how it was written".into());
+        }
+        backend.sync(&path, false).map_err(|e| step("Syncing", e))?;
+        fs::rename(&path, &moved).map_err(|e| step("Renaming", e))?;
+        if source.join(format!("{}-MOVED", PROBE_FILE)).exists() {
+            return Err("File names are case-insensitive, so distinct files collide".into());
+        }
+        Ok(())
+    })();
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(&moved);
+    result
+}
+
+/// Longest file name the filesystem holding `dir` accepts.
+fn name_max(dir: &Path) -> io::Result<u64> {
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    // SAFETY: an all-zero statvfs is valid storage for statvfs to fill in.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers are valid for the call.
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_namemax as u64)
+}
+
+fn check_control_files(source: &Path) -> Check {
+    const NAME: &str = "control files";
+    let names = [
+        vault::CANARY_FILE,
+        vault::VAULT_META_FILE,
+        nonce::COUNTER_FILE,
+        scrub::STATE_FILE,
+    ];
+    let exposed: Vec<String> = std::iter::once(source.to_path_buf())
+        .chain(names.iter().map(|name| source.join(name)))
+        .filter(|path| {
+            fs::symlink_metadata(path).is_ok_and(|m| m.permissions().mode() & 0o022 != 0)
+        })
+        .map(|path| format!("{:?}", path))
+        .collect();
+    if exposed.is_empty() {
+        Check::pass(NAME, "none writable by group or others")
+    } else {
+        Check::warn(
+            NAME,
+            format!("writable by group or others: {}", exposed.join(", ")),
+            "`chmod go-w` them: whoever can write them can roll back nonces \
+             or swap the vault's fingerprint",
+        )
+    }
+}
+
+fn check_leftovers(source: &Path) -> Check {
+    const NAME: &str = "leftovers";
+    let orphans = fs::read_dir(source)
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter(|e| {
+                    let name = e.file_name();
+                    let name = name.to_string_lossy();
+                    name.starts_with(vault::ORPHAN_PREFIX) || name.starts_with(PROBE_FILE)
+                })
+                .count()
+        })
+        .unwrap_or(0);
+    if orphans == 0 {
+        Check::pass(NAME, "none from an unclean shutdown")
+    } else {
+        Check::warn(
+            NAME,
+            format!(
+                "{} file(s) left by a mount that didn't shut down cleanly",
+                orphans
+            ),
+            "The next mount removes them; if one is running, unmount it first",
+        )
+    }
+}
+
+fn check_fuse() -> Check {
+    const NAME: &str = "fuse";
+    if let Err(e) = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")
+    {
+        return Check::fail(
+            NAME,
+            format!("cannot open /dev/fuse: {}", e),
+            "Load the fuse module (`modprobe fuse`), or pass /dev/fuse into the container",
+        );
+    }
+    let helper = std::env::var_os("PATH").is_some_and(|path| {
+        std::env::split_paths(&path)
+            .any(|dir| dir.join("fusermount3").exists() || dir.join("fusermount").exists())
+    });
+    if helper {
+        Check::pass(NAME, "/dev/fuse and fusermount are available")
+    } else {
+        Check::warn(
+            NAME,
+            "fusermount is not on PATH, so only root can mount",
+            "Install fuse3 (or fuse)",
+        )
+    }
+}
+
+fn check_fuse_conf() -> Check {
+    const NAME: &str = "fuse.conf";
+    let allow_other = fs::read_to_string("/etc/fuse.conf")
+        .is_ok_and(|conf| conf.lines().any(|line| line.trim() == "user_allow_other"));
+    // SAFETY: geteuid has no preconditions.
+    if allow_other || unsafe { libc::geteuid() } == 0 {
+        Check::pass(NAME, "--allow-other is available")
+    } else {
+        Check::warn(
+            NAME,
+            "user_allow_other is not set, so --allow-other needs root",
+            "Add `user_allow_other` to /etc/fuse.conf if other users need the mount",
+        )
+    }
+}
+
+fn check_rng() -> Check {
+    const NAME: &str = "rng";
+    let mut byte = [0u8; 1];
+    // SAFETY: `byte` is valid for writes of its length.
+    let n = unsafe { libc::getrandom(byte.as_mut_ptr().cast(), 1, libc::GRND_NONBLOCK) };
+    if n == 1 {
+        return Check::pass(NAME, "the kernel RNG is seeded");
+    }
+    let e = io::Error::last_os_error();
+    let detail = if e.raw_os_error() == Some(libc::EAGAIN) {
+        "the kernel RNG is not seeded yet; nonces and keys would block".to_string()
+    } else {
+        format!("cannot read the kernel RNG: {}", e)
+    };
+    Check::fail(
+        NAME,
+        detail,
+        "Wait for boot to seed it, or run an entropy daemon such as rng-tools",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::LocalBackend;
+
+    /// Local backend on a filesystem that doesn't support fsync.
+    struct NoSync;
+
+    impl Backend for NoSync {
+        fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+            LocalBackend.read(path)
+        }
+        fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+            LocalBackend.write(path, data)
+        }
+        fn sync(&self, _path: &Path, _data_only: bool) -> io::Result<()> {
+            Err(io::Error::from_raw_os_error(libc::EINVAL))
+        }
+    }
+
+    fn find<'a>(checks: &'a [Check], name: &str) -> &'a Check {
+        checks.iter().find(|c| c.name == name).unwrap()
+    }
+
+    #[test]
+    fn reports_a_wrong_key_and_an_unsuitable_backing_store_separately() {
+        let dir = tempfile::tempdir().unwrap();
+        vault::init(dir.path(), &[0x11u8; 32]).unwrap();
+
+        let checks = diagnose(dir.path(), Some(&[0x11u8; 32]), &LocalBackend);
+        assert_eq!(find(&checks, "key").status, Status::Pass);
+        assert_eq!(find(&checks, "backing store").status, Status::Pass);
+
+        let checks = diagnose(dir.path(), Some(&[0x22u8; 32]), &NoSync);
+        let key = find(&checks, "key");
+        assert_eq!(key.status, Status::Fail);
+        assert!(key.detail.contains("does not match"), "{}", key.detail);
+        let store = find(&checks, "backing store");
+        assert_eq!(store.status, Status::Fail);
+        assert!(store.detail.starts_with("Syncing"), "{}", store.detail);
+
+        let names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert!(names
+            .iter()
+            .all(|n| !n.to_string_lossy().starts_with(PROBE_FILE)));
+    }
+
+    #[test]
+    fn missing_vault_fails_without_touching_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("nowhere");
+        let checks = diagnose(&missing, None, &LocalBackend);
+        assert_eq!(find(&checks, "vault").status, Status::Fail);
+        assert!(checks.iter().all(|c| c.name != "key"));
+        assert!(!missing.exists());
+    }
+}
@@ -0,0 +1,158 @@
+//! Storage overhead estimates for a plaintext tree (`estimate`).
+//!
+//! Before a vault exists, `estimate` walks the plaintext it will hold and
+//! works out what storing it would cost, without writing anything. The
+//! vault size is exact: it follows from each file's length and the block
+//! size alone, whatever the layout or cipher (both seal every block with a
+//! 12-byte nonce and a 16-byte tag). With `chunk_size` set it also sizes a
+//! `pack` image of the same tree, compressing every chunk to find out how
+//! well the data compresses; that takes as long as packing, minus the
+//! encryption.
+
+use crate::{crypto, format, image, walk};
+use anyhow::{ensure, Context, Result};
+use std::fs;
+use std::path::Path;
+
+/// What to estimate.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// Plaintext bytes per encrypted block in the vault.
+    pub block_size: u32,
+    /// Plaintext bytes per compressed chunk in a packed image, if one should
+    /// be sized too.
+    pub chunk_size: Option<u32>,
+}
+
+/// Projected sizes, in bytes.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Estimate {
+    pub files: u64,
+    pub dirs: u64,
+    pub plaintext: u64,
+    /// Backing files of a vault holding the tree.
+    pub vault: u64,
+    /// A packed image of the tree, if asked for.
+    pub image: Option<u64>,
+    /// Compressed chunk payload of that image, before sealing.
+    pub compressed: Option<u64>,
+}
+
+impl Estimate {
+    /// Average bytes each file costs in the vault beyond its contents.
+    pub fn per_file_overhead(&self) -> u64 {
+        (self.vault - self.plaintext)
+            .checked_div(self.files)
+            .unwrap_or(0)
+    }
+
+    /// Plaintext bytes per compressed byte, if an image was sized.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        self.compressed
+            .map(|c| self.plaintext as f64 / c.max(1) as f64)
+    }
+}
+
+/// Estimate storing the regular files and directories under `root`.
+pub fn estimate(root: &Path, walk_options: walk::Options, options: Options) -> Result<Estimate> {
+    ensure!(options.block_size > 0, "Block size must be non-zero");
+    ensure!(options.chunk_size != Some(0), "Chunk size must be non-zero");
+    let mut compressor = match options.chunk_size {
+        Some(_) => Some(zstd::bulk::Compressor::new(image::ZSTD_LEVEL)?),
+        None => None,
+    };
+    let mut estimate = Estimate::default();
+    // Header, sealed chunks and the root's index entry; entries are added
+    // as they are found.
+    let mut image_len = image::HEADER_SIZE as u64 + image::index_entry_len(0, 0) as u64 + 8;
+    let mut compressed = 0;
+
+    walk::walk(root, walk_options, |entry| {
+        let name_len = entry.path.file_name().unwrap_or_default().len();
+        if entry.file_type.is_dir() {
+            estimate.dirs += 1;
+            image_len += image::index_entry_len(name_len, 0) as u64;
+            return Ok(());
+        }
+        if !entry.file_type.is_file() {
+            return Ok(());
+        }
+        estimate.files += 1;
+        let len = fs::metadata(&entry.path)?.len();
+        estimate.plaintext += len;
+        estimate.vault += format::Header::new(options.block_size, len).file_len() as u64;
+
+        if let (Some(compressor), Some(chunk_size)) = (&mut compressor, options.chunk_size) {
+            let plaintext =
+                fs::read(&entry.path).with_context(|| format!("Reading {:?}", entry.path))?;
+            let mut chunks = 0;
+            for chunk in plaintext.chunks(chunk_size as usize) {
+                let size = compressor.compress(chunk)?.len() as u64;
+                compressed += size;
+                image_len += size + format::BLOCK_OVERHEAD as u64;
+                chunks += 1;
+            }
+            image_len += image::index_entry_len(name_len, chunks) as u64;
+        }
+        Ok(())
+    })?;
+
+    if options.chunk_size.is_some() {
+        // The index is sealed like everything else.
+        estimate.image = Some(image_len + crypto::HEADER_LEN as u64 + 16);
+        estimate.compressed = Some(compressed);
+    }
+    Ok(estimate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [0x42u8; 32];
+
+    #[test]
+    fn estimate_matches_an_actual_vault_and_image() {
+        let plain = tempfile::tempdir().unwrap();
+        let text = b"the quick brown fox jumps over the lazy dog\n".repeat(3000);
+        let noise: Vec<u8> = (0..50_000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let files: [(&str, &[u8]); 4] = [
+            ("notes.txt", &text),
+            ("docs/noise.bin", &noise),
+            ("docs/empty", b""),
+            ("docs/deep/small", b"hello"),
+        ];
+        let vault = tempfile::tempdir().unwrap();
+        let mut vault_len = 0;
+        for (name, data) in files {
+            for root in [plain.path(), vault.path()] {
+                fs::create_dir_all(root.join(name).parent().unwrap()).unwrap();
+            }
+            fs::write(plain.path().join(name), data).unwrap();
+            let raw = format::encrypt_file(&KEY, data, 1024).unwrap();
+            vault_len += raw.len() as u64;
+            fs::write(vault.path().join(name), raw).unwrap();
+        }
+
+        let options = Options {
+            block_size: 1024,
+            chunk_size: Some(4096),
+        };
+        let estimate = estimate(plain.path(), walk::Options::default(), options).unwrap();
+        assert_eq!((estimate.files, estimate.dirs), (4, 2));
+        assert_eq!(estimate.vault, vault_len);
+        assert!(estimate.compression_ratio().unwrap() > 2.0);
+
+        let images = tempfile::tempdir().unwrap();
+        let out = images.path().join("vault.cmx");
+        image::pack(vault.path(), &KEY, &out, 4096, walk::Options::default()).unwrap();
+        let packed = fs::metadata(&out).unwrap().len();
+        let projected = estimate.image.unwrap();
+        assert!(
+            projected.abs_diff(packed) * 100 <= packed,
+            "projected {projected}, packed {packed}"
+        );
+    }
+}
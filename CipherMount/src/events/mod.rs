@@ -0,0 +1,25 @@
+//! Hooks for embedders that want to react to changes made through a mount
+//! (search indexers, replication, ...).
+//!
+//! A hook is told which entry changed and how, never what it now contains:
+//! paths are relative to the vault root and no plaintext is passed. Hooks
+//! run synchronously on the FUSE worker after the change has succeeded, so
+//! they should hand slow work off to a thread of their own.
+
+use fuser::FileType;
+use std::path::Path;
+
+/// Receives vault change events. Every method defaults to doing nothing.
+pub trait EventHook: Send + Sync {
+    /// `len` bytes were written at `offset` into the file at `path`.
+    fn on_write(&self, _path: &Path, _offset: u64, _len: u64) {}
+
+    /// An entry of `kind` was created at `path` (including new hard links).
+    fn on_create(&self, _path: &Path, _kind: FileType) {}
+
+    /// The entry at `path` was removed.
+    fn on_unlink(&self, _path: &Path) {}
+
+    /// The entry at `from` was moved to `to`.
+    fn on_rename(&self, _from: &Path, _to: &Path) {}
+}
@@ -0,0 +1,969 @@
+//! On-disk file formats.
+//!
+//! Legacy (whole-file) layout, as written by `crypto::encrypt`:
+//!   [ 12-byte nonce ][ ciphertext + 16-byte GCM tag ]
+//!
+//! Block layout:
+//!   [ 56-byte header ][ block 0 ][ block 1 ] ... [ block N-1 ]
+//!
+//!   header = "CMB1" | version u8 | flags u8 | reserved u16
+//!          | block_size u32 LE | plaintext_len u64 LE | sealed mtime
+//!   block  = [ 12-byte nonce ][ up to block_size bytes ciphertext + 16-byte tag ]
+//!   sealed mtime = [ 12-byte nonce ][ Unix ns u64 LE ciphertext + 16-byte tag ]
+//!
+//! Every block is sealed separately so a range can be decrypted without
+//! touching the rest of the file. Each block's AAD binds the fixed header
+//! fields, the block index and whether it is the final block; the final
+//! block's AAD also carries the plaintext length. Reordered, dropped or
+//! truncated blocks, or an edited header, therefore fail authentication.
+//! There is always at least one (possibly empty) final block.
+//!
+//! The sealed mtime is the file's logical modification time, so it survives
+//! re-encryption that necessarily changes the backing file's own mtime. Its
+//! AAD binds the fixed header fields and the plaintext length; blocks don't
+//! depend on it, so a write only has to reseal it alongside the blocks it
+//! touches. Version 1 headers are the first 20 bytes alone, without an mtime.
+//!
+//! With `FLAG_SEPARATE_BLOCKS` set, the backing file holds only the header
+//! and each block is a file of its own (see `layout`). Offsets and lengths
+//! below still describe the packed stream, which is what reassembling the
+//! blocks in order gives back; the flag is part of every block's AAD.
+//!
+//! With `FLAG_DEDUP_BLOCKS` set, what follows the header is one sealed
+//! `REF_LEN`-byte reference per block instead of the block itself: the key
+//! the block is stored under in the vault's shared block store (see
+//! `dedup`). References are sealed with the same AADs blocks would be, so
+//! the file's structure is authenticated just the same.
+//!
+//! With `FLAG_INTEGRITY_ONLY` set, blocks are authenticated but not
+//! encrypted: each is `[ 12-byte nonce ][ plaintext ][ 16-byte tag ]`, the
+//! tag covering the block's AAD and its plaintext (see
+//! `crypto::authenticate_with_nonces`). Sizes and offsets are those of
+//! encrypted blocks, and the flag is in every block's AAD, so it can't be
+//! set or cleared without every block failing. The sealed mtime is still
+//! encrypted.
+//!
+//! With `FLAG_CHACHA20` set, blocks are sealed with ChaCha20-Poly1305
+//! instead of AES-256-GCM; sizes and offsets are unchanged. The sealed mtime
+//! always uses AES-256-GCM.
+//!
+//! With `FLAG_FILE_KEY` set, the header is followed by a key of the file's
+//! own, sealed with the vault key (`WRAPPED_KEY_LEN` bytes, its AAD the
+//! fixed header fields up to the block size), and the blocks are sealed
+//! with that key instead of the vault key. Zeroing the wrapped key leaves
+//! every block permanently undecryptable, however many copies of them
+//! survive (`--secure-delete crypto-erase`). The sealed mtime stays under
+//! the vault key.
+//!
+//! With `FLAG_EXPIRY` set, the header ends with the file's sealed expiry
+//! (`EXPIRY_SEAL_LEN` bytes, Unix seconds, 0 for none). Its AAD is the
+//! header up to and including the sealed mtime, so the expiry can't be
+//! moved to another file, or replayed from an older header of this one
+//! without the mtime that came with it; every reseal of the mtime reseals
+//! the expiry with it.
+//!
+//! Bytes past the last block (a bug, two files concatenated, tampering) are
+//! ignored: the header's plaintext length is authenticated by the final
+//! block, so the file still reads as what it was sealed as. With
+//! `set_strict_trailing` such a file is rejected instead. Legacy files have
+//! no length to go by, so any extra byte breaks their tag either way.
+
+use crate::crypto::{self, Cipher, NonceSource, RandomNonces};
+use anyhow::{anyhow, bail, ensure, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Magic bytes at the start of a block-format file.
+pub const MAGIC: &[u8; 4] = b"CMB1";
+
+/// Current block-format version.
+pub const VERSION: u8 = 2;
+
+/// Size of the current block-format header.
+pub const HEADER_SIZE: usize = V1_HEADER_SIZE + MTIME_SEAL_LEN;
+
+/// Most a block-format header can take up: the current header and a
+/// wrapped file key. Enough of a backing file to decode any header from.
+pub const MAX_HEADER_SIZE: usize = HEADER_SIZE + WRAPPED_KEY_LEN + EXPIRY_SEAL_LEN;
+
+/// Size of a version 1 header, which carries no mtime.
+pub const V1_HEADER_SIZE: usize = 20;
+
+/// Length of the sealed logical mtime: nonce, 8-byte ciphertext, GCM tag.
+pub const MTIME_SEAL_LEN: usize = crypto::HEADER_LEN + 8 + 16;
+
+/// Length of a wrapped file key: nonce, 32-byte ciphertext, GCM tag.
+pub const WRAPPED_KEY_LEN: usize = crypto::HEADER_LEN + 32 + 16;
+
+/// Length of a sealed expiry: nonce, 8-byte ciphertext, GCM tag.
+pub const EXPIRY_SEAL_LEN: usize = crypto::HEADER_LEN + 8 + 16;
+
+/// Per-block overhead: nonce + GCM tag.
+pub const BLOCK_OVERHEAD: usize = crypto::HEADER_LEN + 16;
+
+/// Header flag: blocks are stored one per file instead of after the header.
+pub const FLAG_SEPARATE_BLOCKS: u8 = 0x01;
+
+/// Header flag: blocks are kept in the shared block store and the file holds
+/// a sealed reference to each.
+pub const FLAG_DEDUP_BLOCKS: u8 = 0x02;
+
+/// Header flag: blocks are authenticated but stored in plaintext.
+pub const FLAG_INTEGRITY_ONLY: u8 = 0x04;
+
+/// Header flag: blocks are sealed with ChaCha20-Poly1305.
+pub const FLAG_CHACHA20: u8 = 0x08;
+
+/// Header flag: blocks are sealed with a key of the file's own, wrapped in
+/// the header.
+pub const FLAG_FILE_KEY: u8 = 0x10;
+
+/// Header flag: the header carries the file's sealed expiry.
+pub const FLAG_EXPIRY: u8 = 0x20;
+
+/// Header flags saying how blocks are sealed, as opposed to where they are
+/// stored.
+pub const SEALING_FLAGS: u8 = FLAG_INTEGRITY_ONLY | FLAG_CHACHA20 | FLAG_FILE_KEY;
+
+/// Length of a block reference in a deduplicated file.
+pub const REF_LEN: usize = 32;
+
+/// Plaintext bytes per block unless configured otherwise.
+pub const DEFAULT_BLOCK_SIZE: u32 = 4096;
+
+/// Set once from `--strict-trailing` before the vault is opened, and read by
+/// every length check (`fits`, `check_len`) whether it comes from the mount,
+/// `stream` or another command, so they all agree on which files are whole.
+/// Tests leave it off and call `check_len_as` to exercise strict mode.
+static STRICT_TRAILING: AtomicBool = AtomicBool::new(false);
+
+/// Reject block-format files with bytes past their last block, for the
+/// whole process, rather than ignoring those bytes (`--strict-trailing`).
+pub fn set_strict_trailing(on: bool) {
+    STRICT_TRAILING.store(on, Ordering::Relaxed);
+}
+
+/// Whether bytes past the last block are rejected, as last set by
+/// `set_strict_trailing`; off unless `--strict-trailing` was given.
+pub fn strict_trailing() -> bool {
+    STRICT_TRAILING.load(Ordering::Relaxed)
+}
+
+/// Format of a backing file, as detected from its first bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Nonce-prefixed whole-file ciphertext (pre block mode).
+    Legacy,
+    /// Block format with the given header.
+    Block(Header),
+}
+
+/// Block-format file header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub flags: u8,
+    pub block_size: u32,
+    pub plaintext_len: u64,
+    /// Sealed logical mtime; `None` for version 1 headers. All zeros (and
+    /// so unauthentic) until `seal_mtime` is called.
+    pub mtime: Option<[u8; MTIME_SEAL_LEN]>,
+    /// The file's own key, wrapped with the vault key, if `FLAG_FILE_KEY`
+    /// is set. All zeros once erased.
+    pub file_key: Option<[u8; WRAPPED_KEY_LEN]>,
+    /// The file's sealed expiry, if `FLAG_EXPIRY` is set.
+    pub expiry: Option<[u8; EXPIRY_SEAL_LEN]>,
+}
+
+impl Header {
+    pub fn new(block_size: u32, plaintext_len: u64) -> Self {
+        Self {
+            version: VERSION,
+            flags: 0,
+            block_size,
+            plaintext_len,
+            mtime: Some([0; MTIME_SEAL_LEN]),
+            file_key: None,
+            expiry: None,
+        }
+    }
+
+    /// Size of this header on disk.
+    pub fn size(&self) -> usize {
+        let fixed = match self.mtime {
+            Some(_) => HEADER_SIZE,
+            None => V1_HEADER_SIZE,
+        };
+        fixed + self.file_key.map_or(0, |k| k.len()) + self.expiry.map_or(0, |e| e.len())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![0u8; V1_HEADER_SIZE];
+        out[0..4].copy_from_slice(MAGIC);
+        out[4] = self.version;
+        out[5] = self.flags;
+        out[8..12].copy_from_slice(&self.block_size.to_le_bytes());
+        out[12..20].copy_from_slice(&self.plaintext_len.to_le_bytes());
+        if let Some(sealed) = &self.mtime {
+            out.extend_from_slice(sealed);
+        }
+        if let Some(wrapped) = &self.file_key {
+            out.extend_from_slice(wrapped);
+        }
+        if let Some(sealed) = &self.expiry {
+            out.extend_from_slice(sealed);
+        }
+        out
+    }
+
+    /// Parse a header, returning `None` if `raw` doesn't start with one.
+    /// Reading `MAX_HEADER_SIZE` bytes is always enough.
+    pub fn decode(raw: &[u8]) -> Option<Self> {
+        if raw.len() < V1_HEADER_SIZE || &raw[0..4] != MAGIC {
+            return None;
+        }
+        let mtime = match raw[4] {
+            1 => None,
+            VERSION => Some(raw.get(V1_HEADER_SIZE..HEADER_SIZE)?.try_into().unwrap()),
+            _ => return None,
+        };
+        let flags = raw[5];
+        // Only current headers have room for a file key or an expiry.
+        let mut end = HEADER_SIZE;
+        let mut field = |flag: u8, len: usize| match (flags & flag != 0, raw[4]) {
+            (false, _) => Ok(None),
+            (true, VERSION) => {
+                let bytes = raw.get(end..end + len).ok_or(())?;
+                end += len;
+                Ok(Some(bytes))
+            }
+            (true, _) => Err(()),
+        };
+        let file_key = field(FLAG_FILE_KEY, WRAPPED_KEY_LEN).ok()?;
+        let file_key = file_key.map(|k| k.try_into().unwrap());
+        let expiry = field(FLAG_EXPIRY, EXPIRY_SEAL_LEN).ok()?;
+        let expiry = expiry.map(|e| e.try_into().unwrap());
+        let header = Self {
+            version: raw[4],
+            flags,
+            block_size: u32::from_le_bytes(raw[8..12].try_into().unwrap()),
+            plaintext_len: u64::from_le_bytes(raw[12..20].try_into().unwrap()),
+            mtime,
+            file_key,
+            expiry,
+        };
+        (header.block_size > 0).then_some(header)
+    }
+
+    /// Give the file a fresh random key of its own, wrapped with `key`, and
+    /// set `FLAG_FILE_KEY`. Blocks sealed before no longer open.
+    pub fn wrap_file_key(&mut self, key: &[u8; 32], nonces: &dyn NonceSource) -> Result<()> {
+        ensure!(self.mtime.is_some(), "Version 1 headers carry no file key");
+        self.flags |= FLAG_FILE_KEY;
+        let file_key: [u8; 32] = rand::random();
+        let wrapped = crypto::encrypt_with_nonces(key, nonces, &file_key, &self.file_key_aad())?;
+        self.file_key = Some(wrapped.try_into().unwrap());
+        Ok(())
+    }
+
+    /// Key the blocks are sealed with: the file's own, unwrapped with the
+    /// vault key `key`, or `key` itself.
+    fn block_key(&self, key: &[u8; 32]) -> Result<[u8; 32]> {
+        let Some(wrapped) = &self.file_key else {
+            return Ok(*key);
+        };
+        let raw = crypto::decrypt_with_aad(key, wrapped, &self.file_key_aad())
+            .map_err(|_| anyhow!("File key failed authentication"))?;
+        Ok(raw.try_into().unwrap())
+    }
+
+    fn file_key_aad(&self) -> Vec<u8> {
+        self.encode()[..12].to_vec()
+    }
+
+    /// Seal `mtime_ns` (Unix nanoseconds) as the file's logical mtime,
+    /// resealing the expiry, if there is one, to match. Version 1 headers
+    /// have nowhere to keep it.
+    pub fn seal_mtime(
+        &mut self,
+        key: &[u8; 32],
+        nonces: &dyn NonceSource,
+        mtime_ns: u64,
+    ) -> Result<()> {
+        ensure!(self.mtime.is_some(), "Version 1 headers carry no mtime");
+        let expiry = match self.expiry {
+            Some(_) => Some(self.open_expiry(key)?),
+            None => None,
+        };
+        let sealed =
+            crypto::encrypt_with_nonces(key, nonces, &mtime_ns.to_le_bytes(), &self.mtime_aad())?;
+        self.mtime = Some(sealed.try_into().unwrap());
+        if let Some(at) = expiry {
+            self.seal_expiry(key, nonces, at)?;
+        }
+        Ok(())
+    }
+
+    /// Seal `at` (Unix seconds, `None` for no expiry) as the file's expiry.
+    /// The header must have `FLAG_EXPIRY` set, since blocks are sealed to
+    /// the flags, and its mtime sealed.
+    pub fn seal_expiry(
+        &mut self,
+        key: &[u8; 32],
+        nonces: &dyn NonceSource,
+        at: Option<u64>,
+    ) -> Result<()> {
+        ensure!(
+            self.flags & FLAG_EXPIRY != 0 && self.mtime.is_some(),
+            "The header has no room for an expiry"
+        );
+        let at = at.unwrap_or(0).to_le_bytes();
+        let sealed = crypto::encrypt_with_nonces(key, nonces, &at, &self.expiry_aad())?;
+        self.expiry = Some(sealed.try_into().unwrap());
+        Ok(())
+    }
+
+    /// The expiry in Unix seconds, or `None` if there is none or the
+    /// header has no room for one (see `has_expiry`). A seal that fails
+    /// authentication is an error.
+    pub fn open_expiry(&self, key: &[u8; 32]) -> Result<Option<u64>> {
+        let Some(sealed) = &self.expiry else {
+            return Ok(None);
+        };
+        let raw = crypto::decrypt_with_aad(key, sealed, &self.expiry_aad())
+            .map_err(|_| anyhow!("Header expiry failed authentication"))?;
+        let at = u64::from_le_bytes(raw.try_into().unwrap());
+        Ok((at != 0).then_some(at))
+    }
+
+    fn expiry_aad(&self) -> Vec<u8> {
+        self.encode()[..HEADER_SIZE].to_vec()
+    }
+
+    /// The logical mtime in Unix nanoseconds, or `None` for a version 1
+    /// header. A seal that fails authentication is an error.
+    pub fn open_mtime(&self, key: &[u8; 32]) -> Result<Option<u64>> {
+        let Some(sealed) = &self.mtime else {
+            return Ok(None);
+        };
+        let raw = crypto::decrypt_with_aad(key, sealed, &self.mtime_aad())
+            .map_err(|_| anyhow!("Header mtime failed authentication"))?;
+        Ok(Some(u64::from_le_bytes(raw.try_into().unwrap())))
+    }
+
+    fn mtime_aad(&self) -> Vec<u8> {
+        self.encode()[..V1_HEADER_SIZE].to_vec()
+    }
+
+    /// Number of blocks holding `plaintext_len` bytes (at least one).
+    pub fn block_count(&self) -> u64 {
+        self.plaintext_len.div_ceil(self.block_size as u64).max(1)
+    }
+
+    /// Offset and sealed length of block `index` in the backing file.
+    pub fn block_span(&self, index: u64) -> (u64, usize) {
+        (
+            self.block_offset(index) as u64,
+            self.payload_len(index) + BLOCK_OVERHEAD,
+        )
+    }
+
+    /// Plaintext length of block `index`.
+    pub fn block_len(&self, index: u64) -> usize {
+        let start = index * self.block_size as u64;
+        (self.plaintext_len - start).min(self.block_size as u64) as usize
+    }
+
+    /// Length of what is sealed for block `index`: the block, or its
+    /// reference in a deduplicated file.
+    fn payload_len(&self, index: u64) -> usize {
+        if self.dedup_blocks() {
+            REF_LEN
+        } else {
+            self.block_len(index)
+        }
+    }
+
+    /// Offset of block `index` in the backing file.
+    fn block_offset(&self, index: u64) -> usize {
+        let stride = if self.dedup_blocks() {
+            REF_LEN
+        } else {
+            self.block_size as usize
+        };
+        self.size() + index as usize * (stride + BLOCK_OVERHEAD)
+    }
+
+    /// Total size of header and blocks, packed. Computed in `u128` because
+    /// the header may come from an untrusted file.
+    pub fn file_len(&self) -> u128 {
+        let payload = if self.dedup_blocks() {
+            self.block_count() as u128 * REF_LEN as u128
+        } else {
+            self.plaintext_len as u128
+        };
+        self.size() as u128 + payload + self.block_count() as u128 * BLOCK_OVERHEAD as u128
+    }
+
+    /// Whether each block is stored in a file of its own.
+    pub fn separate_blocks(&self) -> bool {
+        self.flags & FLAG_SEPARATE_BLOCKS != 0
+    }
+
+    /// Whether the blocks are in the shared block store.
+    pub fn dedup_blocks(&self) -> bool {
+        self.flags & FLAG_DEDUP_BLOCKS != 0
+    }
+
+    /// Whether the header carries the file's expiry.
+    pub fn has_expiry(&self) -> bool {
+        self.flags & FLAG_EXPIRY != 0
+    }
+
+    /// Whether blocks are sealed with a key of the file's own.
+    pub fn has_file_key(&self) -> bool {
+        self.flags & FLAG_FILE_KEY != 0
+    }
+
+    /// Whether blocks are authenticated only, not encrypted.
+    pub fn integrity_only(&self) -> bool {
+        self.flags & FLAG_INTEGRITY_ONLY != 0
+    }
+
+    /// Cipher the blocks are sealed with.
+    pub fn cipher(&self) -> Cipher {
+        if self.flags & FLAG_CHACHA20 != 0 {
+            Cipher::ChaCha20Poly1305
+        } else {
+            Cipher::Aes256Gcm
+        }
+    }
+
+    /// Size of the backing file itself: just the header if the blocks are
+    /// stored separately.
+    pub fn stored_len(&self) -> u128 {
+        if self.separate_blocks() {
+            self.size() as u128
+        } else {
+            self.file_len()
+        }
+    }
+
+    fn block_aad(&self, index: u64) -> Vec<u8> {
+        let is_final = index + 1 == self.block_count();
+        let mut aad = self.encode()[..12].to_vec();
+        aad.extend_from_slice(&index.to_le_bytes());
+        aad.push(is_final as u8);
+        if is_final {
+            aad.extend_from_slice(&self.plaintext_len.to_le_bytes());
+        }
+        aad
+    }
+}
+
+/// Detect the format of a backing file.
+pub fn detect(raw: &[u8]) -> Format {
+    match Header::decode(raw) {
+        Some(header) => Format::Block(header),
+        None => Format::Legacy,
+    }
+}
+
+/// Whether a backing file of `file_len` bytes holds what `header`
+/// describes: exactly, or with trailing bytes unless those are rejected.
+pub fn fits(header: &Header, file_len: u64) -> bool {
+    let expected = header.stored_len();
+    let actual = file_len as u128;
+    actual == expected || (actual > expected && !strict_trailing())
+}
+
+/// Plaintext size of a backing file, given its first `MAX_HEADER_SIZE` (or
+/// fewer) bytes and its total length on disk.
+pub fn plaintext_len(prefix: &[u8], file_len: u64) -> u64 {
+    match Header::decode(prefix) {
+        Some(header) if fits(&header, file_len) => header.plaintext_len,
+        _ => file_len.saturating_sub(BLOCK_OVERHEAD as u64),
+    }
+}
+
+/// Current Unix time in nanoseconds, as sealed into headers.
+pub fn now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Encrypt `plaintext` into the block format, stamped as modified now.
+pub fn encrypt_file(key: &[u8; 32], plaintext: &[u8], block_size: u32) -> Result<Vec<u8>> {
+    encrypt_file_with_mtime(key, plaintext, block_size, now_ns())
+}
+
+/// Encrypt `plaintext` into the block format with logical mtime
+/// `mtime_ns`, for rewrites that must not look like content changes.
+pub fn encrypt_file_with_mtime(
+    key: &[u8; 32],
+    plaintext: &[u8],
+    block_size: u32,
+    mtime_ns: u64,
+) -> Result<Vec<u8>> {
+    encrypt_file_with_flags(key, &RandomNonces, plaintext, block_size, 0, mtime_ns)
+}
+
+/// Encrypt `plaintext` into the block format with `flags` set in the
+/// header, taking nonces from `nonces`. With `FLAG_FILE_KEY`, the file gets
+/// a fresh key of its own; with `FLAG_EXPIRY`, room for an expiry, sealed
+/// as none until `set_expiry`. The result is always the packed stream;
+/// `layout::write` splits it up if `FLAG_SEPARATE_BLOCKS` is set.
+pub fn encrypt_file_with_flags(
+    key: &[u8; 32],
+    nonces: &dyn NonceSource,
+    plaintext: &[u8],
+    block_size: u32,
+    flags: u8,
+    mtime_ns: u64,
+) -> Result<Vec<u8>> {
+    ensure!(block_size > 0, "Block size must be non-zero");
+    ensure!(
+        flags & FLAG_DEDUP_BLOCKS == 0,
+        "Deduplicated files are written through the block store"
+    );
+    ensure!(
+        flags & FLAG_INTEGRITY_ONLY == 0 || flags & FLAG_CHACHA20 == 0,
+        "Integrity-only files are not encrypted, so take no cipher"
+    );
+    ensure!(
+        flags & FLAG_INTEGRITY_ONLY == 0 || flags & FLAG_FILE_KEY == 0,
+        "Integrity-only files are not encrypted, so take no file key"
+    );
+    let len = plaintext.len() as u64;
+    let header = new_header(key, nonces, block_size, len, flags, mtime_ns)?;
+    let mut out = Vec::with_capacity(header.file_len() as usize);
+    out.extend_from_slice(&header.encode());
+    for index in 0..header.block_count() {
+        let start = (index * block_size as u64) as usize;
+        let chunk = &plaintext[start..start + header.block_len(index)];
+        out.extend_from_slice(&seal_payload(key, nonces, &header, index, chunk)?);
+    }
+    Ok(out)
+}
+
+/// Header of a new file of `plaintext_len` bytes with `flags` and logical
+/// mtime `mtime_ns`: with a fresh file key if `FLAG_FILE_KEY` is set, and
+/// room for an expiry, sealed as none, if `FLAG_EXPIRY` is.
+pub fn new_header(
+    key: &[u8; 32],
+    nonces: &dyn NonceSource,
+    block_size: u32,
+    plaintext_len: u64,
+    flags: u8,
+    mtime_ns: u64,
+) -> Result<Header> {
+    let mut header = Header::new(block_size, plaintext_len);
+    header.flags = flags & !FLAG_FILE_KEY;
+    if flags & FLAG_FILE_KEY != 0 {
+        header.wrap_file_key(key, nonces)?;
+    }
+    header.seal_mtime(key, nonces, mtime_ns)?;
+    if flags & FLAG_EXPIRY != 0 {
+        header.seal_expiry(key, nonces, None)?;
+    }
+    Ok(header)
+}
+
+/// Seal `at` as the expiry in the header at the start of `raw`, which must
+/// have room for one.
+pub fn set_expiry(
+    key: &[u8; 32],
+    nonces: &dyn NonceSource,
+    raw: &mut [u8],
+    at: Option<u64>,
+) -> Result<()> {
+    let mut header = Header::decode(raw).ok_or_else(|| anyhow!("No block-format header"))?;
+    header.seal_expiry(key, nonces, at)?;
+    raw[..header.size()].copy_from_slice(&header.encode());
+    Ok(())
+}
+
+/// Header flag selecting `cipher` for a file's blocks.
+pub fn cipher_flag(cipher: Cipher) -> u8 {
+    match cipher {
+        Cipher::Aes256Gcm => 0,
+        Cipher::ChaCha20Poly1305 => FLAG_CHACHA20,
+    }
+}
+
+/// Seal `payload` as block `index`: encrypted with the file's cipher, or
+/// only authenticated in an integrity-only file.
+fn seal_payload(
+    key: &[u8; 32],
+    nonces: &dyn NonceSource,
+    header: &Header,
+    index: u64,
+    payload: &[u8],
+) -> Result<Vec<u8>> {
+    let aad = header.block_aad(index);
+    let key = header.block_key(key)?;
+    if header.integrity_only() {
+        crypto::authenticate_with_nonces(&key, nonces, payload, &aad)
+    } else {
+        crypto::encrypt_with_cipher(header.cipher(), &key, nonces, payload, &aad)
+    }
+}
+
+/// Decrypt a backing file in either format.
+pub fn decrypt_file(key: &[u8; 32], raw: &[u8]) -> Result<Vec<u8>> {
+    match detect(raw) {
+        Format::Block(header) => match decrypt_blocks(key, raw, &header) {
+            // A legacy file's random nonce can start with the magic by chance.
+            Err(e) => crypto::decrypt(key, raw).map_err(|_| e),
+            ok => ok,
+        },
+        Format::Legacy => crypto::decrypt(key, raw),
+    }
+}
+
+/// Decrypt `len` plaintext bytes starting at `offset`, opening only the
+/// blocks that cover the range. Legacy files are decrypted whole.
+pub fn decrypt_range(key: &[u8; 32], raw: &[u8], offset: u64, len: usize) -> Result<Vec<u8>> {
+    match detect(raw) {
+        Format::Block(header) => match block_range(key, raw, &header, offset, len) {
+            Err(e) => crypto::decrypt(key, raw)
+                .map(|pt| slice_range(pt, offset, len))
+                .map_err(|_| e),
+            ok => ok,
+        },
+        Format::Legacy => Ok(slice_range(crypto::decrypt(key, raw)?, offset, len)),
+    }
+}
+
+fn block_range(
+    key: &[u8; 32],
+    raw: &[u8],
+    header: &Header,
+    offset: u64,
+    len: usize,
+) -> Result<Vec<u8>> {
+    if offset >= header.plaintext_len || len == 0 {
+        return Ok(vec![]);
+    }
+    check_len(raw, header)?;
+    let end = (offset + len as u64).min(header.plaintext_len);
+    let bs = header.block_size as u64;
+    let mut out = Vec::with_capacity((end - offset) as usize);
+    for index in offset / bs..end.div_ceil(bs) {
+        let block = open_block(key, raw, header, index)?;
+        let block_start = index * bs;
+        let from = offset.saturating_sub(block_start) as usize;
+        let to = ((end - block_start) as usize).min(block.len());
+        out.extend_from_slice(&block[from..to]);
+    }
+    Ok(out)
+}
+
+fn slice_range(plaintext: Vec<u8>, offset: u64, len: usize) -> Vec<u8> {
+    let start = offset as usize;
+    if start >= plaintext.len() {
+        return vec![];
+    }
+    let end = (start + len).min(plaintext.len());
+    plaintext[start..end].to_vec()
+}
+
+/// Check that the packed stream `raw` holds every block `header` describes,
+/// ignoring anything after the last one unless trailing bytes are rejected.
+pub fn check_len(raw: &[u8], header: &Header) -> Result<()> {
+    check_len_as(raw, header, strict_trailing())
+}
+
+fn check_len_as(raw: &[u8], header: &Header, strict: bool) -> Result<()> {
+    let expected = header.file_len();
+    let actual = raw.len() as u128;
+    if actual < expected {
+        bail!(
+            "Backing file is {} bytes, header implies {}",
+            actual,
+            expected
+        );
+    }
+    if actual > expected && strict {
+        bail!(
+            "Backing file has {} trailing byte(s) beyond the last block",
+            actual - expected
+        );
+    }
+    Ok(())
+}
+
+fn decrypt_blocks(key: &[u8; 32], raw: &[u8], header: &Header) -> Result<Vec<u8>> {
+    check_len(raw, header)?;
+    let mut out = Vec::with_capacity(header.plaintext_len as usize);
+    for index in 0..header.block_count() {
+        out.extend_from_slice(&open_block(key, raw, header, index)?);
+    }
+    Ok(out)
+}
+
+fn open_block(key: &[u8; 32], raw: &[u8], header: &Header, index: u64) -> Result<Vec<u8>> {
+    ensure!(
+        !header.dedup_blocks(),
+        "Blocks are deduplicated; they can only be read through the block store"
+    );
+    let (start, len) = header.block_span(index);
+    let sealed = raw
+        .get(start as usize..start as usize + len)
+        .ok_or_else(|| anyhow!("Block {} is truncated", index))?;
+    open_sealed_block(key, header, index, sealed)
+}
+
+/// Seal `plaintext` as block `index` of a file described by `header`, for
+/// rewriting that block in place. The plaintext must fill the block exactly,
+/// or be the block's reference in a deduplicated file.
+pub fn seal_block(
+    key: &[u8; 32],
+    nonces: &dyn NonceSource,
+    header: &Header,
+    index: u64,
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    ensure!(
+        index < header.block_count() && plaintext.len() == header.payload_len(index),
+        "Block {} does not fit the file layout",
+        index
+    );
+    seal_payload(key, nonces, header, index, plaintext)
+}
+
+/// Authenticate and decrypt block `index`, given its sealed bytes as located
+/// by `Header::block_span`.
+pub fn open_sealed_block(
+    key: &[u8; 32],
+    header: &Header,
+    index: u64,
+    sealed: &[u8],
+) -> Result<Vec<u8>> {
+    if index >= header.block_count() || sealed.len() != header.block_span(index).1 {
+        bail!("Block {} is truncated", index);
+    }
+    let aad = header.block_aad(index);
+    let key = header.block_key(key)?;
+    let opened = if header.integrity_only() {
+        crypto::verify_with_aad(&key, sealed, &aad)
+    } else {
+        crypto::decrypt_with_cipher(header.cipher(), &key, sealed, &aad)
+    };
+    opened.map_err(|_| anyhow!("Block {} failed authentication", index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [0x42u8; 32];
+
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn round_trip_across_block_boundaries() {
+        for len in [0, 1, 63, 64, 65, 200] {
+            let pt = sample(len);
+            let ct = encrypt_file(&KEY, &pt, 64).unwrap();
+            assert_eq!(ct.len() as u128, Header::new(64, len as u64).file_len());
+            assert_eq!(decrypt_file(&KEY, &ct).unwrap(), pt, "len {len}");
+        }
+    }
+
+    #[test]
+    fn file_key_seals_the_blocks_until_erased() {
+        let pt = sample(200);
+        let mut ct =
+            encrypt_file_with_flags(&KEY, &RandomNonces, &pt, 64, FLAG_FILE_KEY, 0).unwrap();
+        let header = Header::decode(&ct[..MAX_HEADER_SIZE]).unwrap();
+        let end = HEADER_SIZE + WRAPPED_KEY_LEN;
+        assert!(header.has_file_key());
+        assert_eq!(header.size(), end);
+        assert_eq!(ct.len() as u128, header.file_len());
+        assert_eq!(plaintext_len(&ct[..MAX_HEADER_SIZE], ct.len() as u64), 200);
+        assert_eq!(decrypt_file(&KEY, &ct).unwrap(), pt);
+        assert_eq!(decrypt_range(&KEY, &ct, 70, 10).unwrap(), &pt[70..80]);
+
+        ct[HEADER_SIZE..end].fill(0);
+        assert!(decrypt_file(&KEY, &ct).is_err());
+        let flags = FLAG_FILE_KEY | FLAG_INTEGRITY_ONLY;
+        assert!(encrypt_file_with_flags(&KEY, &RandomNonces, &pt, 64, flags, 0).is_err());
+    }
+
+    #[test]
+    fn expiry_is_bound_to_the_header_it_was_sealed_in() {
+        let pt = sample(200);
+        let flags = FLAG_FILE_KEY | FLAG_EXPIRY;
+        let mut ct = encrypt_file_with_flags(&KEY, &RandomNonces, &pt, 64, flags, 5).unwrap();
+        let header = Header::decode(&ct).unwrap();
+        assert_eq!(header.size(), MAX_HEADER_SIZE);
+        assert_eq!(header.open_expiry(&KEY).unwrap(), None);
+
+        set_expiry(&KEY, &RandomNonces, &mut ct, Some(1_000)).unwrap();
+        let mut header = Header::decode(&ct).unwrap();
+        assert_eq!(header.open_expiry(&KEY).unwrap(), Some(1_000));
+        assert_eq!(decrypt_file(&KEY, &ct).unwrap(), pt);
+        let old = header.expiry;
+        // A new mtime carries the expiry along and retires the old seal.
+        header.seal_mtime(&KEY, &RandomNonces, 6).unwrap();
+        assert_eq!(header.open_expiry(&KEY).unwrap(), Some(1_000));
+        header.expiry = old;
+        assert!(header.open_expiry(&KEY).is_err());
+
+        // Another file's expiry doesn't open in this one.
+        let other = encrypt_file_with_flags(&KEY, &RandomNonces, &pt, 64, flags, 5).unwrap();
+        let mut other = Header::decode(&other).unwrap();
+        other.expiry = Header::decode(&ct).unwrap().expiry;
+        assert!(other.open_expiry(&KEY).is_err());
+        // And a file without room can't be given one.
+        let plain = encrypt_file(&KEY, &pt, 64).unwrap();
+        assert!(set_expiry(&KEY, &RandomNonces, &mut plain.clone(), Some(1)).is_err());
+    }
+
+    #[test]
+    fn range_reads_match_whole_file() {
+        let pt = sample(300);
+        let ct = encrypt_file(&KEY, &pt, 64).unwrap();
+        for (offset, len) in [(0, 10), (60, 10), (64, 64), (250, 100), (299, 1), (300, 5)] {
+            let expected = &pt[offset.min(300)..(offset + len).min(300)];
+            let got = decrypt_range(&KEY, &ct, offset as u64, len).unwrap();
+            assert_eq!(got, expected, "offset {offset} len {len}");
+        }
+    }
+
+    #[test]
+    fn resealed_block_replaces_only_that_block() {
+        let plaintext = sample(200);
+        let mut raw = encrypt_file(&KEY, &plaintext, 64).unwrap();
+        let header = Header::decode(&raw).unwrap();
+
+        let (start, len) = header.block_span(1);
+        let sealed = seal_block(&KEY, &RandomNonces, &header, 1, &[0xAA; 64]).unwrap();
+        raw[start as usize..start as usize + len].copy_from_slice(&sealed);
+
+        let mut expected = plaintext.clone();
+        expected[64..128].fill(0xAA);
+        assert_eq!(decrypt_file(&KEY, &raw).unwrap(), expected);
+        assert!(seal_block(&KEY, &RandomNonces, &header, 3, &[0; 64]).is_err());
+    }
+
+    #[test]
+    fn truncation_and_reordering_are_detected() {
+        let pt = sample(256);
+        let ct = encrypt_file(&KEY, &pt, 64).unwrap();
+
+        // Drop the last block and fix up the header length to match.
+        let mut truncated = ct[..Header::new(64, 192).file_len() as usize].to_vec();
+        truncated[12..20].copy_from_slice(&192u64.to_le_bytes());
+        assert!(decrypt_file(&KEY, &truncated).is_err());
+
+        // Swap blocks 0 and 1.
+        let mut swapped = ct.clone();
+        let rec = 64 + BLOCK_OVERHEAD;
+        let (a, b) = (HEADER_SIZE, HEADER_SIZE + rec);
+        let block0 = ct[a..a + rec].to_vec();
+        swapped[a..a + rec].copy_from_slice(&ct[b..b + rec]);
+        swapped[b..b + rec].copy_from_slice(&block0);
+        assert!(decrypt_file(&KEY, &swapped).is_err());
+    }
+
+    #[test]
+    fn absurd_header_length_is_rejected() {
+        let mut ct = encrypt_file(&KEY, b"abc", 64).unwrap();
+        ct[12..20].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(decrypt_file(&KEY, &ct).is_err());
+        assert!(decrypt_range(&KEY, &ct, 0, 3).is_err());
+    }
+
+    #[test]
+    fn plaintext_len_from_prefix() {
+        let block = encrypt_file(&KEY, &sample(100), 64).unwrap();
+        assert_eq!(
+            plaintext_len(&block[..HEADER_SIZE], block.len() as u64),
+            100
+        );
+        let legacy = crypto::encrypt(&KEY, &sample(100)).unwrap();
+        assert_eq!(
+            plaintext_len(&legacy[..HEADER_SIZE], legacy.len() as u64),
+            100
+        );
+        assert_eq!(plaintext_len(&[], 0), 0);
+    }
+
+    #[test]
+    fn integrity_only_blocks_are_readable_but_tamper_evident() {
+        let pt = sample(200);
+        let raw =
+            encrypt_file_with_flags(&KEY, &RandomNonces, &pt, 64, FLAG_INTEGRITY_ONLY, 0).unwrap();
+        let header = Header::decode(&raw).unwrap();
+        assert!(header.integrity_only());
+        let (start, _) = header.block_span(1);
+        let at = start as usize + crypto::HEADER_LEN;
+        assert_eq!(&raw[at..at + 64], &pt[64..128]);
+        assert_eq!(decrypt_file(&KEY, &raw).unwrap(), pt);
+
+        let mut tampered = raw.clone();
+        tampered[at] ^= 1;
+        assert!(decrypt_file(&KEY, &tampered).is_err());
+        // Clearing the flag doesn't turn the blocks into valid ciphertext.
+        let mut unflagged = raw;
+        unflagged[5] &= !FLAG_INTEGRITY_ONLY;
+        assert!(decrypt_file(&KEY, &unflagged).is_err());
+    }
+
+    #[test]
+    fn sealed_mtime_is_bound_to_the_header() {
+        let raw = encrypt_file_with_mtime(&KEY, &sample(100), 64, 1_234_567_890).unwrap();
+        let header = Header::decode(&raw).unwrap();
+        assert_eq!(header.open_mtime(&KEY).unwrap(), Some(1_234_567_890));
+        assert!(header.open_mtime(&[0x43u8; 32]).is_err());
+
+        // The seal doesn't follow the header to another length.
+        let mut moved = header;
+        moved.plaintext_len = 99;
+        assert!(moved.open_mtime(&KEY).is_err());
+
+        // Version 1 files carry no mtime and still decrypt.
+        let mut v1 = Header::new(64, 3);
+        v1.version = 1;
+        v1.mtime = None;
+        let mut old = v1.encode();
+        for index in 0..v1.block_count() {
+            old.extend_from_slice(&seal_block(&KEY, &RandomNonces, &v1, index, b"abc").unwrap());
+        }
+        let decoded = Header::decode(&old).unwrap();
+        assert_eq!(decoded.open_mtime(&KEY).unwrap(), None);
+        assert_eq!(decrypt_file(&KEY, &old).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn legacy_files_are_still_readable() {
+        let legacy = crypto::encrypt(&KEY, b"old format").unwrap();
+        assert_eq!(detect(&legacy), Format::Legacy);
+        assert_eq!(decrypt_file(&KEY, &legacy).unwrap(), b"old format");
+        assert_eq!(decrypt_range(&KEY, &legacy, 4, 3).unwrap(), b"for");
+    }
+
+    #[test]
+    fn trailing_bytes_are_ignored_unless_strict() {
+        let pt = sample(200);
+        let mut junk = encrypt_file(&KEY, &pt, 64).unwrap();
+        let header = Header::decode(&junk).unwrap();
+        junk.extend_from_slice(b"appended by someone else");
+
+        assert_eq!(decrypt_file(&KEY, &junk).unwrap(), pt);
+        assert_eq!(decrypt_range(&KEY, &junk, 190, 50).unwrap(), &pt[190..]);
+        assert_eq!(plaintext_len(&junk, junk.len() as u64), 200);
+
+        let err = check_len_as(&junk, &header, true).unwrap_err();
+        assert!(err.to_string().contains("24 trailing byte(s)"), "{err}");
+        // Missing bytes are never acceptable.
+        let short = &junk[..header.file_len() as usize - 1];
+        assert!(check_len_as(short, &header, false).is_err());
+    }
+}
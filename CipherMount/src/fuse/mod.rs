@@ -1,382 +1,5980 @@
-/// Week 1: Pass-through filesystem (mirrors a physical directory).
-/// Week 2: Intercepts read/write to encrypt/decrypt with AES-256-GCM.
+//! Week 1: Pass-through filesystem (mirrors a physical directory).
+//! Week 2: Intercepts read/write to encrypt/decrypt with AES-256-GCM.
 
-use crate::crypto;
+use crate::access::AccessLog;
+use crate::backend::{
+    Backend, CloseSync, LocalBackend, RetryBackend, SyncPolicy, TimeoutBackend, Transient,
+};
+use crate::cache::{self, Advice, BlockCache};
+use crate::clock::Clock;
+use crate::crypto::{Cipher, NonceSource, RandomNonces};
+use crate::dedup::{self, Store};
+use crate::events::EventHook;
+use crate::idmap::IdMap;
+use crate::layout::{self, Layout};
+use crate::logging::detail;
+use crate::memprof::{MemoryBudget, MemoryProfile, Reservation, Tracked};
+use crate::meta::{self, FileMeta};
+use crate::shred::{self, SecureDelete};
+use crate::workers::Pool;
+use crate::{crypto, format, mime, vault};
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty,
-    ReplyEntry, ReplyOpen, ReplyWrite, Request,
+    FileAttr, FileType, Filesystem, KernelConfig, MountOption, ReplyAttr, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, ReplyXattr, Request, TimeOrNow,
+};
+use libc::{
+    c_int, EACCES, EAGAIN, EDQUOT, EEXIST, EINVAL, EIO, ENAMETOOLONG, ENODATA, ENOENT, ENOSPC,
+    ENOTDIR, ENOTSUP, EPERM, ERANGE, EROFS, ETIMEDOUT,
 };
-use libc::{ENOENT, ENOTDIR, EIO};
-use std::collections::HashMap;
-use std::ffi::OsStr;
+use ring::hmac;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::{CString, OsStr, OsString};
 use std::fs;
-use std::os::unix::fs::MetadataExt;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, UNIX_EPOCH};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 const TTL: Duration = Duration::from_secs(1);
 const ROOT_INO: u64 = 1;
 
+/// Synthetic read-only file in the mount root listing recent decryption
+/// failures, when enabled.
+pub const ERRORS_FILE: &str = ".ciphermount-errors";
+
+/// Inode of `ERRORS_FILE`; never handed out by `alloc_ino`.
+const ERRORS_INO: u64 = u64::MAX - 1;
+
+/// Failures kept for `ERRORS_FILE`; older ones are dropped first.
+const ERROR_LOG_LEN: usize = 100;
+
+/// Blocks loaded past each read of a file advised to be read sequentially.
+const READAHEAD_BLOCKS: u64 = 8;
+
+/// AAD for sealed symlink targets. Targets are not bound to the link's path,
+/// so a rename moves the backing symlink without resealing it.
+const LINK_AAD: &[u8] = b"ciphermount symlink v1";
+
+/// Writes that touch only some blocks of a block-format file.
+enum FastWrite {
+    /// Overwrite inside the current contents.
+    InPlace(format::Header),
+    /// Append at the end; `None` for an empty backing file.
+    Append(Option<format::Header>),
+}
+
+/// The fields one `setattr` asks to change. Ids are as seen in the mount.
+#[derive(Debug, Clone, Copy, Default)]
+struct AttrChanges {
+    size: Option<u64>,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    atime: Option<TimeOrNow>,
+    mtime: Option<TimeOrNow>,
+}
+
+/// A directory entry as handed to `readdir`: inode, kind and name.
+type DirEntry = (u64, FileType, String);
+
+/// An open file handle.
+#[derive(Debug, Clone, Copy)]
+struct Handle {
+    ino: u64,
+    /// Opened with write intent.
+    writes: bool,
+    /// Written through since the last sync on close.
+    dirty: bool,
+}
+
+/// A content lock taken out of `CipherFS::content_locks`. The last user to
+/// let go removes it from the table, so it only holds inodes in use.
+struct ContentLock {
+    ino: u64,
+    locks: Arc<Mutex<HashMap<u64, Arc<RwLock<()>>>>>,
+    /// Only `None` while dropping.
+    lock: Option<Arc<RwLock<()>>>,
+}
+
+impl std::ops::Deref for ContentLock {
+    type Target = RwLock<()>;
+
+    fn deref(&self) -> &RwLock<()> {
+        self.lock.as_ref().unwrap()
+    }
+}
+
+impl Drop for ContentLock {
+    fn drop(&mut self) {
+        // Let go of this clone with the table locked, so that of two users
+        // dropping at once, the second sees only the table's.
+        let mut locks = self.locks.lock().unwrap();
+        drop(self.lock.take());
+        if locks
+            .get(&self.ino)
+            .is_some_and(|l| Arc::strong_count(l) == 1)
+        {
+            locks.remove(&self.ino);
+        }
+    }
+}
+
+/// What `OpenHandles` reports about one handle. Metadata only, never
+/// contents or names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandleInfo {
+    pub fh: u64,
+    pub ino: u64,
+    pub writes: bool,
+    /// Decrypted blocks of the file held in the cache, and their bytes.
+    pub cached_blocks: usize,
+    pub cached_bytes: u64,
+    /// A writeback failure not yet reported to the application. Writes go
+    /// straight to the backing store, so this is the only state a handle
+    /// can have pending.
+    pub pending_error: Option<c_int>,
+}
+
+/// The open handles of a mount, for diagnosing leaks and oversized caches.
+/// Taken before the filesystem is handed to `fuser` and cheap to clone.
+#[derive(Clone)]
+pub struct OpenHandles {
+    files: Arc<Mutex<HashMap<u64, Handle>>>,
+    dirs: Arc<Mutex<HashMap<u64, Arc<Vec<DirEntry>>>>>,
+    cache: Arc<BlockCache>,
+    write_errors: Arc<Mutex<HashMap<u64, c_int>>>,
+}
+
+impl OpenHandles {
+    /// Open file handles, by handle number.
+    pub fn list(&self) -> Vec<HandleInfo> {
+        let files: Vec<(u64, Handle)> = self
+            .files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(fh, h)| (*fh, *h))
+            .collect();
+        let mut list: Vec<HandleInfo> = files
+            .into_iter()
+            .map(|(fh, h)| {
+                let (cached_blocks, cached_bytes) = self.cache.usage(h.ino);
+                HandleInfo {
+                    fh,
+                    ino: h.ino,
+                    writes: h.writes,
+                    cached_blocks,
+                    cached_bytes,
+                    pending_error: self.write_errors.lock().unwrap().get(&h.ino).copied(),
+                }
+            })
+            .collect();
+        list.sort_by_key(|h| h.fh);
+        list
+    }
+
+    /// A human-readable dump: totals, then one line per file handle.
+    pub fn report(&self) -> String {
+        let list = self.list();
+        let mut out = format!(
+            "{} open file handle(s), {} directory handle(s), {} cached block(s)\n",
+            list.len(),
+            self.dirs.lock().unwrap().len(),
+            self.cache.len()
+        );
+        for h in list {
+            out += &format!(
+                "fh {}: inode {}, {}, {} cached block(s) ({} bytes)",
+                h.fh,
+                h.ino,
+                if h.writes { "read-write" } else { "read-only" },
+                h.cached_blocks,
+                h.cached_bytes
+            );
+            if let Some(e) = h.pending_error {
+                out += &format!(", unreported write error {}", e);
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Mount-time options for `CipherFS`.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Plaintext bytes per encrypted block for files written by this mount.
+    pub block_size: u32,
+    /// Where the blocks of files this mount creates or rewrites whole are
+    /// stored. Every file's own header says how to find its blocks.
+    pub block_layout: Layout,
+    /// Store new files authenticated but not encrypted. Not combined with
+    /// the dedup layout, whose block store only holds ciphertext.
+    pub integrity_only: bool,
+    /// Time-to-live given to newly created files. Files without metadata
+    /// expire this long after their backing mtime.
+    pub default_ttl: Option<Duration>,
+    /// Upper bound on a single backing read or write; slower operations fail
+    /// with ETIMEDOUT instead of wedging the FUSE worker.
+    pub op_timeout: Option<Duration>,
+    /// Times a backing operation failing with a transient error is retried,
+    /// with exponential backoff, before the error reaches the application.
+    pub io_retries: u32,
+    /// Errors retried besides EINTR and EAGAIN.
+    pub retry_on: Vec<Transient>,
+    /// Durability applied after every backing write.
+    pub backing_sync: SyncPolicy,
+    /// Whether closing a file (`flush`) fsyncs it.
+    pub close_sync: CloseSync,
+    /// Number of decrypted blocks kept in memory (0 disables the cache).
+    pub cache_blocks: usize,
+    /// Re-authenticate cached blocks against their ciphertext on every read
+    /// instead of trusting the in-memory plaintext.
+    pub always_authenticate: bool,
+    /// Record the uid of each file's last writer in its sidecar.
+    pub record_writer: bool,
+    /// Record the content type sniffed from what is written at the start
+    /// of each file in its sidecar, unless one was set by hand.
+    pub sniff_content_type: bool,
+    /// How backing file owners are shown, for mounts inside a user
+    /// namespace.
+    pub uid_map: IdMap,
+    pub gid_map: IdMap,
+    /// Refuse every change to the vault with EROFS.
+    pub read_only: bool,
+    /// Dry run on top of `read_only`: writes, truncations, deletions,
+    /// renames and xattr changes are recorded in the access log (or the
+    /// regular log) and reported as done without touching the vault. New
+    /// entries are recorded too but still fail with EROFS, since there
+    /// would be nothing to serve back.
+    pub shadow: bool,
+    /// Serve `ERRORS_FILE` in the mount root.
+    pub error_file: bool,
+    /// List directories in an order derived from the key and each entry's
+    /// name, rather than the backing store's, which can follow creation.
+    pub shuffle_listings: bool,
+    /// Longest name, in bytes, new entries may be given; longer ones fail
+    /// with ENAMETOOLONG before the backing store is touched.
+    pub max_name_length: Option<usize>,
+    /// Permissions every new file and directory is given, whatever the
+    /// creating process asked for.
+    pub force_mode: Option<u32>,
+    /// Directory, relative to the vault root, served as the mount's root
+    /// (checked by `vault::subtree`). Nothing outside it can be reached
+    /// through the mount; files stay sealed under their vault paths.
+    pub subpath: Option<PathBuf>,
+    /// Overwrite the backing data of deleted files, or destroy their keys,
+    /// before removing them.
+    pub secure_delete: SecureDelete,
+    /// Background requests (readahead, writeback) the kernel may keep in
+    /// flight; `None` keeps the kernel default.
+    pub max_background: Option<u16>,
+    /// In-flight background requests at which the kernel considers the
+    /// mount congested; `None` keeps the kernel default.
+    pub congestion_threshold: Option<u16>,
+    /// Mark files incomplete in their sidecar while they are open for
+    /// writing, so a write cut short by a crash is recognisable.
+    pub mark_incomplete: bool,
+    /// Lock cached plaintext in memory so it can't be swapped out, and open
+    /// files with direct I/O so it doesn't sit in the kernel page cache.
+    pub mlock: bool,
+    /// Extension (without the dot) given to the backing file of every new
+    /// regular file and stripped again in the mounted view, so tools that
+    /// go by extension see opaque data. If names are ever encrypted, the
+    /// extension stays outside the encrypted name.
+    pub backing_extension: Option<String>,
+    /// Worker threads serving reads, writes and fsyncs, so requests for
+    /// different files run in parallel. With 1 every request is served on
+    /// the session thread, one at a time.
+    pub threads: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            block_size: format::DEFAULT_BLOCK_SIZE,
+            block_layout: Layout::default(),
+            integrity_only: false,
+            default_ttl: None,
+            op_timeout: None,
+            io_retries: 0,
+            retry_on: Vec::new(),
+            backing_sync: SyncPolicy::default(),
+            close_sync: CloseSync::default(),
+            cache_blocks: 1024,
+            always_authenticate: false,
+            record_writer: false,
+            sniff_content_type: false,
+            uid_map: IdMap::default(),
+            gid_map: IdMap::default(),
+            read_only: false,
+            shadow: false,
+            error_file: false,
+            shuffle_listings: false,
+            max_name_length: None,
+            force_mode: None,
+            subpath: None,
+            secure_delete: SecureDelete::default(),
+            max_background: None,
+            congestion_threshold: None,
+            mark_incomplete: false,
+            mlock: false,
+            backing_extension: None,
+            threads: 1,
+        }
+    }
+}
+
+/// What the kernel lets the mounted files do. Everything is refused unless
+/// asked for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Hardening {
+    /// Allow executing files.
+    pub exec: bool,
+    /// Honour setuid/setgid bits.
+    pub suid: bool,
+    /// Allow device nodes to be opened as devices.
+    pub dev: bool,
+}
+
+/// Options to mount a CipherMount filesystem with.
+pub fn mount_options(hardening: Hardening, allow_other: bool, read_only: bool) -> Vec<MountOption> {
+    let mut options = vec![
+        MountOption::FSName("ciphermount".to_string()),
+        MountOption::AutoUnmount,
+    ];
+    let toggles = [
+        (hardening.exec, MountOption::Exec, MountOption::NoExec),
+        (hardening.suid, MountOption::Suid, MountOption::NoSuid),
+        (hardening.dev, MountOption::Dev, MountOption::NoDev),
+    ];
+    options.extend(
+        toggles
+            .into_iter()
+            .map(|(allow, on, off)| if allow { on } else { off }),
+    );
+    if allow_other {
+        options.push(MountOption::AllowOther);
+    }
+    if read_only {
+        options.push(MountOption::RO);
+    }
+    options
+}
+
+/// The connection settings `init` negotiates, so the negotiation can be
+/// exercised without a kernel.
+trait Connection {
+    fn set_max_background(&mut self, value: u16) -> Result<u16, u16>;
+    fn set_congestion_threshold(&mut self, value: u16) -> Result<u16, u16>;
+}
+
+impl Connection for KernelConfig {
+    fn set_max_background(&mut self, value: u16) -> Result<u16, u16> {
+        KernelConfig::set_max_background(self, value)
+    }
+
+    fn set_congestion_threshold(&mut self, value: u16) -> Result<u16, u16> {
+        KernelConfig::set_congestion_threshold(self, value)
+    }
+}
+
+#[derive(Clone)]
 pub struct CipherFS {
     source: PathBuf,
     key: [u8; 32],
-    /// inode → path mapping (in-memory, rebuilt on each lookup)
-    inodes: Arc<Mutex<HashMap<u64, PathBuf>>>,
+    config: Config,
+    backend: Arc<dyn Backend>,
+    /// inode → paths mapping (in-memory, rebuilt on each lookup). A
+    /// hard-linked file has one inode with every name the mount has seen;
+    /// requests on it go through the first.
+    inodes: Arc<Mutex<HashMap<u64, Vec<PathBuf>>>>,
+    /// Held exclusively by a rename from before it moves the entry until
+    /// the inode map follows, and shared by everything that resolves a
+    /// name and registers the path it found. A lookup racing a rename
+    /// would otherwise register a path just moved away (a stale inode), or
+    /// the new path before the rename remaps the old inode to it (two
+    /// inodes for one file).
+    names: Arc<RwLock<()>>,
     next_ino: Arc<Mutex<u64>>,
+    /// Per-inode content locks. Writes to the same inode serialize on its
+    /// lock while writes to different inodes proceed in parallel; reads take
+    /// the shared side so they never observe a half-written backing file.
+    /// An entry only lives while its lock is in use.
+    content_locks: Arc<Mutex<HashMap<u64, Arc<RwLock<()>>>>>,
+    /// Decrypted blocks, invalidated whenever this mount writes the file.
+    /// Changes made to the backing store behind the mount's back are not
+    /// noticed until the blocks are evicted.
+    cache: Arc<BlockCache>,
+    /// Number of blocks authenticated (opened) so far.
+    auth_count: Arc<AtomicU64>,
+    /// Listings taken at `opendir`, by directory handle, so a paginated
+    /// `readdir` stays consistent even if the directory changes underneath.
+    dir_handles: Arc<Mutex<HashMap<u64, Arc<Vec<DirEntry>>>>>,
+    next_fh: Arc<AtomicU64>,
+    /// Most recent decryption failures, as lines of `ERRORS_FILE`.
+    errors: Arc<Mutex<VecDeque<String>>>,
+    /// Open file handles by handle number.
+    handles: Arc<Mutex<HashMap<u64, Handle>>>,
+    /// Inodes currently marked incomplete in their sidecar.
+    incomplete: Arc<Mutex<HashSet<u64>>>,
+    /// Told about every change made through the mount, if set.
+    hook: Option<Arc<dyn EventHook>>,
+    /// First failed kernel writeback per inode. The application never saw
+    /// those writes fail, so the error is held until the next `flush`,
+    /// `fsync` or `release` of the file reports it.
+    write_errors: Arc<Mutex<HashMap<u64, c_int>>>,
+    /// Audit trail of reads, if set.
+    access: Option<Arc<AccessLog>>,
+    /// Where the blocks of deduplicated files are kept.
+    store: Store,
+    /// Nonces for file contents and symlink targets.
+    nonces: Arc<dyn NonceSource>,
+    /// Wall-clock time for expiry and sealed mtimes, held while the system
+    /// clock is stepped back.
+    clock: Arc<Clock>,
+    /// Accounting of whole-file buffers, if set.
+    memory: Option<Arc<MemoryProfile>>,
+    /// Bound on the whole-file buffers held at once, if set.
+    budget: Option<Arc<MemoryBudget>>,
+    /// Open files advised to be read sequentially: reads also load the
+    /// blocks that follow.
+    sequential: Arc<Mutex<HashSet<u64>>>,
+    /// Each directory's own default cipher (`None` if it names none), as
+    /// read from its sidecar, so finding the one a new file inherits
+    /// doesn't reread every ancestor's sidecar.
+    dir_policies: Arc<Mutex<HashMap<PathBuf, Option<Cipher>>>>,
+    /// Where requests on file contents are served, if `Config::threads`
+    /// asks for more than the session thread.
+    workers: Option<Arc<Pool>>,
 }
 
 impl CipherFS {
     pub fn new(source: PathBuf, key: [u8; 32]) -> Self {
+        Self::with_config(source, key, Config::default())
+    }
+
+    pub fn with_config(source: PathBuf, key: [u8; 32], config: Config) -> Self {
+        Self::with_backend(source, key, config, Arc::new(LocalBackend))
+    }
+
+    pub fn with_backend(
+        source: PathBuf,
+        key: [u8; 32],
+        config: Config,
+        backend: Arc<dyn Backend>,
+    ) -> Self {
+        let backend: Arc<dyn Backend> = match config.op_timeout {
+            Some(timeout) => Arc::new(TimeoutBackend::new(backend, timeout)),
+            None => backend,
+        };
+        // Outside the timeout, so each attempt gets the full time.
+        let backend: Arc<dyn Backend> = match config.io_retries {
+            0 => backend,
+            n => Arc::new(RetryBackend::new(backend, n, &config.retry_on)),
+        };
+        let store = Store::new(&source);
         let mut inodes = HashMap::new();
-        inodes.insert(ROOT_INO, source.clone());
+        let root = config
+            .subpath
+            .as_ref()
+            .map_or_else(|| source.clone(), |sub| source.join(sub));
+        inodes.insert(ROOT_INO, vec![root]);
+        let cache = Arc::new(if config.mlock {
+            BlockCache::locked(config.cache_blocks)
+        } else {
+            BlockCache::new(config.cache_blocks)
+        });
+        let workers = (config.threads > 1).then(|| Arc::new(Pool::new(config.threads)));
         Self {
             source,
             key,
+            config,
+            backend,
             inodes: Arc::new(Mutex::new(inodes)),
+            names: Arc::new(RwLock::new(())),
             next_ino: Arc::new(Mutex::new(2)),
+            content_locks: Arc::new(Mutex::new(HashMap::new())),
+            cache,
+            auth_count: Arc::new(AtomicU64::new(0)),
+            dir_handles: Arc::new(Mutex::new(HashMap::new())),
+            dir_policies: Arc::new(Mutex::new(HashMap::new())),
+            sequential: Arc::new(Mutex::new(HashSet::new())),
+            next_fh: Arc::new(AtomicU64::new(1)),
+            errors: Arc::new(Mutex::new(VecDeque::new())),
+            handles: Arc::new(Mutex::new(HashMap::new())),
+            incomplete: Arc::new(Mutex::new(HashSet::new())),
+            hook: None,
+            write_errors: Arc::new(Mutex::new(HashMap::new())),
+            access: None,
+            store,
+            nonces: Arc::new(RandomNonces),
+            clock: Arc::new(Clock::system()),
+            memory: None,
+            budget: None,
+            workers,
+        }
+    }
+
+    /// Report changes made through the mount to `hook`.
+    pub fn with_hook(mut self, hook: Arc<dyn EventHook>) -> Self {
+        self.hook = Some(hook);
+        self
+    }
+
+    /// Log every open, read and listing to `access`, subject to its rate
+    /// limit.
+    pub fn with_access_log(mut self, access: Arc<AccessLog>) -> Self {
+        self.access = Some(access);
+        self
+    }
+
+    /// Seal file contents with nonces from `nonces` instead of random ones.
+    pub fn with_nonces(mut self, nonces: Arc<dyn NonceSource>) -> Self {
+        self.nonces = nonces;
+        self
+    }
+
+    /// Read the time from `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Count the whole-file buffers reads and writes hold in `memory`.
+    pub fn with_memory_profile(mut self, memory: Arc<MemoryProfile>) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    /// Make operations that buffer whole files wait while `budget` is spent.
+    pub fn with_memory_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// The mount's clock, for work done outside it on the mount's behalf.
+    pub fn clock(&self) -> Arc<Clock> {
+        Arc::clone(&self.clock)
+    }
+
+    /// A view of the open handles that outlives handing `self` to `fuser`.
+    pub fn open_handles(&self) -> OpenHandles {
+        OpenHandles {
+            files: Arc::clone(&self.handles),
+            dirs: Arc::clone(&self.dir_handles),
+            cache: Arc::clone(&self.cache),
+            write_errors: Arc::clone(&self.write_errors),
         }
     }
 
-    fn alloc_ino(&self) -> u64 {
+    /// Next free inode number, or ENOSPC once they run out. Numbers are
+    /// never reused: the mount doesn't track the kernel's `forget`s, so
+    /// one dropped from the map may still be in the kernel's cache, and
+    /// handing it to another path would alias the two.
+    fn alloc_ino(&self) -> Result<u64, c_int> {
         let mut n = self.next_ino.lock().unwrap();
+        if *n >= ERRORS_INO {
+            log::error!("Inode numbers exhausted; refusing to register new paths");
+            return Err(libc::ENOSPC);
+        }
         let ino = *n;
         *n += 1;
-        ino
+        Ok(ino)
     }
 
     fn path_for(&self, ino: u64) -> Option<PathBuf> {
-        self.inodes.lock().unwrap().get(&ino).cloned()
+        self.inodes
+            .lock()
+            .unwrap()
+            .get(&ino)
+            .and_then(|paths| paths.first())
+            .cloned()
+    }
+
+    fn ino_for(&self, path: &Path) -> Option<u64> {
+        let map = self.inodes.lock().unwrap();
+        map.iter()
+            .find(|(_, paths)| paths.iter().any(|p| p == path))
+            .map(|(ino, _)| *ino)
     }
 
-    fn register(&self, path: PathBuf) -> u64 {
+    fn register(&self, path: PathBuf) -> Result<u64, c_int> {
         let mut map = self.inodes.lock().unwrap();
         // Return existing ino if already registered
-        for (ino, p) in map.iter() {
-            if p == &path {
-                return *ino;
+        for (ino, paths) in map.iter() {
+            if paths.contains(&path) {
+                return Ok(*ino);
             }
         }
-        let ino = {
-            let mut n = self.next_ino.lock().unwrap();
-            let i = *n;
-            *n += 1;
-            i
-        };
-        map.insert(ino, path);
-        ino
+        // Another name of a file known under one already shares its inode,
+        // as on the backing filesystem, so `stat` agrees for both.
+        let linked = fs::symlink_metadata(&path)
+            .ok()
+            .filter(|m| m.is_file() && m.nlink() > 1);
+        if let Some(meta) = linked {
+            let same_file = |p: &PathBuf| {
+                fs::symlink_metadata(p)
+                    .is_ok_and(|m| m.dev() == meta.dev() && m.ino() == meta.ino())
+            };
+            let known = map
+                .iter_mut()
+                .find(|(_, paths)| paths.first().is_some_and(same_file));
+            if let Some((ino, paths)) = known {
+                paths.push(path);
+                return Ok(*ino);
+            }
+        }
+        let ino = self.alloc_ino()?;
+        map.insert(ino, vec![path]);
+        Ok(ino)
     }
 
-    fn meta_to_attr(ino: u64, meta: &fs::Metadata) -> FileAttr {
-        let kind = if meta.is_dir() {
-            FileType::Directory
-        } else {
-            FileType::RegularFile
-        };
-        let mtime = meta
-            .modified()
-            .unwrap_or(UNIX_EPOCH)
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default();
-        let atime = meta
-            .accessed()
-            .unwrap_or(UNIX_EPOCH)
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default();
-        FileAttr {
-            ino,
-            size: meta.len(),
-            blocks: meta.blocks(),
-            atime: UNIX_EPOCH + atime,
-            mtime: UNIX_EPOCH + mtime,
-            ctime: UNIX_EPOCH + mtime,
-            crtime: UNIX_EPOCH,
-            kind,
-            perm: meta.mode() as u16,
-            nlink: meta.nlink() as u32,
-            uid: meta.uid(),
-            gid: meta.gid(),
-            rdev: meta.rdev() as u32,
-            blksize: 512,
-            flags: 0,
-        }
+    /// Whether `ino` has a name besides `path` that the mount knows of.
+    fn has_other_names(&self, ino: u64, path: &Path) -> bool {
+        self.inodes
+            .lock()
+            .unwrap()
+            .get(&ino)
+            .is_some_and(|paths| paths.iter().any(|p| p != path))
     }
-}
 
-impl Filesystem for CipherFS {
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        if let Some(path) = self.path_for(ino) {
-            match fs::metadata(&path) {
-                Ok(meta) => reply.attr(&TTL, &Self::meta_to_attr(ino, &meta)),
-                Err(_) => reply.error(ENOENT),
+    /// Drop `path` from the names of `ino`, unless it is the last.
+    fn forget_name(&self, ino: u64, path: &Path) {
+        if let Some(paths) = self.inodes.lock().unwrap().get_mut(&ino) {
+            if paths.len() > 1 {
+                paths.retain(|p| p != path);
             }
-        } else {
-            reply.error(ENOENT);
         }
     }
 
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        if let Some(parent_path) = self.path_for(parent) {
-            let child_path = parent_path.join(name);
-            match fs::metadata(&child_path) {
-                Ok(meta) => {
-                    let ino = self.register(child_path);
-                    reply.entry(&TTL, &Self::meta_to_attr(ino, &meta), 0);
-                }
-                Err(_) => reply.error(ENOENT),
-            }
-        } else {
-            reply.error(ENOENT);
+    /// Check that the backing entry at `path` is still a `kind`. Something
+    /// outside the mount may have replaced a file with a directory or the
+    /// other way round since the kernel looked it up; the request then fails
+    /// with EISDIR or ENOTDIR rather than an EIO from reading a directory.
+    fn expect_kind(path: &Path, kind: FileType) -> Result<(), c_int> {
+        let meta = fs::symlink_metadata(path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => ENOENT,
+            _ => EIO,
+        })?;
+        let actual = Self::file_kind(meta.file_type());
+        if actual == kind {
+            return Ok(());
         }
+        log::warn!(
+            "{} was a {:?} but is now a {:?}; changed outside the mount",
+            detail(path),
+            kind,
+            actual
+        );
+        Err(match (kind, actual) {
+            (_, FileType::Directory) => libc::EISDIR,
+            (FileType::Directory, _) => ENOTDIR,
+            _ => EIO,
+        })
     }
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        let path = match self.path_for(ino) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
+    /// Return the content lock for `ino`, creating it on first use. The table
+    /// mutex is only held long enough to clone the `Arc`.
+    fn content_lock(&self, ino: u64) -> ContentLock {
+        let mut locks = self.content_locks.lock().unwrap();
+        ContentLock {
+            ino,
+            locks: Arc::clone(&self.content_locks),
+            lock: Some(locks.entry(ino).or_default().clone()),
+        }
+    }
+
+    /// Run `job` on a worker if the mount has them, otherwise right away on
+    /// the calling (session) thread. The job gets its own handle on the
+    /// mount, which shares all state with this one.
+    fn dispatch(&self, job: impl FnOnce(&CipherFS) + Send + 'static) {
+        match &self.workers {
+            Some(workers) => {
+                let fs = self.clone();
+                workers.run(move || job(&fs));
             }
-        };
+            None => job(self),
+        }
+    }
 
-        if !path.is_dir() {
-            reply.error(ENOTDIR);
-            return;
+    /// True if the regular file at `path` is past its expiry.
+    fn is_expired(&self, path: &Path) -> bool {
+        path.is_file()
+            && meta::expires_at(&self.key, &self.source, path, self.config.default_ttl)
+                .is_some_and(|t| t <= self.clock.now())
+    }
+
+    /// Remove an expired file the way `unlink` would.
+    fn reclaim(&self, path: &Path) {
+        log::info!("Removing expired file {}", detail(path));
+        if let Err(e) = self.unlink_file(path, true) {
+            let e = io::Error::from_raw_os_error(e);
+            log::warn!("Failed to remove expired file {}: {}", detail(path), e);
         }
+    }
 
-        let entries = match fs::read_dir(&path) {
-            Ok(e) => e,
-            Err(_) => {
-                reply.error(EIO);
-                return;
-            }
+    /// Remove every file in the vault that has expired, each the way a
+    /// lookup of it would. Returns the number removed.
+    pub fn collect_expired(&self) -> anyhow::Result<usize> {
+        self.check_writable()
+            .map_err(|_| anyhow::anyhow!("The mount is read-only"))?;
+        let now = self.clock.now();
+        meta::collect_expired(
+            &self.key,
+            &self.source,
+            now,
+            self.config.default_ttl,
+            |path| {
+                self.unlink_file(path, true)
+                    .map_err(io::Error::from_raw_os_error)
+            },
+        )
+    }
+
+    /// List the entries of directory `ino`, including `.` and `..`, skipping
+    /// hidden and expired entries.
+    fn list_dir(&self, ino: u64) -> Result<Vec<DirEntry>, c_int> {
+        let _names = self.names.read().unwrap();
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        // A directory removed out-of-band is ENOENT, not an I/O error.
+        let not_found = |e: io::Error| match e.kind() {
+            io::ErrorKind::NotFound => ENOENT,
+            _ => EIO,
         };
+        if !fs::metadata(&path).map_err(not_found)?.is_dir() {
+            return Err(ENOTDIR);
+        }
+        let entries = fs::read_dir(&path).map_err(not_found)?;
 
-        let mut all: Vec<(u64, FileType, String)> = vec![
+        let mut all: Vec<DirEntry> = vec![
             (ino, FileType::Directory, ".".to_string()),
             (ino, FileType::Directory, "..".to_string()),
         ];
 
         for entry in entries.flatten() {
-            let child_path = entry.path();
-            let child_ino = self.register(child_path.clone());
-            let kind = if child_path.is_dir() {
-                FileType::Directory
-            } else {
-                FileType::RegularFile
+            if self.is_hidden(ino, &entry.file_name()) {
+                continue;
+            }
+            // Entries deleted while we iterate are simply left out.
+            let Ok(file_type) = entry.file_type() else {
+                continue;
             };
-            let name = entry.file_name().to_string_lossy().to_string();
+            let child_path = entry.path();
+            if self.is_expired(&child_path) {
+                continue;
+            }
+            let child_ino = self.register(child_path.clone())?;
+            let kind = Self::file_kind(file_type);
+            let name = self
+                .logical_name(&entry.file_name(), file_type)
+                .to_string_lossy()
+                .to_string();
             all.push((child_ino, kind, name));
         }
-
-        for (i, (child_ino, kind, name)) in all.iter().enumerate().skip(offset as usize) {
-            if reply.add(*child_ino, (i + 1) as i64, *kind, name) {
-                break;
-            }
+        if ino == ROOT_INO && self.config.error_file {
+            all.push((ERRORS_INO, FileType::RegularFile, ERRORS_FILE.to_string()));
         }
-        reply.ok();
+        if self.config.shuffle_listings {
+            self.shuffle(&path, &mut all[2..]);
+        }
+        Ok(all)
     }
 
-    fn open(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
-        if self.path_for(ino).is_some() {
-            reply.opened(0, 0);
-        } else {
-            reply.error(ENOENT);
-        }
+    /// Order `entries` of directory `dir` by a keyed hash of the directory
+    /// and each name: the same at every listing, so pagination holds, but
+    /// telling nothing about which entry came first.
+    fn shuffle(&self, dir: &Path, entries: &mut [DirEntry]) {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &self.key);
+        let dir = self.relative(dir).as_os_str().as_bytes();
+        entries.sort_by_cached_key(|(_, _, name)| {
+            let mut ctx = hmac::Context::with_key(&key);
+            ctx.update(b"ciphermount listing order v1\0");
+            ctx.update(dir);
+            ctx.update(b"\0");
+            ctx.update(name.as_bytes());
+            ctx.sign().as_ref().to_vec()
+        });
     }
 
-    /// Read: load file from disk → decrypt → return plaintext to caller.
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyData,
-    ) {
-        let path = match self.path_for(ino) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
+    /// Attributes of `parent/name`, registering its inode. An expired file
+    /// is reclaimed on the spot and reported as missing.
+    fn lookup_entry(&self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        let _names = self.names.read().unwrap();
+        if parent == ROOT_INO && name == ERRORS_FILE && self.config.error_file {
+            return Ok(self.errors_attr());
+        }
+        if self.is_hidden(parent, name) {
+            return Err(ENOENT);
+        }
+        let child_path = self.child_path(parent, name)?;
+        let meta = fs::symlink_metadata(&child_path).map_err(|_| ENOENT)?;
+        if self.is_expired(&child_path) {
+            // An untrusted sidecar hides the file but never deletes it.
+            let trusted = meta::expiry(
+                &self.key,
+                &self.source,
+                &child_path,
+                self.config.default_ttl,
+            );
+            if trusted.is_err() {
+                return Err(EIO);
             }
-        };
-
-        let raw = match fs::read(&path) {
-            Ok(b) => b,
-            Err(_) => {
-                reply.error(EIO);
-                return;
+            // A read-only mount leaves it for a read-write one to remove.
+            if self.check_writable().is_ok() {
+                self.reclaim(&child_path);
             }
-        };
-
-        // If file is empty or too short to be encrypted, return empty
-        if raw.len() < crypto::HEADER_LEN + 16 {
-            reply.data(&[]);
-            return;
+            return Err(ENOENT);
         }
+        let ino = self.register(child_path.clone())?;
+        Ok(self.attr(ino, &child_path, &meta))
+    }
 
-        match crypto::decrypt(&self.key, &raw) {
-            Ok(plaintext) => {
-                let start = offset as usize;
-                let end = (start + size as usize).min(plaintext.len());
-                if start >= plaintext.len() {
-                    reply.data(&[]);
-                } else {
-                    reply.data(&plaintext[start..end]);
-                }
+    /// Backing path of the existing entry `name` in directory `parent`:
+    /// with a backing extension, `name.<ext>` if there is one, otherwise
+    /// `name` itself (directories, and files from before the extension).
+    fn child_path(&self, parent: u64, name: &OsStr) -> Result<PathBuf, c_int> {
+        // The kernel resolves these itself; joined here, `..` would climb
+        // out of the mount's root.
+        if name == "." || name == ".." {
+            return Err(EINVAL);
+        }
+        let dir = self.path_for(parent).ok_or(ENOENT)?;
+        if let Some(ext) = &self.config.backing_extension {
+            let with_ext = dir.join(Self::add_extension(name, ext));
+            if fs::symlink_metadata(&with_ext).is_ok() {
+                return Ok(with_ext);
             }
-            Err(e) => {
-                log::error!("Decrypt error on {:?}: {}", path, e);
-                reply.error(EIO);
+            // A regular file `<x>.<ext>` is only reachable as `<x>`; its
+            // backing name is treated as free.
+            let hidden = fs::symlink_metadata(dir.join(name))
+                .is_ok_and(|m| self.logical_name(name, m.file_type()) != name);
+            if hidden {
+                return Ok(with_ext);
             }
         }
+        Ok(dir.join(name))
     }
 
-    /// Write: encrypt buffer → write to disk.
-    fn write(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyWrite,
-    ) {
-        let path = match self.path_for(ino) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
+    /// Backing path for a new entry `name` in directory `parent`, which gets
+    /// the backing extension if it is a regular file. An existing entry of
+    /// that name keeps its own path, so creating over it finds it.
+    fn new_path(&self, parent: u64, name: &OsStr, is_file: bool) -> Result<PathBuf, c_int> {
+        if let Some(max) = self.config.max_name_length.filter(|&max| name.len() > max) {
+            let dir = self.path_for(parent).ok_or(ENOENT)?;
+            log::warn!(
+                "Refusing a {}-byte name in {}: over --max-name-length {}",
+                name.len(),
+                detail(&dir),
+                max
+            );
+            return Err(ENAMETOOLONG);
+        }
+        Self::expect_kind(&self.path_for(parent).ok_or(ENOENT)?, FileType::Directory)?;
+        let existing = self.child_path(parent, name)?;
+        match &self.config.backing_extension {
+            Some(ext) if is_file && fs::symlink_metadata(&existing).is_err() => {
+                Ok(existing.with_file_name(Self::add_extension(name, ext)))
             }
-        };
+            _ => Ok(existing),
+        }
+    }
 
-        // Read existing plaintext (if any) so we can handle partial writes
-        let mut plaintext = if path.exists() {
-            let raw = fs::read(&path).unwrap_or_default();
-            if raw.len() >= crypto::HEADER_LEN + 16 {
-                crypto::decrypt(&self.key, &raw).unwrap_or_default()
-            } else {
-                vec![]
-            }
-        } else {
-            vec![]
-        };
+    fn add_extension(name: &OsStr, ext: &str) -> OsString {
+        let mut with_ext = name.to_os_string();
+        with_ext.push(".");
+        with_ext.push(ext);
+        with_ext
+    }
 
-        // Extend buffer if needed and write at offset
-        let end = offset as usize + data.len();
-        if plaintext.len() < end {
-            plaintext.resize(end, 0);
+    /// Name shown in the mount for the backing entry `name`: regular files
+    /// lose the backing extension.
+    fn logical_name<'a>(&self, name: &'a OsStr, file_type: fs::FileType) -> &'a OsStr {
+        let Some(ext) = &self.config.backing_extension else {
+            return name;
+        };
+        let bytes = name.as_bytes();
+        let suffix_len = ext.len() + 1;
+        let stripped = bytes.len() > suffix_len
+            && file_type.is_file()
+            && bytes.ends_with(ext.as_bytes())
+            && bytes[bytes.len() - suffix_len] == b'.';
+        if stripped {
+            OsStr::from_bytes(&bytes[..bytes.len() - suffix_len])
+        } else {
+            name
         }
-        plaintext[offset as usize..end].copy_from_slice(data);
+    }
 
-        match crypto::encrypt(&self.key, &plaintext) {
-            Ok(ciphertext) => match fs::write(&path, &ciphertext) {
-                Ok(_) => reply.written(data.len() as u32),
-                Err(_) => reply.error(EIO),
-            },
-            Err(e) => {
-                log::error!("Encrypt error on {:?}: {}", path, e);
-                reply.error(EIO);
-            }
-        }
+    /// Snapshot the listing of directory `ino` and return a handle for it.
+    fn open_dir(&self, ino: u64) -> Result<u64, c_int> {
+        let entries = Arc::new(self.list_dir(ino)?);
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        self.dir_handles.lock().unwrap().insert(fh, entries);
+        Ok(fh)
     }
 
-    fn create(
-        &mut self,
-        _req: &Request,
-        parent: u64,
-        name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-        _flags: i32,
-        reply: fuser::ReplyCreate,
-    ) {
-        let parent_path = match self.path_for(parent) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-        let child_path = parent_path.join(name);
-        match fs::File::create(&child_path) {
-            Ok(_) => {
-                let ino = self.register(child_path.clone());
-                let meta = fs::metadata(&child_path).unwrap();
-                reply.created(&TTL, &Self::meta_to_attr(ino, &meta), 0, 0, 0);
-            }
-            Err(_) => reply.error(EIO),
+    /// Entries to serve for `readdir` on `ino`: the `opendir` snapshot for
+    /// `fh` if there is one, otherwise a fresh listing.
+    fn dir_entries(&self, ino: u64, fh: u64) -> Result<Arc<Vec<DirEntry>>, c_int> {
+        if let Some(entries) = self.dir_handles.lock().unwrap().get(&fh) {
+            return Ok(Arc::clone(entries));
         }
+        self.list_dir(ino).map(Arc::new)
     }
 
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let parent_path = match self.path_for(parent) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
+    fn release_dir(&self, fh: u64) {
+        self.dir_handles.lock().unwrap().remove(&fh);
+    }
+
+    /// Seal an expiry `secs` from now (none for 0) into the header of file
+    /// `ino`, where deleting or replaying its sidecar can't undo it. A file
+    /// whose header has no room for one is rewritten to make room.
+    fn set_ttl(&self, ino: u64, secs: u64) -> Result<(), c_int> {
+        self.check_writable()?;
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        if !path.is_file() {
+            return Err(EINVAL);
+        }
+        let lock = self.content_lock(ino);
+        let _guard = lock.write().unwrap();
+        self.check_mutable(&path)?;
+        let at = (secs > 0).then(|| self.clock.now() + secs);
+        let header = format::Header::decode(&self.header_prefix(&path));
+        let Some(mut header) = header.filter(|h| h.has_expiry()) else {
+            let plaintext = self.whole_plaintext(&path)?;
+            return self.rewrite_whole_expiring(ino, &path, &plaintext, Some(at));
         };
-        let child_path = parent_path.join(name);
-        match fs::remove_file(&child_path) {
-            Ok(_) => reply.ok(),
-            Err(_) => reply.error(EIO),
+        header
+            .seal_expiry(&self.key, self.nonces.as_ref(), at)
+            .map_err(|e| {
+                log::error!("Encrypt error on {}: {}", detail(&path), e);
+                EIO
+            })?;
+        let written = self.backend.write_at(&path, 0, &header.encode());
+        self.cache.invalidate(ino);
+        written.map_err(|e| Self::io_errno(&e))?;
+        self.sync_backing(&path).map_err(|e| Self::io_errno(&e))
+    }
+
+    /// Request that file `ino` be encrypted with `cipher` when next written.
+    fn set_cipher(&self, ino: u64, cipher: Cipher) -> Result<(), c_int> {
+        self.check_writable()?;
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        if !path.is_file() {
+            return Err(EINVAL);
+        }
+        self.check_mutable(&path)?;
+        let lock = self.content_lock(ino);
+        let _guard = lock.write().unwrap();
+        self.update_meta(&path, |m| m.cipher = Some(cipher))
+    }
+
+    /// Mark file `ino` immutable, or clear the mark.
+    fn set_immutable(&self, ino: u64, immutable: bool) -> Result<(), c_int> {
+        self.check_writable()?;
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        if !path.is_file() {
+            return Err(EINVAL);
+        }
+        let lock = self.content_lock(ino);
+        let _guard = lock.write().unwrap();
+        self.update_meta(&path, |m| m.immutable = immutable)
+    }
+
+    /// Set the cipher new files below directory `ino` are created with, or
+    /// clear it to inherit from the directories above.
+    fn set_default_cipher(&self, ino: u64, cipher: Option<Cipher>) -> Result<(), c_int> {
+        self.check_writable()?;
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        // The root's sidecar would sit outside the vault.
+        if ino == ROOT_INO || !path.is_dir() {
+            return Err(EINVAL);
+        }
+        let mut dir_meta = FileMeta::load(&self.key, &self.source, &path)
+            .map_err(|e| {
+                log::error!("Not updating metadata of {}: {}", detail(&path), e);
+                EIO
+            })?
+            .unwrap_or_default();
+        dir_meta.default_cipher = cipher;
+        let stored = if dir_meta == FileMeta::default() {
+            meta::remove(&path).map_err(|e| Self::os_errno(&e))
+        } else {
+            dir_meta
+                .store(&self.key, &self.source, &path)
+                .map_err(|_| EIO)
+        };
+        self.dir_policies.lock().unwrap().remove(&path);
+        stored
+    }
+
+    /// Cipher directory `ino` names for new files below it, not counting
+    /// what it inherits.
+    fn default_cipher(&self, ino: u64) -> Result<Option<Cipher>, c_int> {
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        if ino == ROOT_INO || !path.is_dir() {
+            return Ok(None);
+        }
+        match FileMeta::load(&self.key, &self.source, &path) {
+            Ok(dir_meta) => Ok(dir_meta.and_then(|m| m.default_cipher)),
+            Err(e) => {
+                log::error!("Metadata of {}: {}", detail(&path), e);
+                Err(EIO)
+            }
+        }
+    }
+
+    /// Cipher new files in directory `dir` inherit: that of the nearest
+    /// directory, from `dir` up, that names one.
+    fn inherited_cipher(&self, dir: &Path) -> Result<Option<Cipher>, c_int> {
+        let mut policies = self.dir_policies.lock().unwrap();
+        for ancestor in dir.ancestors().take_while(|a| *a != self.source) {
+            let own = match policies.get(ancestor) {
+                Some(own) => *own,
+                None => {
+                    let own = FileMeta::load(&self.key, &self.source, ancestor)
+                        .map_err(|e| {
+                            log::error!("Metadata of {}: {}", detail(ancestor), e);
+                            EIO
+                        })?
+                        .and_then(|m| m.default_cipher);
+                    policies.insert(ancestor.to_path_buf(), own);
+                    own
+                }
+            };
+            if own.is_some() {
+                return Ok(own);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Drop the cached policies of `path` and every directory below it.
+    fn forget_policies(&self, path: &Path) {
+        self.dir_policies
+            .lock()
+            .unwrap()
+            .retain(|dir, _| !dir.starts_with(path));
+    }
+
+    /// Whether file `ino` is marked immutable.
+    fn is_immutable(&self, ino: u64) -> Result<bool, c_int> {
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        match self.check_mutable(&path) {
+            Ok(()) => Ok(false),
+            Err(EPERM) => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// EPERM if the file at `path` is marked immutable. A sidecar that fails
+    /// authentication fails closed (EIO), so tampering can't lift the mark.
+    fn check_mutable(&self, path: &Path) -> Result<(), c_int> {
+        match FileMeta::load(&self.key, &self.source, path) {
+            Ok(Some(file_meta)) if file_meta.immutable => Err(EPERM),
+            Ok(_) => Ok(()),
+            Err(e) => {
+                log::error!("Metadata of {}: {}", detail(path), e);
+                Err(EIO)
+            }
+        }
+    }
+
+    /// Cipher requested for the file at `path` and not yet applied.
+    fn requested_cipher(&self, path: &Path) -> Result<Option<Cipher>, c_int> {
+        match FileMeta::load(&self.key, &self.source, path) {
+            Ok(file_meta) => Ok(file_meta.and_then(|m| m.cipher)),
+            Err(e) => {
+                log::error!("Metadata of {}: {}", detail(path), e);
+                Err(EIO)
+            }
+        }
+    }
+
+    /// Cipher file `ino` is encrypted with, or has been asked to be; `None`
+    /// for an integrity-only file, which isn't encrypted.
+    fn file_cipher(&self, ino: u64) -> Result<Option<Cipher>, c_int> {
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        if let Some(cipher) = self.requested_cipher(&path)? {
+            return Ok(Some(cipher));
+        }
+        let prefix = self.header_prefix(&path);
+        Ok(match format::Header::decode(&prefix) {
+            _ if prefix.is_empty() && self.config.integrity_only => None,
+            Some(header) if header.integrity_only() => None,
+            Some(header) => Some(header.cipher()),
+            None => Some(Cipher::default()),
+        })
+    }
+
+    /// Seconds until file `ino` expires, if it has an expiry.
+    fn remaining_ttl(&self, ino: u64) -> Result<Option<u64>, c_int> {
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        Ok(
+            meta::expires_at(&self.key, &self.source, &path, self.config.default_ttl)
+                .map(|t| t.saturating_sub(self.clock.now())),
+        )
+    }
+
+    /// Apply `change` to the sidecar of a file being written. A sidecar that
+    /// fails authentication is left untouched rather than resealed over, so
+    /// the tampering stays visible. A sidecar left with nothing in it is
+    /// removed.
+    fn update_meta(&self, path: &Path, change: impl FnOnce(&mut FileMeta)) -> Result<(), c_int> {
+        let mut file_meta = match FileMeta::load(&self.key, &self.source, path) {
+            Ok(Some(file_meta)) => file_meta,
+            // The file is being written, so the mtime rule for sidecar-less
+            // files gives the same expiry counted from now.
+            Ok(None) => FileMeta {
+                expires_at: self
+                    .config
+                    .default_ttl
+                    .map(|t| self.clock.now() + t.as_secs()),
+                ..FileMeta::default()
+            },
+            Err(e) => {
+                log::error!("Not updating metadata of {}: {}", detail(path), e);
+                return Err(EIO);
+            }
+        };
+        let before = file_meta.clone();
+        change(&mut file_meta);
+        if file_meta == before {
+            return Ok(());
+        }
+        if file_meta == FileMeta::default() {
+            return meta::remove(path).map_err(|e| Self::os_errno(&e));
+        }
+        file_meta
+            .store(&self.key, &self.source, path)
+            .map_err(|_| EIO)
+    }
+
+    /// Record `uid` as the last writer of file `ino`.
+    fn record_writer(&self, ino: u64, uid: u32) -> Result<(), c_int> {
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        self.update_meta(&path, |m| m.last_writer_uid = Some(uid))
+    }
+
+    /// Content type hint of file `ino`, if it has one.
+    fn file_mime(&self, ino: u64) -> Result<Option<String>, c_int> {
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        match FileMeta::load(&self.key, &self.source, &path) {
+            Ok(file_meta) => Ok(file_meta.and_then(|m| m.mime)),
+            Err(e) => {
+                log::error!("Metadata of {}: {}", detail(&path), e);
+                Err(EIO)
+            }
+        }
+    }
+
+    /// Set the content type hint of file `ino` by hand; an empty `mime`
+    /// removes it.
+    fn set_mime(&self, ino: u64, mime: &str) -> Result<(), c_int> {
+        self.check_writable()?;
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        if !path.is_file() || !(mime.is_empty() || mime::is_valid(mime)) {
+            return Err(EINVAL);
+        }
+        self.check_mutable(&path)?;
+        let lock = self.content_lock(ino);
+        let _guard = lock.write().unwrap();
+        self.update_meta(&path, |m| {
+            m.mime = (!mime.is_empty()).then(|| mime.to_string());
+            m.mime_sniffed = false;
+        })
+    }
+
+    /// Record the content type of `data`, just written at the start of the
+    /// file at `path`, unless the file's hint was set by hand. The data is
+    /// already written; a failure here is logged only.
+    fn sniff_mime(&self, path: &Path, data: &[u8]) {
+        let sniffed = mime::sniff(data);
+        let updated = self.update_meta(path, |m| {
+            if m.mime.is_none() || m.mime_sniffed {
+                m.mime = sniffed.map(String::from);
+                m.mime_sniffed = sniffed.is_some();
+            }
+        });
+        if updated.is_err() {
+            log::warn!("Could not record the content type of {}", detail(path));
+        }
+    }
+
+    /// Open `ino` and return the file handle, tracked until `release_file`.
+    fn open_handle(&self, ino: u64, flags: i32) -> Result<u64, c_int> {
+        // Held from the check on, so the file can't be removed (see
+        // `unlink_file`) before the handle is counted.
+        let mut handles = self.handles.lock().unwrap();
+        self.open_file(ino, flags)?;
+        let writes = flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0;
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        handles.insert(
+            fh,
+            Handle {
+                ino,
+                writes,
+                dirty: false,
+            },
+        );
+        Ok(fh)
+    }
+
+    /// Whether any open handle on `ino` has write intent.
+    fn has_writer(&self, ino: u64) -> bool {
+        self.handles
+            .lock()
+            .unwrap()
+            .values()
+            .any(|h| h.ino == ino && h.writes)
+    }
+
+    /// Whether any handle is open on `ino`.
+    fn is_open(&self, ino: u64) -> bool {
+        self.handles.lock().unwrap().values().any(|h| h.ino == ino)
+    }
+
+    /// Delete the regular file at `path` with its sidecar and blocks, or
+    /// orphan it if it is open and this is its only name. Holds its content lock, so no write to it is
+    /// in flight, and the handle table, so it can't be opened meanwhile. If
+    /// `expired`, only a file whose expiry, checked under those locks, has
+    /// passed is removed. Returns whether it was.
+    fn unlink_file(&self, path: &Path, expired: bool) -> Result<bool, c_int> {
+        let ino = self.ino_for(path);
+        let lock = ino.map(|ino| self.content_lock(ino));
+        let _guard = lock.as_ref().map(|lock| lock.write().unwrap());
+        let handles = self.handles.lock().unwrap();
+        if expired {
+            let expiry = meta::expiry(&self.key, &self.source, path, self.config.default_ttl);
+            if !expiry.is_ok_and(|t| t.is_some_and(|t| t <= self.clock.now())) {
+                return Ok(false);
+            }
+        }
+        let open = |ino| handles.values().any(|h| h.ino == ino);
+        match ino {
+            Some(ino) if open(ino) && !self.has_other_names(ino, path) => self.orphan(ino, path)?,
+            _ => {
+                self.shred(path)
+                    .and_then(|_| meta::delete_file(&self.store, path))
+                    .map_err(|_| EIO)?;
+                if let Some(ino) = ino {
+                    self.cache.invalidate(ino);
+                    self.forget_name(ino, path);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Move the file at `path`, still open as `ino`, out of the namespace
+    /// instead of deleting it, so its handles keep reading and writing it.
+    /// Contents are sealed independently of the path; only the sidecar has
+    /// to be resealed under the new name.
+    fn orphan(&self, ino: u64, path: &Path) -> Result<(), c_int> {
+        let orphan = self.source.join(format!("{}{}", vault::ORPHAN_PREFIX, ino));
+        let file_meta = FileMeta::load(&self.key, &self.source, path).map_err(|e| {
+            log::error!("Metadata of {}: {}", detail(path), e);
+            EIO
+        })?;
+        let blocks = layout::block_dir(path);
+        let has_blocks = blocks.is_dir();
+        if has_blocks {
+            fs::rename(&blocks, layout::block_dir(&orphan)).map_err(|e| Self::os_errno(&e))?;
+        }
+        if let Err(e) = fs::rename(path, &orphan) {
+            if has_blocks {
+                let _ = fs::rename(layout::block_dir(&orphan), &blocks);
+            }
+            return Err(Self::os_errno(&e));
+        }
+        if let Some(file_meta) = file_meta {
+            if let Err(e) = file_meta.store(&self.key, &self.source, &orphan) {
+                log::error!("Failed to reseal metadata for {}: {}", detail(&orphan), e);
+            }
+        }
+        let _ = meta::remove(path);
+        self.inodes.lock().unwrap().insert(ino, vec![orphan]);
+        Ok(())
+    }
+
+    /// Backing path of `ino` if it was unlinked while open.
+    fn orphan_path(&self, ino: u64) -> Option<PathBuf> {
+        self.path_for(ino).filter(|path| {
+            path.parent() == Some(self.source.as_path())
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with(vault::ORPHAN_PREFIX))
+        })
+    }
+
+    /// Delete orphan `ino` at `path`, now that nothing has it open.
+    fn remove_orphan(&self, ino: u64, path: &Path) {
+        self.inodes.lock().unwrap().remove(&ino);
+        self.cache.invalidate(ino);
+        if let Err(e) = self
+            .shred(path)
+            .and_then(|_| fs::remove_file(path))
+            .and_then(|_| meta::remove(path))
+            .and_then(|_| self.store.remove(path))
+        {
+            log::warn!("Failed to remove unlinked file {}: {}", detail(path), e);
+        }
+    }
+
+    /// Overwrite or crypto-erase what is stored for the entry at `path`,
+    /// about to be removed, if `--secure-delete` asks for it.
+    fn shred(&self, path: &Path) -> io::Result<()> {
+        shred::shred(&self.store, path, self.config.secure_delete)
+    }
+
+    /// Delete orphans an earlier mount left behind when it stopped with
+    /// unlinked files still open. Returns how many were removed.
+    fn remove_stale_orphans(&self) -> io::Result<usize> {
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.source)? {
+            let entry = entry?;
+            let is_orphan = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with(vault::ORPHAN_PREFIX));
+            if is_orphan && entry.file_type()?.is_file() {
+                let path = entry.path();
+                self.shred(&path)?;
+                fs::remove_file(&path)?;
+                meta::remove(&path)?;
+                self.store.remove(&path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Before the first write to `ino` through a handle with write intent,
+    /// mark the file incomplete, so the marker is durable before any data
+    /// is.
+    fn begin_write(&self, ino: u64, path: &Path) -> Result<(), c_int> {
+        if !self.has_writer(ino) {
+            return Ok(());
+        }
+        let mut incomplete = self.incomplete.lock().unwrap();
+        if incomplete.contains(&ino) {
+            return Ok(());
+        }
+        self.update_meta(path, |m| m.incomplete = true)?;
+        incomplete.insert(ino);
+        Ok(())
+    }
+
+    /// Close handle `fh`. When the last writer of a file marked incomplete
+    /// closes it, the marker is cleared; when the last handle of a file
+    /// unlinked while open closes, the file is deleted.
+    fn release_file(&self, fh: u64) -> Result<(), c_int> {
+        let Some(Handle { ino, writes, .. }) = self.handles.lock().unwrap().remove(&fh) else {
+            return Ok(());
+        };
+        if !self.is_open(ino) {
+            // Advice lasts while the file is open, as with fadvise.
+            self.sequential.lock().unwrap().remove(&ino);
+            if let Some(path) = self.orphan_path(ino) {
+                self.incomplete.lock().unwrap().remove(&ino);
+                self.remove_orphan(ino, &path);
+                return Ok(());
+            }
+        }
+        if !writes || self.has_writer(ino) || !self.incomplete.lock().unwrap().remove(&ino) {
+            return Ok(());
+        }
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        if !path.exists() {
+            return Ok(());
+        }
+        self.update_meta(&path, |m| m.incomplete = false)
+    }
+
+    /// uid that last wrote file `ino`, if recorded.
+    fn last_writer(&self, ino: u64) -> Result<Option<u32>, c_int> {
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        match FileMeta::load(&self.key, &self.source, &path) {
+            Ok(file_meta) => Ok(file_meta.and_then(|m| m.last_writer_uid)),
+            Err(e) => {
+                log::error!("Metadata of {}: {}", detail(&path), e);
+                Err(EIO)
+            }
+        }
+    }
+
+    /// Count `bytes` of whole-file buffers held by `op` on `path` while the
+    /// guard lives, if memory is being profiled.
+    fn track_buffers(&self, op: &str, path: &Path, bytes: usize) -> Option<Tracked<'_>> {
+        self.memory
+            .as_ref()
+            .map(|memory| memory.track(op, path, bytes))
+    }
+
+    /// Reserve `bytes` of whole-file buffers from the budget, if there is
+    /// one, before allocating them.
+    fn reserve_buffers(&self, bytes: usize) -> Option<Reservation<'_>> {
+        self.budget.as_ref().map(|budget| budget.reserve(bytes))
+    }
+
+    /// Size of the backing file at `path`, for reserving buffers before
+    /// reading it.
+    fn backing_len(path: &Path) -> usize {
+        fs::metadata(path).map_or(0, |m| m.len() as usize)
+    }
+
+    /// Load the backing file for `ino`, decrypt it and return up to `size`
+    /// bytes starting at `offset`.
+    fn read_data(&self, ino: u64, offset: i64, size: u32) -> Result<Vec<u8>, c_int> {
+        if ino == ERRORS_INO && self.config.error_file {
+            let report = self.error_report();
+            let start = (offset as usize).min(report.len());
+            let end = start.saturating_add(size as usize).min(report.len());
+            return Ok(report[start..end].to_vec());
+        }
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        if size == 0 {
+            return Ok(vec![]);
+        }
+        Self::expect_kind(&path, FileType::RegularFile)?;
+        let lock = self.content_lock(ino);
+        let _guard = lock.read().unwrap();
+
+        let prefix = self
+            .backend
+            .read_at(&path, 0, format::MAX_HEADER_SIZE)
+            .map_err(|e| Self::io_errno(&e))?;
+
+        // Legacy and block-format files can coexist in a vault mid-migration.
+        match format::Header::decode(&prefix) {
+            // Block reads never look past the last block, so trailing bytes
+            // are only noticed by decrypting the file whole.
+            Some(header)
+                if format::strict_trailing()
+                    && !format::fits(&header, Self::backing_len(&path) as u64) =>
+            {
+                self.read_whole(&path, offset, size)
+            }
+            Some(header) => {
+                match self.read_blocks(ino, &path, &header, offset as u64, size as usize) {
+                    // A legacy file's random nonce can start with the magic by chance.
+                    Err(EIO) => self.read_whole(&path, offset, size).map_err(|_| EIO),
+                    other => other,
+                }
+            }
+            None => self.read_whole(&path, offset, size),
+        }
+    }
+
+    /// Read a range of a block-format file, a block at a time.
+    fn read_blocks(
+        &self,
+        ino: u64,
+        path: &Path,
+        header: &format::Header,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, c_int> {
+        if offset >= header.plaintext_len || len == 0 {
+            return Ok(vec![]);
+        }
+        let end = (offset + len as u64).min(header.plaintext_len);
+        let bs = header.block_size as u64;
+        let mut out = Vec::with_capacity((end - offset) as usize);
+        for index in offset / bs..end.div_ceil(bs) {
+            let block = self.cached_block(ino, path, header, index)?;
+            let block_start = index * bs;
+            let from = offset.saturating_sub(block_start) as usize;
+            let to = ((end - block_start) as usize).min(block.len());
+            out.extend_from_slice(&block[from..to]);
+        }
+        if self.sequential.lock().unwrap().contains(&ino) {
+            let next = end.div_ceil(bs);
+            let last = header.block_count().min(next + READAHEAD_BLOCKS);
+            for index in next..last {
+                // A bad block is reported when it is actually read.
+                if !self.cache.contains(ino, index)
+                    && self.cached_block(ino, path, header, index).is_err()
+                {
+                    break;
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Act on `advice` about how file `ino` will be read.
+    fn advise(&self, ino: u64, advice: Advice) -> Result<(), c_int> {
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        if !path.is_file() {
+            return Err(EINVAL);
+        }
+        match advice {
+            Advice::Sequential => {
+                self.sequential.lock().unwrap().insert(ino);
+            }
+            Advice::Normal | Advice::Random => {
+                self.sequential.lock().unwrap().remove(&ino);
+            }
+            Advice::DontNeed => self.cache.invalidate(ino),
+            Advice::WillNeed => {
+                let lock = self.content_lock(ino);
+                let _guard = lock.read().unwrap();
+                // Legacy files have no blocks to load ahead of time.
+                if let Some(header) = format::Header::decode(&self.header_prefix(&path)) {
+                    // Loading more than fits would evict the file's own start.
+                    let count = header.block_count().min(self.cache.capacity() as u64);
+                    for index in 0..count {
+                        self.cached_block(ino, &path, &header, index)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Plaintext of block `index`, from the cache if possible.
+    fn cached_block(
+        &self,
+        ino: u64,
+        path: &Path,
+        header: &format::Header,
+        index: u64,
+    ) -> Result<Arc<Vec<u8>>, c_int> {
+        if let Some(entry) = self.cache.get(ino, index) {
+            match &entry.sealed {
+                None => return Ok(entry.plaintext),
+                Some(sealed) => {
+                    if self.open_stored(path, header, index, sealed)? == *entry.plaintext {
+                        return Ok(entry.plaintext);
+                    }
+                    self.record_failure(
+                        path,
+                        format!("cached block {} no longer matches its ciphertext", index),
+                    );
+                    self.cache.remove(ino, index);
+                }
+            }
+        }
+
+        let sealed = self
+            .read_sealed(path, header, index)
+            .map_err(|e| Self::io_errno(&e))?;
+        let plaintext = Arc::new(self.open_stored(path, header, index, &sealed)?);
+        let entry = cache::Entry {
+            plaintext: Arc::clone(&plaintext),
+            sealed: self.config.always_authenticate.then(|| Arc::new(sealed)),
+        };
+        self.cache.insert(ino, index, entry);
+        Ok(plaintext)
+    }
+
+    /// Sealed block `index` of the file at `path`, wherever `header`'s
+    /// layout keeps it.
+    fn read_sealed(&self, path: &Path, header: &format::Header, index: u64) -> io::Result<Vec<u8>> {
+        if header.separate_blocks() {
+            return self.backend.read(&layout::block_file(path, index));
+        }
+        let (start, len) = header.block_span(index);
+        self.backend.read_at(path, start, len)
+    }
+
+    /// Store sealed block `index` of the file at `path` wherever `header`'s
+    /// layout keeps it. A block file is synced on its own, per
+    /// `backing_sync`.
+    fn write_sealed(
+        &self,
+        path: &Path,
+        header: &format::Header,
+        index: u64,
+        sealed: &[u8],
+    ) -> io::Result<()> {
+        if !header.separate_blocks() {
+            let (at, _) = header.block_span(index);
+            return self.backend.write_at(path, at, sealed);
+        }
+        let block = layout::block_file(path, index);
+        // Never write through a link into the shared block store.
+        if fs::symlink_metadata(&block).is_ok_and(|m| m.nlink() > 1) {
+            self.store.unlink(&block)?;
+        }
+        match self.backend.write(&block, sealed) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                fs::create_dir_all(layout::block_dir(path))?;
+                self.backend.write(&block, sealed)
+            }
+            other => other,
+        }?;
+        self.sync_backing(&block)
+    }
+
+    /// The backing file at `path` as the packed stream, with its blocks
+    /// gathered if they are stored separately.
+    fn read_backing(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut raw = self.backend.read(path)?;
+        if let Some(header) = format::Header::decode(&raw).filter(|h| h.separate_blocks()) {
+            // A legacy nonce can look like a header; only real blocks count.
+            if layout::block_dir(path).is_dir() {
+                for index in 0..header.block_count() {
+                    raw.extend_from_slice(&self.backend.read(&layout::block_file(path, index))?);
+                }
+            }
+        }
+        Ok(raw)
+    }
+
+    /// Plaintext of block `index`, given what is sealed for it in the file.
+    /// For a deduplicated file that is the block's reference, and the block
+    /// itself is read from the store.
+    fn open_stored(
+        &self,
+        path: &Path,
+        header: &format::Header,
+        index: u64,
+        sealed: &[u8],
+    ) -> Result<Vec<u8>, c_int> {
+        let plaintext = self.open_block(path, header, index, sealed)?;
+        if !header.dedup_blocks() {
+            return Ok(plaintext);
+        }
+        let stored = self
+            .backend
+            .read(&layout::block_file(path, index))
+            .map_err(|e| Self::io_errno(&e))?;
+        dedup::open_block(&plaintext, header, index, &stored).map_err(|e| {
+            self.record_failure(path, e);
+            EIO
+        })
+    }
+
+    /// Seal `block` as block `index` of the file at `path`, for
+    /// `write_sealed`. A deduplicated block is put in the store and its
+    /// reference sealed instead.
+    fn seal(
+        &self,
+        path: &Path,
+        header: &format::Header,
+        index: u64,
+        block: &[u8],
+    ) -> Result<Vec<u8>, c_int> {
+        let sealed = if header.dedup_blocks() {
+            self.store
+                .put(&self.key, path, index, block)
+                .and_then(|content_key| {
+                    format::seal_block(&self.key, self.nonces.as_ref(), header, index, &content_key)
+                })
+        } else {
+            format::seal_block(&self.key, self.nonces.as_ref(), header, index, block)
+        };
+        sealed.map_err(|e| {
+            log::error!("Encrypt error on {}: {}", detail(path), e);
+            EIO
+        })
+    }
+
+    /// Authenticate and decrypt one sealed block.
+    fn open_block(
+        &self,
+        path: &Path,
+        header: &format::Header,
+        index: u64,
+        sealed: &[u8],
+    ) -> Result<Vec<u8>, c_int> {
+        self.auth_count.fetch_add(1, Ordering::Relaxed);
+        format::open_sealed_block(&self.key, header, index, sealed).map_err(|e| {
+            self.record_failure(path, e);
+            EIO
+        })
+    }
+
+    /// Read a range of a legacy whole-file ciphertext.
+    fn read_whole(&self, path: &Path, offset: i64, size: u32) -> Result<Vec<u8>, c_int> {
+        let _reserved = self.reserve_buffers(Self::backing_len(path));
+        let raw = self.read_backing(path).map_err(|e| Self::io_errno(&e))?;
+        let _tracked = self.track_buffers("read", path, raw.len());
+
+        // If file is empty or too short to be encrypted, return empty
+        if raw.len() < crypto::HEADER_LEN + 16 {
+            return Ok(vec![]);
+        }
+
+        match format::decrypt_range(&self.key, &raw, offset as u64, size as usize) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(e) => {
+                self.record_failure(path, e);
+                Err(EIO)
+            }
+        }
+    }
+
+    /// Log a failure to decrypt the backing file at `path` and keep it for
+    /// `ERRORS_FILE`.
+    fn record_failure(&self, path: &Path, reason: impl std::fmt::Display) {
+        log::error!("Decrypt error on {}: {}", detail(path), reason);
+        let relative = path.strip_prefix(&self.source).unwrap_or(path);
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() == ERROR_LOG_LEN {
+            errors.pop_front();
+        }
+        errors.push_back(format!(
+            "{} {}: {}\n",
+            self.clock.now(),
+            relative.display(),
+            reason
+        ));
+    }
+
+    /// Contents of `ERRORS_FILE`: one "<unix time> <path>: <reason>" line
+    /// per failure, oldest first.
+    fn error_report(&self) -> Vec<u8> {
+        self.errors
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|line| line.bytes())
+            .collect()
+    }
+
+    /// Attributes of `ERRORS_FILE`, sized to the current report.
+    fn errors_attr(&self) -> FileAttr {
+        let now = SystemTime::now();
+        FileAttr {
+            ino: ERRORS_INO,
+            size: self.error_report().len() as u64,
+            blocks: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind: FileType::RegularFile,
+            perm: 0o444,
+            nlink: 1,
+            uid: self.config.uid_map.to_inside(unsafe { libc::getuid() }),
+            gid: self.config.gid_map.to_inside(unsafe { libc::getgid() }),
+            rdev: 0,
+            blksize: 512,
+            flags: 0,
+        }
+    }
+
+    /// Write `data` at `offset` into file `ino` and report it to the hook.
+    /// Returns the number of bytes written.
+    fn write_data(&self, ino: u64, offset: i64, data: &[u8]) -> Result<u32, c_int> {
+        self.check_writable()?;
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        // Nothing to write: the file, its nonces included, stays as it is.
+        if data.is_empty() {
+            return Ok(0);
+        }
+        Self::expect_kind(&path, FileType::RegularFile)?;
+        self.check_mutable(&path)?;
+        let written = self.write_contents(ino, &path, offset, data)?;
+        if self.config.sniff_content_type && offset == 0 {
+            self.sniff_mime(&path, data);
+        }
+        self.notify(|hook| hook.on_write(self.relative(&path), offset as u64, written as u64));
+        Ok(written)
+    }
+
+    /// `write_data` for a `write` request. A failed write the kernel sends
+    /// on writeback (`FUSE_WRITE_CACHE`) is also held for `take_write_error`,
+    /// since the application's own write returned long ago.
+    fn write_request(
+        &self,
+        ino: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+    ) -> Result<u32, c_int> {
+        let result = self.write_data(ino, offset, data);
+        if let Err(e) = result {
+            if write_flags & fuser::consts::FUSE_WRITE_CACHE != 0 {
+                log::error!("Deferred write to inode {} failed: errno {}", ino, e);
+                self.write_errors.lock().unwrap().entry(ino).or_insert(e);
+            }
+        }
+        result
+    }
+
+    /// Report, once, a writeback failure on `ino` held since the last call.
+    fn take_write_error(&self, ino: u64) -> Result<(), c_int> {
+        match self.write_errors.lock().unwrap().remove(&ino) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Read-modify-encrypt-write `data` at `offset` into the backing file at
+    /// `path`. Returns the number of bytes written.
+    fn write_contents(
+        &self,
+        ino: u64,
+        path: &Path,
+        offset: i64,
+        data: &[u8],
+    ) -> Result<u32, c_int> {
+        let lock = self.content_lock(ino);
+        let _guard = lock.write().unwrap();
+        if self.config.mark_incomplete {
+            self.begin_write(ino, path)?;
+        }
+
+        // Page writeback from mmap'd files arrives as page-sized writes
+        // inside the file, and streaming writers append at the end: both
+        // only reseal the blocks they touch, so memory stays bounded by the
+        // block size whatever the file size.
+        let fast = match self.fast_write(path, offset as u64, data.len()) {
+            // Changing the cipher reseals every block.
+            _ if self.requested_cipher(path)?.is_some() => Err(EIO),
+            Some(FastWrite::InPlace(header)) => {
+                self.write_blocks(ino, path, &header, offset as u64, data)
+            }
+            Some(FastWrite::Append(header)) => self.append_blocks(ino, path, header.as_ref(), data),
+            None => Err(EIO),
+        };
+        match fast {
+            Err(EIO) => {} // Not eligible, or possibly a legacy file; rewrite it whole below.
+            other => return other,
+        }
+
+        // The ciphertext, the patched plaintext and its new ciphertext.
+        let len = Self::backing_len(path);
+        let _reserved = self.reserve_buffers(len + 2 * len.max(offset as usize + data.len()));
+
+        // Read existing plaintext (if any) so we can handle partial writes.
+        // A failed read must not be mistaken for an empty file, or the write
+        // below would replace the real contents.
+        let raw = match self.read_backing(path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(Self::io_errno(&e)),
+        };
+        let mut plaintext = if raw.len() >= crypto::HEADER_LEN + 16 {
+            // Patching a file that won't decrypt as if it were empty would
+            // throw away everything outside this write.
+            dedup::decrypt_file(&self.key, path, &raw).map_err(|e| {
+                self.record_failure(path, e);
+                EIO
+            })?
+        } else {
+            vec![]
+        };
+
+        // Extend buffer if needed and write at offset
+        let end = offset as usize + data.len();
+        if plaintext.len() < end {
+            plaintext.resize(end, 0);
+        }
+        plaintext[offset as usize..end].copy_from_slice(data);
+        let _tracked = self.track_buffers("write", path, raw.len() + plaintext.len());
+        self.rewrite_whole(ino, path, &plaintext)?;
+        Ok(data.len() as u32)
+    }
+
+    /// The layout files sealed as `sealing` (`format::SEALING_FLAGS`) are
+    /// written in: the mount's, except that files not sealed the default way
+    /// are packed instead of deduplicated, since the block store only holds
+    /// AES-256-GCM ciphertext.
+    fn layout_for(&self, sealing: u8) -> Layout {
+        match self.config.block_layout {
+            Layout::Dedup if sealing != 0 => Layout::Packed,
+            layout => layout,
+        }
+    }
+
+    /// Header flags for a file written in `layout` and sealed as `sealing`.
+    fn file_flags(layout: Layout, sealing: u8) -> u8 {
+        layout.flags() | sealing
+    }
+
+    /// How this mount seals new files.
+    fn new_file_sealing(&self) -> u8 {
+        if self.config.integrity_only {
+            format::FLAG_INTEGRITY_ONLY
+        } else {
+            self.file_key_flag()
+        }
+    }
+
+    /// `FLAG_FILE_KEY` if files written through this mount get keys of their
+    /// own, so deleting them can destroy the key.
+    fn file_key_flag(&self) -> u8 {
+        if self.config.secure_delete == SecureDelete::CryptoErase {
+            format::FLAG_FILE_KEY
+        } else {
+            0
+        }
+    }
+
+    /// Encrypt `plaintext` as the whole new contents of the file at `path`,
+    /// in the mount's layout, stamped with the current time. A file that
+    /// already has contents keeps its own sealing (integrity-only, its
+    /// cipher, a key of its own) unless another cipher was requested for it;
+    /// the mount's only applies to empty ones. Any file that gets a key of
+    /// its own gets a fresh one. Its expiry moves into the header, if it
+    /// has one (see `carried_expiry`).
+    fn rewrite_whole(&self, ino: u64, path: &Path, plaintext: &[u8]) -> Result<(), c_int> {
+        let expiry = self.carried_expiry(path)?;
+        self.rewrite_whole_expiring(ino, path, plaintext, expiry)
+    }
+
+    /// `rewrite_whole`, sealing `expiry` into the header unless it is `None`.
+    fn rewrite_whole_expiring(
+        &self,
+        ino: u64,
+        path: &Path,
+        plaintext: &[u8],
+        expiry: Option<Option<u64>>,
+    ) -> Result<(), c_int> {
+        let expiry_flag = match expiry {
+            Some(_) => format::FLAG_EXPIRY,
+            None => 0,
+        };
+        let prefix = self.header_prefix(path);
+        let requested = self.requested_cipher(path)?;
+        let sealing = match (requested, format::Header::decode(&prefix)) {
+            (Some(cipher), _) => format::cipher_flag(cipher) | self.file_key_flag(),
+            _ if prefix.is_empty() => self.new_file_sealing(),
+            (None, Some(header)) => header.flags & format::SEALING_FLAGS,
+            (None, None) => self.file_key_flag(),
+        };
+        // Always write the block format, upgrading legacy files as they change.
+        let encrypted = match self.layout_for(sealing) {
+            Layout::Dedup => dedup::encrypt_file(
+                &self.store,
+                &self.key,
+                self.nonces.as_ref(),
+                path,
+                plaintext,
+                self.config.block_size,
+                expiry_flag,
+                self.clock.now_ns(),
+            ),
+            layout => format::encrypt_file_with_flags(
+                &self.key,
+                self.nonces.as_ref(),
+                plaintext,
+                self.config.block_size,
+                Self::file_flags(layout, sealing) | expiry_flag,
+                self.clock.now_ns(),
+            ),
+        };
+        let encrypted = encrypted.and_then(|mut raw| {
+            if let Some(at) = expiry {
+                format::set_expiry(&self.key, self.nonces.as_ref(), &mut raw, at)?;
+            }
+            Ok(raw)
+        });
+        match encrypted {
+            Ok(ciphertext) => {
+                let _tracked = self.track_buffers("encrypt", path, ciphertext.len());
+                let stored = self.store(path, &ciphertext);
+                self.cache.invalidate(ino);
+                stored.map_err(|e| Self::io_errno(&e))?;
+                if requested.is_some() {
+                    self.update_meta(path, |m| m.cipher = None)?;
+                }
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("Encrypt error on {}: {}", detail(path), e);
+                Err(EIO)
+            }
+        }
+    }
+
+    /// The expiry a rewrite of the file at `path` seals into its header:
+    /// `Some` if it has one, from its header, its sidecar or the default
+    /// TTL, or its header has room for one, so moving it there changes
+    /// nothing. A file without either stays as it is.
+    fn carried_expiry(&self, path: &Path) -> Result<Option<Option<u64>>, c_int> {
+        let expiry =
+            meta::expiry(&self.key, &self.source, path, self.config.default_ttl).map_err(|e| {
+                log::error!("Metadata of {}: {}", detail(path), e);
+                EIO
+            })?;
+        let room =
+            format::Header::decode(&self.header_prefix(path)).is_some_and(|h| h.has_expiry());
+        Ok((expiry.is_some() || room).then_some(expiry))
+    }
+
+    /// How a write of `len` bytes at `offset` to the backing file at `path`
+    /// can avoid rewriting the whole file, if it can.
+    fn fast_write(&self, path: &Path, offset: u64, len: usize) -> Option<FastWrite> {
+        if len == 0 {
+            return None;
+        }
+        let prefix = self
+            .backend
+            .read_at(path, 0, format::MAX_HEADER_SIZE)
+            .ok()?;
+        if prefix.is_empty() {
+            return (offset == 0).then_some(FastWrite::Append(None));
+        }
+        // Version 1 headers have no room for the mtime; rewriting the file
+        // whole upgrades them.
+        let header = format::Header::decode(&prefix).filter(|h| h.version == format::VERSION)?;
+        let end = offset.checked_add(len as u64)?;
+        if end <= header.plaintext_len {
+            Some(FastWrite::InPlace(header))
+        } else if offset == header.plaintext_len {
+            Some(FastWrite::Append(Some(header)))
+        } else {
+            None
+        }
+    }
+
+    /// Append `data` to a block-format file (or start one, if `header` is
+    /// `None` and the backing file is empty). Only the old final block is
+    /// resealed — its AAD marks it final and carries the length — before the
+    /// new blocks and then the updated header are written.
+    fn append_blocks(
+        &self,
+        ino: u64,
+        path: &Path,
+        header: Option<&format::Header>,
+        data: &[u8],
+    ) -> Result<u32, c_int> {
+        let (block_size, old_len, first, mut buf) = match header {
+            Some(h) => {
+                let last = h.block_count() - 1;
+                let tail = self.cached_block(ino, path, h, last)?;
+                (h.block_size, h.plaintext_len, last, tail.to_vec())
+            }
+            None => (self.config.block_size, 0, 0, vec![]),
+        };
+        buf.extend_from_slice(data);
+        let mut new_header = format::Header::new(block_size, old_len + data.len() as u64);
+        let expiry = match header {
+            Some(h) => {
+                new_header.flags = h.flags;
+                new_header.file_key = h.file_key;
+                let expiry = h.open_expiry(&self.key).map_err(|e| {
+                    log::error!("Header of {}: {}", detail(path), e);
+                    EIO
+                })?;
+                h.has_expiry().then_some(expiry)
+            }
+            None => {
+                let expiry = self.carried_expiry(path)?;
+                let sealing = self.new_file_sealing();
+                new_header.flags =
+                    Self::file_flags(self.layout_for(sealing), sealing) & !format::FLAG_FILE_KEY;
+                if expiry.is_some() {
+                    new_header.flags |= format::FLAG_EXPIRY;
+                }
+                if sealing & format::FLAG_FILE_KEY != 0 {
+                    new_header
+                        .wrap_file_key(&self.key, self.nonces.as_ref())
+                        .map_err(|e| {
+                            log::error!("Encrypt error on {}: {}", detail(path), e);
+                            EIO
+                        })?;
+                }
+                expiry
+            }
+        };
+        self.stamp_mtime(path, &mut new_header)?;
+        if let Some(at) = expiry {
+            new_header
+                .seal_expiry(&self.key, self.nonces.as_ref(), at)
+                .map_err(|e| {
+                    log::error!("Encrypt error on {}: {}", detail(path), e);
+                    EIO
+                })?;
+        }
+
+        let result = buf
+            .chunks(block_size as usize)
+            .enumerate()
+            .try_for_each(|(i, block)| {
+                let index = first + i as u64;
+                let sealed = self.seal(path, &new_header, index, block)?;
+                self.write_sealed(path, &new_header, index, &sealed)
+                    .map_err(|e| Self::io_errno(&e))
+            })
+            .and_then(|_| {
+                self.backend
+                    .write_at(path, 0, &new_header.encode())
+                    .map_err(|e| Self::io_errno(&e))
+            });
+        self.cache.invalidate(ino);
+        result?;
+        self.sync_backing(path).map_err(|e| Self::io_errno(&e))?;
+        Ok(data.len() as u32)
+    }
+
+    /// Seal the current time into `header` as the file's logical mtime.
+    fn stamp_mtime(&self, path: &Path, header: &mut format::Header) -> Result<(), c_int> {
+        header
+            .seal_mtime(&self.key, self.nonces.as_ref(), self.clock.now_ns())
+            .map_err(|e| {
+                log::error!("Encrypt error on {}: {}", detail(path), e);
+                EIO
+            })
+    }
+
+    /// Overwrite `data` at `offset` by resealing only the blocks it covers.
+    /// The file length, and so the block AADs, is unchanged; only the
+    /// header's sealed mtime is renewed.
+    fn write_blocks(
+        &self,
+        ino: u64,
+        path: &Path,
+        header: &format::Header,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<u32, c_int> {
+        let bs = header.block_size as u64;
+        let end = offset + data.len() as u64;
+        let result = (offset / bs..end.div_ceil(bs)).try_for_each(|index| {
+            let block_start = index * bs;
+            let mut block = self.cached_block(ino, path, header, index)?.to_vec();
+            let from = offset.max(block_start);
+            let to = end.min(block_start + block.len() as u64);
+            block[(from - block_start) as usize..(to - block_start) as usize]
+                .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+
+            let sealed = self.seal(path, header, index, &block)?;
+            self.write_sealed(path, header, index, &sealed)
+                .map_err(|e| Self::io_errno(&e))
+        });
+        let result = result.and_then(|_| {
+            let mut new_header = *header;
+            self.stamp_mtime(path, &mut new_header)?;
+            self.backend
+                .write_at(path, 0, &new_header.encode())
+                .map_err(|e| Self::io_errno(&e))
+        });
+        self.cache.invalidate(ino);
+        result?;
+        self.sync_backing(path).map_err(|e| Self::io_errno(&e))?;
+        Ok(data.len() as u32)
+    }
+
+    /// Apply a `setattr` in a fixed order — size (resealing the contents),
+    /// mode, ownership, then times — while holding the file's content lock,
+    /// so no reader sees some of the changes without the others. Returns the
+    /// attributes with every change applied.
+    fn set_attr(&self, ino: u64, changes: &AttrChanges) -> Result<FileAttr, c_int> {
+        if ino == ERRORS_INO && self.config.error_file {
+            return Err(EACCES);
+        }
+        self.check_writable()?;
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        let lock = self.content_lock(ino);
+        let _guard = lock.write().unwrap();
+        let meta = fs::symlink_metadata(&path).map_err(|e| Self::os_errno(&e))?;
+        if meta.is_file() {
+            self.check_mutable(&path)?;
+        }
+
+        if let Some(size) = changes.size {
+            if meta.is_dir() {
+                return Err(libc::EISDIR);
+            }
+            if !meta.is_file() {
+                return Err(EINVAL);
+            }
+            self.resize(ino, &path, size)?;
+        }
+        // Symlinks have no mode of their own on Linux.
+        if let Some(mode) = changes.mode.filter(|_| !meta.file_type().is_symlink()) {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777))
+                .map_err(|e| Self::os_errno(&e))?;
+        }
+        if changes.uid.is_some() || changes.gid.is_some() {
+            let host = |id: Option<u32>, map: &IdMap| match id {
+                Some(id) => map.to_outside(id).map(Some).ok_or(EINVAL),
+                None => Ok(None),
+            };
+            let uid = host(changes.uid, &self.config.uid_map)?;
+            let gid = host(changes.gid, &self.config.gid_map)?;
+            std::os::unix::fs::lchown(&path, uid, gid).map_err(|e| Self::os_errno(&e))?;
+        }
+        if changes.atime.is_some() || changes.mtime.is_some() {
+            self.set_times(&path, changes.atime, changes.mtime)?;
+        }
+
+        let meta = fs::symlink_metadata(&path).map_err(|e| Self::os_errno(&e))?;
+        Ok(self.attr(ino, &path, &meta))
+    }
+
+    /// Change the plaintext length of the file at `path` to `size`, cutting
+    /// or zero-filling its end. The caller holds the content lock.
+    fn resize(&self, ino: u64, path: &Path, size: u64) -> Result<(), c_int> {
+        let mut plaintext = self.whole_plaintext(path)?;
+        plaintext.resize(size as usize, 0);
+        self.rewrite_whole(ino, path, &plaintext)
+    }
+
+    /// The whole plaintext of the file at `path`.
+    fn whole_plaintext(&self, path: &Path) -> Result<Vec<u8>, c_int> {
+        let raw = self.read_backing(path).map_err(|e| Self::io_errno(&e))?;
+        if raw.len() < crypto::HEADER_LEN + 16 {
+            return Ok(vec![]);
+        }
+        dedup::decrypt_file(&self.key, path, &raw).map_err(|e| {
+            self.record_failure(path, e);
+            EIO
+        })
+    }
+
+    /// Set the backing times of `path`, and reseal a changed mtime as the
+    /// file's logical mtime if its header carries one.
+    fn set_times(
+        &self,
+        path: &Path,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+    ) -> Result<(), c_int> {
+        let resolve = |time: TimeOrNow| match time {
+            TimeOrNow::SpecificTime(t) => t,
+            TimeOrNow::Now => UNIX_EPOCH + Duration::from_nanos(self.clock.now_ns()),
+        };
+        let atime = atime.map(resolve);
+        let mtime = mtime.map(resolve);
+        let spec = |time: Option<SystemTime>| match time {
+            Some(t) => {
+                let since = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+                libc::timespec {
+                    tv_sec: since.as_secs() as libc::time_t,
+                    tv_nsec: since.subsec_nanos() as libc::c_long,
+                }
+            }
+            None => libc::timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_OMIT,
+            },
+        };
+        // Resealing the header writes to the backing file, which moves its
+        // mtime, so the times are set last.
+        if let Some(mtime) = mtime {
+            self.reseal_mtime(path, mtime)?;
+        }
+        let times = [spec(atime), spec(mtime)];
+        let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|_| EINVAL)?;
+        // SAFETY: `c_path` is NUL-terminated and `times` holds two timespecs.
+        let set = unsafe {
+            libc::utimensat(
+                libc::AT_FDCWD,
+                c_path.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if set != 0 {
+            return Err(Self::os_errno(&io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Seal `mtime` into the header of the file at `path`, if it has a
+    /// header with room for one.
+    fn reseal_mtime(&self, path: &Path, mtime: SystemTime) -> Result<(), c_int> {
+        let prefix = self.header_prefix(path);
+        let Some(mut header) = format::Header::decode(&prefix).filter(|h| h.mtime.is_some()) else {
+            return Ok(());
+        };
+        let ns = mtime
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        header
+            .seal_mtime(&self.key, self.nonces.as_ref(), ns)
+            .map_err(|e| {
+                log::error!("Encrypt error on {}: {}", detail(path), e);
+                EIO
+            })?;
+        self.backend
+            .write_at(path, 0, &header.encode())
+            .and_then(|_| self.sync_backing(path))
+            .map_err(|e| Self::io_errno(&e))
+    }
+
+    /// Create an empty regular file and open it with `flags`, returning its
+    /// attributes and file handle.
+    ///
+    /// The kernel only asks after a lookup found nothing, but the backing
+    /// file may have appeared since. It is never truncated behind the
+    /// caller's back: with `O_EXCL` the create fails with EEXIST, otherwise
+    /// the file is opened as `open(O_CREAT)` would, emptied only for
+    /// `O_TRUNC`.
+    fn create_file(&self, parent: u64, name: &OsStr, flags: i32) -> Result<(FileAttr, u64), c_int> {
+        self.check_writable()?;
+        let _names = self.names.read().unwrap();
+        if self.is_hidden(parent, name) {
+            return Err(EACCES);
+        }
+        let child_path = self.new_path(parent, name, true)?;
+        let created = match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&child_path)
+        {
+            Ok(_) => true,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && flags & libc::O_EXCL == 0 => {
+                false
+            }
+            Err(e) => return Err(Self::os_errno(&e)),
+        };
+        if !created {
+            log::warn!(
+                "{} appeared in the backing store before create; opening it",
+                detail(&child_path)
+            );
+            let ino = self.register(child_path.clone())?;
+            if flags & libc::O_TRUNC != 0 {
+                let lock = self.content_lock(ino);
+                let _guard = lock.write().unwrap();
+                let emptied = fs::OpenOptions::new()
+                    .write(true)
+                    .truncate(true)
+                    .open(&child_path)
+                    .and_then(|_| self.store.remove(&child_path));
+                self.cache.invalidate(ino);
+                emptied.map_err(|e| Self::os_errno(&e))?;
+            }
+            let meta = fs::metadata(&child_path).map_err(|e| Self::os_errno(&e))?;
+            let fh = self.open_handle(ino, flags)?;
+            return Ok((self.attr(ino, &child_path, &meta), fh));
+        }
+        if let Err(e) = self.force_mode(&child_path, false) {
+            let _ = fs::remove_file(&child_path);
+            return Err(e);
+        }
+        // The inherited cipher is requested like one set on the file itself,
+        // and applied as its first contents are written.
+        let inherited = match child_path.parent().map(|dir| self.inherited_cipher(dir)) {
+            Some(Err(e)) => {
+                let _ = fs::remove_file(&child_path);
+                return Err(e);
+            }
+            Some(Ok(cipher)) => cipher,
+            None => None,
+        };
+        if self.config.default_ttl.is_some() || inherited.is_some() {
+            let file_meta = FileMeta {
+                expires_at: self
+                    .config
+                    .default_ttl
+                    .map(|ttl| self.clock.now() + ttl.as_secs()),
+                cipher: inherited,
+                ..FileMeta::default()
+            };
+            if let Err(e) = file_meta.store(&self.key, &self.source, &child_path) {
+                log::warn!(
+                    "Failed to store metadata for {}: {}",
+                    detail(&child_path),
+                    e
+                );
+            }
+        }
+        let ino = self.register(child_path.clone())?;
+        let meta = fs::metadata(&child_path).map_err(|e| Self::os_errno(&e))?;
+        let fh = self.open_handle(ino, flags)?;
+        self.notify(|hook| hook.on_create(self.relative(&child_path), FileType::RegularFile));
+        Ok((self.attr(ino, &child_path, &meta), fh))
+    }
+
+    fn make_dir(&self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        self.check_writable()?;
+        let _names = self.names.read().unwrap();
+        if self.is_hidden(parent, name) {
+            return Err(EACCES);
+        }
+        let child_path = self.new_path(parent, name, false)?;
+        fs::create_dir(&child_path).map_err(|_| EIO)?;
+        if let Err(e) = self.force_mode(&child_path, true) {
+            let _ = fs::remove_dir(&child_path);
+            return Err(e);
+        }
+        let ino = self.register(child_path.clone())?;
+        let meta = fs::metadata(&child_path).map_err(|e| Self::os_errno(&e))?;
+        self.notify(|hook| hook.on_create(self.relative(&child_path), FileType::Directory));
+        Ok(self.attr(ino, &child_path, &meta))
+    }
+
+    /// Give a new entry the `--force-mode` permissions, if set. Directories
+    /// also get search permission wherever the mode grants read, or nobody
+    /// could enter them.
+    fn force_mode(&self, path: &Path, dir: bool) -> Result<(), c_int> {
+        let Some(mode) = self.config.force_mode else {
+            return Ok(());
+        };
+        let mode = if dir {
+            mode | (mode & 0o444) >> 2
+        } else {
+            mode
+        };
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(|e| Self::os_errno(&e))
+    }
+
+    /// Remove `parent/name`: an empty directory if `dir`, otherwise a
+    /// non-directory entry along with its sidecar.
+    fn remove_entry(&self, parent: u64, name: &OsStr, dir: bool) -> Result<(), c_int> {
+        self.check_writable()?;
+        if self.is_hidden(parent, name) {
+            return Err(ENOENT);
+        }
+        let child_path = self.child_path(parent, name)?;
+        if !dir {
+            self.check_mutable(&child_path)?;
+        }
+        if dir {
+            fs::remove_dir(&child_path)
+                .and_then(|_| self.shred(&child_path))
+                .and_then(|_| meta::remove(&child_path))
+                .map_err(|_| EIO)?;
+            self.forget_policies(&child_path);
+        } else {
+            self.unlink_file(&child_path, false)?;
+        }
+        self.notify(|hook| hook.on_unlink(self.relative(&child_path)));
+        Ok(())
+    }
+
+    /// Record `op` by `uid` on `ino` in the access log, if there is one.
+    /// EAGAIN if `uid` is over its rate limit.
+    fn log_access(&self, uid: u32, op: &str, ino: u64) -> Result<(), c_int> {
+        let Some(access) = &self.access else {
+            return Ok(());
+        };
+        let path = match self.path_for(ino) {
+            Some(path) => self.relative(&path).to_path_buf(),
+            None if ino == ERRORS_INO => PathBuf::from(ERRORS_FILE),
+            None => return Err(ENOENT),
+        };
+        if access.record(uid, op, &path) {
+            Ok(())
+        } else {
+            Err(EAGAIN)
+        }
+    }
+
+    /// On a shadow mount, record that `uid` attempted `op` on `name` in
+    /// directory `ino` (or on `ino` itself) and return true: the caller then
+    /// skips the change. False on any other mount.
+    fn shadowed(&self, uid: u32, op: &str, ino: u64, name: Option<&OsStr>) -> bool {
+        if !self.config.shadow {
+            return false;
+        }
+        let mut path = self
+            .path_for(ino)
+            .map(|p| self.relative(&p).to_path_buf())
+            .unwrap_or_else(|| PathBuf::from(format!("<inode {}>", ino)));
+        if let Some(name) = name {
+            path.push(name);
+        }
+        match &self.access {
+            Some(access) => {
+                access.record(uid, &format!("shadow {}", op), &path);
+            }
+            None => log::info!("Shadow: uid={} {} {}", uid, op, detail(&path)),
+        }
+        true
+    }
+
+    /// `write_request` on behalf of `uid`, recording `uid` as the writer if
+    /// the mount records writers. A shadow mount only records the attempt.
+    fn write_as(
+        &self,
+        uid: u32,
+        ino: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+    ) -> Result<u32, c_int> {
+        let op = format!("write offset={} len={}", offset, data.len());
+        if self.shadowed(uid, &op, ino, None) {
+            return Ok(data.len() as u32);
+        }
+        let written = self.write_request(ino, offset, data, write_flags)?;
+        // The data is already written; a failure here is logged only.
+        if self.config.record_writer && self.record_writer(ino, uid).is_err() {
+            log::warn!("Could not record uid {} as writer of inode {}", uid, ino);
+        }
+        Ok(written)
+    }
+
+    /// `read_data` on behalf of `uid`, recorded in the access log.
+    fn read_as(&self, uid: u32, ino: u64, offset: i64, size: u32) -> Result<Vec<u8>, c_int> {
+        self.log_access(uid, "read", ino)?;
+        self.read_data(ino, offset, size)
+    }
+
+    /// Pass an event to the hook, if one is installed.
+    fn notify(&self, event: impl FnOnce(&dyn EventHook)) {
+        if let Some(hook) = &self.hook {
+            event(hook.as_ref());
+        }
+    }
+
+    /// `path` relative to the vault root, as events report it.
+    fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.source).unwrap_or(path)
+    }
+
+    /// Create a symlink whose backing target is the sealed, hex-encoded
+    /// `target`, so link targets are as private as file contents.
+    fn make_symlink(&self, parent: u64, name: &OsStr, target: &Path) -> Result<FileAttr, c_int> {
+        self.check_writable()?;
+        let _names = self.names.read().unwrap();
+        if self.is_hidden(parent, name) {
+            return Err(EACCES);
+        }
+        let path = self.new_path(parent, name, false)?;
+        let sealed = crypto::encrypt_with_nonces(
+            &self.key,
+            self.nonces.as_ref(),
+            target.as_os_str().as_bytes(),
+            LINK_AAD,
+        )
+        .map_err(|_| EIO)?;
+        std::os::unix::fs::symlink(hex::encode(sealed), &path).map_err(|e| Self::os_errno(&e))?;
+        let ino = self.register(path.clone())?;
+        let meta = fs::symlink_metadata(&path).map_err(|e| Self::os_errno(&e))?;
+        self.notify(|hook| hook.on_create(self.relative(&path), FileType::Symlink));
+        Ok(self.attr(ino, &path, &meta))
+    }
+
+    /// Decrypted target of the symlink at `path`.
+    fn link_target(&self, path: &Path) -> Result<Vec<u8>, c_int> {
+        let backing = fs::read_link(path).map_err(|e| Self::os_errno(&e))?;
+        hex::decode(backing.as_os_str().as_bytes())
+            .ok()
+            .and_then(|sealed| crypto::decrypt_with_aad(&self.key, &sealed, LINK_AAD).ok())
+            .ok_or_else(|| {
+                log::error!("Symlink {} has an unreadable target", detail(path));
+                EIO
+            })
+    }
+
+    /// Create a FIFO, socket, device node or empty regular file.
+    fn make_node(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+    ) -> Result<FileAttr, c_int> {
+        self.check_writable()?;
+        let _names = self.names.read().unwrap();
+        if self.is_hidden(parent, name) {
+            return Err(EACCES);
+        }
+        let is_file = mode & libc::S_IFMT == libc::S_IFREG;
+        let path = self.new_path(parent, name, is_file)?;
+        let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|_| EINVAL)?;
+        // SAFETY: `c_path` is a valid NUL-terminated string for the call.
+        if unsafe { libc::mknod(c_path.as_ptr(), mode as libc::mode_t, rdev as libc::dev_t) } != 0 {
+            return Err(Self::os_errno(&io::Error::last_os_error()));
+        }
+        let ino = self.register(path.clone())?;
+        let meta = fs::symlink_metadata(&path).map_err(|e| Self::os_errno(&e))?;
+        let kind = Self::file_kind(meta.file_type());
+        self.notify(|hook| hook.on_create(self.relative(&path), kind));
+        Ok(self.attr(ino, &path, &meta))
+    }
+
+    /// Hard-link `ino` as `newparent/newname`. Contents are sealed to their
+    /// header, not their path, so every name decrypts the same blob; only
+    /// the path-bound sidecar is resealed for the new name. From then on each
+    /// name keeps its own sidecar, while both share the inode.
+    fn link_entry(&self, ino: u64, newparent: u64, newname: &OsStr) -> Result<FileAttr, c_int> {
+        self.check_writable()?;
+        if self.is_hidden(newparent, newname) {
+            return Err(EACCES);
+        }
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        let meta = fs::symlink_metadata(&path).map_err(|e| Self::os_errno(&e))?;
+        if meta.is_dir() {
+            return Err(EPERM);
+        }
+        // Both names would share one block directory, named after only one.
+        if layout::block_dir(&path).is_dir() {
+            return Err(EPERM);
+        }
+        // A second name could be cleared and written through.
+        self.check_mutable(&path)?;
+        let target = self.new_path(newparent, newname, meta.is_file())?;
+        let file_meta = FileMeta::load(&self.key, &self.source, &path).map_err(|e| {
+            log::error!("Metadata of {}: {}", detail(&path), e);
+            EIO
+        })?;
+        fs::hard_link(&path, &target).map_err(|e| Self::os_errno(&e))?;
+        if let Some(file_meta) = file_meta {
+            if let Err(e) = file_meta.store(&self.key, &self.source, &target) {
+                log::error!("Failed to reseal metadata for {}: {}", detail(&target), e);
+                let _ = fs::remove_file(&target);
+                return Err(EIO);
+            }
+        }
+        self.inodes
+            .lock()
+            .unwrap()
+            .entry(ino)
+            .or_default()
+            .push(target.clone());
+        let meta = fs::symlink_metadata(&path).map_err(|e| Self::os_errno(&e))?;
+        let kind = Self::file_kind(meta.file_type());
+        self.notify(|hook| hook.on_create(self.relative(&target), kind));
+        Ok(self.attr(ino, &path, &meta))
+    }
+
+    /// Rename `parent/name` to `newparent/newname`, whatever kind of entry it
+    /// is. Sidecars are sealed to their file's path, so each one at or below
+    /// the renamed entry is resealed under its new path; inodes keep pointing
+    /// at the moved entries.
+    fn rename_entry(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<(), c_int> {
+        self.check_writable()?;
+        let _names = self.names.write().unwrap();
+        if self.is_hidden(parent, name) {
+            return Err(ENOENT);
+        }
+        if self.is_hidden(newparent, newname) {
+            return Err(EACCES);
+        }
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            return Err(EINVAL);
+        }
+        let from = self.child_path(parent, name)?;
+        let meta = fs::symlink_metadata(&from).map_err(|e| Self::os_errno(&e))?;
+        let to = self.new_path(newparent, newname, meta.is_file())?;
+        if flags & libc::RENAME_NOREPLACE != 0 && fs::symlink_metadata(&to).is_ok() {
+            return Err(EEXIST);
+        }
+        // An immutable file may neither move nor be replaced.
+        if meta.is_file() {
+            self.check_mutable(&from)?;
+        }
+        if fs::symlink_metadata(&to).is_ok_and(|m| m.is_file()) {
+            self.check_mutable(&to)?;
+        }
+
+        // Read every sidecar under the old paths first: a sidecar that fails
+        // authentication stops the rename instead of being resealed as valid.
+        let mut sidecars = vec![];
+        self.collect_sidecars(&from, Path::new(""), &meta, &mut sidecars)?;
+
+        // A file replaced while open lives on for its handles, as if unlinked.
+        let replaced_open = self
+            .ino_for(&to)
+            .filter(|&ino| self.is_open(ino) && !self.has_other_names(ino, &to));
+        if let Some(ino) = replaced_open.filter(|_| to != from && !meta.is_dir()) {
+            self.orphan(ino, &to)?;
+        }
+        let replaced_dir = fs::symlink_metadata(&to).is_ok_and(|m| m.is_dir());
+        // Separately stored blocks go first, so a failure leaves the file
+        // readable where it was.
+        let from_blocks = layout::block_dir(&from);
+        let has_blocks = meta.is_file() && from_blocks.is_dir();
+        if meta.is_file() && !replaced_dir && to != from {
+            self.shred(&to)
+                .and_then(|_| self.store.remove(&to))
+                .map_err(|e| Self::os_errno(&e))?;
+        }
+        if has_blocks {
+            fs::rename(&from_blocks, layout::block_dir(&to)).map_err(|e| Self::os_errno(&e))?;
+        }
+        if let Err(e) = Self::move_entry(&from, &to, &meta) {
+            if has_blocks {
+                let _ = fs::rename(layout::block_dir(&to), &from_blocks);
+            }
+            return Err(Self::os_errno(&e));
+        }
+        let _ = meta::remove(&from);
+        if !replaced_dir || meta.is_dir() {
+            let _ = meta::remove(&to);
+        }
+        if meta.is_dir() {
+            self.forget_policies(&from);
+            self.forget_policies(&to);
+        }
+        // `join("")` would add a trailing slash, changing the sidecar's AAD.
+        let moved_path = |rest: &Path| {
+            if rest.as_os_str().is_empty() {
+                to.clone()
+            } else {
+                to.join(rest)
+            }
+        };
+        for (suffix, file_meta) in sidecars {
+            let moved = moved_path(&suffix);
+            if let Err(e) = file_meta.store(&self.key, &self.source, &moved) {
+                log::error!("Failed to reseal metadata for {}: {}", detail(&moved), e);
+            }
+        }
+
+        let mut inodes = self.inodes.lock().unwrap();
+        for paths in inodes.values_mut() {
+            paths.retain(|p| !p.starts_with(&to));
+        }
+        inodes.retain(|_, paths| !paths.is_empty());
+        for p in inodes.values_mut().flatten() {
+            if let Ok(rest) = p.strip_prefix(&from) {
+                *p = moved_path(rest);
+            }
+        }
+        drop(inodes);
+        self.notify(|hook| hook.on_rename(self.relative(&from), self.relative(&to)));
+        Ok(())
+    }
+
+    /// Sidecar metadata of `path` and, for a directory, of every entry
+    /// inside it, keyed by path relative to `path`.
+    fn collect_sidecars(
+        &self,
+        path: &Path,
+        suffix: &Path,
+        meta: &fs::Metadata,
+        out: &mut Vec<(PathBuf, FileMeta)>,
+    ) -> Result<(), c_int> {
+        let full = path.join(suffix);
+        if meta.is_dir() {
+            for entry in fs::read_dir(&full)
+                .map_err(|e| Self::os_errno(&e))?
+                .flatten()
+            {
+                let name = entry.file_name();
+                let name_str = name.to_string_lossy();
+                if meta::is_sidecar(&name_str) || layout::is_block_dir(&name_str) {
+                    continue;
+                }
+                let child_meta = entry.metadata().map_err(|e| Self::os_errno(&e))?;
+                self.collect_sidecars(path, &suffix.join(&name), &child_meta, out)?;
+            }
+        }
+        // A directory's own sidecar holds the defaults it passes down.
+        if let Some(file_meta) = FileMeta::load(&self.key, &self.source, &full).map_err(|e| {
+            log::error!("Metadata of {}: {}", detail(&full), e);
+            EIO
+        })? {
+            out.push((suffix.to_path_buf(), file_meta));
+        }
+        Ok(())
+    }
+
+    /// `fs::rename`, falling back to copy-and-remove when the backing store
+    /// spans filesystems. Directories are left to the caller (EXDEV).
+    fn move_entry(from: &Path, to: &Path, meta: &fs::Metadata) -> io::Result<()> {
+        match fs::rename(from, to) {
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) && !meta.is_dir() => {
+                Self::copy_entry(from, to, meta)?;
+                fs::remove_file(from)
+            }
+            other => other,
+        }
+    }
+
+    /// Recreate the non-directory entry `from` at `to` by kind: symlinks are
+    /// recreated with the same (sealed) target, special files with the same
+    /// mode and device, regular files are copied.
+    fn copy_entry(from: &Path, to: &Path, meta: &fs::Metadata) -> io::Result<()> {
+        match fs::remove_file(to) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let file_type = meta.file_type();
+        if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(from)?, to)
+        } else if file_type.is_file() {
+            fs::copy(from, to).map(|_| ())
+        } else {
+            let c_path = CString::new(to.as_os_str().as_bytes())?;
+            // SAFETY: `c_path` is a valid NUL-terminated string for the call.
+            let rc = unsafe {
+                libc::mknod(
+                    c_path.as_ptr(),
+                    meta.mode() as libc::mode_t,
+                    meta.rdev() as libc::dev_t,
+                )
+            };
+            if rc != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    /// Kernel-facing kind of a backing entry.
+    fn file_kind(file_type: fs::FileType) -> FileType {
+        if file_type.is_dir() {
+            FileType::Directory
+        } else if file_type.is_symlink() {
+            FileType::Symlink
+        } else if file_type.is_fifo() {
+            FileType::NamedPipe
+        } else if file_type.is_socket() {
+            FileType::Socket
+        } else if file_type.is_char_device() {
+            FileType::CharDevice
+        } else if file_type.is_block_device() {
+            FileType::BlockDevice
+        } else {
+            FileType::RegularFile
+        }
+    }
+
+    /// CipherMount's own files (vault files in the vault root, metadata
+    /// sidecars and block directories anywhere) are not part of the view.
+    fn is_hidden(&self, parent: u64, name: &OsStr) -> bool {
+        let vault_root = parent == ROOT_INO && self.config.subpath.is_none();
+        name.to_str().is_some_and(|name| {
+            meta::is_sidecar(name)
+                || layout::is_block_dir(name)
+                || (vault_root && vault::is_reserved(name))
+                || (parent == ROOT_INO && name == ERRORS_FILE)
+        })
+    }
+
+    /// Write the packed stream `ciphertext` to the backing file in the
+    /// layout its header asks for, and sync it per `backing_sync`. Blocks
+    /// the file no longer has are removed (a deduplicated file's current
+    /// blocks are already in the store).
+    fn store(&self, path: &Path, ciphertext: &[u8]) -> io::Result<()> {
+        match format::Header::decode(ciphertext).filter(|h| h.separate_blocks()) {
+            Some(header) => {
+                for (index, sealed) in layout::split(ciphertext, &header)?.iter().enumerate() {
+                    self.write_sealed(path, &header, index as u64, sealed)?;
+                }
+                self.backend.write(path, &ciphertext[..header.size()])?;
+                self.store.prune(path, header.block_count())?;
+            }
+            None => {
+                self.backend.write(path, ciphertext)?;
+                match format::Header::decode(ciphertext).filter(|h| h.dedup_blocks()) {
+                    Some(header) => self.store.prune(path, header.block_count())?,
+                    None => self.store.remove(path)?,
+                }
+            }
+        }
+        self.sync_backing(path)
+    }
+
+    /// Sync the backing file at `path` per `backing_sync`.
+    fn sync_backing(&self, path: &Path) -> io::Result<()> {
+        match self.config.backing_sync {
+            SyncPolicy::None => Ok(()),
+            SyncPolicy::Data => self.backend.sync(path, true),
+            SyncPolicy::Full => self.backend.sync(path, false),
+        }
+    }
+
+    /// Flush the backing file for `ino` to stable storage, then report any
+    /// held writeback failure: a successful sync doesn't bring that data back.
+    fn fsync_data(&self, ino: u64, datasync: bool) -> Result<(), c_int> {
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        let lock = self.content_lock(ino);
+        let _guard = lock.read().unwrap();
+        let synced = self.backend.sync(&path, datasync);
+        self.take_write_error(ino)?;
+        synced.map_err(|e| Self::io_errno(&e))
+    }
+
+    /// Note whether `fh` has written since it was last synced.
+    fn set_dirty(&self, fh: u64, dirty: bool) {
+        if let Some(handle) = self.handles.lock().unwrap().get_mut(&fh) {
+            handle.dirty = dirty;
+        }
+    }
+
+    /// Sync the file behind `fh` as it is closed, per `close_sync`. Done at
+    /// `flush`, which `close(2)` waits for, rather than at `release`, which
+    /// the kernel sends after the application has moved on.
+    fn sync_on_close(&self, ino: u64, fh: u64) -> Result<(), c_int> {
+        if self.config.read_only || ino == ERRORS_INO {
+            return Ok(());
+        }
+        let dirty = match self.handles.lock().unwrap().get_mut(&fh) {
+            Some(handle) => std::mem::take(&mut handle.dirty),
+            None => false,
+        };
+        let sync = match self.config.close_sync {
+            CloseSync::Always => true,
+            CloseSync::Never => false,
+            CloseSync::Dirty => dirty,
+        };
+        if sync {
+            self.fsync_data(ino, false)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// `FOPEN_*` flags for newly opened files.
+    fn open_flags(&self) -> u32 {
+        if self.config.mlock {
+            fuser::consts::FOPEN_DIRECT_IO
+        } else {
+            0
+        }
+    }
+
+    /// EROFS if the mount is read-only.
+    fn check_writable(&self) -> Result<(), c_int> {
+        if self.config.read_only {
+            Err(EROFS)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check an `open` of `ino` with `flags`. On a read-only mount any
+    /// write intent fails here, as POSIX expects, not at the first write.
+    fn open_file(&self, ino: u64, flags: i32) -> Result<(), c_int> {
+        let writes = flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0;
+        if ino == ERRORS_INO && self.config.error_file {
+            return if writes { Err(EACCES) } else { Ok(()) };
+        }
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        Self::expect_kind(&path, FileType::RegularFile)?;
+        // A shadow mount accepts the writes, to record them.
+        if writes && !self.config.shadow {
+            self.check_writable()?;
+            self.check_mutable(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Pass an OS error through to the kernel, defaulting to EIO.
+    fn os_errno(e: &io::Error) -> c_int {
+        e.raw_os_error().unwrap_or(EIO)
+    }
+
+    /// Map a backend error to the errno reported to the kernel. A full disk
+    /// or quota is passed through so applications can tell it apart.
+    fn io_errno(e: &io::Error) -> c_int {
+        match (e.kind(), e.raw_os_error()) {
+            (io::ErrorKind::TimedOut, _) => ETIMEDOUT,
+            (_, Some(errno @ (ENOSPC | EDQUOT))) => errno,
+            _ => EIO,
+        }
+    }
+
+    /// Apply the configured queue limits to the kernel connection. A value
+    /// the kernel refuses is replaced by the nearest one it accepts.
+    fn tune_connection(&self, conn: &mut impl Connection) {
+        if let Some(value) = self.config.max_background {
+            if let Err(nearest) = conn.set_max_background(value) {
+                log::warn!("max_background {} refused; using {}", value, nearest);
+                let _ = conn.set_max_background(nearest);
+            }
+        }
+        if let Some(value) = self.config.congestion_threshold {
+            if let Err(nearest) = conn.set_congestion_threshold(value) {
+                log::warn!("congestion_threshold {} refused; using {}", value, nearest);
+                let _ = conn.set_congestion_threshold(nearest);
+            }
+        }
+    }
+
+    /// Attributes for `ino` as seen through the mount.
+    fn attr(&self, ino: u64, path: &Path, meta: &fs::Metadata) -> FileAttr {
+        let mut attr = if meta.is_file() {
+            let prefix = self.header_prefix(path);
+            let mut attr = Self::meta_to_attr(ino, meta, self.logical_mtime(path, &prefix));
+            attr.size = format::plaintext_len(&prefix, meta.len());
+            attr
+        } else {
+            let mut attr = Self::meta_to_attr(ino, meta, None);
+            if meta.is_dir() {
+                attr.nlink = self.dir_nlink(ino, path);
+            } else if meta.file_type().is_symlink() {
+                attr.size = self.link_target(path).map_or(0, |t| t.len() as u64);
+            }
+            attr
+        };
+        attr.uid = self.config.uid_map.to_inside(attr.uid);
+        attr.gid = self.config.gid_map.to_inside(attr.gid);
+        attr
+    }
+
+    /// The first `MAX_HEADER_SIZE` (or fewer) bytes of the backing file at
+    /// `path`; empty if they can't be read.
+    fn header_prefix(&self, path: &Path) -> Vec<u8> {
+        self.backend
+            .read_at(path, 0, format::MAX_HEADER_SIZE)
+            .unwrap_or_default()
+    }
+
+    /// Logical mtime sealed in the header at the start of `prefix`. Files
+    /// without one (legacy and version 1 files) report the backing mtime,
+    /// as does a header whose mtime fails authentication.
+    fn logical_mtime(&self, path: &Path, prefix: &[u8]) -> Option<SystemTime> {
+        let header = format::Header::decode(prefix)?;
+        match header.open_mtime(&self.key) {
+            Ok(ns) => ns.map(|ns| UNIX_EPOCH + Duration::from_nanos(ns)),
+            Err(e) => {
+                log::warn!("Header of {}: {}", detail(path), e);
+                None
+            }
+        }
+    }
+
+    /// A directory's link count is 2 (its entry and its own `.`) plus one
+    /// `..` per subdirectory. Count the subdirectories visible in the mount
+    /// rather than trusting the backing nlink, which also counts CipherMount's
+    /// own entries and stops matching once the on-disk layout diverges.
+    fn dir_nlink(&self, ino: u64, path: &Path) -> u32 {
+        let subdirs = fs::read_dir(path)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .filter(|e| !self.is_hidden(ino, &e.file_name()))
+                    .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+                    .count()
+            })
+            .unwrap_or(0);
+        2 + subdirs as u32
+    }
+
+    fn getattr_for(&self, ino: u64) -> Result<FileAttr, c_int> {
+        if ino == ERRORS_INO && self.config.error_file {
+            return Ok(self.errors_attr());
+        }
+        let path = self.path_for(ino).ok_or(ENOENT)?;
+        let meta = fs::symlink_metadata(&path).map_err(|_| ENOENT)?;
+        Ok(self.attr(ino, &path, &meta))
+    }
+
+    /// Attributes of a backing entry. `logical_mtime`, when known, is
+    /// reported instead of the backing mtime, which also moves when a file
+    /// is only re-encrypted; ctime keeps tracking the backing file.
+    fn meta_to_attr(ino: u64, meta: &fs::Metadata, logical_mtime: Option<SystemTime>) -> FileAttr {
+        let kind = Self::file_kind(meta.file_type());
+        let backing_mtime = meta
+            .modified()
+            .unwrap_or(UNIX_EPOCH)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let atime = meta
+            .accessed()
+            .unwrap_or(UNIX_EPOCH)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        FileAttr {
+            ino,
+            size: meta.len(),
+            blocks: meta.blocks(),
+            atime: UNIX_EPOCH + atime,
+            mtime: logical_mtime.unwrap_or(UNIX_EPOCH + backing_mtime),
+            ctime: UNIX_EPOCH + backing_mtime,
+            crtime: UNIX_EPOCH,
+            kind,
+            perm: meta.mode() as u16,
+            nlink: meta.nlink() as u32,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            rdev: meta.rdev() as u32,
+            blksize: 512,
+            flags: 0,
+        }
+    }
+}
+
+impl Filesystem for CipherFS {
+    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+        self.tune_connection(config);
+        // Nothing writes to the store yet, so this can't race a new block.
+        if self.check_writable().is_ok() {
+            match self.remove_stale_orphans() {
+                Ok(0) => {}
+                Ok(n) => log::info!("Removed {} file(s) unlinked while open", n),
+                Err(e) => log::warn!("Removing files unlinked while open failed: {}", e),
+            }
+            match self.store.collect_garbage() {
+                Ok(0) => {}
+                Ok(n) => log::info!("Removed {} unreferenced blocks from the block store", n),
+                Err(e) => log::warn!("Block store garbage collection failed: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.getattr_for(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let changes = AttrChanges {
+            size,
+            mode,
+            uid,
+            gid,
+            atime,
+            mtime,
+        };
+        if self.shadowed(req.uid(), &format!("setattr {:?}", changes), ino, None) {
+            match self.getattr_for(ino) {
+                Ok(attr) => reply.attr(&TTL, &attr),
+                Err(e) => reply.error(e),
+            }
+            return;
+        }
+        match self.set_attr(ino, &changes) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.lookup_entry(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let all = match self.dir_entries(ino, fh) {
+            Ok(all) => all,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        for (i, (child_ino, kind, name)) in all.iter().enumerate().skip(offset as usize) {
+            if reply.add(*child_ino, (i + 1) as i64, *kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn opendir(&mut self, req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
+        match self
+            .log_access(req.uid(), "list", ino)
+            .and_then(|_| self.open_dir(ino))
+        {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        self.release_dir(fh);
+        reply.ok();
+    }
+
+    /// Files are opened without `FOPEN_DIRECT_IO`, so reads, writes and mmap
+    /// all go through the kernel page cache and stay coherent with each other.
+    /// Dirty mmap pages reach `write` on writeback and `fsync` on `msync`.
+    /// With `mlock` plaintext is kept out of the page cache instead, at the
+    /// cost of shared mmap on kernels that refuse it for direct I/O.
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self
+            .log_access(req.uid(), "open", ino)
+            .and_then(|_| self.open_handle(ino, flags))
+        {
+            Ok(fh) => reply.opened(fh, self.open_flags()),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Called on every `close` of a handle; the only chance to fail it.
+    fn flush(&mut self, _req: &Request, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        let synced = self.sync_on_close(ino, fh);
+        match self.take_write_error(ino).and(synced) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let released = self.release_file(fh);
+        match self.take_write_error(ino).and(released) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Read: load file from disk → decrypt → return plaintext to caller.
+    fn read(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let uid = req.uid();
+        self.dispatch(move |fs| match fs.read_as(uid, ino, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        });
+    }
+
+    /// Write: encrypt buffer → write to disk.
+    fn write(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let (uid, data) = (req.uid(), data.to_vec());
+        self.dispatch(
+            move |fs| match fs.write_as(uid, ino, offset, &data, write_flags) {
+                Ok(n) => {
+                    fs.set_dirty(fh, true);
+                    reply.written(n)
+                }
+                Err(e) => reply.error(e),
+            },
+        );
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        flags: i32,
+        reply: fuser::ReplyCreate,
+    ) {
+        // Recorded on a shadow mount, then refused as read-only: there is no
+        // new file to hand back.
+        self.shadowed(req.uid(), "create", parent, Some(name));
+        match self.create_file(parent, name, flags) {
+            Ok((attr, fh)) => reply.created(&TTL, &attr, 0, fh, self.open_flags()),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if self.shadowed(req.uid(), "unlink", parent, Some(name)) {
+            reply.ok();
+            return;
+        }
+        match self.remove_entry(parent, name, false) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        self.shadowed(req.uid(), "symlink", parent, Some(link_name));
+        match self.make_symlink(parent, link_name, target) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
+        match self
+            .log_access(req.uid(), "readlink", ino)
+            .and_then(|_| self.path_for(ino).ok_or(ENOENT))
+            .and_then(|p| self.link_target(&p))
+        {
+            Ok(target) => reply.data(&target),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        self.shadowed(req.uid(), "mknod", parent, Some(name));
+        match self.make_node(parent, name, mode & !umask, rdev) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let to = self
+            .path_for(newparent)
+            .map(|p| self.relative(&p).join(newname))
+            .unwrap_or_default();
+        let op = format!("rename to {:?}", to);
+        if self.shadowed(req.uid(), &op, parent, Some(name)) {
+            reply.ok();
+            return;
+        }
+        match self.rename_entry(parent, name, newparent, newname, flags) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn link(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        self.shadowed(req.uid(), "link", newparent, Some(newname));
+        match self.link_entry(ino, newparent, newname) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
         }
     }
 
     fn mkdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         _mode: u32,
         _umask: u32,
         reply: ReplyEntry,
     ) {
-        let parent_path = match self.path_for(parent) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
+        self.shadowed(req.uid(), "mkdir", parent, Some(name));
+        match self.make_dir(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if self.shadowed(req.uid(), "rmdir", parent, Some(name)) {
+            reply.ok();
+            return;
+        }
+        match self.remove_entry(parent, name, true) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        self.dispatch(move |fs| match fs.fsync_data(ino, datasync) {
+            Ok(()) => {
+                fs.set_dirty(fh, false);
+                reply.ok()
+            }
+            Err(e) => reply.error(e),
+        });
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let value = std::str::from_utf8(value).ok().map(str::trim);
+        let op = format!("setxattr {:?}={:?}", name, value.unwrap_or("<binary>"));
+        if self.shadowed(req.uid(), &op, ino, None) {
+            reply.ok();
+            return;
+        }
+        let result = if name == meta::TTL_XATTR {
+            match value.and_then(|v| v.parse().ok()) {
+                Some(secs) => self.set_ttl(ino, secs),
+                None => Err(EINVAL),
+            }
+        } else if name == meta::MIME_XATTR {
+            match value {
+                Some(v) => self.set_mime(ino, v),
+                None => Err(EINVAL),
+            }
+        } else if name == cache::ADVICE_XATTR {
+            match value.and_then(|v| v.parse().ok()) {
+                Some(advice) => self.advise(ino, advice),
+                None => Err(EINVAL),
+            }
+        } else if name == meta::DEFAULT_CIPHER_XATTR {
+            match value {
+                Some("inherit") => self.set_default_cipher(ino, None),
+                Some(v) => match v.parse() {
+                    Ok(cipher) => self.set_default_cipher(ino, Some(cipher)),
+                    Err(_) => Err(EINVAL),
+                },
+                None => Err(EINVAL),
+            }
+        } else if name == meta::IMMUTABLE_XATTR {
+            match value {
+                Some("1") => self.set_immutable(ino, true),
+                Some("0") => self.set_immutable(ino, false),
+                _ => Err(EINVAL),
+            }
+        } else if name == meta::CIPHER_XATTR {
+            // Unknown cipher names are refused rather than ignored.
+            match value.and_then(|v| v.parse().ok()) {
+                Some(cipher) => self.set_cipher(ino, cipher),
+                None => Err(EINVAL),
+            }
+        } else {
+            Err(ENOTSUP)
+        };
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let value = if name == meta::TTL_XATTR {
+            self.remaining_ttl(ino).map(|t| t.map(|t| t.to_string()))
+        } else if name == meta::WRITER_XATTR {
+            self.last_writer(ino)
+                .map(|uid| uid.map(|uid| uid.to_string()))
+        } else if name == meta::MIME_XATTR {
+            self.file_mime(ino)
+        } else if name == meta::DEFAULT_CIPHER_XATTR {
+            self.default_cipher(ino)
+                .map(|c| c.map(|c| c.name().to_string()))
+        } else if name == meta::IMMUTABLE_XATTR {
+            self.is_immutable(ino)
+                .map(|on| Some(u8::from(on).to_string()))
+        } else if name == meta::CIPHER_XATTR {
+            self.file_cipher(ino)
+                .map(|c| c.map(|c| c.name().to_string()))
+        } else {
+            Err(ENODATA)
+        };
+        let value = match value {
+            Ok(Some(value)) => value.into_bytes(),
+            Ok(None) => {
+                reply.error(ENODATA);
+                return;
+            }
+            Err(e) => {
+                reply.error(e);
                 return;
             }
         };
-        let child_path = parent_path.join(name);
-        match fs::create_dir(&child_path) {
-            Ok(_) => {
-                let ino = self.register(child_path.clone());
-                let meta = fs::metadata(&child_path).unwrap();
-                reply.entry(&TTL, &Self::meta_to_attr(ino, &meta), 0);
+        if size == 0 {
+            reply.size(value.len() as u32);
+        } else if value.len() > size as usize {
+            reply.error(ERANGE);
+        } else {
+            reply.data(&value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Instant;
+
+    fn test_fs() -> (tempfile::TempDir, Arc<CipherFS>) {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = CipherFS::new(dir.path().to_path_buf(), [0x42u8; 32]);
+        (dir, Arc::new(fs))
+    }
+
+    fn new_file(fs: &CipherFS, dir: &tempfile::TempDir, name: &str) -> u64 {
+        let path = dir.path().join(name);
+        fs::File::create(&path).unwrap();
+        fs.register(path).unwrap()
+    }
+
+    #[test]
+    fn expired_file_is_hidden_and_collected() {
+        let (dir, fs) = test_fs();
+        let keep = new_file(&fs, &dir, "keep");
+        let old = new_file(&fs, &dir, "old");
+        fs.set_ttl(keep, 3600).unwrap();
+        let old_path = dir.path().join("old");
+        FileMeta {
+            expires_at: Some(1),
+            ..FileMeta::default()
+        }
+        .store(&fs.key, &fs.source, &old_path)
+        .unwrap();
+
+        let names: Vec<String> = fs
+            .list_dir(ROOT_INO)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.2)
+            .collect();
+        assert_eq!(
+            names.len(),
+            3,
+            "sidecars and expired files must not be listed: {names:?}"
+        );
+        assert!(names.contains(&"keep".to_string()));
+        assert!(fs.remaining_ttl(keep).unwrap().unwrap() > 3500);
+        assert_eq!(fs.remaining_ttl(old).unwrap(), Some(0));
+
+        let removed = fs.collect_expired().unwrap();
+        assert_eq!(removed, 1);
+        assert!(!old_path.exists());
+        assert!(dir.path().join("keep").exists());
+    }
+
+    #[test]
+    fn expiry_in_the_header_outlives_the_sidecar() {
+        let (dir, fs) = test_fs();
+        let ino = new_file(&fs, &dir, "f");
+        let path = dir.path().join("f");
+        fs.write_data(ino, 0, b"contents").unwrap();
+        fs.set_ttl(ino, 3600).unwrap();
+        let expiring = |fs: &CipherFS| fs.remaining_ttl(ino).unwrap().is_some_and(|s| s > 3500);
+
+        // Neither losing the sidecar nor one saying otherwise cancels it.
+        let _ = fs::remove_file(meta::sidecar_path(&path));
+        assert!(expiring(&fs));
+        FileMeta::default()
+            .store(&fs.key, &fs.source, &path)
+            .unwrap();
+        assert!(expiring(&fs));
+        // Writes in place, appends and whole rewrites keep it.
+        fs.write_data(ino, 0, b"C").unwrap();
+        fs.write_data(ino, 8, b" and more").unwrap();
+        fs.write_data(ino, 100, b"past the end").unwrap();
+        assert!(expiring(&fs));
+        assert_eq!(&fs.read_data(ino, 0, 17).unwrap(), b"Contents and more");
+
+        fs.set_ttl(ino, 0).unwrap();
+        assert_eq!(fs.remaining_ttl(ino).unwrap(), None);
+    }
+
+    #[test]
+    fn expired_open_file_lingers_until_released() {
+        const SEC: u64 = 1_000_000_000;
+        let dir = tempfile::tempdir().unwrap();
+        let time = Arc::new(AtomicU64::new(1_000 * SEC));
+        let clock = Clock::with_source({
+            let time = Arc::clone(&time);
+            move || time.load(Ordering::SeqCst)
+        });
+        let fs = CipherFS::new(dir.path().into(), [0x42u8; 32]).with_clock(Arc::new(clock));
+        for (name, sweep) in [("looked_up", false), ("swept", true)] {
+            let ino = new_file(&fs, &dir, name);
+            fs.write_data(ino, 0, b"still readable").unwrap();
+            fs.set_ttl(ino, 10).unwrap();
+            let fh = fs.open_handle(ino, libc::O_RDONLY).unwrap();
+            time.fetch_add(100 * SEC, Ordering::SeqCst);
+
+            if sweep {
+                assert_eq!(fs.collect_expired().unwrap(), 1);
+            } else {
+                assert_eq!(
+                    fs.lookup_entry(ROOT_INO, OsStr::new(name)).map(|e| e.ino),
+                    Err(ENOENT)
+                );
+            }
+            assert!(!dir.path().join(name).exists());
+            assert_eq!(fs.read_data(ino, 0, 64).unwrap(), b"still readable");
+            fs.release_file(fh).unwrap();
+            let orphan = dir.path().join(format!("{}{}", vault::ORPHAN_PREFIX, ino));
+            assert!(!orphan.exists());
+        }
+    }
+
+    #[test]
+    fn backward_clock_step_neither_revives_files_nor_repeats_nonces() {
+        const SEC: u64 = 1_000_000_000;
+        let dir = tempfile::tempdir().unwrap();
+        let time = Arc::new(AtomicU64::new(1_000 * SEC));
+        let clock = Clock::with_source({
+            let time = Arc::clone(&time);
+            move || time.load(Ordering::SeqCst)
+        });
+        let counter = crate::nonce::Counter::open(dir.path(), &[0x42u8; 32]).unwrap();
+        let fs = CipherFS::new(dir.path().into(), [0x42u8; 32])
+            .with_clock(Arc::new(clock))
+            .with_nonces(Arc::new(counter));
+        let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+        let ino = new_file(&fs, &dir, "old");
+        fs.write_data(ino, 0, b"same").unwrap();
+        fs.set_ttl(ino, 100).unwrap();
+
+        time.store(1_200 * SEC, Ordering::SeqCst);
+        assert!(fs.is_expired(&old));
+        // Stepped back to before the expiry: the file stays expired.
+        time.store(1_050 * SEC, Ordering::SeqCst);
+        assert!(fs.is_expired(&old));
+        assert_eq!(fs.remaining_ttl(ino).unwrap(), Some(0));
+
+        // Written after the step: a fresh nonce, and no older mtime.
+        let ino = new_file(&fs, &dir, "new");
+        fs.write_data(ino, 0, b"same").unwrap();
+        let (old_raw, new_raw) = (fs::read(&old).unwrap(), fs::read(&new).unwrap());
+        let nonce = format::HEADER_SIZE..format::HEADER_SIZE + 12;
+        assert_ne!(old_raw[nonce.clone()], new_raw[nonce]);
+        assert!(
+            fs.logical_mtime(&new, &new_raw).unwrap() >= fs.logical_mtime(&old, &old_raw).unwrap()
+        );
+    }
+
+    #[test]
+    fn cipher_request_applies_to_one_file_on_its_next_write() {
+        let (dir, fs) = test_fs();
+        let header =
+            |name: &str| format::Header::decode(&fs::read(dir.path().join(name)).unwrap()).unwrap();
+        let strong = new_file(&fs, &dir, "strong");
+        let plain = new_file(&fs, &dir, "plain");
+        for ino in [strong, plain] {
+            fs.write_data(ino, 0, b"before").unwrap();
+        }
+        fs.set_cipher(strong, Cipher::ChaCha20Poly1305).unwrap();
+        assert_eq!(fs.file_cipher(strong), Ok(Some(Cipher::ChaCha20Poly1305)));
+        assert_eq!(header("strong").cipher(), Cipher::Aes256Gcm);
+
+        fs.write_data(strong, 0, b"after").unwrap();
+        fs.write_data(plain, 0, b"after").unwrap();
+        assert_eq!(header("strong").cipher(), Cipher::ChaCha20Poly1305);
+        assert_eq!(header("plain").cipher(), Cipher::Aes256Gcm);
+        assert_eq!(fs.read_data(strong, 0, 64).unwrap(), b"aftere");
+
+        // Applied, so the request is gone; later writes keep the cipher.
+        assert!(!meta::sidecar_path(&dir.path().join("strong")).exists());
+        fs.write_data(strong, 6, b"!").unwrap();
+        assert_eq!(header("strong").cipher(), Cipher::ChaCha20Poly1305);
+        assert_eq!(fs.read_data(strong, 0, 64).unwrap(), b"aftere!");
+    }
+
+    #[test]
+    fn new_files_inherit_the_nearest_directory_cipher() {
+        let (dir, fs) = test_fs();
+        let header =
+            |path: &str| format::Header::decode(&fs::read(dir.path().join(path)).unwrap()).unwrap();
+        let media = fs.make_dir(ROOT_INO, OsStr::new("media")).unwrap().ino;
+        let raw = fs.make_dir(media, OsStr::new("raw")).unwrap().ino;
+        fs.set_default_cipher(media, Some(Cipher::ChaCha20Poly1305))
+            .unwrap();
+        assert_eq!(fs.set_default_cipher(ROOT_INO, None), Err(EINVAL));
+
+        let create = |parent: u64, name: &str| {
+            let (attr, fh) = fs.create_file(parent, OsStr::new(name), 0).unwrap();
+            fs.write_data(attr.ino, 0, b"data").unwrap();
+            fs.release_file(fh).unwrap();
+        };
+        create(raw, "clip");
+        create(ROOT_INO, "note");
+        assert_eq!(header("media/raw/clip").cipher(), Cipher::ChaCha20Poly1305);
+        assert_eq!(header("note").cipher(), Cipher::Aes256Gcm);
+
+        // The policy moves with its directory, and can be dropped again.
+        fs.rename_entry(
+            ROOT_INO,
+            OsStr::new("media"),
+            ROOT_INO,
+            OsStr::new("video"),
+            0,
+        )
+        .unwrap();
+        let video = fs.register(dir.path().join("video")).unwrap();
+        assert_eq!(fs.default_cipher(video), Ok(Some(Cipher::ChaCha20Poly1305)));
+        let raw = fs.register(dir.path().join("video/raw")).unwrap();
+        create(raw, "moved");
+        assert_eq!(header("video/raw/moved").cipher(), Cipher::ChaCha20Poly1305);
+        fs.set_default_cipher(video, None).unwrap();
+        create(raw, "after");
+        assert_eq!(header("video/raw/after").cipher(), Cipher::Aes256Gcm);
+    }
+
+    #[test]
+    fn sniffed_mime_hint_survives_reencryption() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            sniff_content_type: true,
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let ino = new_file(&fs, &dir, "scan");
+        fs.write_data(ino, 0, png).unwrap();
+        assert_eq!(fs.file_mime(ino), Ok(Some("image/png".into())));
+
+        fs.set_cipher(ino, Cipher::ChaCha20Poly1305).unwrap();
+        fs.write_data(ino, 16, b"more").unwrap();
+        let raw = fs::read(dir.path().join("scan")).unwrap();
+        assert_eq!(
+            format::Header::decode(&raw).unwrap().cipher(),
+            Cipher::ChaCha20Poly1305
+        );
+        assert_eq!(fs.file_mime(ino), Ok(Some("image/png".into())));
+
+        // A hint set by hand isn't replaced by sniffing.
+        fs.set_mime(ino, "image/x-scan").unwrap();
+        fs.write_data(ino, 0, b"plain text").unwrap();
+        assert_eq!(fs.file_mime(ino), Ok(Some("image/x-scan".into())));
+        assert_eq!(fs.set_mime(ino, "text/plain\nimmutable=1"), Err(EINVAL));
+    }
+
+    #[test]
+    fn directory_nlink_counts_logical_subdirectories() {
+        let (dir, fs) = test_fs();
+        for name in ["a", "b", "c"] {
+            std::fs::create_dir(dir.path().join(name)).unwrap();
+        }
+        new_file(&fs, &dir, "file");
+        fs.set_ttl(fs.register(dir.path().join("file")).unwrap(), 60)
+            .unwrap();
+
+        assert_eq!(fs.getattr_for(ROOT_INO).unwrap().nlink, 2 + 3);
+        let a = fs.register(dir.path().join("a")).unwrap();
+        assert_eq!(fs.getattr_for(a).unwrap().nlink, 2);
+    }
+
+    #[test]
+    fn slow_backend_read_times_out() {
+        struct Stalled;
+        impl Backend for Stalled {
+            fn read(&self, _path: &Path) -> io::Result<Vec<u8>> {
+                thread::sleep(Duration::from_secs(5));
+                Ok(vec![])
+            }
+            fn write(&self, _path: &Path, _data: &[u8]) -> io::Result<()> {
+                thread::sleep(Duration::from_secs(5));
+                Ok(())
+            }
+            fn sync(&self, _path: &Path, _data_only: bool) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            op_timeout: Some(Duration::from_millis(50)),
+            ..Config::default()
+        };
+        let fs = CipherFS::with_backend(
+            dir.path().to_path_buf(),
+            [0u8; 32],
+            config,
+            Arc::new(Stalled),
+        );
+        let ino = new_file(&fs, &dir, "f");
+
+        assert_eq!(fs.read_data(ino, 0, 16), Err(ETIMEDOUT));
+        assert_eq!(fs.write_data(ino, 0, b"x"), Err(ETIMEDOUT));
+    }
+
+    #[test]
+    fn legacy_and_block_files_coexist() {
+        let (dir, fs) = test_fs();
+        let legacy = new_file(&fs, &dir, "legacy");
+        let block = new_file(&fs, &dir, "block");
+        let legacy_path = dir.path().join("legacy");
+        std::fs::write(
+            &legacy_path,
+            crypto::encrypt(&fs.key, b"written long ago").unwrap(),
+        )
+        .unwrap();
+        fs.write_data(block, 0, b"written in block mode").unwrap();
+
+        assert_eq!(fs.read_data(legacy, 0, 64).unwrap(), b"written long ago");
+        assert_eq!(fs.read_data(block, 8, 5).unwrap(), b"in bl");
+        assert_eq!(fs.getattr_for(legacy).unwrap().size, 16);
+        assert_eq!(fs.getattr_for(block).unwrap().size, 21);
+
+        // Writing to the legacy file upgrades it to the block format.
+        fs.write_data(legacy, 0, b"W").unwrap();
+        let raw = std::fs::read(&legacy_path).unwrap();
+        assert!(matches!(format::detect(&raw), format::Format::Block(_)));
+        assert_eq!(fs.read_data(legacy, 0, 64).unwrap(), b"Written long ago");
+    }
+
+    #[test]
+    fn re_encryption_keeps_the_logical_mtime() {
+        let (dir, fs) = test_fs();
+        let ino = new_file(&fs, &dir, "notes");
+        fs.write_data(ino, 0, b"first draft").unwrap();
+        let before = fs.getattr_for(ino).unwrap().mtime;
+        let path = dir.path().join("notes");
+        let old_raw = fs::read(&path).unwrap();
+
+        // Re-encrypt with fresh nonces, as a rekey or repair would, and move
+        // the backing mtime well away from the logical one.
+        thread::sleep(Duration::from_millis(20));
+        let options = crate::walk::Options::default();
+        crate::rewrite::reblock(dir.path(), &fs.key, 64, options, |_| {}).unwrap();
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+
+        assert_ne!(fs::read(&path).unwrap(), old_raw);
+        let attr = fs.getattr_for(ino).unwrap();
+        assert_eq!(attr.mtime, before);
+        assert_eq!(attr.ctime, later);
+        assert_eq!(fs.read_data(ino, 0, 64).unwrap(), b"first draft");
+
+        // A write through the mount does move it.
+        fs.write_data(ino, 0, b"F").unwrap();
+        assert!(fs.getattr_for(ino).unwrap().mtime > before);
+    }
+
+    #[test]
+    fn hook_sees_changes_by_path_without_contents() {
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<String>>);
+        impl EventHook for Recorder {
+            fn on_write(&self, path: &Path, offset: u64, len: u64) {
+                let event = format!("write {} {}+{}", path.display(), offset, len);
+                self.0.lock().unwrap().push(event);
+            }
+            fn on_create(&self, path: &Path, kind: FileType) {
+                let event = format!("create {} {:?}", path.display(), kind);
+                self.0.lock().unwrap().push(event);
+            }
+            fn on_unlink(&self, path: &Path) {
+                let event = format!("unlink {}", path.display());
+                self.0.lock().unwrap().push(event);
+            }
+            fn on_rename(&self, from: &Path, to: &Path) {
+                let event = format!("rename {} {}", from.display(), to.display());
+                self.0.lock().unwrap().push(event);
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let recorder = Arc::new(Recorder::default());
+        let fs = CipherFS::new(dir.path().into(), [0x42u8; 32]).with_hook(recorder.clone());
+
+        let sub = fs.make_dir(ROOT_INO, OsStr::new("docs")).unwrap().ino;
+        let (attr, _) = fs
+            .create_file(sub, OsStr::new("draft"), libc::O_WRONLY)
+            .unwrap();
+        fs.write_data(attr.ino, 0, b"hello").unwrap();
+        fs.write_data(attr.ino, 5, b", world").unwrap();
+        fs.rename_entry(sub, OsStr::new("draft"), ROOT_INO, OsStr::new("final"), 0)
+            .unwrap();
+        fs.link_entry(attr.ino, sub, OsStr::new("alias")).unwrap();
+        fs.remove_entry(ROOT_INO, OsStr::new("final"), false)
+            .unwrap();
+        fs.remove_entry(sub, OsStr::new("alias"), false).unwrap();
+        fs.remove_entry(ROOT_INO, OsStr::new("docs"), true).unwrap();
+        // A failed change is not reported.
+        assert!(fs.remove_entry(ROOT_INO, OsStr::new("docs"), true).is_err());
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                "create docs Directory",
+                "create docs/draft RegularFile",
+                "write docs/draft 0+5",
+                "write docs/draft 5+7",
+                "rename docs/draft final",
+                "create docs/alias RegularFile",
+                "unlink final",
+                "unlink docs/alias",
+                "unlink docs",
+            ]
+        );
+    }
+
+    #[test]
+    fn backing_extension_is_hidden_from_the_view() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            backing_extension: Some("cmenc".to_string()),
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+        fs::write(dir.path().join("old.txt"), b"").unwrap();
+        let docs = fs.make_dir(ROOT_INO, OsStr::new("docs")).unwrap().ino;
+        let (photo, _) = fs
+            .create_file(docs, OsStr::new("photo.jpg"), libc::O_WRONLY)
+            .unwrap();
+        fs.write_data(photo.ino, 0, b"not really a jpeg").unwrap();
+        fs.rename_entry(
+            docs,
+            OsStr::new("photo.jpg"),
+            ROOT_INO,
+            OsStr::new("p.jpg"),
+            0,
+        )
+        .unwrap();
+
+        let backing: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert!(backing.contains(&"p.jpg.cmenc".to_string()), "{backing:?}");
+        assert!(fs::read_dir(dir.path().join("docs"))
+            .unwrap()
+            .next()
+            .is_none());
+
+        let mut names: Vec<String> = fs
+            .list_dir(ROOT_INO)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.2)
+            .collect();
+        names.sort();
+        assert_eq!(names, [".", "..", "docs", "old.txt", "p.jpg"]);
+        let found = fs.lookup_entry(ROOT_INO, OsStr::new("p.jpg")).unwrap();
+        assert_eq!(found.ino, photo.ino);
+        assert_eq!(
+            fs.read_data(found.ino, 0, 64).unwrap(),
+            b"not really a jpeg"
+        );
+        // Files from before the extension was turned on are still found.
+        assert!(fs.lookup_entry(ROOT_INO, OsStr::new("old.txt")).is_ok());
+        assert_eq!(
+            fs.lookup_entry(ROOT_INO, OsStr::new("p.jpg.cmenc")),
+            Err(ENOENT)
+        );
+    }
+
+    #[test]
+    fn panic_mode_refuses_writes_and_logs_every_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let logs = tempfile::tempdir().unwrap();
+        let log_path = logs.path().join("access.log");
+        let writer = CipherFS::new(dir.path().into(), [0x42u8; 32]);
+        let ino = new_file(&writer, &dir, "secret.txt");
+        writer.write_data(ino, 0, b"evidence").unwrap();
+
+        let config = Config {
+            read_only: true,
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config)
+            .with_access_log(Arc::new(AccessLog::open(&log_path).unwrap()));
+        let ino = fs
+            .lookup_entry(ROOT_INO, OsStr::new("secret.txt"))
+            .unwrap()
+            .ino;
+        assert_eq!(fs.open_file(ino, libc::O_RDWR), Err(EROFS));
+        assert_eq!(fs.write_data(ino, 0, b"wiped"), Err(EROFS));
+        assert_eq!(
+            fs.create_file(ROOT_INO, OsStr::new("drop"), libc::O_WRONLY)
+                .err(),
+            Some(EROFS)
+        );
+        for uid in [1000, 1001, 1000] {
+            assert_eq!(fs.read_as(uid, ino, 0, 64).unwrap(), b"evidence");
+        }
+
+        let log = fs::read_to_string(&log_path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 3, "{log}");
+        for (line, uid) in lines.iter().zip([1000, 1001, 1000]) {
+            assert!(
+                line.ends_with(&format!(" uid={uid} read \"secret.txt\"")),
+                "{line}"
+            );
+        }
+    }
+
+    #[test]
+    fn shadow_mode_logs_writes_without_applying_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let logs = tempfile::tempdir().unwrap();
+        let log_path = logs.path().join("access.log");
+        let writer = CipherFS::new(dir.path().into(), [0x42u8; 32]);
+        let ino = new_file(&writer, &dir, "ledger");
+        writer.write_data(ino, 0, b"balance 100").unwrap();
+        let before = fs::read(dir.path().join("ledger")).unwrap();
+
+        let config = Config {
+            read_only: true,
+            shadow: true,
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config)
+            .with_access_log(Arc::new(AccessLog::open(&log_path).unwrap()));
+        let ino = fs.lookup_entry(ROOT_INO, OsStr::new("ledger")).unwrap().ino;
+        assert_eq!(fs.open_file(ino, libc::O_RDWR), Ok(()));
+        assert_eq!(fs.write_as(1000, ino, 8, b"999", 0), Ok(3));
+
+        assert_eq!(fs::read(dir.path().join("ledger")).unwrap(), before);
+        assert_eq!(fs.read_data(ino, 0, 64).unwrap(), b"balance 100");
+        let log = fs::read_to_string(&log_path).unwrap();
+        assert!(
+            log.trim_end()
+                .ends_with(" uid=1000 shadow write offset=8 len=3 \"ledger\""),
+            "{log}"
+        );
+    }
+
+    #[test]
+    fn random_block_access_under_both_layouts() {
+        for layout in [Layout::Packed, Layout::Separate] {
+            let dir = tempfile::tempdir().unwrap();
+            let config = Config {
+                block_size: 64,
+                block_layout: layout,
+                ..Config::default()
+            };
+            let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+            let ino = new_file(&fs, &dir, "f");
+            let mut expected = vec![0u8; 1000];
+            fs.write_data(ino, 0, &expected).unwrap();
+
+            let mut seed = 7u64;
+            for _ in 0..50 {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                let offset = (seed >> 33) as usize % 1000;
+                let len = ((seed >> 13) as usize % 150).clamp(1, 1000 - offset);
+                let byte = seed as u8;
+                fs.write_data(ino, offset as i64, &vec![byte; len]).unwrap();
+                expected[offset..offset + len].fill(byte);
+                let at = (seed >> 40) as usize % 1000;
+                assert_eq!(
+                    fs.read_data(ino, at as i64, 100).unwrap(),
+                    expected[at..(at + 100).min(1000)],
+                    "{layout:?}"
+                );
+            }
+            // Writing past the end rewrites the whole file.
+            fs.write_data(ino, 1100, b"tail").unwrap();
+            expected.resize(1100, 0);
+            expected.extend_from_slice(b"tail");
+
+            let fresh = CipherFS::new(dir.path().into(), [0x42u8; 32]);
+            let found = fresh.lookup_entry(ROOT_INO, OsStr::new("f")).unwrap();
+            assert_eq!(found.size, expected.len() as u64);
+            assert_eq!(fresh.read_data(found.ino, 0, 2000).unwrap(), expected);
+            let blocks = layout::block_dir(&dir.path().join("f"));
+            if layout == Layout::Separate {
+                let header_only = fs::metadata(dir.path().join("f")).unwrap().len();
+                assert_eq!(header_only, format::HEADER_SIZE as u64);
+                assert_eq!(
+                    fs::read_dir(&blocks).unwrap().count(),
+                    1104usize.div_ceil(64)
+                );
+            } else {
+                assert!(!blocks.exists());
+            }
+            let names: Vec<String> = fresh
+                .list_dir(ROOT_INO)
+                .unwrap()
+                .into_iter()
+                .map(|e| e.2)
+                .collect();
+            assert_eq!(names.len(), 3, "{names:?}");
+        }
+    }
+
+    #[test]
+    fn integrity_only_files_store_plaintext_but_reject_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            integrity_only: true,
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+        let ino = new_file(&fs, &dir, "f");
+        fs.write_data(ino, 0, b"public but signed").unwrap();
+        fs.write_data(ino, 7, b"BUT").unwrap();
+        assert_eq!(fs.read_data(ino, 0, 64).unwrap(), b"public BUT signed");
+
+        let path = dir.path().join("f");
+        let mut raw = fs::read(&path).unwrap();
+        let at = raw
+            .windows(6)
+            .position(|w| w == b"signed")
+            .expect("plaintext is stored as is");
+        raw[at] ^= 1;
+        fs::write(&path, raw).unwrap();
+        fs.cache.invalidate(ino);
+        assert_eq!(fs.read_data(ino, 0, 64), Err(EIO));
+    }
+
+    #[test]
+    fn deduplicated_files_share_blocks_and_survive_deletion() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            block_size: 64,
+            block_layout: Layout::Dedup,
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+        let content: Vec<u8> = (0..1024).map(|i| (i / 64) as u8).collect();
+        for name in ["a", "b"] {
+            let ino = new_file(&fs, &dir, name);
+            fs.write_data(ino, 0, &content).unwrap();
+        }
+        let store = dir.path().join(dedup::STORE_DIR);
+        let stored = || fs::read_dir(&store).unwrap().count();
+        assert_eq!(stored(), 16);
+        let inode = |name: &str| {
+            fs::metadata(layout::block_file(&dir.path().join(name), 3))
+                .unwrap()
+                .ino()
+        };
+        assert_eq!(inode("a"), inode("b"));
+
+        fs.remove_entry(ROOT_INO, OsStr::new("a"), false).unwrap();
+        assert_eq!(stored(), 16);
+        let fresh = CipherFS::new(dir.path().into(), [0x42u8; 32]);
+        let b = fresh.lookup_entry(ROOT_INO, OsStr::new("b")).unwrap();
+        assert_eq!(fresh.read_data(b.ino, 0, 2048).unwrap(), content);
+        let names: Vec<String> = fresh
+            .list_dir(ROOT_INO)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.2)
+            .collect();
+        assert_eq!(names.len(), 3, "{names:?}");
+
+        fresh
+            .remove_entry(ROOT_INO, OsStr::new("b"), false)
+            .unwrap();
+        assert_eq!(stored(), 0);
+    }
+
+    #[test]
+    fn expired_deduplicated_file_releases_its_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            block_size: 64,
+            block_layout: Layout::Dedup,
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+        let content: Vec<u8> = (0..256).map(|i| (i / 64) as u8).collect();
+        let ino = new_file(&fs, &dir, "old");
+        fs.write_data(ino, 0, &content).unwrap();
+        let store = dir.path().join(dedup::STORE_DIR);
+        assert_eq!(fs::read_dir(&store).unwrap().count(), 4);
+
+        FileMeta {
+            expires_at: Some(1),
+            ..FileMeta::default()
+        }
+        .store(&fs.key, &fs.source, &dir.path().join("old"))
+        .unwrap();
+        let removed = fs.collect_expired().unwrap();
+        assert_eq!(removed, 1);
+        assert_eq!(fs::read_dir(&store).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn write_to_undecryptable_file_fails_without_touching_it() {
+        let (dir, fs) = test_fs();
+        let ino = new_file(&fs, &dir, "f");
+        fs.write_data(ino, 0, b"contents that must survive")
+            .unwrap();
+        let path = dir.path().join("f");
+        let mut raw = fs::read(&path).unwrap();
+        *raw.last_mut().unwrap() ^= 1;
+        fs::write(&path, &raw).unwrap();
+        fs.cache.invalidate(ino);
+
+        assert_eq!(fs.write_data(ino, 0, b"X"), Err(EIO));
+        assert_eq!(fs::read(&path).unwrap(), raw);
+    }
+
+    #[test]
+    fn owners_are_shown_through_the_id_map() {
+        let dir = tempfile::tempdir().unwrap();
+        // SAFETY: getuid and getgid cannot fail.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let config = Config {
+            uid_map: IdMap::new(vec![format!("1000:{uid}:1").parse().unwrap()]).unwrap(),
+            gid_map: IdMap::new(vec![format!("2000:{gid}:1").parse().unwrap()]).unwrap(),
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+        fs::write(dir.path().join("host"), b"").unwrap();
+
+        let found = fs.lookup_entry(ROOT_INO, OsStr::new("host")).unwrap();
+        assert_eq!((found.uid, found.gid), (1000, 2000));
+        let attr = fs.getattr_for(found.ino).unwrap();
+        assert_eq!((attr.uid, attr.gid), (1000, 2000));
+        let (created, _) = fs
+            .create_file(ROOT_INO, OsStr::new("new"), libc::O_WRONLY)
+            .unwrap();
+        assert_eq!((created.uid, created.gid), (1000, 2000));
+    }
+
+    #[test]
+    fn combined_setattr_applies_size_mode_and_mtime_together() {
+        let (dir, fs) = test_fs();
+        let ino = new_file(&fs, &dir, "log");
+        fs.write_data(ino, 0, &[7u8; 10_000]).unwrap();
+        fs.read_data(ino, 0, 4096).unwrap(); // Cache a block the resize drops.
+
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let changes = AttrChanges {
+            size: Some(5000),
+            mode: Some(0o100600),
+            mtime: Some(TimeOrNow::SpecificTime(mtime)),
+            ..AttrChanges::default()
+        };
+        let attr = fs.set_attr(ino, &changes).unwrap();
+        assert_eq!(
+            (attr.size, attr.perm & 0o7777, attr.mtime),
+            (5000, 0o600, mtime)
+        );
+
+        // The same on disk, and through a fresh mount.
+        let path = dir.path().join("log");
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!(meta.mode() & 0o7777, 0o600);
+        assert_eq!(meta.mtime(), 1_000_000_000);
+        let other = CipherFS::new(dir.path().into(), [0x42u8; 32]);
+        let attr = other.getattr_for(other.register(path).unwrap()).unwrap();
+        assert_eq!((attr.size, attr.mtime), (5000, mtime));
+        assert_eq!(fs.read_data(ino, 0, 8192).unwrap(), [7u8; 5000]);
+
+        // Growing zero-fills.
+        let grow = AttrChanges {
+            size: Some(6000),
+            ..AttrChanges::default()
+        };
+        assert_eq!(fs.set_attr(ino, &grow).unwrap().size, 6000);
+        assert_eq!(
+            fs.read_data(ino, 4990, 20).unwrap(),
+            [[7u8; 10], [0u8; 10]].concat()
+        );
+    }
+
+    #[test]
+    fn create_never_truncates_a_file_that_appeared_out_of_band() {
+        let (dir, fs) = test_fs();
+        let ino = new_file(&fs, &dir, "report");
+        fs.write_data(ino, 0, b"keep me").unwrap();
+        // Written by another mount between the kernel's lookup and create.
+        let other = CipherFS::new(dir.path().into(), [0x42u8; 32]);
+        let racer = new_file(&other, &dir, "racer");
+        other.write_data(racer, 0, b"keep me").unwrap();
+
+        assert_eq!(
+            fs.create_file(ROOT_INO, OsStr::new("racer"), libc::O_WRONLY | libc::O_EXCL)
+                .err(),
+            Some(EEXIST)
+        );
+        let (attr, _) = fs
+            .create_file(ROOT_INO, OsStr::new("racer"), libc::O_WRONLY)
+            .unwrap();
+        assert_eq!(fs.read_data(attr.ino, 0, 64).unwrap(), b"keep me");
+
+        let (attr, _) = fs
+            .create_file(
+                ROOT_INO,
+                OsStr::new("report"),
+                libc::O_WRONLY | libc::O_TRUNC,
+            )
+            .unwrap();
+        assert_eq!(attr.ino, ino);
+        assert_eq!(fs.read_data(ino, 0, 64).unwrap(), b"");
+    }
+
+    #[test]
+    fn ephemeral_vault_lives_in_memory_and_is_removed() {
+        use crate::scratch::{self, Scratch};
+
+        let root = Path::new(scratch::DEFAULT_ROOT);
+        if !scratch::in_memory(root).unwrap_or(false) {
+            return; // No memory filesystem to test on.
+        }
+        let scratch = Scratch::create(root).unwrap();
+        let path = scratch.path().to_path_buf();
+        let config = Config {
+            mlock: true,
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(path.clone(), rand::random(), config);
+        let (attr, _) = fs
+            .create_file(ROOT_INO, OsStr::new("notes"), libc::O_WRONLY)
+            .unwrap();
+        fs.write_data(attr.ino, 0, b"transient secret").unwrap();
+        assert_eq!(fs.read_data(attr.ino, 0, 64).unwrap(), b"transient secret");
+
+        // All that was stored is ciphertext, on the memory filesystem.
+        for entry in fs::read_dir(&path).unwrap() {
+            let stored = entry.unwrap().path();
+            assert!(scratch::in_memory(&stored).unwrap());
+            let bytes = fs::read(&stored).unwrap();
+            assert!(!bytes.windows(9).any(|w| w == b"transient"));
+        }
+        drop(fs);
+        drop(scratch);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn open_handles_are_listed_until_released() {
+        let (dir, fs) = test_fs();
+        let handles = fs.open_handles();
+        let a = new_file(&fs, &dir, "a");
+        let b = new_file(&fs, &dir, "b");
+        fs.write_data(a, 0, b"secret contents").unwrap();
+        fs.read_data(a, 0, 64).unwrap();
+
+        let fa = fs.open_handle(a, libc::O_RDONLY).unwrap();
+        let fb = fs.open_handle(b, libc::O_RDWR).unwrap();
+        let fa2 = fs.open_handle(a, libc::O_WRONLY).unwrap();
+        let list = handles.list();
+        let listed: Vec<(u64, u64, bool)> = list.iter().map(|h| (h.fh, h.ino, h.writes)).collect();
+        assert_eq!(listed, [(fa, a, false), (fb, b, true), (fa2, a, true)]);
+        assert_eq!((list[0].cached_blocks, list[0].cached_bytes), (1, 15));
+        assert_eq!(list[1].cached_blocks, 0);
+
+        let report = handles.report();
+        assert!(report.starts_with("3 open file handle(s)"), "{report}");
+        assert!(!report.contains("secret") && !report.contains('"'));
+
+        fs.release_file(fa).unwrap();
+        fs.release_file(fb).unwrap();
+        assert_eq!(handles.list().len(), 1);
+    }
+
+    #[test]
+    fn memory_profile_reports_whole_file_reads_over_the_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let memory = Arc::new(MemoryProfile::new(64 * 1024));
+        let fs = CipherFS::new(dir.path().to_path_buf(), [0x42u8; 32])
+            .with_memory_profile(Arc::clone(&memory));
+        let small = new_file(&fs, &dir, "small");
+        let large = new_file(&fs, &dir, "large");
+        std::fs::write(
+            dir.path().join("small"),
+            crypto::encrypt(&fs.key, b"tiny").unwrap(),
+        )
+        .unwrap();
+        let raw = crypto::encrypt(&fs.key, &vec![7u8; 1 << 20]).unwrap();
+        std::fs::write(dir.path().join("large"), &raw).unwrap();
+
+        fs.read_data(small, 0, 4).unwrap();
+        assert_eq!(memory.spikes(), 0);
+        assert_eq!(fs.read_data(large, 0, 4).unwrap(), [7; 4]);
+        assert_eq!(memory.spikes(), 1);
+        assert_eq!(memory.peak(), raw.len() as u64);
+        assert_eq!(memory.current(), 0);
+    }
+
+    #[test]
+    fn parallel_whole_file_reads_stay_within_the_memory_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw_len = crypto::encrypt(&[0u8; 32], &vec![0u8; 1 << 20])
+            .unwrap()
+            .len();
+        // Room for two files' buffers at a time, not three.
+        let budget = Arc::new(MemoryBudget::new(5 * raw_len as u64 / 2));
+        let memory = Arc::new(MemoryProfile::new(u64::MAX));
+        let fs = CipherFS::new(dir.path().to_path_buf(), [0x42u8; 32])
+            .with_memory_budget(Arc::clone(&budget))
+            .with_memory_profile(Arc::clone(&memory));
+        let inos: Vec<u64> = (0..8u8)
+            .map(|i| {
+                let name = format!("f{i}");
+                let ino = new_file(&fs, &dir, &name);
+                let raw = crypto::encrypt(&fs.key, &vec![i; 1 << 20]).unwrap();
+                std::fs::write(dir.path().join(&name), raw).unwrap();
+                ino
+            })
+            .collect();
+
+        std::thread::scope(|scope| {
+            for (i, &ino) in inos.iter().enumerate() {
+                for _ in 0..4 {
+                    let fs = &fs;
+                    scope.spawn(move || {
+                        assert_eq!(fs.read_data(ino, 0, 4).unwrap(), [i as u8; 4]);
+                    });
+                }
+            }
+        });
+        assert!(memory.peak() <= 5 * raw_len as u64 / 2, "{}", memory.peak());
+        assert_eq!(budget.in_use(), 0);
+    }
+
+    #[test]
+    fn open_handle_survives_rename_and_unlink() {
+        let (dir, fs) = test_fs();
+        let ino = new_file(&fs, &dir, "a");
+        fs.write_data(ino, 0, b"before").unwrap();
+        let fh = fs.open_handle(ino, libc::O_RDWR).unwrap();
+
+        fs.rename_entry(ROOT_INO, OsStr::new("a"), ROOT_INO, OsStr::new("b"), 0)
+            .unwrap();
+        assert_eq!(fs.read_data(ino, 0, 64).unwrap(), b"before");
+
+        fs.remove_entry(ROOT_INO, OsStr::new("b"), false).unwrap();
+        assert!(!dir.path().join("b").exists());
+        let names: Vec<String> = fs
+            .list_dir(ROOT_INO)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.2)
+            .collect();
+        assert_eq!(names, [".", ".."]);
+        fs.write_data(ino, 6, b" and after").unwrap();
+        fs.cache.invalidate(ino);
+        assert_eq!(fs.read_data(ino, 0, 64).unwrap(), b"before and after");
+
+        fs.release_file(fh).unwrap();
+        assert_eq!(fs.read_data(ino, 0, 64), Err(ENOENT));
+        let orphan = dir.path().join(format!("{}{}", vault::ORPHAN_PREFIX, ino));
+        assert!(!orphan.exists() && !meta::sidecar_path(&orphan).exists());
+    }
+
+    #[test]
+    fn immutable_files_refuse_changes_until_cleared() {
+        let (dir, fs) = test_fs();
+        let ino = new_file(&fs, &dir, "a");
+        fs.write_data(ino, 0, b"kept").unwrap();
+        fs.set_immutable(ino, true).unwrap();
+        assert_eq!(fs.is_immutable(ino), Ok(true));
+
+        assert_eq!(fs.write_data(ino, 0, b"x"), Err(EPERM));
+        assert_eq!(fs.open_file(ino, libc::O_WRONLY), Err(EPERM));
+        assert_eq!(
+            fs.remove_entry(ROOT_INO, OsStr::new("a"), false),
+            Err(EPERM)
+        );
+        assert_eq!(
+            fs.rename_entry(ROOT_INO, OsStr::new("a"), ROOT_INO, OsStr::new("b"), 0),
+            Err(EPERM)
+        );
+        assert_eq!(fs.read_data(ino, 0, 64).unwrap(), b"kept");
+
+        // A tampered sidecar fails closed instead of reading as cleared.
+        let sidecar = meta::sidecar_path(&dir.path().join("a"));
+        let raw = fs::read(&sidecar).unwrap();
+        let mut tampered = raw.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        fs::write(&sidecar, &tampered).unwrap();
+        assert_eq!(fs.write_data(ino, 0, b"x"), Err(EIO));
+        assert_eq!(fs.is_immutable(ino), Err(EIO));
+        fs::write(&sidecar, &raw).unwrap();
+
+        fs.set_immutable(ino, false).unwrap();
+        fs.write_data(ino, 0, b"K").unwrap();
+        fs.remove_entry(ROOT_INO, OsStr::new("a"), false).unwrap();
+    }
+
+    /// Local backend whose writes fail with ENOSPC while `full` is set.
+    #[derive(Default)]
+    struct FullDisk {
+        full: std::sync::atomic::AtomicBool,
+    }
+
+    impl Backend for FullDisk {
+        fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+            LocalBackend.read(path)
+        }
+        fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+            if self.full.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(io::Error::from_raw_os_error(ENOSPC));
+            }
+            LocalBackend.write(path, data)
+        }
+        fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+            if self.full.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(io::Error::from_raw_os_error(ENOSPC));
+            }
+            LocalBackend.write_at(path, offset, data)
+        }
+        fn sync(&self, path: &Path, data_only: bool) -> io::Result<()> {
+            LocalBackend.sync(path, data_only)
+        }
+    }
+
+    #[test]
+    fn failed_writeback_is_reported_at_flush_and_release() {
+        use fuser::consts::FUSE_WRITE_CACHE;
+        use std::sync::atomic::Ordering::SeqCst;
+
+        let dir = tempfile::tempdir().unwrap();
+        let backend = Arc::new(FullDisk::default());
+        let fs = CipherFS::with_backend(
+            dir.path().into(),
+            [0u8; 32],
+            Config::default(),
+            backend.clone(),
+        );
+        let ino = new_file(&fs, &dir, "f");
+        fs.write_data(ino, 0, b"hello").unwrap();
+
+        backend.full.store(true, SeqCst);
+        // A direct write fails in the caller's face and isn't held.
+        assert_eq!(fs.write_request(ino, 0, b"HELLO", 0), Err(ENOSPC));
+        assert_eq!(fs.take_write_error(ino), Ok(()));
+        // A writeback failure is reported by the next flush, once.
+        assert_eq!(
+            fs.write_request(ino, 0, b"HELLO", FUSE_WRITE_CACHE),
+            Err(ENOSPC)
+        );
+        assert_eq!(fs.take_write_error(ino), Err(ENOSPC));
+        assert_eq!(fs.take_write_error(ino), Ok(()));
+        // ... or by fsync, even though the sync itself succeeds.
+        assert!(fs.write_request(ino, 5, b"!", FUSE_WRITE_CACHE).is_err());
+        assert_eq!(fs.fsync_data(ino, true), Err(ENOSPC));
+        assert_eq!(fs.fsync_data(ino, true), Ok(()));
+        backend.full.store(false, SeqCst);
+        assert_eq!(fs.read_data(ino, 0, 64).unwrap(), b"hello");
+    }
+
+    /// Local backend that counts sync calls by kind.
+    #[derive(Default)]
+    struct CountingBackend {
+        data_syncs: std::sync::atomic::AtomicUsize,
+        full_syncs: std::sync::atomic::AtomicUsize,
+    }
+
+    impl Backend for CountingBackend {
+        fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+            LocalBackend.read(path)
+        }
+        fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+            LocalBackend.write(path, data)
+        }
+        fn sync(&self, _path: &Path, data_only: bool) -> io::Result<()> {
+            let counter = if data_only {
+                &self.data_syncs
+            } else {
+                &self.full_syncs
+            };
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn backing_sync_policy_controls_sync_calls() {
+        use std::sync::atomic::Ordering::SeqCst;
+
+        for (policy, data, full) in [
+            (SyncPolicy::None, 0, 0),
+            (SyncPolicy::Data, 3, 0),
+            (SyncPolicy::Full, 0, 3),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let backend = Arc::new(CountingBackend::default());
+            let config = Config {
+                backing_sync: policy,
+                ..Config::default()
+            };
+            let fs = CipherFS::with_backend(dir.path().into(), [0u8; 32], config, backend.clone());
+            let ino = new_file(&fs, &dir, "f");
+            for i in 0..3 {
+                fs.write_data(ino, i, b"x").unwrap();
+            }
+            assert_eq!(backend.data_syncs.load(SeqCst), data, "{policy:?}");
+            assert_eq!(backend.full_syncs.load(SeqCst), full, "{policy:?}");
+        }
+    }
+
+    #[test]
+    fn close_sync_policy_controls_syncs_at_flush() {
+        use std::sync::atomic::Ordering::SeqCst;
+
+        for (policy, expected) in [
+            (CloseSync::Never, 0),
+            (CloseSync::Always, 2),
+            (CloseSync::Dirty, 1),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let backend = Arc::new(CountingBackend::default());
+            let config = Config {
+                backing_sync: SyncPolicy::None,
+                close_sync: policy,
+                ..Config::default()
+            };
+            let fs = CipherFS::with_backend(dir.path().into(), [0u8; 32], config, backend.clone());
+            let ino = new_file(&fs, &dir, "f");
+            let written = fs.open_handle(ino, libc::O_RDWR).unwrap();
+            let untouched = fs.open_handle(ino, libc::O_RDWR).unwrap();
+            fs.write_data(ino, 0, b"x").unwrap();
+            fs.set_dirty(written, true);
+
+            fs.sync_on_close(ino, written).unwrap();
+            fs.sync_on_close(ino, untouched).unwrap();
+            assert_eq!(backend.full_syncs.load(SeqCst), expected, "{policy:?}");
+            assert_eq!(backend.data_syncs.load(SeqCst), 0, "{policy:?}");
+        }
+    }
+
+    #[test]
+    fn advice_preloads_reads_ahead_and_drops_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            block_size: 64,
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0u8; 32], config);
+        let ino = new_file(&fs, &dir, "f");
+        let contents: Vec<u8> = (0..20 * 64).map(|i| i as u8).collect();
+        fs.write_data(ino, 0, &contents).unwrap();
+        fs.cache.invalidate(ino);
+
+        fs.advise(ino, Advice::WillNeed).unwrap();
+        assert_eq!(fs.cache.usage(ino).0, 20);
+        let opened = fs.auth_count.load(Ordering::Relaxed);
+        assert_eq!(fs.read_data(ino, 0, 20 * 64).unwrap(), contents);
+        assert_eq!(fs.auth_count.load(Ordering::Relaxed), opened);
+
+        fs.advise(ino, Advice::DontNeed).unwrap();
+        assert_eq!(fs.cache.usage(ino).0, 0);
+
+        fs.advise(ino, Advice::Sequential).unwrap();
+        fs.read_data(ino, 0, 64).unwrap();
+        assert_eq!(fs.cache.usage(ino).0, 1 + READAHEAD_BLOCKS as usize);
+        fs.advise(ino, Advice::Random).unwrap();
+        fs.cache.invalidate(ino);
+        fs.read_data(ino, 0, 64).unwrap();
+        assert_eq!(fs.cache.usage(ino).0, 1);
+    }
+
+    #[test]
+    fn cache_hits_skip_authentication_unless_always_authenticate() {
+        for (always, expected) in [(false, 0), (true, 3)] {
+            let dir = tempfile::tempdir().unwrap();
+            let config = Config {
+                always_authenticate: always,
+                ..Config::default()
+            };
+            let fs = CipherFS::with_config(dir.path().into(), [0u8; 32], config);
+            let ino = new_file(&fs, &dir, "f");
+            fs.write_data(ino, 0, b"cached contents").unwrap();
+
+            fs.read_data(ino, 0, 64).unwrap();
+            let after_miss = fs.auth_count.load(Ordering::Relaxed);
+            for _ in 0..3 {
+                assert_eq!(fs.read_data(ino, 0, 64).unwrap(), b"cached contents");
             }
-            Err(_) => reply.error(EIO),
+            let on_hits = fs.auth_count.load(Ordering::Relaxed) - after_miss;
+            assert_eq!(on_hits, expected, "always_authenticate = {always}");
         }
     }
 
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let parent_path = match self.path_for(parent) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
+    #[test]
+    fn write_invalidates_cached_blocks() {
+        let (dir, fs) = test_fs();
+        let ino = new_file(&fs, &dir, "f");
+        fs.write_data(ino, 0, b"before").unwrap();
+        assert_eq!(fs.read_data(ino, 0, 64).unwrap(), b"before");
+        fs.write_data(ino, 0, b"AFTER!").unwrap();
+        assert_eq!(fs.read_data(ino, 0, 64).unwrap(), b"AFTER!");
+    }
+
+    #[test]
+    fn page_writeback_reseals_only_touched_blocks() {
+        const PAGE: usize = 4096;
+        let (dir, fs) = test_fs();
+        let ino = new_file(&fs, &dir, "mapped");
+        let original: Vec<u8> = (0..3 * PAGE).map(|i| (i % 251) as u8).collect();
+        fs.write_data(ino, 0, &original).unwrap();
+        let before = fs::read(dir.path().join("mapped")).unwrap();
+
+        // What the kernel sends for a dirtied page after msync(MS_SYNC).
+        let page = vec![0xEE; PAGE];
+        fs.write_data(ino, PAGE as i64, &page).unwrap();
+        fs.fsync_data(ino, true).unwrap();
+
+        let after = fs::read(dir.path().join("mapped")).unwrap();
+        let header = format::Header::decode(&after).unwrap();
+        assert_eq!(after.len(), before.len());
+        for (index, changed) in [(0, false), (1, true), (2, false)] {
+            let (start, len) = header.block_span(index);
+            let span = start as usize..start as usize + len;
+            assert_eq!(
+                before[span.clone()] != after[span],
+                changed,
+                "block {index}"
+            );
+        }
+
+        let mut expected = original;
+        expected[PAGE..2 * PAGE].copy_from_slice(&page);
+        assert_eq!(fs.read_data(ino, 0, 3 * PAGE as u32).unwrap(), expected);
+
+        // A sub-page write straddling two blocks.
+        fs.write_data(ino, PAGE as i64 - 2, b"abcd").unwrap();
+        expected[PAGE - 2..PAGE + 2].copy_from_slice(b"abcd");
+        let reopened = CipherFS::new(dir.path().into(), [0x42u8; 32]);
+        let ino = reopened.register(dir.path().join("mapped")).unwrap();
+        assert_eq!(
+            reopened.read_data(ino, 0, 3 * PAGE as u32).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn writer_uid_is_recorded_and_authenticated() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            default_ttl: Some(Duration::from_secs(3600)),
+            record_writer: true,
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+        let ino = new_file(&fs, &dir, "f");
+        assert_eq!(fs.last_writer(ino), Ok(None));
+
+        fs.record_writer(ino, 1000).unwrap();
+        assert_eq!(fs.last_writer(ino), Ok(Some(1000)));
+        // A sidecar created for the writer still carries the default expiry.
+        assert!(fs.remaining_ttl(ino).unwrap().is_some_and(|s| s > 3500));
+
+        let sidecar = meta::sidecar_path(&dir.path().join("f"));
+        let mut raw = fs::read(&sidecar).unwrap();
+        *raw.last_mut().unwrap() ^= 1;
+        fs::write(&sidecar, &raw).unwrap();
+        assert_eq!(fs.last_writer(ino), Err(EIO));
+        assert_eq!(fs.record_writer(ino, 0), Err(EIO));
+        assert_eq!(fs::read(&sidecar).unwrap(), raw);
+    }
+
+    #[test]
+    fn listing_survives_directory_removed_mid_readdir() {
+        let (dir, fs) = test_fs();
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        for name in ["a", "b", "c"] {
+            fs::write(sub.join(name), b"").unwrap();
+        }
+        let ino = fs.register(sub.clone()).unwrap();
+
+        let fh = fs.open_dir(ino).unwrap();
+        let first = fs.dir_entries(ino, fh).unwrap();
+        fs::remove_dir_all(&sub).unwrap();
+
+        // The rest of the listing comes from the opendir snapshot...
+        assert_eq!(fs.dir_entries(ino, fh).unwrap(), first);
+        assert_eq!(first.len(), 5);
+        // ...while a fresh listing reports the directory as gone.
+        assert_eq!(fs.list_dir(ino), Err(ENOENT));
+        fs.release_dir(fh);
+        assert_eq!(fs.dir_entries(ino, fh), Err(ENOENT));
+        assert_eq!(fs.open_dir(ino), Err(ENOENT));
+    }
+
+    #[test]
+    fn names_over_the_limit_fail_before_touching_the_backing_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_name_length: Some(16),
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+        let long = OsStr::new("seventeen-bytes!!");
+        assert_eq!(
+            fs.create_file(ROOT_INO, long, libc::O_RDWR).unwrap_err(),
+            ENAMETOOLONG
+        );
+        assert_eq!(fs.make_dir(ROOT_INO, long).unwrap_err(), ENAMETOOLONG);
+        new_file(&fs, &dir, "short");
+        assert_eq!(
+            fs.rename_entry(ROOT_INO, OsStr::new("short"), ROOT_INO, long, 0),
+            Err(ENAMETOOLONG)
+        );
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let (_, fh) = fs
+            .create_file(ROOT_INO, OsStr::new("sixteen-bytes!!!"), libc::O_RDWR)
+            .unwrap();
+        fs.release_file(fh).unwrap();
+    }
+
+    #[test]
+    fn entries_replaced_outside_the_mount_report_their_new_type() {
+        let (dir, fs) = test_fs();
+        let ino = new_file(&fs, &dir, "was-a-file");
+        fs.write_data(ino, 0, b"hello").unwrap();
+        let path = dir.path().join("was-a-file");
+        fs::remove_file(&path).unwrap();
+        fs::create_dir(&path).unwrap();
+        assert_eq!(fs.read_data(ino, 0, 16), Err(libc::EISDIR));
+        assert_eq!(fs.write_data(ino, 0, b"x"), Err(libc::EISDIR));
+        assert_eq!(fs.open_file(ino, libc::O_RDONLY), Err(libc::EISDIR));
+
+        // And a directory that became a file can't have entries created in it.
+        fs::remove_dir(&path).unwrap();
+        fs::write(&path, b"").unwrap();
+        assert_eq!(
+            fs.create_file(ino, OsStr::new("child"), libc::O_RDWR)
+                .unwrap_err(),
+            ENOTDIR
+        );
+        assert_eq!(fs.list_dir(ino).unwrap_err(), ENOTDIR);
+    }
+
+    #[test]
+    fn secure_delete_overwrites_the_unlinked_ciphertext() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            secure_delete: SecureDelete::Overwrite,
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+        let ino = new_file(&fs, &dir, "secret");
+        fs.write_data(ino, 0, &[7u8; 10_000]).unwrap();
+        fs.set_mime(ino, "text/plain").unwrap();
+        let path = dir.path().join("secret");
+        let len = fs::metadata(&path).unwrap().len() as usize;
+        // Whatever still holds the old data, like the free space on disk.
+        let left_behind = fs::File::open(&path).unwrap();
+        let sidecar = fs::File::open(meta::sidecar_path(&path)).unwrap();
+
+        fs.remove_entry(ROOT_INO, OsStr::new("secret"), false)
+            .unwrap();
+        assert!(!path.exists());
+        let read = |mut file: &fs::File| {
+            let mut data = vec![];
+            io::Read::read_to_end(&mut file, &mut data).unwrap();
+            data
+        };
+        assert_eq!(read(&left_behind), vec![0u8; len]);
+        assert!(read(&sidecar).iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn secure_delete_overwrites_dedup_blocks_only_this_file_holds() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            secure_delete: SecureDelete::Overwrite,
+            block_layout: Layout::Dedup,
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+        let bs = fs.config.block_size as usize;
+        let ino = new_file(&fs, &dir, "secret");
+        let contents = [vec![7u8; bs], vec![9u8; bs]].concat();
+        fs.write_data(ino, 0, &contents).unwrap();
+        // Block 1 is shared with another file.
+        let other = new_file(&fs, &dir, "other");
+        fs.write_data(other, 0, &vec![9u8; bs]).unwrap();
+        let path = dir.path().join("secret");
+        let unique = fs::read(layout::block_file(&path, 0)).unwrap();
+        let shared = fs::read(layout::block_file(&path, 1)).unwrap();
+        let left_behind = [0, 1].map(|i| fs::File::open(layout::block_file(&path, i)).unwrap());
+
+        fs.remove_entry(ROOT_INO, OsStr::new("secret"), false)
+            .unwrap();
+        let read = |mut file: &fs::File| {
+            let mut data = vec![];
+            io::Read::read_to_end(&mut file, &mut data).unwrap();
+            data
+        };
+        assert_eq!(read(&left_behind[0]), vec![0u8; unique.len()]);
+        assert_eq!(read(&left_behind[1]), shared);
+        assert_eq!(fs.read_data(other, 0, bs as u32).unwrap(), vec![9u8; bs]);
+    }
+
+    #[test]
+    fn crypto_erase_leaves_the_deleted_ciphertext_undecryptable() {
+        let dir = tempfile::tempdir().unwrap();
+        // Written before the mode was on, so without a key of its own.
+        let before = CipherFS::new(dir.path().into(), [0x42u8; 32]);
+        let ino = new_file(&before, &dir, "older");
+        before.write_data(ino, 0, &[5u8; 1000]).unwrap();
+        drop(before);
+        let config = Config {
+            secure_delete: SecureDelete::CryptoErase,
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+        let ino = new_file(&fs, &dir, "secret");
+        let mut contents = vec![7u8; 10_000];
+        fs.write_data(ino, 0, &contents[..6000]).unwrap();
+        fs.write_data(ino, 6000, &contents[6000..]).unwrap();
+        fs.write_data(ino, 100, &[8u8; 50]).unwrap();
+        contents[100..150].fill(8);
+        assert_eq!(fs.read_data(ino, 0, 20_000).unwrap(), contents);
+
+        let path = dir.path().join("secret");
+        let raw = fs::read(&path).unwrap();
+        assert!(format::Header::decode(&raw).unwrap().has_file_key());
+        assert_eq!(format::decrypt_file(&fs.key, &raw).unwrap(), contents);
+        let left_behind = fs::File::open(&path).unwrap();
+        let older = fs::File::open(dir.path().join("older")).unwrap();
+        let older_len = older.metadata().unwrap().len() as usize;
+
+        fs.remove_entry(ROOT_INO, OsStr::new("secret"), false)
+            .unwrap();
+        fs.remove_entry(ROOT_INO, OsStr::new("older"), false)
+            .unwrap();
+        let read = |mut file: &fs::File| {
+            let mut data = vec![];
+            io::Read::read_to_end(&mut file, &mut data).unwrap();
+            data
+        };
+        // Every block is still there, but nothing opens them any more.
+        let left = read(&left_behind);
+        let key_end = format::HEADER_SIZE + format::WRAPPED_KEY_LEN;
+        assert_eq!(left[key_end..], raw[key_end..]);
+        assert!(left[format::HEADER_SIZE..key_end].iter().all(|&b| b == 0));
+        assert!(format::decrypt_file(&fs.key, &left).is_err());
+        assert_eq!(read(&older), vec![0u8; older_len]);
+    }
+
+    #[test]
+    fn secure_delete_covers_expired_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            secure_delete: SecureDelete::Overwrite,
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+        let mut left_behind = vec![];
+        for name in ["looked_up", "swept"] {
+            let ino = new_file(&fs, &dir, name);
+            fs.write_data(ino, 0, &[7u8; 10_000]).unwrap();
+            let path = dir.path().join(name);
+            FileMeta {
+                expires_at: Some(1),
+                ..FileMeta::default()
+            }
+            .store(&fs.key, &fs.source, &path)
+            .unwrap();
+            let len = fs::metadata(&path).unwrap().len() as usize;
+            left_behind.push((fs::File::open(&path).unwrap(), len));
+        }
+
+        assert_eq!(
+            fs.lookup_entry(ROOT_INO, OsStr::new("looked_up"))
+                .map(|e| e.ino),
+            Err(ENOENT)
+        );
+        let removed = fs.collect_expired().unwrap();
+        assert_eq!(removed, 1);
+        for (mut file, len) in left_behind {
+            let mut data = vec![];
+            io::Read::read_to_end(&mut file, &mut data).unwrap();
+            assert_eq!(data, vec![0u8; len]);
+        }
+    }
+
+    #[test]
+    fn subpath_mount_only_reaches_its_subtree() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("shared")).unwrap();
+        fs::write(dir.path().join("private"), b"").unwrap();
+        let config = Config {
+            subpath: Some("shared".into()),
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+        let (attr, fh) = fs
+            .create_file(ROOT_INO, OsStr::new("notes"), libc::O_RDWR)
+            .unwrap();
+        fs.write_data(attr.ino, 0, b"for the team").unwrap();
+        fs.release_file(fh).unwrap();
+        assert!(dir.path().join("shared/notes").exists());
+
+        let names: Vec<String> = fs
+            .list_dir(ROOT_INO)
+            .unwrap()
+            .into_iter()
+            .map(|(_, _, name)| name)
+            .collect();
+        assert_eq!(names, [".", "..", "notes"]);
+        assert_eq!(
+            fs.lookup_entry(ROOT_INO, OsStr::new("private"))
+                .unwrap_err(),
+            ENOENT
+        );
+        assert_eq!(
+            fs.lookup_entry(ROOT_INO, OsStr::new("..")).unwrap_err(),
+            EINVAL
+        );
+        assert_eq!(
+            fs.create_file(ROOT_INO, OsStr::new(".."), libc::O_RDWR)
+                .unwrap_err(),
+            EINVAL
+        );
+
+        // Sealed under its vault path, the file reads the same in a full mount.
+        let full = CipherFS::new(dir.path().into(), [0x42u8; 32]);
+        let shared = full.lookup_entry(ROOT_INO, OsStr::new("shared")).unwrap();
+        let notes = full.lookup_entry(shared.ino, OsStr::new("notes")).unwrap();
+        assert_eq!(full.read_data(notes.ino, 0, 64).unwrap(), b"for the team");
+
+        for bad in ["../x", "/etc", "shared/..", "private", ".cmstore"] {
+            assert!(vault::subtree(dir.path(), Path::new(bad)).is_err(), "{bad}");
+        }
+        assert!(vault::subtree(dir.path(), Path::new("shared")).is_ok());
+    }
+
+    #[test]
+    fn forced_mode_replaces_the_permissions_of_new_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            force_mode: Some(0o640),
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+        let mode = |name: &str| fs::metadata(dir.path().join(name)).unwrap().mode() & 0o7777;
+
+        let (attr, fh) = fs
+            .create_file(ROOT_INO, OsStr::new("deposit"), libc::O_RDWR)
+            .unwrap();
+        fs.release_file(fh).unwrap();
+        assert_eq!(attr.perm & 0o7777, 0o640);
+        assert_eq!(mode("deposit"), 0o640);
+
+        // Directories can still be entered by whoever may read them.
+        let inbox = fs.make_dir(ROOT_INO, OsStr::new("inbox")).unwrap();
+        assert_eq!(inbox.perm & 0o7777, 0o750);
+        assert_eq!(mode("inbox"), 0o750);
+    }
+
+    #[test]
+    fn shuffled_listing_is_stable_but_not_creation_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let created: Vec<String> = (0..20).map(|i| format!("file{:02}", i)).collect();
+        let listing = |key: u8| {
+            let config = Config {
+                shuffle_listings: true,
+                ..Config::default()
+            };
+            let fs = CipherFS::with_config(dir.path().into(), [key; 32], config);
+            let listed: Vec<String> = fs
+                .list_dir(ROOT_INO)
+                .unwrap()
+                .into_iter()
+                .map(|e| e.2)
+                .collect();
+            assert_eq!(listed[..2], [".", ".."]);
+            listed[2..].to_vec()
+        };
+        for name in &created {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+
+        let first = listing(1);
+        assert_eq!(listing(1), first);
+        assert_ne!(first, created);
+        let mut sorted = first.clone();
+        sorted.sort();
+        assert_eq!(sorted, created);
+        // The order comes from the key, not from the names alone.
+        assert_ne!(listing(2), first);
+    }
+
+    #[test]
+    fn listing_is_the_opendir_snapshot_under_concurrent_changes() {
+        let (dir, fs) = test_fs();
+        for name in ["a", "b", "c"] {
+            new_file(&fs, &dir, name);
+        }
+        let names = |entries: &[DirEntry]| -> Vec<String> {
+            entries.iter().skip(2).map(|e| e.2.clone()).collect()
+        };
+
+        let fh = fs.open_dir(ROOT_INO).unwrap();
+        let first_page = fs.dir_entries(ROOT_INO, fh).unwrap()[..3].to_vec();
+        let (_, created) = fs
+            .create_file(ROOT_INO, OsStr::new("d"), libc::O_RDWR)
+            .unwrap();
+        fs.release_file(created).unwrap();
+        fs.remove_entry(ROOT_INO, OsStr::new("b"), false).unwrap();
+
+        // Later pages come from the same snapshot: `b` is still there, `d`
+        // not yet, and nothing is served twice or skipped.
+        let rest = fs.dir_entries(ROOT_INO, fh).unwrap();
+        assert_eq!(rest[..3], first_page[..]);
+        let mut listed = names(&rest);
+        listed.sort();
+        assert_eq!(listed, ["a", "b", "c"]);
+        fs.release_dir(fh);
+
+        let fh = fs.open_dir(ROOT_INO).unwrap();
+        let mut listed = names(&fs.dir_entries(ROOT_INO, fh).unwrap());
+        listed.sort();
+        assert_eq!(listed, ["a", "c", "d"]);
+        fs.release_dir(fh);
+        assert!(fs.dir_handles.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn renamed_symlink_and_fifo_keep_working() {
+        let (dir, fs) = test_fs();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let sub = fs.register(dir.path().join("sub")).unwrap();
+        let link = fs
+            .make_symlink(ROOT_INO, OsStr::new("link"), Path::new("../etc/hostname"))
+            .unwrap();
+        let fifo = fs
+            .make_node(ROOT_INO, OsStr::new("pipe"), libc::S_IFIFO | 0o644, 0)
+            .unwrap();
+        assert_eq!(link.kind, FileType::Symlink);
+        assert_eq!(link.size, "../etc/hostname".len() as u64);
+        assert_eq!(fifo.kind, FileType::NamedPipe);
+        // The backing symlink does not reveal the target.
+        let backing = fs::read_link(dir.path().join("link")).unwrap();
+        assert!(!backing.to_string_lossy().contains("hostname"));
+
+        fs.rename_entry(
+            ROOT_INO,
+            OsStr::new("link"),
+            sub,
+            OsStr::new("moved-link"),
+            0,
+        )
+        .unwrap();
+        fs.rename_entry(
+            ROOT_INO,
+            OsStr::new("pipe"),
+            sub,
+            OsStr::new("moved-pipe"),
+            0,
+        )
+        .unwrap();
+
+        assert_eq!(
+            fs.path_for(link.ino).unwrap(),
+            dir.path().join("sub/moved-link")
+        );
+        let target = fs.link_target(&fs.path_for(link.ino).unwrap()).unwrap();
+        assert_eq!(target, b"../etc/hostname");
+        assert_eq!(fs.getattr_for(fifo.ino).unwrap().kind, FileType::NamedPipe);
+        let moved = fs::symlink_metadata(dir.path().join("sub/moved-pipe")).unwrap();
+        assert!(moved.file_type().is_fifo());
+        assert!(fs::symlink_metadata(dir.path().join("link")).is_err());
+        assert_eq!(
+            fs.rename_entry(ROOT_INO, OsStr::new("link"), sub, OsStr::new("x"), 0),
+            Err(ENOENT)
+        );
+    }
+
+    #[test]
+    fn cross_device_fallback_recreates_each_kind() {
+        let (dir, fs) = test_fs();
+        fs.make_symlink(ROOT_INO, OsStr::new("link"), Path::new("target"))
+            .unwrap();
+        fs.make_node(ROOT_INO, OsStr::new("pipe"), libc::S_IFIFO | 0o600, 0)
+            .unwrap();
+        fs::write(dir.path().join("file"), b"data").unwrap();
+
+        for name in ["link", "pipe", "file"] {
+            let (from, to) = (dir.path().join(name), dir.path().join(format!("{name}2")));
+            let meta = fs::symlink_metadata(&from).unwrap();
+            CipherFS::copy_entry(&from, &to, &meta).unwrap();
+            let copied = fs::symlink_metadata(&to).unwrap();
+            assert_eq!(copied.file_type(), meta.file_type(), "{name}");
+        }
+        assert_eq!(
+            fs.link_target(&dir.path().join("link2")).unwrap(),
+            b"target"
+        );
+        assert_eq!(fs::read(dir.path().join("file2")).unwrap(), b"data");
+    }
+
+    #[test]
+    fn rename_reseals_sidecars_under_new_paths() {
+        let (dir, fs) = test_fs();
+        fs::create_dir(dir.path().join("d")).unwrap();
+        let d = fs.register(dir.path().join("d")).unwrap();
+        let inner = new_file(&fs, &dir, "d/inner");
+        let top = new_file(&fs, &dir, "top");
+        fs.set_ttl(inner, 3600).unwrap();
+        fs.set_ttl(top, 3600).unwrap();
+
+        fs.rename_entry(ROOT_INO, OsStr::new("top"), d, OsStr::new("top2"), 0)
+            .unwrap();
+        fs.rename_entry(ROOT_INO, OsStr::new("d"), ROOT_INO, OsStr::new("e"), 0)
+            .unwrap();
+
+        assert_eq!(fs.path_for(inner).unwrap(), dir.path().join("e/inner"));
+        assert_eq!(fs.path_for(top).unwrap(), dir.path().join("e/top2"));
+        assert!(fs.remaining_ttl(inner).unwrap().is_some_and(|s| s > 3500));
+        assert!(fs.remaining_ttl(top).unwrap().is_some_and(|s| s > 3500));
+        assert!(!meta::sidecar_path(&dir.path().join("top")).exists());
+    }
+
+    #[test]
+    fn write_opens_fail_at_open_on_read_only_mount() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("f"), b"").unwrap();
+        let config = Config {
+            read_only: true,
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+        let ino = fs.register(dir.path().join("f")).unwrap();
+
+        assert_eq!(fs.open_file(ino, libc::O_RDONLY), Ok(()));
+        assert_eq!(fs.open_file(ino, libc::O_WRONLY), Err(EROFS));
+        assert_eq!(fs.open_file(ino, libc::O_RDWR), Err(EROFS));
+        assert_eq!(
+            fs.open_file(ino, libc::O_RDONLY | libc::O_TRUNC),
+            Err(EROFS)
+        );
+        assert_eq!(fs.write_data(ino, 0, b"x"), Err(EROFS));
+
+        let (rw_dir, rw) = test_fs();
+        let rw_ino = new_file(&rw, &rw_dir, "g");
+        assert_eq!(rw.open_file(rw_ino, libc::O_RDWR), Ok(()));
+    }
+
+    /// Local backend recording how much is read and written at once.
+    #[derive(Default)]
+    struct RecordingBackend {
+        whole_reads: std::sync::atomic::AtomicUsize,
+        largest_io: std::sync::atomic::AtomicUsize,
+        calls: std::sync::atomic::AtomicUsize,
+    }
+
+    impl RecordingBackend {
+        fn saw(&self, len: usize) {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.largest_io
+                .fetch_max(len, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    impl Backend for RecordingBackend {
+        fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.whole_reads
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            LocalBackend.read(path)
+        }
+        fn read_at(&self, path: &Path, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+            self.saw(len);
+            LocalBackend.read_at(path, offset, len)
+        }
+        fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+            self.saw(data.len());
+            LocalBackend.write(path, data)
+        }
+        fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+            self.saw(data.len());
+            LocalBackend.write_at(path, offset, data)
+        }
+        fn sync(&self, _path: &Path, _data_only: bool) -> io::Result<()> {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn zero_length_reads_and_writes_skip_the_backend() {
+        use std::sync::atomic::Ordering::SeqCst;
+
+        let dir = tempfile::tempdir().unwrap();
+        let backend = Arc::new(RecordingBackend::default());
+        let fs = CipherFS::with_backend(
+            dir.path().into(),
+            [0x42u8; 32],
+            Config::default(),
+            backend.clone(),
+        );
+        let ino = new_file(&fs, &dir, "f");
+        fs.write_data(ino, 0, b"unchanged").unwrap();
+        let before = fs::read(dir.path().join("f")).unwrap();
+        let calls = backend.calls.load(SeqCst);
+
+        assert_eq!(fs.read_data(ino, 3, 0).unwrap(), b"");
+        assert_eq!(fs.write_data(ino, 3, b"").unwrap(), 0);
+        assert_eq!(fs.write_data(ino, 100, b"").unwrap(), 0);
+        assert_eq!(backend.calls.load(SeqCst), calls);
+        // No reseal either: the ciphertext is byte for byte the same.
+        assert_eq!(fs::read(dir.path().join("f")).unwrap(), before);
+        assert_eq!(fs.read_data(ino, 0, 64).unwrap(), b"unchanged");
+    }
+
+    #[test]
+    fn exhausted_inode_numbers_are_refused_not_wrapped() {
+        let (dir, fs) = test_fs();
+        let first = new_file(&fs, &dir, "first");
+        *fs.next_ino.lock().unwrap() = ERRORS_INO - 1;
+        let last = new_file(&fs, &dir, "last");
+        assert_eq!(last, ERRORS_INO - 1);
+
+        fs::write(dir.path().join("more"), b"").unwrap();
+        assert_eq!(fs.register(dir.path().join("more")), Err(libc::ENOSPC));
+        assert_eq!(
+            fs.lookup_entry(ROOT_INO, OsStr::new("more")).unwrap_err(),
+            libc::ENOSPC
+        );
+        // Paths already known keep their numbers, and nothing was handed
+        // the error file's inode or wrapped round to an existing one.
+        assert_eq!(fs.register(dir.path().join("first")), Ok(first));
+        assert_eq!(fs.register(dir.path().join("last")), Ok(last));
+        assert_eq!(*fs.next_ino.lock().unwrap(), ERRORS_INO);
+        assert!(fs.path_for(ERRORS_INO).is_none());
+        assert_eq!(fs.inodes.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn sequential_writes_stream_with_bounded_io() {
+        use std::sync::atomic::Ordering::SeqCst;
+
+        let dir = tempfile::tempdir().unwrap();
+        let backend = Arc::new(RecordingBackend::default());
+        let config = Config {
+            block_size: 4096,
+            ..Config::default()
+        };
+        let fs = CipherFS::with_backend(dir.path().into(), [0x42u8; 32], config, backend.clone());
+        let ino = new_file(&fs, &dir, "stream");
+
+        // 4 MiB in odd-sized writes, far more than any single I/O may carry.
+        let total = 4 << 20;
+        let expected: Vec<u8> = (0..total).map(|i| (i % 253) as u8).collect();
+        for chunk in expected.chunks(3000) {
+            let offset = chunk.as_ptr() as usize - expected.as_ptr() as usize;
+            fs.write_data(ino, offset as i64, chunk).unwrap();
+        }
+
+        assert_eq!(backend.whole_reads.load(SeqCst), 0);
+        let bound = 4096 + 3000 + 2 * format::BLOCK_OVERHEAD;
+        assert!(backend.largest_io.load(SeqCst) <= bound);
+        let raw = fs::read(dir.path().join("stream")).unwrap();
+        assert_eq!(format::decrypt_file(&[0x42u8; 32], &raw).unwrap(), expected);
+        assert_eq!(
+            fs.read_data(ino, 1_000_000, 10).unwrap(),
+            expected[1_000_000..1_000_010]
+        );
+    }
+
+    #[test]
+    fn failed_reads_are_reported_in_the_error_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            block_size: 64,
+            error_file: true,
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+        let ino = new_file(&fs, &dir, "ledger");
+        fs.write_data(ino, 0, &[7u8; 200]).unwrap();
+        assert!(fs.read_data(ERRORS_INO, 0, 4096).unwrap().is_empty());
+
+        let path = dir.path().join("ledger");
+        let mut raw = fs::read(&path).unwrap();
+        raw[format::HEADER_SIZE + 20] ^= 1;
+        fs::write(&path, raw).unwrap();
+        fs.cache.invalidate(ino);
+        assert_eq!(fs.read_data(ino, 0, 10), Err(EIO));
+
+        let report = fs.read_data(ERRORS_INO, 0, 4096).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains("ledger:"), "{report}");
+        assert_eq!(
+            fs.getattr_for(ERRORS_INO).unwrap().size,
+            report.len() as u64
+        );
+        assert_eq!(fs.open_file(ERRORS_INO, libc::O_WRONLY), Err(EACCES));
+        let root = fs.list_dir(ROOT_INO).unwrap();
+        assert!(root
+            .iter()
+            .any(|(ino, _, name)| *ino == ERRORS_INO && name == ERRORS_FILE));
+
+        // Off by default: the inode doesn't exist.
+        let (_dir, plain) = test_fs();
+        assert_eq!(plain.getattr_for(ERRORS_INO), Err(ENOENT));
+    }
+
+    #[test]
+    fn init_passes_queue_limits_to_the_connection() {
+        #[derive(Default)]
+        struct Recorder {
+            max_background: Vec<u16>,
+            congestion_threshold: Vec<u16>,
+        }
+        impl Connection for Recorder {
+            fn set_max_background(&mut self, value: u16) -> Result<u16, u16> {
+                self.max_background.push(value);
+                Ok(12)
+            }
+            fn set_congestion_threshold(&mut self, value: u16) -> Result<u16, u16> {
+                self.congestion_threshold.push(value);
+                // Pretend the kernel caps it below max_background.
+                if value > 200 {
+                    Err(200)
+                } else {
+                    Ok(9)
+                }
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_background: Some(256),
+            congestion_threshold: Some(300),
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+        let mut conn = Recorder::default();
+        fs.tune_connection(&mut conn);
+        assert_eq!(conn.max_background, [256]);
+        assert_eq!(conn.congestion_threshold, [300, 200]);
+
+        let (_dir, defaults) = test_fs();
+        let mut conn = Recorder::default();
+        defaults.tune_connection(&mut conn);
+        assert!(conn.max_background.is_empty() && conn.congestion_threshold.is_empty());
+    }
+
+    #[test]
+    fn hard_linked_file_reads_through_both_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            block_size: 64,
+            default_ttl: Some(Duration::from_secs(3600)),
+            ..Config::default()
+        };
+        let fs = CipherFS::with_config(dir.path().into(), [0x42u8; 32], config);
+        let ino = new_file(&fs, &dir, "original");
+        fs.set_ttl(ino, 600).unwrap();
+        fs.set_mime(ino, "text/plain").unwrap();
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        fs.write_data(ino, 0, &data).unwrap();
+
+        let attr = fs.link_entry(ino, ROOT_INO, OsStr::new("alias")).unwrap();
+        assert_eq!((attr.ino, attr.nlink), (ino, 2));
+        let alias = fs.lookup_entry(ROOT_INO, OsStr::new("alias")).unwrap().ino;
+        assert_eq!(alias, ino);
+        assert_eq!(fs.read_data(alias, 0, 1000).unwrap(), data);
+        assert_eq!(fs.read_data(ino, 0, 1000).unwrap(), data);
+        fs.write_data(alias, 10, b"shared").unwrap();
+        assert_eq!(fs.read_data(ino, 10, 6).unwrap(), b"shared");
+
+        // The new name got its own, authentic copy of the metadata.
+        let key = [0x42u8; 32];
+        let original = FileMeta::load(&key, dir.path(), &dir.path().join("original")).unwrap();
+        let linked = FileMeta::load(&key, dir.path(), &dir.path().join("alias")).unwrap();
+        assert!(linked.is_some() && linked == original);
+        // The expiry is in the contents both names share.
+        assert!(fs.remaining_ttl(alias).unwrap().is_some_and(|s| s <= 600));
+
+        assert_eq!(
+            fs.link_entry(ino, ROOT_INO, OsStr::new("alias"))
+                .unwrap_err(),
+            EEXIST
+        );
+        assert_eq!(
+            fs.link_entry(ROOT_INO, ROOT_INO, OsStr::new("dir"))
+                .unwrap_err(),
+            EPERM
+        );
+    }
+
+    #[test]
+    fn hard_links_share_one_inode() {
+        let (dir, fs) = test_fs();
+        let ino = new_file(&fs, &dir, "a");
+        fs.write_data(ino, 0, b"linked").unwrap();
+        // Linked behind the mount's back, and looked up fresh.
+        fs::hard_link(dir.path().join("a"), dir.path().join("b")).unwrap();
+        let fs = CipherFS::new(dir.path().into(), [0x42u8; 32]);
+        let a = fs.lookup_entry(ROOT_INO, OsStr::new("a")).unwrap();
+        let b = fs.lookup_entry(ROOT_INO, OsStr::new("b")).unwrap();
+        assert_eq!(a.ino, b.ino);
+        assert_eq!(fs.getattr_for(a.ino).unwrap().nlink, 2);
+        let c = fs.link_entry(a.ino, ROOT_INO, OsStr::new("c")).unwrap();
+        assert_eq!(
+            fs.lookup_entry(ROOT_INO, OsStr::new("c")).unwrap().ino,
+            c.ino
+        );
+        assert_eq!(c.ino, a.ino);
+
+        // Unlinking the name requests go through leaves the others working,
+        // and an open handle on the inode isn't orphaned by it.
+        let fh = fs.open_handle(a.ino, libc::O_RDWR).unwrap();
+        fs.remove_entry(ROOT_INO, OsStr::new("a"), false).unwrap();
+        assert_eq!(fs.getattr_for(a.ino).unwrap().nlink, 2);
+        fs.write_data(a.ino, 0, b"L").unwrap();
+        assert_eq!(fs.read_data(a.ino, 0, 64).unwrap(), b"Linked");
+        fs.release_file(fh).unwrap();
+        fs.remove_entry(ROOT_INO, OsStr::new("b"), false).unwrap();
+        assert_eq!(fs.path_for(c.ino).unwrap(), dir.path().join("c"));
+        assert_eq!(fs.read_data(c.ino, 0, 64).unwrap(), b"Linked");
+    }
+
+    /// Logger keeping every line, for checking what reaches the logs.
+    struct Capture(Mutex<Vec<String>>);
+
+    impl log::Log for Capture {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+
+    #[test]
+    fn vault_details_are_logged_only_when_sensitive() {
+        let _ = log::set_logger(&CAPTURE);
+        log::set_max_level(log::LevelFilter::Trace);
+        let (dir, fs) = test_fs();
+        let ino = new_file(&fs, &dir, "payroll-2026.xlsx");
+        fs.write_data(ino, 0, &[1u8; 20000]).unwrap();
+        let path = dir.path().join("payroll-2026.xlsx");
+        let mut raw = fs::read(&path).unwrap();
+        let last = raw.len() - 1;
+        raw[last] ^= 1;
+        fs::write(&path, raw).unwrap();
+
+        let logged = |name: &str| {
+            CAPTURE
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|line| line.contains(name))
+                .count()
+        };
+        assert_eq!(fs.read_data(ino, 16384, 100), Err(EIO));
+        assert_eq!(logged("payroll-2026"), 0);
+        assert_eq!(logged("16384"), 0);
+
+        crate::logging::set_sensitive(true);
+        let _ = fs.read_data(ino, 16384, 100);
+        crate::logging::set_sensitive(false);
+        assert!(logged("payroll-2026") > 0);
+    }
+
+    #[test]
+    fn interrupted_write_is_reported_incomplete_not_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = [0x42u8; 32];
+        let config = Config {
+            block_size: 4096,
+            mark_incomplete: true,
+            ..Config::default()
+        };
+        let scrub = || {
+            let mut state = crate::scrub::State::default();
+            let options = crate::scrub::Options {
+                full: true,
+                ..Default::default()
+            };
+            crate::scrub::scrub(dir.path(), &key, &mut state, meta::now(), options).unwrap()
+        };
+
+        let fs = CipherFS::with_config(dir.path().into(), key, config.clone());
+        let ino = new_file(&fs, &dir, "big.iso");
+        fs.open_handle(ino, libc::O_WRONLY).unwrap();
+        for i in 0..64 {
+            fs.write_data(ino, i * 3000, &[i as u8; 3000]).unwrap();
+        }
+        // The mount dies mid-copy, tearing the last block on its way down.
+        drop(fs);
+        let path = dir.path().join("big.iso");
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 100)
+            .unwrap();
+
+        let report = scrub();
+        assert_eq!(report.incomplete, vec![path.clone()]);
+        assert!(report.failed.is_empty());
+
+        // What reached the vault intact can still be read back to resume from.
+        let fs = CipherFS::with_config(dir.path().into(), key, config);
+        let ino = fs.register(path.clone()).unwrap();
+        assert_eq!(fs.read_data(ino, 0, 4096).unwrap()[..3000], [0u8; 3000]);
+
+        // A write that runs to close leaves no marker behind.
+        let done = new_file(&fs, &dir, "done.bin");
+        let fh = fs.open_handle(done, libc::O_WRONLY).unwrap();
+        fs.write_data(done, 0, &[2u8; 5000]).unwrap();
+        assert!(meta::sidecar_path(&dir.path().join("done.bin")).exists());
+        fs.release_file(fh).unwrap();
+
+        let report = scrub();
+        assert_eq!(report.incomplete, vec![path.clone()]);
+        assert!(report.failed.is_empty());
+        assert_eq!(report.verified, 1);
+        assert!(!meta::sidecar_path(&dir.path().join("done.bin")).exists());
+    }
+
+    #[test]
+    fn mount_options_follow_hardening_toggles() {
+        let strict = mount_options(Hardening::default(), false, false);
+        for option in [MountOption::NoExec, MountOption::NoSuid, MountOption::NoDev] {
+            assert!(strict.contains(&option));
+        }
+
+        let exec = Hardening {
+            exec: true,
+            ..Hardening::default()
+        };
+        let options = mount_options(exec, false, true);
+        assert!(!options.contains(&MountOption::NoExec));
+        assert!(options.contains(&MountOption::Exec));
+        assert!(options.contains(&MountOption::NoSuid));
+        assert!(options.contains(&MountOption::RO));
+    }
+
+    #[test]
+    fn distinct_inodes_do_not_share_a_lock() {
+        let (dir, fs) = test_fs();
+        let a = new_file(&fs, &dir, "a");
+        let b = new_file(&fs, &dir, "b");
+
+        let lock_a = fs.content_lock(a);
+        let _held = lock_a.write().unwrap();
+
+        // A writer on `a` must not block a writer on `b`...
+        assert!(fs.content_lock(b).try_write().is_ok());
+        // ...but must block a second writer on `a`.
+        assert!(fs.content_lock(a).try_write().is_err());
+    }
+
+    #[test]
+    fn write_to_one_file_proceeds_while_another_is_locked() {
+        let (dir, fs) = test_fs();
+        let a = new_file(&fs, &dir, "a");
+        let b = new_file(&fs, &dir, "b");
+        let lock_a = fs.content_lock(a);
+        let held = lock_a.write().unwrap();
+
+        let (done, finished) = std::sync::mpsc::channel();
+        let writer = {
+            let fs = Arc::clone(&fs);
+            thread::spawn(move || {
+                fs.write_data(b, 0, b"not waiting on a").unwrap();
+                done.send(()).unwrap();
+            })
+        };
+        // Were writes still serialized, this would wait forever.
+        assert!(finished.recv_timeout(Duration::from_secs(10)).is_ok());
+        writer.join().unwrap();
+        drop(held);
+        assert_eq!(fs.read_data(b, 0, 64).unwrap(), b"not waiting on a");
+    }
+
+    #[test]
+    fn content_locks_are_dropped_once_unused() {
+        let (dir, fs) = test_fs();
+        let a = new_file(&fs, &dir, "a");
+        fs.write_data(a, 0, b"x").unwrap();
+        assert_eq!(fs.read_data(a, 0, 1).unwrap(), b"x");
+        assert!(fs.content_locks.lock().unwrap().is_empty());
+
+        let first = fs.content_lock(a);
+        let second = fs.content_lock(a);
+        drop(first);
+        assert_eq!(fs.content_locks.lock().unwrap().len(), 1);
+        drop(second);
+        assert!(fs.content_locks.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn parallel_writes_to_distinct_files() {
+        const FILES: usize = 16;
+        const CHUNKS: usize = 32;
+        const CHUNK: usize = 256;
+
+        let (dir, fs) = test_fs();
+        let inos: Vec<u64> = (0..FILES)
+            .map(|i| new_file(&fs, &dir, &format!("f{i}")))
+            .collect();
+
+        let barrier = Arc::new(Barrier::new(FILES));
+        let handles: Vec<_> = inos
+            .iter()
+            .enumerate()
+            .map(|(i, &ino)| {
+                let fs = Arc::clone(&fs);
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    barrier.wait();
+                    for c in 0..CHUNKS {
+                        let chunk = vec![i as u8; CHUNK];
+                        let n = fs.write_data(ino, (c * CHUNK) as i64, &chunk).unwrap();
+                        assert_eq!(n as usize, CHUNK);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        for (i, &ino) in inos.iter().enumerate() {
+            let data = fs.read_data(ino, 0, (CHUNKS * CHUNK) as u32).unwrap();
+            assert_eq!(data, vec![i as u8; CHUNKS * CHUNK]);
+        }
+    }
+
+    #[test]
+    fn dispatched_writes_to_distinct_files_beat_the_serialized_baseline() {
+        const FILES: usize = 8;
+        const DELAY: Duration = Duration::from_millis(50);
+
+        /// Local files behind a link that takes `DELAY` per write.
+        struct Slow;
+        impl Backend for Slow {
+            fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+                LocalBackend.read(path)
+            }
+            fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+                thread::sleep(DELAY);
+                LocalBackend.write(path, data)
+            }
+            fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+                thread::sleep(DELAY);
+                LocalBackend.write_at(path, offset, data)
             }
+            fn sync(&self, path: &Path, data_only: bool) -> io::Result<()> {
+                LocalBackend.sync(path, data_only)
+            }
+        }
+
+        // Writes one file each the way the `write` handler does, and
+        // returns how long they took together.
+        let run = |threads: usize| {
+            let dir = tempfile::tempdir().unwrap();
+            let config = Config {
+                threads,
+                ..Config::default()
+            };
+            let fs =
+                CipherFS::with_backend(dir.path().into(), [0x42u8; 32], config, Arc::new(Slow));
+            let inos: Vec<u64> = (0..FILES)
+                .map(|i| new_file(&fs, &dir, &format!("f{i}")))
+                .collect();
+            let (done, finished) = std::sync::mpsc::channel();
+            let start = Instant::now();
+            for (i, &ino) in inos.iter().enumerate() {
+                let done = done.clone();
+                fs.dispatch(move |fs| {
+                    done.send(fs.write_data(ino, 0, &[i as u8; 512])).unwrap();
+                });
+            }
+            for _ in 0..FILES {
+                let written = finished.recv_timeout(Duration::from_secs(30)).unwrap();
+                assert_eq!(written, Ok(512));
+            }
+            let elapsed = start.elapsed();
+            for (i, &ino) in inos.iter().enumerate() {
+                assert_eq!(fs.read_data(ino, 0, 1024).unwrap(), [i as u8; 512]);
+            }
+            elapsed
         };
-        let child_path = parent_path.join(name);
-        match fs::remove_dir(&child_path) {
-            Ok(_) => reply.ok(),
-            Err(_) => reply.error(EIO),
+
+        let serialized = run(1);
+        let parallel = run(FILES);
+        assert!(serialized >= DELAY * FILES as u32, "{serialized:?}");
+        assert!(
+            parallel * 3 < serialized,
+            "{FILES} workers took {parallel:?}, one took {serialized:?}"
+        );
+    }
+
+    #[test]
+    fn concurrent_writes_to_same_file_serialize() {
+        const WRITERS: usize = 8;
+        const CHUNK: usize = 128;
+
+        let (dir, fs) = test_fs();
+        let ino = new_file(&fs, &dir, "shared");
+
+        let handles: Vec<_> = (0..WRITERS)
+            .map(|i| {
+                let fs = Arc::clone(&fs);
+                thread::spawn(move || {
+                    let chunk = vec![i as u8 + 1; CHUNK];
+                    fs.write_data(ino, (i * CHUNK) as i64, &chunk).unwrap();
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        // Without serialization, racing read-modify-write cycles would drop
+        // some writers' chunks.
+        let data = fs.read_data(ino, 0, (WRITERS * CHUNK) as u32).unwrap();
+        for i in 0..WRITERS {
+            assert_eq!(
+                &data[i * CHUNK..(i + 1) * CHUNK],
+                &vec![i as u8 + 1; CHUNK][..]
+            );
+        }
+    }
+
+    #[test]
+    fn concurrent_lookups_and_renames_keep_one_inode_per_path() {
+        const FILES: usize = 8;
+        const ROUNDS: usize = 200;
+        const LOOKERS: usize = 2;
+
+        let (dir, fs) = test_fs();
+        for i in 0..FILES {
+            new_file(&fs, &dir, &format!("a{i}"));
+        }
+        let barrier = Arc::new(Barrier::new(FILES + LOOKERS));
+        let mut handles: Vec<_> = (0..FILES)
+            .map(|i| {
+                let fs = Arc::clone(&fs);
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    let (a, b) = (format!("a{i}"), format!("b{i}"));
+                    let (a, b) = (OsStr::new(&a), OsStr::new(&b));
+                    barrier.wait();
+                    for _ in 0..ROUNDS {
+                        fs.rename_entry(ROOT_INO, a, ROOT_INO, b, 0).unwrap();
+                        fs.rename_entry(ROOT_INO, b, ROOT_INO, a, 0).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..LOOKERS {
+            let fs = Arc::clone(&fs);
+            let barrier = Arc::clone(&barrier);
+            handles.push(thread::spawn(move || {
+                barrier.wait();
+                for round in 0..ROUNDS {
+                    for i in 0..FILES {
+                        let prefix = if round % 2 == 0 { "a" } else { "b" };
+                        // Either name may be missing at any moment.
+                        let _ = fs.lookup_entry(ROOT_INO, OsStr::new(&format!("{prefix}{i}")));
+                    }
+                }
+            }));
+        }
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let inodes = fs.inodes.lock().unwrap();
+        let mut paths = HashSet::new();
+        for path in inodes.values().flatten() {
+            assert!(paths.insert(path), "{} has two inodes", path.display());
+            assert!(path.exists(), "stale inode for {}", path.display());
+        }
+    }
+
+    /// Guard against regressions of the hot paths, without a mount. The
+    /// budgets are an order of magnitude above what an unoptimised build
+    /// needs on a slow machine, so they only trip when a path has stopped
+    /// being block-at-a-time, never on a busy CI runner. Tighten them only
+    /// with numbers from the slowest CI machine.
+    #[test]
+    fn sequential_100_mb_stays_within_budget() {
+        const MB: usize = 100;
+        const WRITE_BUDGET: Duration = Duration::from_secs(120);
+        const READ_BUDGET: Duration = Duration::from_secs(60);
+        const READ_SIZE: u32 = 128 * 1024;
+
+        let (dir, fs) = test_fs();
+        let ino = new_file(&fs, &dir, "big");
+        let chunk: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+
+        let started = Instant::now();
+        for i in 0..MB {
+            fs.write_data(ino, (i * chunk.len()) as i64, &chunk)
+                .unwrap();
+        }
+        let took = started.elapsed();
+        assert!(
+            took < WRITE_BUDGET,
+            "Writing {MB} MB sequentially took {took:?}, budget {WRITE_BUDGET:?}. \
+             Likely causes: appends rewriting the whole file instead of sealing \
+             only the new blocks, or a sync per block instead of per write."
+        );
+
+        let opened_before = fs.auth_count.load(Ordering::Relaxed);
+        let started = Instant::now();
+        let mut offset = 0;
+        loop {
+            let data = fs.read_data(ino, offset as i64, READ_SIZE).unwrap();
+            if data.is_empty() {
+                break;
+            }
+            assert_eq!(data[0], chunk[offset % chunk.len()]);
+            offset += data.len();
         }
+        let took = started.elapsed();
+        assert_eq!(offset, MB << 20);
+        assert!(
+            took < READ_BUDGET,
+            "Reading {MB} MB sequentially took {took:?}, budget {READ_BUDGET:?}. \
+             Likely causes: reads falling back to decrypting the whole file \
+             instead of the blocks in range, or the block cache thrashing."
+        );
+        // Independent of timing: each block is authenticated once.
+        let blocks = (MB << 20) as u64 / format::DEFAULT_BLOCK_SIZE as u64;
+        let opened = fs.auth_count.load(Ordering::Relaxed) - opened_before;
+        assert_eq!(
+            opened, blocks,
+            "{opened} block authentications to read {blocks} blocks once"
+        );
     }
 }
@@ -0,0 +1,676 @@
+//! Read-only access to gocryptfs vaults (`--gocryptfs`), for migrating them
+//! or reading them in place.
+//!
+//! Only the default gocryptfs mode is supported: AES-GCM content with
+//! 128-bit IVs, and EME-encrypted, base64 names with a per-directory IV
+//! (`gocryptfs.diriv`), optionally HKDF-derived keys, `Raw64` names and
+//! long names. Vaults using AES-SIV (reverse mode) or XChaCha20-Poly1305 are
+//! refused. The master key is taken as given — unwrapping it from
+//! `gocryptfs.conf` with the password is left to gocryptfs, which prints it
+//! at `-init` (`gocryptfs-xray -dumpmasterkey` recovers it later).
+//!
+//! File layout:
+//!   [ header: version u16 BE = 2 | file id [u8; 16] ][ block ]...
+//!   block: IV [u8; 16] | ciphertext (up to 4096 bytes) | tag [u8; 16]
+//!
+//! Each block is authenticated with its number (u64 BE) and the file id as
+//! AAD. An empty file has no header, and an all-zero block is a hole that
+//! reads as zeros. Only regular files and directories are served.
+
+use aes_gcm::aead::consts::U16;
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes_gcm::aes::Aes256;
+use aes_gcm::AesGcm;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use base64::Engine;
+use fuser::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyOpen,
+    Request,
+};
+use libc::{c_int, EIO, ENOENT, ENOTDIR, EROFS};
+use ring::hkdf;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+pub const CONF_NAME: &str = "gocryptfs.conf";
+pub const DIRIV_NAME: &str = "gocryptfs.diriv";
+pub const LONGNAME_PREFIX: &str = "gocryptfs.longname.";
+const LONGNAME_SUFFIX: &str = ".name";
+
+pub const HEADER_LEN: u64 = 18;
+const VERSION: u16 = 2;
+pub const BLOCK_SIZE: u64 = 4096;
+const IV_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+const BLOCK_OVERHEAD: u64 = (IV_LEN + TAG_LEN) as u64;
+const CIPHER_BLOCK_SIZE: u64 = BLOCK_SIZE + BLOCK_OVERHEAD;
+
+/// EME handles names of at most this many 16-byte blocks.
+const EME_MAX_BLOCKS: usize = 128;
+
+const TTL: Duration = Duration::from_secs(1);
+
+/// Feature flags this implementation understands; any other flag in
+/// `gocryptfs.conf` means a mode it cannot read.
+const KNOWN_FLAGS: &[&str] = &[
+    "GCMIV128",
+    "DirIV",
+    "EMENames",
+    "LongNames",
+    "LongNameMax",
+    "Raw64",
+    "HKDF",
+    "PlaintextNames",
+];
+
+/// AES-256-GCM with gocryptfs's 128-bit IVs.
+type ContentCipher = AesGcm<Aes256, U16>;
+
+struct HkdfLen(usize);
+
+impl hkdf::KeyType for HkdfLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// Derive a subkey the way gocryptfs does: HKDF-SHA256, no salt.
+fn derive(master_key: &[u8; 32], info: &str) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, &[])
+        .extract(master_key)
+        .expand(&[info.as_bytes()], HkdfLen(out.len()))
+        .and_then(|okm| okm.fill(&mut out))
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    out
+}
+
+/// The `FeatureFlags` list of a `gocryptfs.conf`.
+fn feature_flags(conf: &str) -> Result<Vec<String>> {
+    let field = conf
+        .find("\"FeatureFlags\"")
+        .ok_or_else(|| anyhow!("No FeatureFlags in {}", CONF_NAME))?;
+    let rest = &conf[field..];
+    let (Some(open), Some(close)) = (rest.find('['), rest.find(']')) else {
+        bail!("Malformed FeatureFlags in {}", CONF_NAME);
+    };
+    ensure!(open < close, "Malformed FeatureFlags in {}", CONF_NAME);
+    Ok(rest[open + 1..close]
+        .split(',')
+        .map(|flag| flag.trim().trim_matches('"').to_string())
+        .filter(|flag| !flag.is_empty())
+        .collect())
+}
+
+/// `2·x` in GF(2^128), little-endian, as EME defines it.
+fn mult_by_two(x: &[u8; 16]) -> [u8; 16] {
+    let mut out = [0u8; 16];
+    out[0] = x[0] << 1;
+    if x[15] & 0x80 != 0 {
+        out[0] ^= 135;
+    }
+    for j in 1..16 {
+        out[j] = (x[j] << 1) | (x[j - 1] >> 7);
+    }
+    out
+}
+
+fn xor(a: &[u8; 16], b: &[u8; 16]) -> [u8; 16] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+fn aes_block(cipher: &Aes256, block: &[u8; 16], decrypt: bool) -> [u8; 16] {
+    let mut b = GenericArray::clone_from_slice(block);
+    if decrypt {
+        cipher.decrypt_block(&mut b);
+    } else {
+        cipher.encrypt_block(&mut b);
+    }
+    b.into()
+}
+
+/// Decrypt `data` (whole 16-byte blocks) with EME under `tweak`.
+fn eme_decrypt(cipher: &Aes256, tweak: &[u8; 16], data: &[u8]) -> Result<Vec<u8>> {
+    let m = data.len() / 16;
+    ensure!(
+        data.len().is_multiple_of(16) && (1..=EME_MAX_BLOCKS).contains(&m),
+        "Encrypted name has an invalid length"
+    );
+    let mut l = Vec::with_capacity(m);
+    let mut li = aes_block(cipher, &[0u8; 16], false);
+    for _ in 0..m {
+        li = mult_by_two(&li);
+        l.push(li);
+    }
+    let mut c: Vec<[u8; 16]> = data
+        .chunks_exact(16)
+        .zip(&l)
+        .map(|(block, lj)| aes_block(cipher, &xor(block.try_into().unwrap(), lj), true))
+        .collect();
+    let mp = c[1..]
+        .iter()
+        .fold(xor(&c[0], tweak), |acc, cj| xor(&acc, cj));
+    let mc = aes_block(cipher, &mp, true);
+    let mut mask = xor(&mp, &mc);
+    for cj in c.iter_mut().skip(1) {
+        mask = mult_by_two(&mask);
+        *cj = xor(cj, &mask);
+    }
+    c[0] = c[1..].iter().fold(xor(&mc, tweak), |acc, cj| xor(&acc, cj));
+    Ok(c.iter()
+        .zip(&l)
+        .flat_map(|(cj, lj)| xor(&aes_block(cipher, cj, true), lj))
+        .collect())
+}
+
+/// Plaintext size of a content file `cipher_len` bytes long.
+pub fn plaintext_size(cipher_len: u64) -> u64 {
+    if cipher_len <= HEADER_LEN {
+        return 0;
+    }
+    let body = cipher_len - HEADER_LEN;
+    body.saturating_sub(body.div_ceil(CIPHER_BLOCK_SIZE) * BLOCK_OVERHEAD)
+}
+
+/// Whether a backing entry called `name` is gocryptfs bookkeeping rather
+/// than an encrypted file.
+fn is_internal(name: &str) -> bool {
+    name == CONF_NAME
+        || name == DIRIV_NAME
+        || (name.starts_with(LONGNAME_PREFIX) && name.ends_with(LONGNAME_SUFFIX))
+}
+
+/// A gocryptfs vault opened with its master key.
+pub struct Volume {
+    root: PathBuf,
+    content: ContentCipher,
+    names: Aes256,
+    raw64: bool,
+    plaintext_names: bool,
+}
+
+impl Volume {
+    /// Open the gocryptfs vault at `root`, checking that its
+    /// `gocryptfs.conf` describes a mode this module can read.
+    pub fn open(root: &Path, master_key: &[u8; 32]) -> Result<Self> {
+        let conf_path = root.join(CONF_NAME);
+        let conf =
+            fs::read_to_string(&conf_path).with_context(|| format!("Reading {:?}", conf_path))?;
+        let flags = feature_flags(&conf)?;
+        let has = |flag: &str| flags.iter().any(|f| f == flag);
+        for flag in &flags {
+            ensure!(
+                KNOWN_FLAGS.contains(&flag.as_str()),
+                "Unsupported gocryptfs feature {} (only the default AES-GCM mode can be read)",
+                flag
+            );
+        }
+        ensure!(
+            has("GCMIV128"),
+            "Unsupported gocryptfs vault: content IVs are not 128-bit"
+        );
+        let plaintext_names = has("PlaintextNames");
+        ensure!(
+            plaintext_names || (has("DirIV") && has("EMENames")),
+            "Unsupported gocryptfs vault: names are not EME with per-directory IVs"
+        );
+
+        let (content_key, name_key) = if has("HKDF") {
+            (
+                derive(master_key, "AES-GCM file content encryption"),
+                derive(master_key, "EME filename encryption"),
+            )
+        } else {
+            (*master_key, *master_key)
+        };
+        Ok(Self {
+            root: root.to_path_buf(),
+            content: ContentCipher::new(&content_key.into()),
+            names: Aes256::new(&name_key.into()),
+            raw64: has("Raw64"),
+            plaintext_names,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Decrypt the encrypted name `name` of an entry in a directory with IV
+    /// `dir_iv`.
+    pub fn decrypt_name(&self, name: &str, dir_iv: &[u8; 16]) -> Result<String> {
+        let engine = if self.raw64 {
+            &base64::engine::general_purpose::URL_SAFE_NO_PAD
+        } else {
+            &base64::engine::general_purpose::URL_SAFE
+        };
+        let encrypted = engine
+            .decode(name)
+            .map_err(|_| anyhow!("Encrypted name is not base64"))?;
+        let mut padded = eme_decrypt(&self.names, dir_iv, &encrypted)?;
+        let pad = *padded.last().unwrap() as usize;
+        ensure!(
+            (1..=16).contains(&pad)
+                && padded[padded.len() - pad..]
+                    .iter()
+                    .all(|&b| b as usize == pad),
+            "Encrypted name has invalid padding (wrong key?)"
+        );
+        padded.truncate(padded.len() - pad);
+        let plain = String::from_utf8(padded).map_err(|_| anyhow!("Name is not UTF-8"))?;
+        ensure!(
+            !plain.is_empty() && plain != "." && plain != ".." && !plain.contains(['/', '\0']),
+            "Decrypted name is not a valid file name"
+        );
+        Ok(plain)
+    }
+
+    /// Entries of the backing directory `dir`, as (plaintext name, backing
+    /// path, is a directory). Entries whose names fail to decrypt are
+    /// skipped with a warning, as are symlinks and special files.
+    pub fn list(&self, dir: &Path) -> Result<Vec<(String, PathBuf, bool)>> {
+        let dir_iv: [u8; 16] = if self.plaintext_names {
+            [0u8; 16]
+        } else {
+            fs::read(dir.join(DIRIV_NAME))?
+                .try_into()
+                .map_err(|_| anyhow!("{:?} has a malformed {}", dir, DIRIV_NAME))?
+        };
+        let mut out = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let kind = entry.file_type()?;
+            if !kind.is_file() && !kind.is_dir() {
+                continue;
+            }
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if is_internal(&name) {
+                continue;
+            }
+            let plain = if self.plaintext_names {
+                Ok(name.clone())
+            } else if name.starts_with(LONGNAME_PREFIX) {
+                fs::read_to_string(dir.join(format!("{}{}", name, LONGNAME_SUFFIX)))
+                    .map_err(Into::into)
+                    .and_then(|full| self.decrypt_name(full.trim(), &dir_iv))
+            } else {
+                self.decrypt_name(&name, &dir_iv)
+            };
+            match plain {
+                Ok(plain) => out.push((plain, entry.path(), kind.is_dir())),
+                Err(e) => log::warn!("Skipping {:?}: {}", entry.path(), e),
+            }
+        }
+        Ok(out)
+    }
+
+    /// Read up to `len` plaintext bytes at `offset` of the content file at
+    /// `path`, decrypting only the blocks that cover the range.
+    pub fn read(&self, path: &Path, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let file = fs::File::open(path)?;
+        let cipher_len = file.metadata()?.len();
+        let size = plaintext_size(cipher_len);
+        if offset >= size || len == 0 {
+            return Ok(vec![]);
+        }
+        let mut header = [0u8; HEADER_LEN as usize];
+        file.read_exact_at(&mut header, 0)?;
+        let version = u16::from_be_bytes([header[0], header[1]]);
+        ensure!(version == VERSION, "Unsupported file version {}", version);
+        let file_id = &header[2..];
+        ensure!(file_id.iter().any(|&b| b != 0), "File header is all zeros");
+
+        let end = (offset + len as u64).min(size);
+        let mut out = Vec::with_capacity((end - offset) as usize);
+        for n in offset / BLOCK_SIZE..end.div_ceil(BLOCK_SIZE) {
+            let at = HEADER_LEN + n * CIPHER_BLOCK_SIZE;
+            let mut sealed = vec![0u8; CIPHER_BLOCK_SIZE.min(cipher_len - at) as usize];
+            file.read_exact_at(&mut sealed, at)?;
+            let block = self.open_block(&sealed, n, file_id)?;
+            let from = offset.saturating_sub(n * BLOCK_SIZE) as usize;
+            let to = ((end - n * BLOCK_SIZE) as usize).min(block.len());
+            out.extend_from_slice(&block[from..to]);
+        }
+        Ok(out)
+    }
+
+    /// Authenticate and decrypt block `n` of the file with id `file_id`.
+    fn open_block(&self, sealed: &[u8], n: u64, file_id: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() as u64 == CIPHER_BLOCK_SIZE && sealed.iter().all(|&b| b == 0) {
+            return Ok(vec![0u8; BLOCK_SIZE as usize]);
+        }
+        ensure!(
+            sealed.len() as u64 > BLOCK_OVERHEAD,
+            "Block {} is truncated",
+            n
+        );
+        let (iv, body) = sealed.split_at(IV_LEN);
+        ensure!(iv.iter().any(|&b| b != 0), "Block {} has an all-zero IV", n);
+        let mut aad = n.to_be_bytes().to_vec();
+        aad.extend_from_slice(file_id);
+        self.content
+            .decrypt(
+                GenericArray::from_slice(iv),
+                Payload {
+                    msg: body,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| anyhow!("Block {} failed authentication", n))
+    }
+}
+
+/// One file or directory the kernel has been told about.
+struct Node {
+    parent: u64,
+    path: PathBuf,
+    is_dir: bool,
+}
+
+/// Read-only FUSE view of a gocryptfs vault. Inode 1 is the root; others
+/// are handed out as entries are looked up or listed. Directories are
+/// listed (and their names decrypted) afresh on each lookup and readdir.
+pub struct GocryptfsFS {
+    volume: Volume,
+    nodes: Vec<Node>,
+    inodes: HashMap<PathBuf, u64>,
+}
+
+impl GocryptfsFS {
+    pub fn new(volume: Volume) -> Self {
+        let root = Node {
+            parent: 1,
+            path: volume.root().to_path_buf(),
+            is_dir: true,
+        };
+        Self {
+            volume,
+            nodes: vec![root],
+            inodes: HashMap::new(),
+        }
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get(ino.checked_sub(1)? as usize)
+    }
+
+    /// Inode of backing entry `path` in directory `parent`.
+    fn inode(&mut self, parent: u64, path: PathBuf, is_dir: bool) -> u64 {
+        if let Some(&ino) = self.inodes.get(&path) {
+            return ino;
+        }
+        self.nodes.push(Node {
+            parent,
+            path: path.clone(),
+            is_dir,
+        });
+        let ino = self.nodes.len() as u64;
+        self.inodes.insert(path, ino);
+        ino
+    }
+
+    fn list(&mut self, ino: u64) -> Result<Vec<(u64, bool, String)>, c_int> {
+        let node = self.node(ino).ok_or(ENOENT)?;
+        if !node.is_dir {
+            return Err(ENOTDIR);
+        }
+        let entries = self.volume.list(&node.path).map_err(|e| {
+            log::error!("Cannot list gocryptfs directory inode {}: {}", ino, e);
+            EIO
+        })?;
+        Ok(entries
+            .into_iter()
+            .map(|(name, path, is_dir)| (self.inode(ino, path, is_dir), is_dir, name))
+            .collect())
+    }
+
+    fn attr(&self, ino: u64) -> Result<FileAttr, c_int> {
+        let node = self.node(ino).ok_or(ENOENT)?;
+        let meta = fs::symlink_metadata(&node.path).map_err(|_| ENOENT)?;
+        let (kind, size) = if node.is_dir {
+            (FileType::Directory, meta.len())
+        } else {
+            (FileType::RegularFile, plaintext_size(meta.len()))
+        };
+        let time = |secs: i64| UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64);
+        Ok(FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: time(meta.atime()),
+            mtime: time(meta.mtime()),
+            ctime: time(meta.ctime()),
+            crtime: time(meta.mtime()),
+            kind,
+            perm: (meta.mode() & 0o7555) as u16,
+            nlink: meta.nlink() as u32,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            rdev: 0,
+            blksize: BLOCK_SIZE as u32,
+            flags: 0,
+        })
+    }
+}
+
+impl Filesystem for GocryptfsFS {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let found = self.list(parent).and_then(|entries| {
+            entries
+                .into_iter()
+                .find(|(_, _, n)| OsStr::new(n) == name)
+                .ok_or(ENOENT)
+        });
+        match found.and_then(|(ino, _, _)| self.attr(ino)) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let entries = match self.list(ino) {
+            Ok(entries) => entries,
+            Err(e) => return reply.error(e),
+        };
+        let parent = self.nodes[ino as usize - 1].parent;
+        let mut all = vec![
+            (ino, FileType::Directory, ".".to_string()),
+            (parent, FileType::Directory, "..".to_string()),
+        ];
+        for (child, is_dir, name) in entries {
+            let kind = if is_dir {
+                FileType::Directory
+            } else {
+                FileType::RegularFile
+            };
+            all.push((child, kind, name));
+        }
+        for (i, (child, kind, name)) in all.iter().enumerate().skip(offset as usize) {
+            if reply.add(*child, (i + 1) as i64, *kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self.node(ino) {
+            None => reply.error(ENOENT),
+            Some(_) if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 => {
+                reply.error(EROFS)
+            }
+            Some(node) if node.is_dir => reply.error(libc::EISDIR),
+            Some(_) => reply.opened(0, 0),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let Some(node) = self.node(ino) else {
+            return reply.error(ENOENT);
+        };
+        match self.volume.read(&node.path, offset as u64, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(e) => {
+                log::error!("gocryptfs read error on inode {}: {}", ino, e);
+                reply.error(EIO);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::digest;
+
+    // Generated from the gocryptfs format specification with an independent
+    // implementation (Python `cryptography`): master key 00 01 .. 1f,
+    // file id 10 11 .. 1f, root directory IV c0 c1 .. cf.
+    const MASTER_KEY: &str = "00010203-04050607-08090a0b-0c0d0e0f-\
+                              10111213-14151617-18191a1b-1c1d1e1f";
+    const DIR_IV: &str = "c0c1c2c3c4c5c6c7c8c9cacbcccdcecf";
+    const HELLO_NAME: &str = "S5VABECfR4Fv2ghvXHRLHQ";
+    const HELLO_FILE: &str = "0002101112131415161718191a1b1c1d1e1fa0a1a2a3a4a5a6a7a8a9aaab\
+                              acadaeafd35360472f5111710153116495510bf732f37b51369e6c4d3ca0528a\
+                              8b9031bd5dd3613fd214";
+    const DOCS_NAME: &str = "EXNcx5QpXFsRR0BGr_YvAw";
+    /// Block 1 of a file with the same id, sealing "tail after a hole".
+    const TAIL_BLOCK: &str = "b0b1b2b3b4b5b6b7b8b9babbbcbdbebf47746847e05cdf996fa61b86c3c1d5\
+                              c691b0e78e005962c4b70405a45bbe5459b0";
+    const LONG_NAME: &str = "RnrXioSknUfgiW3ylU2pqHoyxIb7OfcOAnxbB9jhuKHGZLiFMmPbPDoQmBHpaw6d\
+                             -55XKhDnZhl_96uDWEQIi1ty0zZ_XIj1l4wU4mHiturIgAPOrPzEoQbqPGvXPtxi\
+                             3RyHF5B-5f86YIkMT7Hq8t2CCSPBB3WT5UwDK90YTZ1jtD40aplNvWLyUr3_QprF\
+                             Ma-iOvrIsOpwM5WxKTAPKKRrz02oQz8ipd6n6E4LZgygfg9VFAQkWxY_LsBJLy5g\
+                             CzkwfVw937HILVmhxmSZxQ";
+    const CONF: &str = r#"{
+	"Creator": "gocryptfs v2.4.0",
+	"Version": 2,
+	"FeatureFlags": [
+		"HKDF",
+		"GCMIV128",
+		"DirIV",
+		"EMENames",
+		"LongNames",
+		"Raw64"
+	]
+}"#;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        hex::decode(s.split_whitespace().collect::<String>()).unwrap()
+    }
+
+    fn vault() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join(CONF_NAME), CONF).unwrap();
+        fs::write(root.join(DIRIV_NAME), unhex(DIR_IV)).unwrap();
+        fs::write(root.join(HELLO_NAME), unhex(HELLO_FILE)).unwrap();
+        fs::create_dir(root.join(DOCS_NAME)).unwrap();
+        fs::write(root.join(DOCS_NAME).join(DIRIV_NAME), [7u8; 16]).unwrap();
+
+        // A long name, with block 0 left as a hole.
+        let hash = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(digest::digest(&digest::SHA256, LONG_NAME.as_bytes()));
+        let stored = format!("{}{}", LONGNAME_PREFIX, hash);
+        let mut sparse = unhex(HELLO_FILE)[..HEADER_LEN as usize].to_vec();
+        sparse.resize(sparse.len() + CIPHER_BLOCK_SIZE as usize, 0);
+        sparse.extend_from_slice(&unhex(TAIL_BLOCK));
+        fs::write(root.join(&stored), sparse).unwrap();
+        fs::write(
+            root.join(format!("{}{}", stored, LONGNAME_SUFFIX)),
+            LONG_NAME,
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn known_vault_decrypts_to_plaintext() {
+        let dir = vault();
+        let key = crate::key::from_hex(MASTER_KEY, crate::key::Source::Flag).unwrap();
+        let volume = Volume::open(dir.path(), &key).unwrap();
+
+        let mut entries = volume.list(dir.path()).unwrap();
+        entries.sort();
+        let names: Vec<(&str, bool)> = entries
+            .iter()
+            .map(|(name, _, is_dir)| (name.as_str(), *is_dir))
+            .collect();
+        let long = format!("{}end.txt", "a-very-long-file-name-".repeat(9));
+        assert_eq!(
+            names,
+            [(long.as_str(), false), ("docs", true), ("hello.txt", false)]
+        );
+
+        let hello = dir.path().join(HELLO_NAME);
+        assert_eq!(
+            plaintext_size(fs::metadata(&hello).unwrap().len()),
+            "Hello from gocryptfs!\n".len() as u64
+        );
+        assert_eq!(
+            volume.read(&hello, 0, 4096).unwrap(),
+            b"Hello from gocryptfs!\n"
+        );
+        assert_eq!(volume.read(&hello, 11, 9).unwrap(), b"gocryptfs");
+
+        let sparse = &entries[0].1;
+        let mut expected = vec![0u8; BLOCK_SIZE as usize];
+        expected.extend_from_slice(b"tail after a hole");
+        assert_eq!(
+            plaintext_size(fs::metadata(sparse).unwrap().len()),
+            expected.len() as u64
+        );
+        assert_eq!(volume.read(sparse, 0, 8192).unwrap(), expected);
+
+        // A flipped ciphertext bit fails authentication.
+        let mut tampered = unhex(HELLO_FILE);
+        tampered[40] ^= 1;
+        fs::write(&hello, tampered).unwrap();
+        assert!(volume.read(&hello, 0, 4096).is_err());
+    }
+
+    #[test]
+    fn unsupported_modes_are_refused() {
+        let dir = vault();
+        let key = [0x42u8; 32];
+        for flags in [r#""GCMIV128", "AESSIV""#, r#""DirIV", "EMENames""#] {
+            let conf = format!("{{\"Version\": 2, \"FeatureFlags\": [{}]}}", flags);
+            fs::write(dir.path().join(CONF_NAME), conf).unwrap();
+            assert!(Volume::open(dir.path(), &key).is_err());
+        }
+        fs::remove_file(dir.path().join(CONF_NAME)).unwrap();
+        assert!(Volume::open(dir.path(), &key).is_err());
+    }
+}
@@ -0,0 +1,142 @@
+//! Id translation for mounts inside a user namespace (`--uid-map`,
+//! `--gid-map`).
+//!
+//! Backing files are owned by host ids, which mean nothing inside a user
+//! namespace. A map lists ranges the way `newuidmap` and
+//! `/proc/<pid>/uid_map` do — `INSIDE:OUTSIDE:COUNT` shows host ids
+//! `OUTSIDE..OUTSIDE+COUNT` as `INSIDE..INSIDE+COUNT` — and the mount
+//! reports ownership through it. Host ids no range covers are shown as the
+//! overflow id, as the kernel shows ids it cannot map.
+
+use anyhow::{ensure, Result};
+use std::str::FromStr;
+
+/// Id shown for host ids outside every range (`nobody`).
+pub const OVERFLOW_ID: u32 = 65534;
+
+/// One contiguous run of mapped ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+    /// First id inside the namespace.
+    pub inside: u32,
+    /// First host id.
+    pub outside: u32,
+    pub count: u32,
+}
+
+impl Range {
+    fn end(start: u32, count: u32) -> u64 {
+        start as u64 + count as u64
+    }
+
+    fn overlaps(start: u32, other_start: u32, count: u32, other_count: u32) -> bool {
+        (start as u64) < Self::end(other_start, other_count)
+            && (other_start as u64) < Self::end(start, count)
+    }
+}
+
+impl FromStr for Range {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split(':').collect();
+        let [inside, outside, count] = fields[..] else {
+            return Err("expected INSIDE:OUTSIDE:COUNT".to_string());
+        };
+        let id = |field: &str| {
+            field
+                .parse::<u32>()
+                .map_err(|_| format!("`{}` is not an id", field))
+        };
+        let range = Range {
+            inside: id(inside)?,
+            outside: id(outside)?,
+            count: id(count)?,
+        };
+        if range.count == 0
+            || Range::end(range.inside, range.count) > u32::MAX as u64 + 1
+            || Range::end(range.outside, range.count) > u32::MAX as u64 + 1
+        {
+            return Err("the range must be non-empty and within 32-bit ids".to_string());
+        }
+        Ok(range)
+    }
+}
+
+/// Translation of host ids to namespace ids. The empty map leaves ids as
+/// they are.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap {
+    ranges: Vec<Range>,
+}
+
+impl IdMap {
+    /// A map of `ranges`, which must not overlap on either side.
+    pub fn new(ranges: Vec<Range>) -> Result<Self> {
+        for (i, a) in ranges.iter().enumerate() {
+            for b in &ranges[i + 1..] {
+                ensure!(
+                    !Range::overlaps(a.inside, b.inside, a.count, b.count)
+                        && !Range::overlaps(a.outside, b.outside, a.count, b.count),
+                    "Id ranges {}:{}:{} and {}:{}:{} overlap",
+                    a.inside,
+                    a.outside,
+                    a.count,
+                    b.inside,
+                    b.outside,
+                    b.count
+                );
+            }
+        }
+        Ok(Self { ranges })
+    }
+
+    /// The id host id `host` is shown as.
+    pub fn to_inside(&self, host: u32) -> u32 {
+        if self.ranges.is_empty() {
+            return host;
+        }
+        self.ranges
+            .iter()
+            .find(|r| host >= r.outside && (host as u64) < Range::end(r.outside, r.count))
+            .map_or(OVERFLOW_ID, |r| r.inside + (host - r.outside))
+    }
+
+    /// The host id shown as `inside`, if any range covers it.
+    pub fn to_outside(&self, inside: u32) -> Option<u32> {
+        if self.ranges.is_empty() {
+            return Some(inside);
+        }
+        self.ranges
+            .iter()
+            .find(|r| inside >= r.inside && (inside as u64) < Range::end(r.inside, r.count))
+            .map(|r| r.outside + (inside - r.inside))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_translate_and_unmapped_ids_overflow() {
+        let map = IdMap::new(vec![
+            "0:100000:65536".parse().unwrap(),
+            "65536:1000:1".parse().unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(map.to_inside(100000), 0);
+        assert_eq!(map.to_inside(101000), 1000);
+        assert_eq!(map.to_inside(1000), 65536);
+        assert_eq!(map.to_inside(0), OVERFLOW_ID);
+        assert_eq!(IdMap::default().to_inside(1000), 1000);
+        assert_eq!(map.to_outside(1000), Some(101000));
+        assert_eq!(map.to_outside(70000), None);
+
+        assert!("0:1".parse::<Range>().is_err());
+        assert!("0:1:0".parse::<Range>().is_err());
+        assert!("1:4294967295:2".parse::<Range>().is_err());
+        let overlapping = ["0:1000:10", "5:2000:10"].map(|r| r.parse().unwrap());
+        assert!(IdMap::new(overlapping.to_vec()).is_err());
+    }
+}
@@ -0,0 +1,783 @@
+//! Read-only packed images.
+//!
+//! `pack` turns a vault into a single file that can be shipped and mounted
+//! read-only with `--image`. File contents are split into chunks; each chunk
+//! is zstd-compressed and sealed with AES-256-GCM, and an index at the end of
+//! the image maps every file to its chunks, so a read only touches the chunks
+//! it covers.
+//!
+//! Image layout:
+//!   [ 40-byte header ][ sealed chunk ]...[ sealed index ]
+//!   header: "CMX1" | version u8 | flags u8 | reserved [u8; 2] | image id [u8; 16]
+//!           | index offset u64 LE | index length u64 LE
+//!
+//! If the vault has a trained compression dictionary (see
+//! `vault::train_dictionary`), chunks are compressed with it, the
+//! `FLAG_DICTIONARY` flag is set and the dictionary leads the index.
+//!
+//! The index is sealed with the header as AAD. Each chunk is sealed with the
+//! image id, its entry number and its chunk number as AAD, so chunks cannot
+//! be moved between files or spliced in from another image.
+//!
+//! Both are sealed under the image key (`image_key`), derived from the vault
+//! key rather than the vault key itself, so nothing in an image, which is
+//! made to be shipped elsewhere, is ciphertext under the key of the vault's
+//! own files. Version 1 images were sealed under the vault key and have to
+//! be packed again.
+
+use crate::{crypto, dedup, layout, meta, vault, walk};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use fuser::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyOpen,
+    Request,
+};
+use libc::{c_int, EIO, ENOENT, ENOTDIR, EROFS};
+use ring::hmac;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+pub const MAGIC: &[u8; 4] = b"CMX1";
+pub const VERSION: u8 = 2;
+pub const HEADER_SIZE: usize = 40;
+
+/// Header flag: chunks are compressed with the dictionary stored in the
+/// index.
+pub const FLAG_DICTIONARY: u8 = 1;
+
+/// Plaintext bytes per chunk unless `pack` is told otherwise.
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+
+pub(crate) const ZSTD_LEVEL: i32 = 3;
+const TTL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Dir,
+    File,
+}
+
+/// One file or directory in the image. Entry 0 is the root.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub parent: u32,
+    pub name: String,
+    pub kind: Kind,
+    pub size: u64,
+    /// Offset and sealed length of each chunk.
+    chunks: Vec<(u64, u32)>,
+}
+
+/// Key an image packed from the vault with key `key` is sealed under: an
+/// HMAC-SHA256 of a fixed label, keeping it apart from the vault key.
+pub fn image_key(key: &[u8; 32]) -> [u8; 32] {
+    let tag = hmac::sign(
+        &hmac::Key::new(hmac::HMAC_SHA256, key),
+        b"CipherMount image key v1",
+    );
+    tag.as_ref().try_into().unwrap()
+}
+
+fn chunk_aad(id: &[u8; 16], entry: u32, chunk: u32) -> Vec<u8> {
+    let mut aad = id.to_vec();
+    aad.extend_from_slice(&entry.to_le_bytes());
+    aad.extend_from_slice(&chunk.to_le_bytes());
+    aad
+}
+
+fn encode_header(id: &[u8; 16], flags: u8, index_offset: u64, index_len: u64) -> [u8; HEADER_SIZE] {
+    let mut out = [0u8; HEADER_SIZE];
+    out[..4].copy_from_slice(MAGIC);
+    out[4] = VERSION;
+    out[5] = flags;
+    out[8..24].copy_from_slice(id);
+    out[24..32].copy_from_slice(&index_offset.to_le_bytes());
+    out[32..40].copy_from_slice(&index_len.to_le_bytes());
+    out
+}
+
+/// Bytes `encode_index` gives an entry with a `name_len`-byte name and
+/// `chunks` chunks.
+pub(crate) fn index_entry_len(name_len: usize, chunks: usize) -> usize {
+    4 + 1 + 2 + name_len + 8 + 4 + chunks * 12
+}
+
+fn encode_index(chunk_size: u32, dictionary: Option<&[u8]>, entries: &[Entry]) -> Vec<u8> {
+    let mut out = Vec::new();
+    if let Some(dict) = dictionary {
+        out.extend_from_slice(&(dict.len() as u32).to_le_bytes());
+        out.extend_from_slice(dict);
+    }
+    out.extend_from_slice(&chunk_size.to_le_bytes());
+    out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    for e in entries {
+        out.extend_from_slice(&e.parent.to_le_bytes());
+        out.push(matches!(e.kind, Kind::File) as u8);
+        out.extend_from_slice(&(e.name.len() as u16).to_le_bytes());
+        out.extend_from_slice(e.name.as_bytes());
+        out.extend_from_slice(&e.size.to_le_bytes());
+        out.extend_from_slice(&(e.chunks.len() as u32).to_le_bytes());
+        for (offset, len) in &e.chunks {
+            out.extend_from_slice(&offset.to_le_bytes());
+            out.extend_from_slice(&len.to_le_bytes());
+        }
+    }
+    out
+}
+
+/// Cursor over an authenticated index; any short read is a format error.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        ensure!(self.0.len() >= n, "Image index is truncated");
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+}
+
+/// Index contents: chunk size, dictionary and entries.
+type Index = (u32, Option<Vec<u8>>, Vec<Entry>);
+
+fn decode_index(raw: &[u8], flags: u8) -> Result<Index> {
+    let mut r = Reader(raw);
+    let dictionary = if flags & FLAG_DICTIONARY != 0 {
+        let len = r.u32()? as usize;
+        Some(r.take(len)?.to_vec())
+    } else {
+        None
+    };
+    let chunk_size = r.u32()?;
+    ensure!(chunk_size > 0, "Image chunk size is zero");
+    let count = r.u32()?;
+    let mut entries = Vec::new();
+    for i in 0..count {
+        let parent = r.u32()?;
+        let kind = if r.u8()? == 1 { Kind::File } else { Kind::Dir };
+        let name_len = r.u16()? as usize;
+        let name = String::from_utf8(r.take(name_len)?.to_vec())?;
+        let size = r.u64()?;
+        let chunk_count = r.u32()?;
+        ensure!(
+            parent < count && (i == 0 || parent < i),
+            "Image entry {} has an invalid parent",
+            i
+        );
+        ensure!(
+            chunk_count as u64 == size.div_ceil(chunk_size as u64),
+            "Image entry {} has the wrong number of chunks",
+            i
+        );
+        let chunks = (0..chunk_count)
+            .map(|_| Ok((r.u64()?, r.u32()?)))
+            .collect::<Result<_>>()?;
+        entries.push(Entry {
+            parent,
+            name,
+            kind,
+            size,
+            chunks,
+        });
+    }
+    ensure!(
+        entries.first().is_some_and(|root| root.kind == Kind::Dir),
+        "Image has no root directory"
+    );
+    Ok((chunk_size, dictionary, entries))
+}
+
+/// Pack the vault at `source`, whose key is `key`, into a new image at
+/// `out` sealed under `image_key(key)`, compressing with the vault's
+/// dictionary if it has one. Metadata sidecars, vault files and expired
+/// files are left out. Returns the number of files packed.
+pub fn pack(
+    source: &Path,
+    key: &[u8; 32],
+    out: &Path,
+    chunk_size: u32,
+    options: walk::Options,
+) -> Result<usize> {
+    ensure!(chunk_size > 0, "Chunk size must be non-zero");
+    let dictionary = vault::dictionary(source, key)?;
+    let mut compressor = match &dictionary {
+        Some(dict) => zstd::bulk::Compressor::with_dictionary(ZSTD_LEVEL, dict)?,
+        None => zstd::bulk::Compressor::new(ZSTD_LEVEL)?,
+    };
+    let id: [u8; 16] = rand::random();
+    let sealing_key = image_key(key);
+    let tmp = out.with_extension("cmx.tmp");
+    let mut file = fs::File::create(&tmp).with_context(|| format!("Creating {:?}", tmp))?;
+    file.write_all(&[0u8; HEADER_SIZE])?;
+
+    let mut entries = vec![Entry {
+        parent: 0,
+        name: String::new(),
+        kind: Kind::Dir,
+        size: 0,
+        chunks: vec![],
+    }];
+    let mut offset = HEADER_SIZE as u64;
+    let now = meta::now();
+
+    // The walk visits a directory before its contents, so every entry's
+    // parent precedes it in the index.
+    let mut dirs = HashMap::from([(source.to_path_buf(), 0u32)]);
+    walk::walk(source, options, |child| {
+        let path = &child.path;
+        let parent = dirs[path.parent().unwrap_or(source)];
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let index = entries.len() as u32;
+        if child.file_type.is_dir() {
+            entries.push(Entry {
+                parent,
+                name,
+                kind: Kind::Dir,
+                size: 0,
+                chunks: vec![],
+            });
+            dirs.insert(path.clone(), index);
+            return Ok(());
+        }
+        if !child.file_type.is_file()
+            || meta::expires_at(key, source, path, None).is_some_and(|t| t <= now)
+        {
+            return Ok(());
+        }
+        let raw = layout::read(path)?;
+        let plaintext = if raw.len() >= crypto::HEADER_LEN + 16 {
+            dedup::decrypt_file(key, path, &raw)
+                .with_context(|| format!("Decrypting {:?}", path))?
+        } else {
+            vec![]
+        };
+        let mut chunks = vec![];
+        for (n, chunk) in plaintext.chunks(chunk_size as usize).enumerate() {
+            let compressed = compressor.compress(chunk)?;
+            let sealed = crypto::encrypt_with_aad(
+                &sealing_key,
+                &compressed,
+                &chunk_aad(&id, index, n as u32),
+            )?;
+            file.write_all(&sealed)?;
+            chunks.push((offset, sealed.len() as u32));
+            offset += sealed.len() as u64;
+        }
+        entries.push(Entry {
+            parent,
+            name,
+            kind: Kind::File,
+            size: plaintext.len() as u64,
+            chunks,
+        });
+        Ok(())
+    })?;
+
+    let index = encode_index(chunk_size, dictionary.as_deref(), &entries);
+    // The sealed index is the plaintext plus nonce and tag.
+    let index_len = (index.len() + crypto::HEADER_LEN + 16) as u64;
+    let flags = if dictionary.is_some() {
+        FLAG_DICTIONARY
+    } else {
+        0
+    };
+    let header = encode_header(&id, flags, offset, index_len);
+    file.write_all(&crypto::encrypt_with_aad(&sealing_key, &index, &header)?)?;
+    file.write_all_at(&header, 0)?;
+    file.sync_all()?;
+    fs::rename(&tmp, out)?;
+    Ok(entries.iter().filter(|e| e.kind == Kind::File).count())
+}
+
+/// An opened image.
+pub struct Image {
+    file: fs::File,
+    /// The image key, not the vault's.
+    key: [u8; 32],
+    id: [u8; 16],
+    chunk_size: u32,
+    dictionary: Option<Vec<u8>>,
+    entries: Vec<Entry>,
+    children: HashMap<u32, Vec<u32>>,
+}
+
+impl Image {
+    /// Open the image at `path`, packed from the vault with key `key`, and
+    /// authenticate its index.
+    pub fn open(path: &Path, key: &[u8; 32]) -> Result<Self> {
+        let key = &image_key(key);
+        let file = fs::File::open(path).with_context(|| format!("Opening image {:?}", path))?;
+        let mut header = [0u8; HEADER_SIZE];
+        file.read_exact_at(&mut header, 0)
+            .map_err(|_| anyhow!("{:?} is not a CipherMount image", path))?;
+        if &header[..4] != MAGIC {
+            bail!("{:?} is not a CipherMount image", path);
+        }
+        ensure!(
+            header[4] != 1,
+            "{:?} is a version 1 image, sealed under the vault key; pack the vault again",
+            path
+        );
+        ensure!(
+            header[4] == VERSION,
+            "Unsupported image version {}",
+            header[4]
+        );
+        let id: [u8; 16] = header[8..24].try_into()?;
+        let index_offset = u64::from_le_bytes(header[24..32].try_into()?);
+        let index_len = u64::from_le_bytes(header[32..40].try_into()?);
+        ensure!(
+            index_offset.checked_add(index_len) == Some(file.metadata()?.len()),
+            "Image is truncated or has trailing data"
+        );
+
+        let mut sealed = vec![0u8; index_len as usize];
+        file.read_exact_at(&mut sealed, index_offset)?;
+        let index = crypto::decrypt_with_aad(key, &sealed, &header).map_err(|_| {
+            anyhow!("Image index failed authentication: wrong key or tampered image")
+        })?;
+        let (chunk_size, dictionary, entries) = decode_index(&index, header[5])?;
+
+        let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+        for (i, e) in entries.iter().enumerate().skip(1) {
+            children.entry(e.parent).or_default().push(i as u32);
+        }
+        Ok(Self {
+            file,
+            key: *key,
+            id,
+            chunk_size,
+            dictionary,
+            entries,
+            children,
+        })
+    }
+
+    pub fn entry(&self, index: u32) -> Option<&Entry> {
+        self.entries.get(index as usize)
+    }
+
+    /// Child entries of directory `index`.
+    pub fn children(&self, index: u32) -> &[u32] {
+        self.children.get(&index).map_or(&[], Vec::as_slice)
+    }
+
+    /// Child of directory `parent` called `name`.
+    pub fn lookup(&self, parent: u32, name: &str) -> Option<u32> {
+        self.children(parent)
+            .iter()
+            .copied()
+            .find(|&i| self.entries[i as usize].name == name)
+    }
+
+    /// Entry at `path`, relative to the image root.
+    pub fn find(&self, path: &Path) -> Option<u32> {
+        path.iter()
+            .try_fold(0, |dir, name| self.lookup(dir, name.to_str()?))
+    }
+
+    /// Read up to `len` bytes of file `index` at `offset`, decrypting and
+    /// decompressing only the chunks that cover the range.
+    pub fn read(&self, index: u32, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let entry = self
+            .entry(index)
+            .filter(|e| e.kind == Kind::File)
+            .ok_or_else(|| anyhow!("Image entry {} is not a file", index))?;
+        if offset >= entry.size || len == 0 {
+            return Ok(vec![]);
+        }
+        let end = (offset + len as u64).min(entry.size);
+        let cs = self.chunk_size as u64;
+        let mut out = Vec::with_capacity((end - offset) as usize);
+        let mut decompressor = match &self.dictionary {
+            Some(dict) => zstd::bulk::Decompressor::with_dictionary(dict)?,
+            None => zstd::bulk::Decompressor::new()?,
+        };
+        for n in offset / cs..end.div_ceil(cs) {
+            let (at, sealed_len) = entry.chunks[n as usize];
+            let mut sealed = vec![0u8; sealed_len as usize];
+            self.file.read_exact_at(&mut sealed, at)?;
+            let compressed =
+                crypto::decrypt_with_aad(&self.key, &sealed, &chunk_aad(&self.id, index, n as u32))
+                    .map_err(|_| anyhow!("Chunk {} of entry {} failed authentication", n, index))?;
+            let expected = (entry.size - n * cs).min(cs) as usize;
+            let chunk = decompressor.decompress(&compressed, expected)?;
+            ensure!(
+                chunk.len() == expected,
+                "Chunk {} of entry {} has the wrong length",
+                n,
+                index
+            );
+            let from = offset.saturating_sub(n * cs) as usize;
+            let to = ((end - n * cs) as usize).min(chunk.len());
+            out.extend_from_slice(&chunk[from..to]);
+        }
+        Ok(out)
+    }
+}
+
+/// Read-only FUSE view of an image. Inode `n + 1` is entry `n`.
+pub struct ImageFS {
+    image: Image,
+    attr_base: fs::Metadata,
+}
+
+impl ImageFS {
+    pub fn new(image: Image, path: &Path) -> Result<Self> {
+        Ok(Self {
+            image,
+            attr_base: fs::metadata(path)?,
+        })
+    }
+
+    fn index(ino: u64) -> u32 {
+        (ino - 1) as u32
+    }
+
+    fn attr(&self, index: u32) -> Option<FileAttr> {
+        let entry = self.image.entry(index)?;
+        let (kind, perm, nlink) = match entry.kind {
+            Kind::Dir => {
+                let subdirs = self
+                    .image
+                    .children(index)
+                    .iter()
+                    .filter(|&&c| self.image.entries[c as usize].kind == Kind::Dir)
+                    .count();
+                (FileType::Directory, 0o555, 2 + subdirs as u32)
+            }
+            Kind::File => (FileType::RegularFile, 0o444, 1),
+        };
+        let mtime = UNIX_EPOCH + Duration::from_secs(self.attr_base.mtime().max(0) as u64);
+        Some(FileAttr {
+            ino: index as u64 + 1,
+            size: entry.size,
+            blocks: entry.size.div_ceil(512),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind,
+            perm,
+            nlink,
+            uid: self.attr_base.uid(),
+            gid: self.attr_base.gid(),
+            rdev: 0,
+            blksize: self.image.chunk_size,
+            flags: 0,
+        })
+    }
+
+    /// Images are immutable: any open for writing is refused.
+    fn check_open(&self, ino: u64, flags: i32) -> Result<(), c_int> {
+        let entry = self.image.entry(Self::index(ino)).ok_or(ENOENT)?;
+        if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+            return Err(EROFS);
+        }
+        if entry.kind == Kind::Dir {
+            return Err(libc::EISDIR);
+        }
+        Ok(())
+    }
+}
+
+impl Filesystem for ImageFS {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let child = name
+            .to_str()
+            .and_then(|name| self.image.lookup(Self::index(parent), name));
+        match child.and_then(|c| self.attr(c)) {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.attr(Self::index(ino)) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let index = Self::index(ino);
+        match self.image.entry(index) {
+            Some(e) if e.kind == Kind::Dir => {}
+            Some(_) => return reply.error(ENOTDIR),
+            None => return reply.error(ENOENT),
+        }
+        let parent = self.image.entries[index as usize].parent as u64 + 1;
+        let mut all = vec![
+            (ino, FileType::Directory, "."),
+            (parent, FileType::Directory, ".."),
+        ];
+        for &c in self.image.children(index) {
+            let e = &self.image.entries[c as usize];
+            let kind = match e.kind {
+                Kind::Dir => FileType::Directory,
+                Kind::File => FileType::RegularFile,
+            };
+            all.push((c as u64 + 1, kind, e.name.as_str()));
+        }
+        for (i, (child_ino, kind, name)) in all.iter().enumerate().skip(offset as usize) {
+            if reply.add(*child_ino, (i + 1) as i64, *kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self.check_open(ino, flags) {
+            Ok(()) => reply.opened(0, fuser::consts::FOPEN_KEEP_CACHE),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self
+            .image
+            .read(Self::index(ino), offset as u64, size as usize)
+        {
+            Ok(data) => reply.data(&data),
+            Err(e) => {
+                log::error!("Image read error on inode {}: {}", ino, e);
+                reply.error(EIO);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format;
+    use crate::vault;
+
+    const KEY: [u8; 32] = [0x42u8; 32];
+
+    fn sample(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+            .collect()
+    }
+
+    fn vault_with_tree() -> (tempfile::TempDir, Vec<(&'static str, Vec<u8>)>) {
+        let dir = tempfile::tempdir().unwrap();
+        vault::init(dir.path(), &KEY).unwrap();
+        fs::create_dir_all(dir.path().join("assets/levels")).unwrap();
+        let files = vec![
+            ("readme", b"hello".to_vec()),
+            ("empty", vec![]),
+            ("assets/big.bin", sample(1000, 7)),
+            ("assets/levels/one", sample(130, 1)),
+            ("assets/levels/two", vec![0u8; 4096]),
+        ];
+        for (name, data) in &files {
+            let raw = format::encrypt_file(&KEY, data, 64).unwrap();
+            fs::write(dir.path().join(name), raw).unwrap();
+        }
+        meta::FileMeta::default()
+            .store(&KEY, dir.path(), &dir.path().join("readme"))
+            .unwrap();
+        (dir, files)
+    }
+
+    #[test]
+    fn packed_image_serves_random_access_reads() {
+        let (vault, files) = vault_with_tree();
+        let out = tempfile::tempdir().unwrap();
+        let image_path = out.path().join("bundle.cmx");
+        assert_eq!(
+            pack(
+                vault.path(),
+                &KEY,
+                &image_path,
+                100,
+                walk::Options::default()
+            )
+            .unwrap(),
+            files.len()
+        );
+
+        let fs = ImageFS::new(Image::open(&image_path, &KEY).unwrap(), &image_path).unwrap();
+        let image = &fs.image;
+        for (name, data) in &files {
+            let index = image.find(Path::new(name)).unwrap();
+            assert_eq!(image.entry(index).unwrap().size, data.len() as u64);
+            assert_eq!(&image.read(index, 0, data.len() + 10).unwrap(), data);
+            for (offset, len) in [(0, 1), (99, 2), (150, 300), (999, 5)] {
+                let start = offset.min(data.len());
+                let end = (offset + len).min(data.len());
+                assert_eq!(
+                    image.read(index, offset as u64, len).unwrap(),
+                    data[start..end],
+                    "{name} @{offset}+{len}"
+                );
+            }
+        }
+        // Vault bookkeeping files are not part of the image.
+        assert!(image.find(Path::new(vault::CANARY_FILE)).is_none());
+        assert_eq!(image.children(0).len(), 3);
+
+        let file = image.find(Path::new("readme")).unwrap() as u64 + 1;
+        assert_eq!(fs.check_open(file, libc::O_RDONLY), Ok(()));
+        assert_eq!(fs.check_open(file, libc::O_RDWR), Err(EROFS));
+        assert_eq!(fs.check_open(file, libc::O_WRONLY), Err(EROFS));
+        assert_eq!(fs.attr(0).unwrap().nlink, 3);
+    }
+
+    #[test]
+    fn tampered_or_foreign_images_are_rejected() {
+        let (vault, _) = vault_with_tree();
+        let out = tempfile::tempdir().unwrap();
+        let image_path = out.path().join("bundle.cmx");
+        pack(
+            vault.path(),
+            &KEY,
+            &image_path,
+            100,
+            walk::Options::default(),
+        )
+        .unwrap();
+
+        assert!(Image::open(&image_path, &[0u8; 32]).is_err());
+
+        let mut raw = fs::read(&image_path).unwrap();
+        raw[HEADER_SIZE + 5] ^= 1;
+        fs::write(&image_path, &raw).unwrap();
+        // The first chunk written belongs to "assets/big.bin"; other files
+        // still read.
+        let image = Image::open(&image_path, &KEY).unwrap();
+        let readme = image.find(Path::new("readme")).unwrap();
+        let big = image.find(Path::new("assets/big.bin")).unwrap();
+        assert!(image.read(big, 0, 1).is_err());
+        assert_eq!(image.read(readme, 0, 64).unwrap(), b"hello");
+
+        raw.truncate(raw.len() - 1);
+        fs::write(&image_path, &raw).unwrap();
+        assert!(Image::open(&image_path, &KEY).is_err());
+    }
+
+    #[test]
+    fn images_are_sealed_under_their_own_key() {
+        let (vault, _) = vault_with_tree();
+        let out = tempfile::tempdir().unwrap();
+        let image_path = out.path().join("bundle.cmx");
+        pack(
+            vault.path(),
+            &KEY,
+            &image_path,
+            100,
+            walk::Options::default(),
+        )
+        .unwrap();
+        assert_ne!(image_key(&KEY), KEY);
+
+        let raw = fs::read(&image_path).unwrap();
+        let header = &raw[..HEADER_SIZE];
+        let index_offset = u64::from_le_bytes(header[24..32].try_into().unwrap()) as usize;
+        let sealed_index = &raw[index_offset..];
+        assert!(crypto::decrypt_with_aad(&image_key(&KEY), sealed_index, header).is_ok());
+        assert!(crypto::decrypt_with_aad(&KEY, sealed_index, header).is_err());
+        let image = Image::open(&image_path, &KEY).unwrap();
+        let big = image.find(Path::new("assets/big.bin")).unwrap();
+        let (offset, len) = image.entry(big).unwrap().chunks[0];
+        let chunk = &raw[offset as usize..][..len as usize];
+        let aad = chunk_aad(&image.id, big, 0);
+        assert!(crypto::decrypt_with_aad(&image_key(&KEY), chunk, &aad).is_ok());
+        assert!(crypto::decrypt_with_aad(&KEY, chunk, &aad).is_err());
+
+        // An image from before the image key is refused with a clear error.
+        let mut old = raw.clone();
+        old[4] = 1;
+        fs::write(&image_path, &old).unwrap();
+        let err = Image::open(&image_path, &KEY).err().unwrap().to_string();
+        assert!(err.contains("pack the vault again"), "{err}");
+    }
+
+    #[test]
+    fn trained_dictionary_shrinks_similar_small_files() {
+        let dir = tempfile::tempdir().unwrap();
+        vault::init(dir.path(), &KEY).unwrap();
+        let files: Vec<(String, Vec<u8>)> = (0..400)
+            .map(|i| {
+                let json = format!(
+                    "{{\"service\":\"svc-{}\",\"replicas\":{},\"region\":\"eu-west-{}\",\
+                     \"healthcheck\":{{\"path\":\"/healthz\",\"interval_seconds\":{}}},\
+                     \"labels\":{{\"team\":\"platform\",\"tier\":\"backend\"}}}}",
+                    i,
+                    i % 7,
+                    i % 3,
+                    10 + i % 5
+                );
+                (format!("cfg-{:03}.json", i), json.into_bytes())
+            })
+            .collect();
+        for (name, data) in &files {
+            let raw = format::encrypt_file(&KEY, data, 4096).unwrap();
+            fs::write(dir.path().join(name), raw).unwrap();
+        }
+        let packed_size = |name: &str| {
+            let path = dir.path().with_extension(name);
+            pack(dir.path(), &KEY, &path, 4096, walk::Options::default()).unwrap();
+            let size = fs::metadata(&path).unwrap().len();
+            (path, size)
+        };
+
+        let (plain_path, plain) = packed_size("plain.cmx");
+        vault::train_dictionary(dir.path(), &KEY, 4096, walk::Options::default()).unwrap();
+        let (dict_path, with_dict) = packed_size("dict.cmx");
+
+        assert!(with_dict * 3 < plain * 2, "{with_dict} vs {plain}");
+        for path in [&plain_path, &dict_path] {
+            let image = Image::open(path, &KEY).unwrap();
+            for (name, data) in files.iter().step_by(37) {
+                let index = image.find(Path::new(name)).unwrap();
+                assert_eq!(&image.read(index, 0, data.len()).unwrap(), data);
+            }
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
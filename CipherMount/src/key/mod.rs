@@ -0,0 +1,311 @@
+//! Key acquisition: turning user-supplied key material into the raw
+//! 32-byte AES-256 key used by the crypto layer.
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Length of the raw AES-256 key.
+pub const KEY_LEN: usize = 32;
+
+/// Where key material came from, named in errors about it.
+#[derive(Debug, Clone, Copy)]
+pub enum Source<'a> {
+    /// `--key` on the command line.
+    Flag,
+    /// The `CIPHER_KEY` environment variable.
+    Env,
+    /// A systemd credential, by name.
+    Credential(&'a str),
+    /// A key file, by path.
+    File(&'a Path),
+}
+
+impl fmt::Display for Source<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Flag => write!(f, "--key"),
+            Source::Env => write!(f, "CIPHER_KEY"),
+            Source::Credential(name) => write!(f, "credential {:?}", name),
+            Source::File(path) => write!(f, "key file {:?}", path),
+        }
+    }
+}
+
+/// Turn decoded key material into the key, checking its length. Every key
+/// source ends here, so a wrong-length key is reported the same way,
+/// naming where it came from.
+pub fn finalize(bytes: &[u8], source: Source) -> Result<[u8; KEY_LEN]> {
+    <[u8; KEY_LEN]>::try_from(bytes).map_err(|_| {
+        anyhow!(
+            "Key from {} is {} bytes, need {}",
+            source,
+            bytes.len(),
+            KEY_LEN
+        )
+    })
+}
+
+/// On-disk encodings a key file can use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Encoding {
+    /// 64 hex characters.
+    Hex,
+    /// The 32 key bytes themselves.
+    Raw,
+    /// Standard base64 with padding (44 characters).
+    Base64,
+}
+
+/// Decode key material from `source` in `encoding`. Surrounding whitespace
+/// is ignored for the text encodings.
+pub fn decode(bytes: &[u8], encoding: Encoding, source: Source) -> Result<[u8; KEY_LEN]> {
+    match encoding {
+        Encoding::Raw => finalize(bytes, source),
+        Encoding::Hex => from_hex(
+            std::str::from_utf8(bytes)
+                .map_err(|_| anyhow!("Hex key from {} is not valid text", source))?,
+            source,
+        ),
+        Encoding::Base64 => {
+            let decoded = BASE64
+                .decode(bytes.trim_ascii())
+                .map_err(|e| anyhow!("Invalid base64 key from {}: {}", source, e))?;
+            finalize(&decoded, source)
+        }
+    }
+}
+
+/// Encode `key` in `encoding`. Text encodings end with a newline.
+pub fn encode(key: &[u8; KEY_LEN], encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Raw => key.to_vec(),
+        Encoding::Hex => format!("{}\n", hex::encode(key)).into_bytes(),
+        Encoding::Base64 => format!("{}\n", BASE64.encode(key)).into_bytes(),
+    }
+}
+
+/// Re-encode the key file at `input` into a new file at `output`, readable
+/// only by its owner. An existing `output` is never overwritten.
+pub fn convert(input: &Path, from: Encoding, output: &Path, to: Encoding) -> Result<()> {
+    let bytes = fs::read(input).with_context(|| format!("Reading key file {:?}", input))?;
+    let key = decode(&bytes, from, Source::File(input))?;
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(output)
+        .with_context(|| format!("Creating {:?}", output))?;
+    file.write_all(&encode(&key, to))?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Parse a 64-char hex string from `source` into a 32-byte key. The key may
+/// also be written as eight dash-separated groups of eight, as gocryptfs
+/// prints master keys; a dash anywhere else is an error.
+pub fn from_hex(hex_key: &str, source: Source) -> Result<[u8; KEY_LEN]> {
+    let hex_key = hex_key.trim();
+    let groups: Vec<&str> = hex_key.split('-').collect();
+    let joined;
+    let hex_key = if groups.len() == 1 {
+        hex_key
+    } else if groups.len() == KEY_LEN / 4 && groups.iter().all(|g| g.len() == 8) {
+        joined = groups.concat();
+        &joined
+    } else {
+        bail!(
+            "Key from {} is not 64-char hex: dashes only go between groups of 8",
+            source
+        );
+    };
+    let bytes = hex::decode(hex_key)
+        .map_err(|e| anyhow!("Key from {} is not 64-char hex: {}", source, e))?;
+    finalize(&bytes, source)
+}
+
+/// Interpret key material read from `source`: exactly 32 raw bytes, or a
+/// 64-char hex string (surrounding whitespace allowed). Anything that isn't
+/// hex is taken as raw bytes, so its length is what gets reported.
+pub fn from_bytes(bytes: &[u8], source: Source) -> Result<[u8; KEY_LEN]> {
+    if bytes.len() == KEY_LEN {
+        return finalize(bytes, source);
+    }
+    let is_hex = bytes
+        .iter()
+        .all(|&b| b.is_ascii_hexdigit() || b == b'-' || b.is_ascii_whitespace());
+    match std::str::from_utf8(bytes) {
+        Ok(text) if is_hex => from_hex(text, source),
+        _ => finalize(bytes, source),
+    }
+}
+
+/// Describe why `key` looks weak, if it does.
+///
+/// This is a heuristic for keys that were typed or patterned rather than
+/// generated: repetitions, arithmetic runs and keys drawn from a handful of
+/// byte values. A random key essentially never trips it.
+pub fn weakness(key: &[u8; KEY_LEN]) -> Option<&'static str> {
+    if let Some(period) =
+        (1..=KEY_LEN / 2).find(|&p| key.iter().zip(&key[p..]).all(|(a, b)| a == b))
+    {
+        return Some(if period == 1 {
+            "every byte is the same"
+        } else {
+            "it repeats a short pattern"
+        });
+    }
+    let step = key[1].wrapping_sub(key[0]);
+    if key.windows(2).all(|w| w[1].wrapping_sub(w[0]) == step) {
+        return Some("its bytes form an arithmetic sequence");
+    }
+    let mut seen = [false; 256];
+    key.iter().for_each(|&b| seen[b as usize] = true);
+    if seen.iter().filter(|&&s| s).count() < KEY_LEN / 2 {
+        return Some("it uses very few distinct byte values");
+    }
+    None
+}
+
+/// Read the key from a systemd credential: `$CREDENTIALS_DIRECTORY/<name>`,
+/// as delivered by `LoadCredential=` / `LoadCredentialEncrypted=`.
+pub fn from_credential(name: &str) -> Result<[u8; KEY_LEN]> {
+    let dir = std::env::var_os("CREDENTIALS_DIRECTORY")
+        .ok_or_else(|| anyhow!("CREDENTIALS_DIRECTORY is not set (not running under systemd?)"))?;
+    from_credential_in(Path::new(&dir), name)
+}
+
+/// Read credential `name` from the credentials directory `dir`.
+pub fn from_credential_in(dir: &Path, name: &str) -> Result<[u8; KEY_LEN]> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        bail!("Invalid credential name {:?}", name);
+    }
+    let path = dir.join(name);
+    let bytes = fs::read(&path).with_context(|| format!("Reading credential {:?}", path))?;
+    from_bytes(&bytes, Source::Credential(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_bytes_accepts_raw_and_hex() {
+        let raw = [0x5au8; KEY_LEN];
+        assert_eq!(from_bytes(&raw, Source::Flag).unwrap(), raw);
+        let hex = format!("{}\n", hex::encode(raw));
+        assert_eq!(from_bytes(hex.as_bytes(), Source::Flag).unwrap(), raw);
+        assert!(from_bytes(&[0u8; 16], Source::Flag).is_err());
+    }
+
+    #[test]
+    fn dashes_are_only_accepted_between_groups_of_eight() {
+        let key: [u8; KEY_LEN] = std::array::from_fn(|i| i as u8);
+        let hex = hex::encode(key);
+        let grouped = (0..8)
+            .map(|i| &hex[i * 8..i * 8 + 8])
+            .collect::<Vec<_>>()
+            .join("-");
+        assert_eq!(from_hex(&grouped, Source::Flag).unwrap(), key);
+        assert_eq!(from_hex(&hex, Source::Flag).unwrap(), key);
+
+        let stray = format!("{}-{}", &hex[..10], &hex[10..]);
+        assert!(from_hex(&stray, Source::Flag).is_err());
+        let dashed = hex.chars().flat_map(|c| [c, '-']).collect::<String>();
+        assert!(from_hex(&dashed, Source::Env).is_err());
+        assert!(from_bytes(stray.as_bytes(), Source::Flag).is_err());
+    }
+
+    #[test]
+    fn encodings_round_trip() {
+        let key: [u8; KEY_LEN] = std::array::from_fn(|i| i as u8 * 7);
+        for encoding in [Encoding::Hex, Encoding::Raw, Encoding::Base64] {
+            let encoded = encode(&key, encoding);
+            assert_eq!(decode(&encoded, encoding, Source::Flag).unwrap(), key);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let (hex_file, raw_file, b64_file, back) = (
+            dir.path().join("k.hex"),
+            dir.path().join("k.raw"),
+            dir.path().join("k.b64"),
+            dir.path().join("k2.hex"),
+        );
+        fs::write(&hex_file, encode(&key, Encoding::Hex)).unwrap();
+        convert(&hex_file, Encoding::Hex, &raw_file, Encoding::Raw).unwrap();
+        convert(&raw_file, Encoding::Raw, &b64_file, Encoding::Base64).unwrap();
+        convert(&b64_file, Encoding::Base64, &back, Encoding::Hex).unwrap();
+        assert_eq!(fs::read(&raw_file).unwrap(), key);
+        assert_eq!(fs::read(&back).unwrap(), fs::read(&hex_file).unwrap());
+        assert!(convert(&hex_file, Encoding::Hex, &raw_file, Encoding::Raw).is_err());
+    }
+
+    #[test]
+    fn wrong_length_keys_are_rejected() {
+        let flag = Source::Flag;
+        let err = decode(&[0u8; 31], Encoding::Raw, flag).unwrap_err();
+        assert_eq!(err.to_string(), "Key from --key is 31 bytes, need 32");
+        let short = BASE64.encode([0u8; 16]);
+        assert!(decode(short.as_bytes(), Encoding::Base64, flag).is_err());
+        assert!(decode(b"abcd", Encoding::Hex, flag).is_err());
+        assert!(decode(b"not base64!", Encoding::Base64, flag).is_err());
+    }
+
+    #[test]
+    fn wrong_length_errors_name_the_key_source() {
+        let short_hex = hex::encode([0x5au8; 16]);
+        let err = from_hex(&short_hex, Source::Flag).unwrap_err();
+        assert_eq!(err.to_string(), "Key from --key is 16 bytes, need 32");
+        let err = from_hex(&short_hex, Source::Env).unwrap_err();
+        assert_eq!(err.to_string(), "Key from CIPHER_KEY is 16 bytes, need 32");
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("short"), [0x5au8; 16]).unwrap();
+        let err = from_credential_in(dir.path(), "short").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Key from credential \"short\" is 16 bytes, need 32"
+        );
+
+        let file = dir.path().join("k.b64");
+        fs::write(&file, BASE64.encode([0x5au8; 24])).unwrap();
+        let out = dir.path().join("k.hex");
+        let err = convert(&file, Encoding::Base64, &out, Encoding::Hex).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Key from key file {:?} is 24 bytes, need 32", file)
+        );
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn low_entropy_keys_are_flagged() {
+        let sequential: [u8; KEY_LEN] = std::array::from_fn(|i| i as u8);
+        let pattern: [u8; KEY_LEN] = std::array::from_fn(|i| b"hunter2!"[i % 8]);
+        let few: [u8; KEY_LEN] = std::array::from_fn(|i| (i * i % 7) as u8);
+        for weak in [[0u8; KEY_LEN], [0x42; KEY_LEN], sequential, pattern, few] {
+            assert!(weakness(&weak).is_some(), "{weak:?}");
+        }
+        for _ in 0..100 {
+            let strong: [u8; KEY_LEN] = rand::random();
+            assert_eq!(weakness(&strong), None, "{strong:?}");
+        }
+    }
+
+    #[test]
+    fn key_is_read_from_systemd_credential() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = [0x7eu8; KEY_LEN];
+        fs::write(dir.path().join("vault-key"), raw).unwrap();
+
+        std::env::set_var("CREDENTIALS_DIRECTORY", dir.path());
+        assert_eq!(from_credential("vault-key").unwrap(), raw);
+        assert!(from_credential("missing").is_err());
+        assert!(from_credential("../vault-key").is_err());
+    }
+}
@@ -0,0 +1,194 @@
+//! Where the blocks of a block-format file are stored.
+//!
+//! `packed` keeps the header and every block in the backing file, one file
+//! per logical file. `separate` leaves only the header there and stores
+//! block `i` as `.cmblocks.<name>/<i>` beside it: many more files, but a
+//! block that doesn't change is a file that doesn't change, which
+//! deduplicating and file-level sync backends handle far better. The
+//! header's `FLAG_SEPARATE_BLOCKS` says which applies, so both layouts
+//! coexist in a vault and a file keeps its layout until it is rewritten.
+//!
+//! `dedup` keeps each distinct block once, in the vault's shared block
+//! store; the same `.cmblocks.<name>/<i>` entries are then hard links into
+//! the store, one per block the file references (see `dedup`).
+
+use crate::format::{Header, FLAG_DEDUP_BLOCKS, FLAG_SEPARATE_BLOCKS};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Prefix of the directory holding a file's separately stored blocks.
+pub const BLOCK_DIR_PREFIX: &str = ".cmblocks.";
+
+/// Block storage layout for files written from now on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Layout {
+    /// Header and blocks in one backing file.
+    #[default]
+    Packed,
+    /// One backing file per block.
+    Separate,
+    /// Identical blocks stored once and shared between files. Opt-in: it
+    /// reveals which blocks are equal (see `dedup`).
+    Dedup,
+}
+
+impl Layout {
+    /// Header flags selecting this layout.
+    pub fn flags(self) -> u8 {
+        match self {
+            Layout::Packed => 0,
+            Layout::Separate => FLAG_SEPARATE_BLOCKS,
+            Layout::Dedup => FLAG_DEDUP_BLOCKS,
+        }
+    }
+}
+
+/// Whether a backing entry named `name` is a block directory.
+pub fn is_block_dir(name: &str) -> bool {
+    name.starts_with(BLOCK_DIR_PREFIX)
+}
+
+/// Directory holding the separately stored blocks of the file at `path`.
+pub fn block_dir(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}{}", BLOCK_DIR_PREFIX, name))
+}
+
+/// Backing file of block `index` of the file at `path`.
+pub fn block_file(path: &Path, index: u64) -> PathBuf {
+    block_dir(path).join(index.to_string())
+}
+
+/// The sealed blocks of the packed stream `raw`, in order.
+pub fn split<'a>(raw: &'a [u8], header: &Header) -> io::Result<Vec<&'a [u8]>> {
+    (0..header.block_count())
+        .map(|index| {
+            let (start, len) = header.block_span(index);
+            raw.get(start as usize..start as usize + len)
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "block runs past the end")
+                })
+        })
+        .collect()
+}
+
+/// Read the file at `path` as the packed stream, gathering its blocks if
+/// they are stored separately.
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    let mut raw = fs::read(path)?;
+    // A legacy file's nonce may look like a header by chance; only a block
+    // directory makes it a separately stored file.
+    if let Some(header) = Header::decode(&raw).filter(|h| h.separate_blocks()) {
+        if block_dir(path).is_dir() {
+            for index in 0..header.block_count() {
+                raw.extend_from_slice(&fs::read(block_file(path, index))?);
+            }
+        }
+    }
+    Ok(raw)
+}
+
+/// Write the packed stream `raw` to `path` in the layout its header asks
+/// for, dropping blocks the file no longer has (all of them, if it is now
+/// packed).
+pub fn write(path: &Path, raw: &[u8]) -> io::Result<()> {
+    match Header::decode(raw).filter(|h| h.separate_blocks()) {
+        Some(header) => {
+            write_blocks(path, raw, &header)?;
+            fs::write(path, &raw[..header.size()])?;
+            prune(path, header.block_count())
+        }
+        None => {
+            fs::write(path, raw)?;
+            remove(path)
+        }
+    }
+}
+
+/// Store each block of the packed stream `raw` as a file of its own.
+pub fn write_blocks(path: &Path, raw: &[u8], header: &Header) -> io::Result<()> {
+    let blocks = split(raw, header)?;
+    fs::create_dir_all(block_dir(path))?;
+    for (index, sealed) in blocks.iter().enumerate() {
+        fs::write(block_file(path, index as u64), sealed)?;
+    }
+    Ok(())
+}
+
+/// Remove stored blocks of `path` from index `keep` on.
+pub fn prune(path: &Path, keep: u64) -> io::Result<()> {
+    prune_with(path, keep, |block| fs::remove_file(block))
+}
+
+/// Call `remove` on each stored block of `path` from index `keep` on.
+pub fn prune_with(
+    path: &Path,
+    keep: u64,
+    mut remove: impl FnMut(&Path) -> io::Result<()>,
+) -> io::Result<()> {
+    let entries = match fs::read_dir(block_dir(path)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        other => other?,
+    };
+    for entry in entries {
+        let entry = entry?;
+        let index = entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.parse::<u64>().ok());
+        if !matches!(index, Some(i) if i < keep) {
+            remove(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the separately stored blocks of `path`, if it has any.
+pub fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(block_dir(path)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::RandomNonces;
+    use crate::format;
+
+    const KEY: [u8; 32] = [0x42u8; 32];
+
+    fn encrypt(plaintext: &[u8], layout: Layout) -> Vec<u8> {
+        format::encrypt_file_with_flags(&KEY, &RandomNonces, plaintext, 64, layout.flags(), 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn separate_blocks_round_trip_and_shrink() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f");
+        let big = encrypt(&[7u8; 300], Layout::Separate);
+        write(&path, &big).unwrap();
+        assert_eq!(fs::read_dir(block_dir(&path)).unwrap().count(), 5);
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            format::HEADER_SIZE as u64
+        );
+        assert_eq!(read(&path).unwrap(), big);
+
+        let small = encrypt(&[8u8; 100], Layout::Separate);
+        write(&path, &small).unwrap();
+        assert_eq!(fs::read_dir(block_dir(&path)).unwrap().count(), 2);
+        assert_eq!(
+            format::decrypt_file(&KEY, &read(&path).unwrap()).unwrap(),
+            [8u8; 100]
+        );
+
+        let packed = encrypt(&[9u8; 100], Layout::Packed);
+        write(&path, &packed).unwrap();
+        assert!(!block_dir(&path).exists());
+        assert_eq!(read(&path).unwrap(), packed);
+    }
+}
@@ -1 +1,36 @@
+pub mod access;
+pub mod audit;
+pub mod backend;
+pub mod backup;
+pub mod cache;
+pub mod clock;
+#[allow(clippy::empty_line_after_doc_comments)]
 pub mod crypto;
+pub mod dedup;
+pub mod doctor;
+pub mod estimate;
+pub mod events;
+pub mod format;
+pub mod fuse;
+pub mod gocryptfs;
+pub mod idmap;
+pub mod image;
+pub mod key;
+pub mod layout;
+pub mod logging;
+pub mod maintenance;
+pub mod memlock;
+pub mod memprof;
+pub mod meta;
+pub mod mime;
+pub mod mountpoint;
+pub mod nonce;
+pub mod rewrite;
+pub mod scratch;
+pub mod scrub;
+pub mod shred;
+pub mod stats;
+pub mod stream;
+pub mod vault;
+pub mod walk;
+pub mod workers;
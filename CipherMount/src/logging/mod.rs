@@ -0,0 +1,38 @@
+//! Privacy-by-default logging.
+//!
+//! File names, sizes and offsets say a lot about what a vault holds, so log
+//! lines leave them out unless the operator opts in with `--log-sensitive`
+//! (for debugging on a trusted machine). Call sites wrap such values in
+//! `detail`, which prints them only when sensitive logging is on.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static SENSITIVE: AtomicBool = AtomicBool::new(false);
+
+/// Turn logging of vault details on or off for the whole process.
+pub fn set_sensitive(on: bool) {
+    SENSITIVE.store(on, Ordering::Relaxed);
+}
+
+pub fn sensitive() -> bool {
+    SENSITIVE.load(Ordering::Relaxed)
+}
+
+/// A value that is only logged with `--log-sensitive`.
+pub struct Detail<T>(T);
+
+/// Wrap `value` so it is logged only when sensitive logging is on.
+pub fn detail<T: fmt::Debug>(value: T) -> Detail<T> {
+    Detail(value)
+}
+
+impl<T: fmt::Debug> fmt::Display for Detail<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if sensitive() {
+            write!(f, "{:?}", self.0)
+        } else {
+            f.write_str("<hidden>")
+        }
+    }
+}
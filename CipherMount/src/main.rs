@@ -1,59 +1,1223 @@
-pub mod crypto;
-mod fuse;
-
-use clap::Parser;
-use fuser::MountOption;
+use clap::{Parser, Subcommand};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ciphermount::access::AccessLog;
+use ciphermount::backend::{CloseSync, LocalBackend, SyncPolicy, Transient};
+use ciphermount::fuse::{mount_options, CipherFS, Config, Hardening, OpenHandles};
+use ciphermount::gocryptfs::{GocryptfsFS, Volume};
+use ciphermount::idmap::{IdMap, Range};
+use ciphermount::image::{self, Image, ImageFS};
+use ciphermount::layout::Layout;
+use ciphermount::logging::{self, detail};
+use ciphermount::maintenance::{self, Maintenance, Window};
+use ciphermount::nonce::{Counter, NonceStrategy};
+use ciphermount::shred::SecureDelete;
+use ciphermount::{
+    audit, backup, doctor, estimate, format, key, memlock, memprof, meta, mountpoint, rewrite,
+    scratch, scrub, stats, stream, vault, walk,
+};
 
-use crate::fuse::CipherFS;
+/// How often `--on-mount-loss` checks that the source and mountpoint are
+/// still there.
+const MOUNT_CHECK_INTERVAL: Duration = Duration::from_secs(2);
 
 /// CipherMount — encrypted FUSE filesystem (AES-256-GCM)
 #[derive(Parser, Debug)]
-#[command(author, version, about)]
+#[command(
+    author,
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Physical backing directory (encrypted files stored here)
-    #[arg(short, long)]
-    source: PathBuf,
+    #[arg(short, long, required_unless_present_any = ["image", "ephemeral", "gocryptfs"])]
+    source: Option<PathBuf>,
+
+    /// Serve a packed image (see `pack`) read-only instead of a vault
+    #[arg(long, value_name = "FILE", conflicts_with = "source")]
+    image: Option<PathBuf>,
+
+    /// Serve a gocryptfs vault read-only instead of a vault; --key is its
+    /// master key (as printed by gocryptfs, dashes allowed)
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["source", "image"])]
+    gocryptfs: Option<PathBuf>,
+
+    /// Mount a throwaway vault kept in RAM (under /dev/shm) with a random
+    /// key: still encrypted in memory, gone after unmount. Implies --mlock
+    #[arg(long, default_value_t = false, conflicts_with_all = ["source", "image", "gocryptfs"])]
+    ephemeral: bool,
 
     /// Mount point (decrypted view exposed here)
-    #[arg(short, long)]
-    mountpoint: PathBuf,
+    #[arg(short, long, required = true)]
+    mountpoint: Option<PathBuf>,
 
-    /// 32-byte key as 64-char hex string. Can also be set via CIPHER_KEY env var.
-    #[arg(short, long, env = "CIPHER_KEY")]
-    key: String,
+    /// Create the mount point (and its parents) if it does not exist
+    #[arg(long, default_value_t = false)]
+    create_mountpoint: bool,
+
+    /// What to do if the source, or the directory holding the mountpoint,
+    /// becomes unreachable while mounted: continue (log it and keep serving
+    /// cached data) or unmount. Unwatched if not given
+    #[arg(long, value_enum, value_name = "POLICY")]
+    on_mount_loss: Option<mountpoint::LossPolicy>,
+
+    #[command(flatten)]
+    key: KeyArgs,
+
+    /// Include file names, sizes and offsets in log lines (they are left
+    /// out by default, as they can reveal what the vault holds)
+    #[arg(long, global = true, default_value_t = false)]
+    log_sensitive: bool,
+
+    /// Refuse to read files with bytes past their last block, instead of
+    /// ignoring those bytes
+    #[arg(long, global = true, default_value_t = false)]
+    strict_trailing: bool,
 
     /// Allow other users to access the mount
     #[arg(long, default_value_t = false)]
     allow_other: bool,
+
+    #[command(flatten)]
+    hardening: HardeningArgs,
+
+    /// Mount read-only: opens for writing and every change fail with EROFS
+    #[arg(long, default_value_t = false)]
+    read_only: bool,
+
+    /// Incident-response mode: mount read-only and record every access in
+    /// the --access-log, so data stays available while nothing changes
+    #[arg(long, default_value_t = false, requires = "access_log")]
+    panic: bool,
+
+    /// Dry run: reads work, while writes, truncations, deletions and renames
+    /// are recorded (in the --access-log if given, else the log) and
+    /// reported as done without changing the vault
+    #[arg(long, default_value_t = false, conflicts_with_all = ["read_only", "panic"])]
+    shadow: bool,
+
+    /// Append a line (time, uid, operation, path) for every open, read and
+    /// listing to FILE. Paths are written in the clear; keep it private
+    #[arg(long, value_name = "FILE")]
+    access_log: Option<PathBuf>,
+
+    /// Refuse (EAGAIN) accesses beyond N per second from any one uid
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "access_log",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    rate_limit: Option<u32>,
+
+    /// Refuse to mount a vault that has no canary file (see `init`)
+    #[arg(long, default_value_t = false)]
+    canary_file: bool,
+
+    /// Expire new files after this many seconds (per-file override via the
+    /// `user.ciphermount.ttl` xattr). Expired files are hidden and deleted.
+    #[arg(long, value_name = "SECS")]
+    default_ttl: Option<u64>,
+
+    /// How often to sweep the vault for expired files, in seconds (0 = only
+    /// reclaim expired files when they are looked up)
+    #[arg(long, value_name = "SECS", default_value_t = 300)]
+    ttl_gc_interval: u64,
+
+    /// Plaintext bytes per encrypted block for files written by this mount.
+    /// Existing files keep their own block size (or legacy format) until
+    /// they are next written.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = format::DEFAULT_BLOCK_SIZE,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    block_size: u32,
+
+    /// Where the blocks of files written whole by this mount are stored:
+    /// packed into one backing file, separate (one backing file per block),
+    /// or dedup (identical blocks stored once; reveals which blocks are equal)
+    #[arg(long, value_enum, default_value_t = Layout::Packed)]
+    block_layout: Layout,
+
+    /// How file contents get their nonces: random, or counter (numbered
+    /// from a persisted per-vault counter; refuses to mount while another
+    /// writer holds it)
+    #[arg(long, value_enum, default_value_t = NonceStrategy::Random)]
+    nonce_strategy: NonceStrategy,
+
+    /// Store new files as authenticated plaintext: readable without the
+    /// key, but any change to them is detected. Existing files keep their
+    /// own mode. Cannot be combined with --block-layout dedup
+    #[arg(long)]
+    integrity_only: bool,
+
+    /// Fail a backing read/write with ETIMEDOUT if it takes longer than this
+    /// many milliseconds (for slow or network-backed sources)
+    #[arg(long, value_name = "MS")]
+    op_timeout: Option<u64>,
+
+    /// Retry a backing read/write failing with a transient error (EINTR,
+    /// EAGAIN, plus --retry-on) up to N times, waiting 10 ms, 20 ms, 40 ms...
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    io_retries: u32,
+
+    /// Also retry these errors: timed-out, connection, io (comma-separated)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    retry_on: Vec<Transient>,
+
+    /// Sync each backing write to stable storage: none, data (fdatasync)
+    /// or full (fsync)
+    #[arg(long, value_enum, default_value_t = SyncPolicy::Data)]
+    backing_sync: SyncPolicy,
+
+    /// fsync files as they are closed: always, never, or dirty (only if
+    /// the handle wrote since it was last synced)
+    #[arg(long, value_enum, default_value_t = CloseSync::Never)]
+    fsync_on_close: CloseSync,
+
+    /// Number of decrypted blocks to keep in memory (0 disables the cache)
+    #[arg(long, value_name = "BLOCKS", default_value_t = 1024)]
+    cache_blocks: usize,
+
+    /// Re-verify the GCM tag of cached blocks on every read instead of
+    /// trusting decrypted data held in memory
+    #[arg(long, default_value_t = false)]
+    always_authenticate: bool,
+
+    /// Scrub the vault in the background every SECS seconds (0 disables).
+    /// Only files changed or not verified within a week are re-checked.
+    #[arg(long, value_name = "SECS", default_value_t = 0)]
+    scrub_interval: u64,
+
+    /// Only run background scrubs and expiry sweeps between START and END
+    /// local time (HH:MM-HH:MM, e.g. 22:00-06:00); a scrub still running
+    /// when the window closes stops and resumes in the next one
+    #[arg(long, value_name = "START-END", value_parser = parse_window)]
+    maintenance_window: Option<Window>,
+
+    /// Open the maintenance window on these days only (e.g. sat,sun); a
+    /// window past midnight belongs to the day it starts on
+    #[arg(long, value_name = "DAYS", requires = "maintenance_window", value_parser = parse_days)]
+    maintenance_days: Option<u8>,
+
+    /// Record the uid of each file's last writer in its authenticated
+    /// metadata (readable as the user.ciphermount.writer xattr)
+    #[arg(long, default_value_t = false)]
+    record_writer: bool,
+
+    /// Record the content type of each file, recognised from the start of
+    /// what is written to it, in its authenticated metadata (readable as
+    /// the user.ciphermount.mime xattr)
+    #[arg(long, default_value_t = false)]
+    sniff_content_type: bool,
+
+    /// Show backing file owners translated into a user namespace: host uids
+    /// OUTSIDE.. appear as INSIDE.. (INSIDE:OUTSIDE:COUNT, comma-separated,
+    /// as in /proc/PID/uid_map). Unmapped uids appear as 65534
+    #[arg(long, value_name = "INSIDE:OUTSIDE:COUNT", value_delimiter = ',')]
+    uid_map: Vec<Range>,
+
+    /// Like --uid-map, for group ids
+    #[arg(long, value_name = "INSIDE:OUTSIDE:COUNT", value_delimiter = ',')]
+    gid_map: Vec<Range>,
+
+    /// Serve a read-only .ciphermount-errors file in the mount root listing
+    /// recent decryption failures
+    #[arg(long, default_value_t = false)]
+    error_file: bool,
+
+    /// List directories in a stable, key-derived order instead of the
+    /// backing store's, so listings don't reveal creation order
+    #[arg(long, default_value_t = false)]
+    shuffle_listings: bool,
+
+    /// Refuse (ENAMETOOLONG) new names longer than N bytes, whatever the
+    /// backing filesystem would take
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    max_name_length: Option<u16>,
+
+    /// Give every new file this mode (octal, e.g. 0640) and every new
+    /// directory the same plus search wherever it grants read, whatever
+    /// the creating process asked for
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    force_mode: Option<u32>,
+
+    /// Serve only this directory of the vault (relative to its root) as
+    /// the mount's root; nothing outside it is reachable
+    #[arg(long, value_name = "REL")]
+    subpath: Option<PathBuf>,
+
+    /// What deleting a file does to its backing data: `overwrite` zeroes
+    /// and syncs it before it is unlinked; `crypto-erase` gives every file
+    /// written a key of its own and destroys that key instead
+    #[arg(long, value_enum, default_value_t = SecureDelete::None)]
+    secure_delete: SecureDelete,
+
+    /// Background FUSE requests (readahead, writeback) the kernel may keep
+    /// in flight (default: the kernel's, usually 12)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    max_background: Option<u16>,
+
+    /// In-flight background requests at which the kernel treats the mount as
+    /// congested (default: the kernel's, 3/4 of max-background)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    congestion_threshold: Option<u16>,
+
+    /// Worker threads serving reads, writes and fsyncs, so requests for
+    /// different files run in parallel; 1 serves everything in turn
+    #[arg(long, value_name = "N", default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    threads: u16,
+
+    /// Mark files incomplete while they are open for writing, so files cut
+    /// short by a crash are reported as incomplete (not corrupt) by `scrub`
+    #[arg(long, default_value_t = false)]
+    mark_incomplete: bool,
+
+    /// Lock decrypted blocks in memory so they are never swapped to disk,
+    /// and keep file contents out of the kernel page cache (direct I/O).
+    /// Needs an RLIMIT_MEMLOCK of at least --cache-blocks × --block-size
+    #[arg(long, default_value_t = false)]
+    mlock: bool,
+
+    /// Count the memory whole-file reads and writes buffer, logging any
+    /// operation that buffers more than BYTES and the peak at unmount
+    /// (and on SIGUSR2)
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    profile_memory: Option<u64>,
+
+    /// Cap the memory whole-file reads and writes buffer across all
+    /// operations at once; operations wait their turn beyond it
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    memory_budget: Option<u64>,
+
+    /// Store new files' ciphertext as NAME.EXT (e.g. `cmenc`) so backup and
+    /// sync tools see opaque data; the mount shows them without it
+    #[arg(long, value_name = "EXT", value_parser = parse_extension)]
+    backing_extension: Option<String>,
+}
+
+/// Permission bits in octal, with or without a leading `0o`.
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err("must be octal permission bits such as 0640".to_string()),
+    }
+}
+
+/// A backing extension: a non-empty name part without dots or slashes.
+fn parse_extension(ext: &str) -> Result<String, String> {
+    let ext = ext.strip_prefix('.').unwrap_or(ext);
+    if ext.is_empty() || ext.contains(['.', '/']) {
+        return Err("must be a single extension such as `cmenc`".to_string());
+    }
+    Ok(ext.to_string())
+}
+
+/// A maintenance window, `HH:MM-HH:MM` in local time.
+fn parse_window(window: &str) -> Result<Window, String> {
+    window.parse().map_err(|e: anyhow::Error| e.to_string())
+}
+
+/// Days of the week a maintenance window applies on, such as `sat,sun`.
+fn parse_days(days: &str) -> Result<u8, String> {
+    maintenance::parse_days(days).map_err(|e| e.to_string())
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Initialise a vault: create the backing directory and write its canary
+    Init {
+        /// Physical backing directory to initialise
+        #[arg(short, long)]
+        source: PathBuf,
+
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+
+    /// Pack a vault into a single read-only image that can be mounted with
+    /// --image
+    Pack {
+        /// Vault to pack
+        #[arg(short, long)]
+        source: PathBuf,
+
+        /// Image file to write
+        #[arg(long, value_name = "FILE")]
+        out: PathBuf,
+
+        /// Plaintext bytes per compressed, encrypted chunk
+        #[arg(long, default_value_t = image::DEFAULT_CHUNK_SIZE,
+              value_parser = clap::value_parser!(u32).range(1..))]
+        chunk_size: u32,
+
+        #[command(flatten)]
+        walk: WalkArgs,
+
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+
+    /// Train a compression dictionary on the vault's files and store it in
+    /// vault.meta, for `pack` to use
+    TrainDictionary {
+        /// Vault to train on
+        #[arg(short, long)]
+        source: PathBuf,
+
+        /// Largest dictionary to train, in bytes
+        #[arg(long, value_name = "BYTES", default_value_t = 64 * 1024)]
+        max_size: usize,
+
+        #[command(flatten)]
+        walk: WalkArgs,
+
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+
+    /// Rewrite every file of an unmounted vault with a new block size
+    Reblock {
+        /// Vault to rewrite
+        #[arg(short, long)]
+        source: PathBuf,
+
+        /// New plaintext bytes per encrypted block
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        block_size: u32,
+
+        #[command(flatten)]
+        walk: WalkArgs,
+
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+
+    /// Project how much space a vault (and optionally a packed image) of a
+    /// plaintext tree would take, without writing anything
+    Estimate {
+        /// Plaintext directory to analyse
+        #[arg(long)]
+        plaintext: PathBuf,
+
+        /// Plaintext bytes per encrypted block
+        #[arg(long, default_value_t = format::DEFAULT_BLOCK_SIZE,
+              value_parser = clap::value_parser!(u32).range(1..))]
+        block_size: u32,
+
+        /// Also size a compressed `pack` image (compresses every file)
+        #[arg(long, default_value_t = false)]
+        compress: bool,
+
+        /// Plaintext bytes per compressed chunk of the image
+        #[arg(long, default_value_t = image::DEFAULT_CHUNK_SIZE,
+              value_parser = clap::value_parser!(u32).range(1..))]
+        chunk_size: u32,
+
+        #[command(flatten)]
+        walk: WalkArgs,
+    },
+
+    /// Decrypt one file of an unmounted vault to stdout (or FILE, such as
+    /// a named pipe) a block at a time, in bounded memory
+    Stream {
+        /// Vault holding the file
+        #[arg(short, long)]
+        source: PathBuf,
+
+        /// Backing path of the file, relative to the vault root
+        #[arg(long)]
+        path: PathBuf,
+
+        /// Write the plaintext here instead of to stdout
+        #[arg(long, value_name = "FILE")]
+        out: Option<PathBuf>,
+
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+
+    /// Report files whose ciphertext is larger than their contents justify
+    Audit {
+        /// Vault to audit
+        #[arg(short, long)]
+        source: PathBuf,
+
+        #[command(flatten)]
+        walk: WalkArgs,
+
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+
+    /// Check that a backup copy of a vault has the same files, byte for
+    /// byte, and that every one of them authenticates
+    ValidateBackup {
+        /// Original vault
+        #[arg(short, long)]
+        source: PathBuf,
+
+        /// Backup copy to check
+        #[arg(long)]
+        backup: PathBuf,
+
+        #[command(flatten)]
+        walk: WalkArgs,
+
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+
+    /// Re-authenticate the vault's files, skipping ones verified recently
+    /// and unchanged since
+    Scrub {
+        /// Vault to scrub
+        #[arg(short, long)]
+        source: PathBuf,
+
+        /// Verify every file, ignoring when each was last verified
+        #[arg(long, default_value_t = false)]
+        full: bool,
+
+        /// Re-verify unchanged files last verified longer ago than this
+        #[arg(long, value_name = "SECS", default_value_t = scrub::DEFAULT_WINDOW.as_secs())]
+        window: u64,
+
+        /// Reseal files that verify but are stored in an outdated format
+        /// (legacy, or version 1 headers). Only for unmounted vaults
+        #[arg(long, default_value_t = false)]
+        repair: bool,
+
+        /// Pause this long after each repaired file
+        #[arg(long, value_name = "MS", default_value_t = 0)]
+        repair_throttle: u64,
+
+        #[command(flatten)]
+        walk: WalkArgs,
+
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+
+    /// Summarise a vault: file count, logical and on-disk size, and how
+    /// the files are stored
+    Stats {
+        /// Vault to summarise
+        #[arg(short, long)]
+        source: PathBuf,
+
+        /// Print the summary as JSON
+        #[arg(long, default_value_t = false)]
+        json: bool,
+
+        #[command(flatten)]
+        walk: WalkArgs,
+
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+
+    /// Check a vault and this machine for common mount problems: wrong
+    /// key, unsuitable backing store, loose permissions, FUSE setup
+    Doctor {
+        /// Vault to check
+        #[arg(short, long)]
+        source: PathBuf,
+
+        /// Key to check against the vault (optional)
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+
+    /// Key file utilities
+    Key {
+        #[command(subcommand)]
+        command: KeyCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum KeyCommand {
+    /// Validate a key file and write it out in another encoding
+    Convert {
+        /// Key file to read
+        #[arg(long = "in", value_name = "FILE")]
+        input: PathBuf,
+
+        /// Encoding of the input file
+        #[arg(long, value_enum)]
+        in_format: key::Encoding,
+
+        /// New key file to write (created with mode 0600; must not exist)
+        #[arg(long = "out", value_name = "FILE")]
+        output: PathBuf,
+
+        /// Encoding of the output file
+        #[arg(long, value_enum)]
+        out_format: key::Encoding,
+    },
+
+    /// Print the key's non-secret fingerprint, as recorded by `init`
+    Fingerprint {
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+}
+
+/// How bulk commands walk the vault.
+#[derive(clap::Args, Debug)]
+struct WalkArgs {
+    /// Refuse to descend more than this many directory levels
+    #[arg(long, default_value_t = walk::DEFAULT_MAX_DEPTH)]
+    max_depth: usize,
+
+    /// Descend into symlinked directories (cycles are detected and reported)
+    #[arg(long, default_value_t = false)]
+    follow_links: bool,
+}
+
+impl WalkArgs {
+    fn options(&self) -> walk::Options {
+        walk::Options {
+            max_depth: self.max_depth,
+            follow_links: self.follow_links,
+        }
+    }
+}
+
+/// Kernel restrictions on the mounted files. Each defaults to the safe
+/// side; the last of a pair given wins.
+#[derive(clap::Args, Debug)]
+struct HardeningArgs {
+    /// Allow executing programs stored in the vault
+    #[arg(long, overrides_with = "no_exec")]
+    exec: bool,
+
+    /// Refuse to execute files (default)
+    #[arg(long, overrides_with = "exec")]
+    no_exec: bool,
+
+    /// Honour setuid/setgid bits on files in the vault
+    #[arg(long, overrides_with = "no_suid")]
+    suid: bool,
+
+    /// Ignore setuid/setgid bits (default)
+    #[arg(long, overrides_with = "suid")]
+    no_suid: bool,
+
+    /// Allow device nodes in the vault to be used as devices
+    #[arg(long, overrides_with = "no_dev")]
+    dev: bool,
+
+    /// Refuse to open device nodes as devices (default)
+    #[arg(long, overrides_with = "dev")]
+    no_dev: bool,
+}
+
+impl HardeningArgs {
+    fn hardening(&self) -> Hardening {
+        Hardening {
+            exec: self.exec,
+            suid: self.suid,
+            dev: self.dev,
+        }
+    }
+}
+
+/// Where to get the 32-byte key from.
+#[derive(clap::Args, Debug)]
+struct KeyArgs {
+    /// 32-byte key as 64-char hex string. Can also be set via CIPHER_KEY env var.
+    #[arg(short, long, env = "CIPHER_KEY", hide_env_values = true)]
+    key: Option<String>,
+
+    /// Read the key (raw or hex) from the systemd credential NAME, i.e.
+    /// $CREDENTIALS_DIRECTORY/NAME. Takes precedence over --key.
+    #[arg(long, value_name = "NAME")]
+    key_credential: Option<String>,
+
+    /// Refuse keys that look weak (repeated or patterned bytes) instead of
+    /// only warning about them
+    #[arg(long, default_value_t = false)]
+    strict_key: bool,
+}
+
+impl KeyArgs {
+    /// Whether any key source was given.
+    fn given(&self) -> bool {
+        self.key.is_some() || self.key_credential.is_some()
+    }
+
+    fn load(&self) -> anyhow::Result<[u8; 32]> {
+        let key = match (&self.key_credential, &self.key) {
+            (Some(name), _) => key::from_credential(name)?,
+            (None, Some(hex_key)) => {
+                // clap fills --key from CIPHER_KEY when the flag is absent.
+                let from_env =
+                    std::env::var_os("CIPHER_KEY").is_some_and(|v| v == hex_key.as_str());
+                let source = if from_env {
+                    key::Source::Env
+                } else {
+                    key::Source::Flag
+                };
+                key::from_hex(hex_key, source)?
+            }
+            (None, None) => {
+                anyhow::bail!("No key given: use --key, CIPHER_KEY or --key-credential")
+            }
+        };
+        if let Some(reason) = key::weakness(&key) {
+            if self.strict_key {
+                anyhow::bail!("Key looks weak: {} (refusing with --strict-key)", reason);
+            }
+            log::warn!(
+                "Key looks weak: {}; generate one with `openssl rand -hex 32`",
+                reason
+            );
+        }
+        Ok(key)
+    }
+}
+
+/// Log a dump of the mount's open handles, and its memory profile if there
+/// is one, whenever the process gets SIGUSR2. The signal is blocked in the
+/// calling thread, and so in every thread it starts afterwards, leaving
+/// only the dumper's `sigwait` to take it.
+fn dump_handles_on_sigusr2(handles: OpenHandles, memory: Option<Arc<memprof::MemoryProfile>>) {
+    // SAFETY: an all-zero sigset_t is valid storage for sigemptyset.
+    let mut set: libc::sigset_t = unsafe { std::mem::zeroed() };
+    // SAFETY: `set` is a valid signal set for all three calls.
+    unsafe {
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGUSR2);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+    }
+    std::thread::spawn(move || loop {
+        let mut signal = 0;
+        // SAFETY: `set` and `signal` are valid for the call.
+        if unsafe { libc::sigwait(&set, &mut signal) } == 0 {
+            log::info!("Open handles:\n{}", handles.report());
+            if let Some(memory) = &memory {
+                log::info!("Memory: {}", memory.report());
+            }
+        }
+    });
+}
+
+/// One incremental scrub of `source`, persisting what was verified. Stops
+/// early once `keep_going` returns false.
+fn run_scrub(
+    source: &std::path::Path,
+    key: &[u8; 32],
+    options: scrub::Options,
+    keep_going: impl Fn() -> bool,
+) -> anyhow::Result<scrub::Report> {
+    let mut state = scrub::State::load(source, key);
+    let report = scrub::scrub_while(source, key, &mut state, meta::now(), options, keep_going)?;
+    state.store(source, key)?;
+    log::info!(
+        "Scrub{}: {} verified, {} skipped as recently verified, {} failed",
+        if report.paused { " paused" } else { "" },
+        report.verified,
+        report.skipped,
+        report.failed.len()
+    );
+    Ok(report)
 }
 
 fn main() -> anyhow::Result<()> {
     env_logger::init();
 
     let args = Args::parse();
+    logging::set_sensitive(args.log_sensitive);
+    format::set_strict_trailing(args.strict_trailing);
 
-    let key_bytes = hex::decode(&args.key)
-        .map_err(|e| anyhow::anyhow!("Invalid key (must be 64-char hex): {}", e))?;
-    anyhow::ensure!(key_bytes.len() == 32, "Key must be exactly 32 bytes (64 hex chars)");
+    if let Some(command) = args.command {
+        return match command {
+            Command::Init { source, key } => {
+                vault::init(&source, &key.load()?)?;
+                log::info!("Initialised vault at {:?}", source);
+                Ok(())
+            }
+            Command::Pack {
+                source,
+                out,
+                chunk_size,
+                walk,
+                key,
+            } => {
+                let files = image::pack(&source, &key.load()?, &out, chunk_size, walk.options())?;
+                log::info!("Packed {} file(s) from {:?} into {:?}", files, source, out);
+                Ok(())
+            }
+            Command::TrainDictionary {
+                source,
+                max_size,
+                walk,
+                key,
+            } => {
+                let size =
+                    vault::train_dictionary(&source, &key.load()?, max_size, walk.options())?;
+                log::info!("Stored a {}-byte dictionary in {:?}", size, source);
+                Ok(())
+            }
+            Command::Reblock {
+                source,
+                block_size,
+                walk,
+                key,
+            } => {
+                let key = key.load()?;
+                let summary = rewrite::reblock(&source, &key, block_size, walk.options(), |p| {
+                    let what = if p.skipped {
+                        "already done"
+                    } else {
+                        "rewritten"
+                    };
+                    log::info!("[{}/{}] {}: {}", p.done, p.total, detail(&p.path), what);
+                })?;
+                log::info!(
+                    "Reblocked {} file(s) to {} bytes ({} already at that size)",
+                    summary.rewritten,
+                    block_size,
+                    summary.skipped
+                );
+                Ok(())
+            }
+            Command::Estimate {
+                plaintext,
+                block_size,
+                compress,
+                chunk_size,
+                walk,
+            } => {
+                let options = estimate::Options {
+                    block_size,
+                    chunk_size: compress.then_some(chunk_size),
+                };
+                let e = estimate::estimate(&plaintext, walk.options(), options)?;
+                let percent = |n: u64| (n as f64 / e.plaintext.max(1) as f64 - 1.0) * 100.0;
+                println!("Files:      {} ({} directories)", e.files, e.dirs);
+                println!("Plaintext:  {} bytes", e.plaintext);
+                println!(
+                    "Vault:      {} bytes ({:+.1}%, {} bytes per file at block size {})",
+                    e.vault,
+                    percent(e.vault),
+                    e.per_file_overhead(),
+                    block_size
+                );
+                if let (Some(image), Some(ratio)) = (e.image, e.compression_ratio()) {
+                    println!(
+                        "Image:      {} bytes ({:+.1}%, compression ratio {:.2})",
+                        image,
+                        percent(image),
+                        ratio
+                    );
+                }
+                Ok(())
+            }
+            Command::Stream {
+                source,
+                path,
+                out,
+                key,
+            } => {
+                let key = key.load()?;
+                let streamed = match out {
+                    Some(out) => {
+                        let mut file = std::fs::File::create(&out)?;
+                        stream::stream(&key, &source, &path, &mut file)
+                    }
+                    None => {
+                        let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+                        stream::stream(&key, &source, &path, &mut stdout)
+                    }
+                };
+                let broken_pipe = |e: &anyhow::Error| {
+                    e.downcast_ref::<std::io::Error>()
+                        .is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe)
+                };
+                match streamed {
+                    // The reader stopped early (`| head`); that is its call.
+                    Err(e) if broken_pipe(&e) => Ok(()),
+                    other => other.map(|_| ()),
+                }
+            }
+            Command::Audit { source, walk, key } => {
+                let findings = audit::audit(&source, &key.load()?, walk.options())?;
+                for finding in &findings {
+                    println!("{}: {}", finding.path.display(), finding.issue);
+                }
+                if !findings.is_empty() {
+                    anyhow::bail!(
+                        "{} issue(s) found (`reblock` rewrites files with trailing bytes)",
+                        findings.len()
+                    );
+                }
+                Ok(())
+            }
+            Command::ValidateBackup {
+                source,
+                backup,
+                walk,
+                key,
+            } => {
+                let findings = backup::validate(&source, &backup, &key.load()?, walk.options())?;
+                for finding in &findings {
+                    println!("{}: {}", finding.path.display(), finding.problem);
+                }
+                if !findings.is_empty() {
+                    anyhow::bail!("Backup {:?} has {} problem(s)", backup, findings.len());
+                }
+                Ok(())
+            }
+            Command::Scrub {
+                source,
+                full,
+                window,
+                repair,
+                repair_throttle,
+                walk,
+                key,
+            } => {
+                let options = scrub::Options {
+                    full,
+                    window: Duration::from_secs(window),
+                    repair,
+                    throttle: Duration::from_millis(repair_throttle),
+                    walk: walk.options(),
+                };
+                let report = run_scrub(&source, &key.load()?, options, || true)?;
+                for path in &report.repaired {
+                    println!("{}: resealed in the current format", path.display());
+                }
+                for (path, reason) in &report.failed {
+                    println!("{}: {}", path.display(), reason);
+                }
+                for path in &report.incomplete {
+                    println!(
+                        "{}: incomplete (an earlier write was interrupted)",
+                        path.display()
+                    );
+                }
+                if !report.failed.is_empty() {
+                    anyhow::bail!("{} file(s) failed verification", report.failed.len());
+                }
+                Ok(())
+            }
+            Command::Stats {
+                source,
+                json,
+                walk,
+                key,
+            } => {
+                let s = stats::stats(&source, &key.load()?, walk.options())?;
+                if json {
+                    println!("{}", s.to_json());
+                    return Ok(());
+                }
+                let breakdown = |map: &BTreeMap<String, u64>| {
+                    map.iter()
+                        .map(|(name, n)| format!("{} {}", n, name))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                };
+                println!("Files:      {}", s.files);
+                println!("Logical:    {} bytes", s.logical);
+                println!("Physical:   {} bytes", s.physical);
+                println!("Formats:    {}", breakdown(&s.formats));
+                println!("Ciphers:    {}", breakdown(&s.ciphers));
+                println!("Layouts:    {}", breakdown(&s.layouts));
+                if s.integrity_only > 0 {
+                    println!("Integrity-only: {}", s.integrity_only);
+                }
+                if s.unauthenticated > 0 {
+                    log::warn!(
+                        "{} file header(s) failed to authenticate under this key",
+                        s.unauthenticated
+                    );
+                }
+                Ok(())
+            }
+            Command::Doctor { source, key } => {
+                let key = key.given().then(|| key.load()).transpose()?;
+                let checks = doctor::diagnose(&source, key.as_ref(), &LocalBackend);
+                for check in &checks {
+                    println!("[{}] {}: {}", check.status, check.name, check.detail);
+                    if let Some(hint) = check.hint {
+                        println!("       {}", hint);
+                    }
+                }
+                let failed = checks
+                    .iter()
+                    .filter(|c| c.status == doctor::Status::Fail)
+                    .count();
+                if failed > 0 {
+                    anyhow::bail!("{} check(s) failed", failed);
+                }
+                Ok(())
+            }
+            Command::Key {
+                command:
+                    KeyCommand::Convert {
+                        input,
+                        in_format,
+                        output,
+                        out_format,
+                    },
+            } => key::convert(&input, in_format, &output, out_format),
+            Command::Key {
+                command: KeyCommand::Fingerprint { key },
+            } => {
+                println!("{}", vault::fingerprint(&key.load()?));
+                Ok(())
+            }
+        };
+    }
+
+    // clap enforces these when no subcommand is given
+    let mountpoint = args.mountpoint.unwrap();
+    // An ephemeral vault's key never leaves this process.
+    let key = if args.ephemeral {
+        rand::random()
+    } else {
+        args.key.load()?
+    };
 
-    let key: [u8; 32] = key_bytes.try_into().unwrap();
+    if let mountpoint::Mountpoint::NonEmpty(n) =
+        mountpoint::check(&mountpoint, args.create_mountpoint)?
+    {
+        log::warn!(
+            "Mountpoint {:?} is not empty: its {} entry(ies) will be hidden while mounted",
+            mountpoint,
+            n
+        );
+    }
+
+    if let Some(path) = args.image {
+        log::info!("CipherMount serving image {:?} at {:?}", path, mountpoint);
+        let fs = ImageFS::new(Image::open(&path, &key)?, &path)?;
+        let options = mount_options(args.hardening.hardening(), args.allow_other, true);
+        fuser::mount2(fs, &mountpoint, &options)?;
+        return Ok(());
+    }
+    if let Some(path) = args.gocryptfs {
+        log::info!(
+            "CipherMount serving gocryptfs vault {:?} at {:?}",
+            path,
+            mountpoint
+        );
+        let fs = GocryptfsFS::new(Volume::open(&path, &key)?);
+        let options = mount_options(args.hardening.hardening(), args.allow_other, true);
+        fuser::mount2(fs, &mountpoint, &options)?;
+        return Ok(());
+    }
+    let scratch = if args.ephemeral {
+        let scratch = scratch::Scratch::create(std::path::Path::new(scratch::DEFAULT_ROOT))?;
+        vault::init(scratch.path(), &key)?;
+        Some(scratch)
+    } else {
+        None
+    };
+    let source = match &scratch {
+        Some(scratch) => scratch.path().to_path_buf(),
+        None => args.source.unwrap(),
+    };
 
     log::info!("CipherMount starting");
-    log::info!("  Source:     {:?}", args.source);
-    log::info!("  Mountpoint: {:?}", args.mountpoint);
+    log::info!("  Source:     {:?}", source);
+    log::info!("  Mountpoint: {:?}", mountpoint);
+
+    if vault::check_fingerprint(&source, &key)? {
+        log::info!("  Key:        {}", vault::fingerprint(&key));
+    }
+    match vault::verify_canary(&source, &key)? {
+        vault::Canary::Valid => log::info!("  Canary:     ok"),
+        vault::Canary::Missing if args.canary_file => {
+            anyhow::bail!("No canary in {:?} (run `ciphermount init` first)", source)
+        }
+        vault::Canary::Missing => log::warn!("  Canary:     none (key cannot be checked)"),
+    }
+
+    if let Some(subpath) = &args.subpath {
+        let root = vault::subtree(&source, subpath)?;
+        log::info!("  Subpath:    {:?}", root);
+    }
 
-    let mut options = vec![
-        MountOption::FSName("ciphermount".to_string()),
-        MountOption::AutoUnmount,
-        MountOption::NoExec,
-    ];
-    if args.allow_other {
-        options.push(MountOption::AllowOther);
+    let read_only = args.read_only || args.panic;
+    if args.panic {
+        log::warn!("  Panic mode: read-only, every access is logged");
     }
+    if args.shadow {
+        log::warn!("  Shadow mode: changes are recorded, not applied");
+    }
+    let options = mount_options(args.hardening.hardening(), args.allow_other, read_only);
+    // Only the kernel is told a shadow mount is writable, so that writes
+    // reach it; the vault itself is as safe as on a read-only mount.
+    let read_only = read_only || args.shadow;
+
+    if args.integrity_only && args.block_layout == Layout::Dedup {
+        anyhow::bail!("--integrity-only cannot be combined with --block-layout dedup");
+    }
+    if args.secure_delete == SecureDelete::CryptoErase {
+        if args.integrity_only {
+            anyhow::bail!("--secure-delete crypto-erase cannot be combined with --integrity-only");
+        }
+        if args.block_layout == Layout::Dedup {
+            anyhow::bail!(
+                "--secure-delete crypto-erase cannot be combined with --block-layout dedup"
+            );
+        }
+    }
+    let config = Config {
+        block_size: args.block_size,
+        block_layout: args.block_layout,
+        default_ttl: args.default_ttl.map(Duration::from_secs),
+        op_timeout: args.op_timeout.map(Duration::from_millis),
+        io_retries: args.io_retries,
+        retry_on: args.retry_on,
+        backing_sync: args.backing_sync,
+        close_sync: args.fsync_on_close,
+        cache_blocks: args.cache_blocks,
+        always_authenticate: args.always_authenticate,
+        record_writer: args.record_writer,
+        sniff_content_type: args.sniff_content_type,
+        uid_map: IdMap::new(args.uid_map)?,
+        gid_map: IdMap::new(args.gid_map)?,
+        read_only,
+        shadow: args.shadow,
+        error_file: args.error_file,
+        shuffle_listings: args.shuffle_listings,
+        max_name_length: args.max_name_length.map(usize::from),
+        force_mode: args.force_mode,
+        subpath: args.subpath,
+        secure_delete: args.secure_delete,
+        max_background: args.max_background,
+        congestion_threshold: args.congestion_threshold,
+        threads: usize::from(args.threads),
+        mark_incomplete: args.mark_incomplete,
+        mlock: args.mlock || args.ephemeral,
+        backing_extension: args.backing_extension,
+        integrity_only: args.integrity_only,
+    };
 
-    let fs = CipherFS::new(args.source, key);
-    fuser::mount2(fs, &args.mountpoint, &options)?;
+    if config.mlock {
+        let wanted = args.cache_blocks as u64 * args.block_size as u64;
+        match memlock::limit() {
+            Ok(Some(limit)) if limit < wanted => log::warn!(
+                "RLIMIT_MEMLOCK is {} bytes but the cache can hold {}; \
+                 blocks beyond the limit will not be locked",
+                limit,
+                wanted
+            ),
+            Ok(_) => {}
+            Err(e) => log::warn!("Cannot read RLIMIT_MEMLOCK: {}", e),
+        }
+    }
 
+    let mut fs = CipherFS::with_config(source.clone(), key, config);
+    if let Some(path) = &args.access_log {
+        let mut access = AccessLog::open(path)?;
+        if let Some(rate) = args.rate_limit {
+            access = access.with_rate_limit(rate);
+        }
+        fs = fs.with_access_log(Arc::new(access));
+    }
+    // A read-only mount seals nothing, so it leaves the counter free.
+    if args.nonce_strategy == NonceStrategy::Counter && !read_only {
+        fs = fs.with_nonces(Arc::new(Counter::open(&source, &key)?));
+        log::info!("  Nonces:     counter");
+    }
+    let memory = args
+        .profile_memory
+        .map(|threshold| Arc::new(memprof::MemoryProfile::new(threshold)));
+    if let Some(memory) = &memory {
+        fs = fs.with_memory_profile(Arc::clone(memory));
+    }
+    if let Some(budget) = args.memory_budget {
+        fs = fs.with_memory_budget(Arc::new(memprof::MemoryBudget::new(budget)));
+    }
+    // Before any other thread starts, so they all inherit the blocked signal.
+    dump_handles_on_sigusr2(fs.open_handles(), memory.clone());
+
+    let window = args
+        .maintenance_window
+        .map(|w| args.maintenance_days.map_or(w, |days| w.on_days(days)));
+    let maintenance = Arc::new(Maintenance::new(window, fs.clock()));
+    // A read-only mount must not change the vault behind the kernel's back.
+    // The sweep removes files through the mount, so open ones are orphaned
+    // and writes in flight finish first, as with an unlink.
+    if args.ttl_gc_interval > 0 && !read_only {
+        let sweeper = fs.clone();
+        let maintenance = Arc::clone(&maintenance);
+        let interval = Duration::from_secs(args.ttl_gc_interval);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            maintenance.wait_until_open();
+            match sweeper.collect_expired() {
+                Ok(0) => {}
+                Ok(n) => log::info!("Removed {} expired file(s)", n),
+                Err(e) => log::warn!("Expiry sweep failed: {}", e),
+            }
+        });
+    }
+
+    if args.scrub_interval > 0 && read_only {
+        log::warn!("Background scrubs are disabled on read-only mounts");
+    } else if args.scrub_interval > 0 {
+        let source = source.clone();
+        let interval = Duration::from_secs(args.scrub_interval);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            maintenance.wait_until_open();
+            match run_scrub(&source, &key, scrub::Options::default(), || {
+                maintenance.is_open()
+            }) {
+                Ok(report) => {
+                    for path in report.incomplete {
+                        log::warn!("Scrub: {} is incomplete", detail(&path));
+                    }
+                    for (path, reason) in report.failed {
+                        log::error!("Scrub: {} failed verification: {}", detail(&path), reason);
+                    }
+                }
+                Err(e) => log::warn!("Scrub failed: {}", e),
+            }
+        });
+    }
+
+    let ended = match args.on_mount_loss {
+        None => {
+            fuser::mount2(fs, &mountpoint, &options)?;
+            mountpoint::Ended::Unmounted
+        }
+        Some(policy) => {
+            // The mountpoint itself is the mount; watch the directory it is in.
+            let mut watched = vec![source.clone()];
+            watched.extend(mountpoint.canonicalize()?.parent().map(PathBuf::from));
+            let session = fuser::spawn_mount2(fs, &mountpoint, &options)?;
+            mountpoint::supervise(session, &watched, MOUNT_CHECK_INTERVAL, policy)
+        }
+    };
+    // Removes an ephemeral vault now that nothing can reach it.
+    drop(scratch);
+    if let Some(memory) = &memory {
+        log::info!("Memory: {}", memory.report());
+    }
+
+    if let mountpoint::Ended::Lost(path) = ended {
+        anyhow::bail!("Unmounted because {:?} became unreachable", path);
+    }
     Ok(())
 }
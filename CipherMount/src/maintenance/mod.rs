@@ -0,0 +1,202 @@
+//! Maintenance windows for background work (`--maintenance-window`).
+//!
+//! Background scrubs and expiry sweeps compete with applications for the
+//! backing disk. A `Maintenance` schedule confines them to a daily window,
+//! optionally on some days of the week only: a task starts only while the
+//! window is open and checks it again as it goes, stopping where it is once
+//! the window closes. Scrubs record what they verified, so the next window
+//! picks up where the last one stopped instead of starting over. Expired
+//! files are hidden by the mount as soon as they expire, so deferring their
+//! removal changes nothing but disk usage.
+//!
+//! Windows are in local time by default. One that ends before it starts
+//! (`22:00-06:00`) runs past midnight and belongs to the day it starts on.
+
+use crate::clock::Clock;
+use anyhow::{anyhow, bail, ensure, Result};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often a task waiting for the window checks whether it has opened.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// A daily window, `HH:MM-HH:MM`, on some days of the week.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    /// Minutes after midnight.
+    start: u32,
+    end: u32,
+    /// Bit `d` set if the window opens on weekday `d` (0 = Sunday).
+    days: u8,
+}
+
+impl Window {
+    /// Only open on `days`, as parsed by `parse_days`.
+    pub fn on_days(mut self, days: u8) -> Self {
+        self.days = days;
+        self
+    }
+
+    /// Whether the window is open at `minute` after midnight on `weekday`.
+    pub fn is_open(&self, weekday: u32, minute: u32) -> bool {
+        let opens_on = |day: u32| self.days & (1 << (day % 7)) != 0;
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute) && opens_on(weekday)
+        } else if minute >= self.start {
+            opens_on(weekday)
+        } else {
+            // Still open from the evening before.
+            minute < self.end && opens_on(weekday + 6)
+        }
+    }
+}
+
+impl FromStr for Window {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("Expected START-END, like 22:00-06:00"))?;
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        ensure!(start != end, "The window must not be empty");
+        Ok(Self {
+            start,
+            end,
+            days: 0x7f,
+        })
+    }
+}
+
+fn parse_time(s: &str) -> Result<u32> {
+    let (h, m) = s
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Expected HH:MM, got {:?}", s))?;
+    let (h, m): (u32, u32) = (h.parse()?, m.parse()?);
+    ensure!(h < 24 && m < 60, "{:?} is not a time of day", s);
+    Ok(h * 60 + m)
+}
+
+/// Parse a comma-separated list of days (`mon,tue` or `sat,sun`) into a
+/// bit set for `Window::on_days`.
+pub fn parse_days(s: &str) -> Result<u8> {
+    let mut days = 0;
+    for name in s.split(',') {
+        let name = name.trim().to_ascii_lowercase();
+        let Some(day) = DAY_NAMES.iter().position(|d| name.starts_with(d)) else {
+            bail!("Unknown day {:?}; use mon, tue, ... sun", name);
+        };
+        days |= 1 << day;
+    }
+    Ok(days)
+}
+
+/// When background tasks may run.
+pub struct Maintenance {
+    window: Option<Window>,
+    clock: Arc<Clock>,
+    /// Seconds east of UTC the window is given in; `None` for the system's
+    /// local time (following daylight saving changes).
+    utc_offset: Option<i64>,
+}
+
+impl Maintenance {
+    /// Tasks may run whenever `window` is open in local time, or at any
+    /// time without one.
+    pub fn new(window: Option<Window>, clock: Arc<Clock>) -> Self {
+        Self {
+            window,
+            clock,
+            utc_offset: None,
+        }
+    }
+
+    /// Read the window as `offset` seconds east of UTC instead of local time.
+    pub fn with_utc_offset(mut self, offset: i64) -> Self {
+        self.utc_offset = Some(offset);
+        self
+    }
+
+    /// Whether background tasks may run now.
+    pub fn is_open(&self) -> bool {
+        let Some(window) = &self.window else {
+            return true;
+        };
+        let now = self.clock.now() as i64;
+        let offset = self.utc_offset.unwrap_or_else(|| local_offset(now));
+        let local = now + offset;
+        let minute = (local.rem_euclid(86_400) / 60) as u32;
+        // 1970-01-01 was a Thursday.
+        let weekday = (local.div_euclid(86_400) + 4).rem_euclid(7) as u32;
+        window.is_open(weekday, minute)
+    }
+
+    /// Block until background tasks may run.
+    pub fn wait_until_open(&self) {
+        while !self.is_open() {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Seconds east of UTC of the system's local time at Unix time `at`.
+fn local_offset(at: i64) -> i64 {
+    let time = at as libc::time_t;
+    // SAFETY: an all-zero tm is valid storage for localtime_r to fill in.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers are valid for the call.
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    tm.tm_gmtoff as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// Unix time of Saturday 2026-10-17 at `hh:mm` UTC.
+    fn saturday(hh: u64, mm: u64) -> u64 {
+        1_792_195_200 + hh * 3600 + mm * 60
+    }
+
+    #[test]
+    fn tasks_run_only_inside_the_window() {
+        let now = Arc::new(AtomicU64::new(0));
+        let clock = Arc::new(Clock::with_source({
+            let now = Arc::clone(&now);
+            move || now.load(Ordering::SeqCst) * 1_000_000_000
+        }));
+        let window = "22:00-06:00"
+            .parse::<Window>()
+            .unwrap()
+            .on_days(parse_days("fri,sat").unwrap());
+        let maintenance = Maintenance::new(Some(window), clock).with_utc_offset(0);
+        let open_at = |secs| {
+            now.store(secs, Ordering::SeqCst);
+            maintenance.is_open()
+        };
+
+        // Friday night's window runs into Saturday morning.
+        assert!(open_at(saturday(5, 59)));
+        assert!(!open_at(saturday(6, 0)));
+        assert!(!open_at(saturday(21, 59)));
+        assert!(open_at(saturday(22, 0)));
+        // Saturday night's also runs into Sunday; Sunday night has none.
+        assert!(open_at(saturday(24 + 3, 0)));
+        assert!(!open_at(saturday(24 + 22, 0)));
+    }
+
+    #[test]
+    fn rejects_malformed_windows() {
+        for bad in ["22:00", "25:00-01:00", "10:00-10:00", "9-17"] {
+            assert!(bad.parse::<Window>().is_err(), "{bad}");
+        }
+        assert!(parse_days("mon,someday").is_err());
+    }
+}
@@ -0,0 +1,115 @@
+//! Keeping decrypted buffers out of swap.
+//!
+//! `mlock` works on whole pages and does not nest: unlocking a page unlocks
+//! it for every buffer that shares it. Locks are therefore counted per page
+//! across the process, and a page is only unlocked once the last buffer on
+//! it is released.
+//!
+//! Locking is best effort. When `RLIMIT_MEMLOCK` (or a missing capability)
+//! refuses a lock, a warning is logged once and the buffer is used unlocked.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+static PAGES: Mutex<Option<HashMap<usize, usize>>> = Mutex::new(None);
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+fn page_size() -> usize {
+    static SIZE: OnceLock<usize> = OnceLock::new();
+    // SAFETY: sysconf has no preconditions.
+    *SIZE.get_or_init(|| unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as usize)
+}
+
+/// The pages of a buffer, locked in memory until dropped.
+#[derive(Debug)]
+pub struct Locked {
+    first: usize,
+    pages: usize,
+}
+
+/// Lock the pages holding `buf`. Returns `None` (after warning once) if the
+/// kernel refuses, and for an empty buffer.
+pub fn lock(buf: &[u8]) -> Option<Locked> {
+    if buf.is_empty() {
+        return None;
+    }
+    let page = page_size();
+    let first = buf.as_ptr() as usize / page;
+    let last = (buf.as_ptr() as usize + buf.len() - 1) / page;
+    let mut guard = PAGES.lock().unwrap();
+    let counts = guard.get_or_insert_with(HashMap::new);
+    for p in first..=last {
+        if !counts.contains_key(&p) {
+            if let Err(e) = mlock_page(p) {
+                // Undo what was taken so far.
+                release(counts, first, p - first);
+                warn_once(&e);
+                return None;
+            }
+        }
+        *counts.entry(p).or_default() += 1;
+    }
+    Some(Locked {
+        first,
+        pages: last - first + 1,
+    })
+}
+
+impl Drop for Locked {
+    fn drop(&mut self) {
+        if let Some(counts) = PAGES.lock().unwrap().as_mut() {
+            release(counts, self.first, self.pages);
+        }
+    }
+}
+
+fn release(counts: &mut HashMap<usize, usize>, first: usize, pages: usize) {
+    for p in first..first + pages {
+        let Some(count) = counts.get_mut(&p) else {
+            continue;
+        };
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&p);
+            let addr = (p * page_size()) as *const libc::c_void;
+            // SAFETY: munlock only changes residency; the page was locked by
+            // us and an unmapped page just makes the call fail.
+            unsafe { libc::munlock(addr, page_size()) };
+        }
+    }
+}
+
+fn mlock_page(p: usize) -> io::Result<()> {
+    let addr = (p * page_size()) as *const libc::c_void;
+    // SAFETY: the page belongs to a live buffer borrowed by the caller.
+    if unsafe { libc::mlock(addr, page_size()) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn warn_once(e: &io::Error) {
+    if !WARNED.swap(true, Ordering::Relaxed) {
+        log::warn!(
+            "Cannot lock decrypted data in memory ({}); it may be swapped out. \
+             Raise RLIMIT_MEMLOCK (ulimit -l) to lock all of it",
+            e
+        );
+    }
+}
+
+/// The soft `RLIMIT_MEMLOCK` in bytes, or `None` if it is unlimited.
+pub fn limit() -> io::Result<Option<u64>> {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `rlim` is a valid out-pointer for the call.
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut rlim) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((rlim.rlim_cur != libc::RLIM_INFINITY).then_some(rlim.rlim_cur))
+}
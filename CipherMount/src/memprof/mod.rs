@@ -0,0 +1,219 @@
+//! Accounting of whole-file buffers (`--profile-memory`).
+//!
+//! Most reads and writes only touch the blocks they need, but some still
+//! hold a whole file in memory: reads of legacy files, and writes that
+//! can't reseal blocks in place (a cipher change, a write to a legacy
+//! file), which decrypt the file, patch it and encrypt it again. A large
+//! file then costs several times its size for the length of the operation.
+//! A `MemoryProfile` counts those buffers while they are alive, keeps the
+//! peak, and logs every operation whose buffers exceed a threshold, so the
+//! files behind a memory spike can be found before the mount runs out of
+//! memory. The counts are of buffers, not of the allocator.
+//!
+//! A `MemoryBudget` bounds the same buffers across all operations at once:
+//! each operation reserves what it will hold before allocating it, and
+//! waits, in arrival order, while the budget is spent. An operation that
+//! already holds a reservation never waits for a second one (it would wait
+//! on itself); the extra is counted against the budget all the same.
+
+use crate::logging::detail;
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+
+/// Bytes of whole-file buffers held by a mount.
+#[derive(Debug)]
+pub struct MemoryProfile {
+    /// Buffers an operation may hold before it is logged.
+    threshold: u64,
+    current: AtomicU64,
+    peak: AtomicU64,
+    /// Operations that went over the threshold.
+    spikes: AtomicU64,
+}
+
+/// Buffers counted against a profile until this is dropped.
+#[must_use]
+pub struct Tracked<'a> {
+    profile: &'a MemoryProfile,
+    bytes: u64,
+}
+
+impl MemoryProfile {
+    pub fn new(threshold: u64) -> Self {
+        Self {
+            threshold,
+            current: AtomicU64::new(0),
+            peak: AtomicU64::new(0),
+            spikes: AtomicU64::new(0),
+        }
+    }
+
+    /// Count `bytes` of buffers held by `op` on `path` until the returned
+    /// guard is dropped, logging the operation if they exceed the threshold.
+    pub fn track(&self, op: &str, path: &Path, bytes: usize) -> Tracked<'_> {
+        let bytes = bytes as u64;
+        let current = self.current.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        self.peak.fetch_max(current, Ordering::SeqCst);
+        if bytes > self.threshold {
+            self.spikes.fetch_add(1, Ordering::Relaxed);
+            log::warn!(
+                "Memory: {} of {} buffers {} bytes (threshold {}, {} held in total)",
+                op,
+                detail(path),
+                bytes,
+                self.threshold,
+                current
+            );
+        }
+        Tracked {
+            profile: self,
+            bytes,
+        }
+    }
+
+    /// Bytes of buffers held right now.
+    pub fn current(&self) -> u64 {
+        self.current.load(Ordering::SeqCst)
+    }
+
+    /// Most bytes of buffers ever held at once.
+    pub fn peak(&self) -> u64 {
+        self.peak.load(Ordering::SeqCst)
+    }
+
+    /// Operations logged for going over the threshold.
+    pub fn spikes(&self) -> u64 {
+        self.spikes.load(Ordering::Relaxed)
+    }
+
+    /// One-line summary for the log.
+    pub fn report(&self) -> String {
+        format!(
+            "{} bytes of whole-file buffers held, peak {}, {} operation(s) over {}",
+            self.current(),
+            self.peak(),
+            self.spikes(),
+            self.threshold
+        )
+    }
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        self.profile.current.fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
+
+/// Upper bound on the whole-file buffers all operations hold at once.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: u64,
+    state: Mutex<BudgetState>,
+    freed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct BudgetState {
+    in_use: u64,
+    /// Tickets handed to waiting operations, and the one served next, so
+    /// a large reservation isn't starved by a stream of small ones.
+    next_ticket: u64,
+    serving: u64,
+}
+
+thread_local! {
+    /// Reservations held by this thread.
+    static HELD: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Bytes reserved from a budget until this is dropped, on the thread that
+/// reserved them.
+#[must_use]
+pub struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: u64,
+    _thread: PhantomData<*const ()>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            state: Mutex::new(BudgetState::default()),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Reserve `bytes`, waiting behind earlier callers until they fit. A
+    /// request larger than the whole budget waits for all of it.
+    pub fn reserve(&self, bytes: usize) -> Reservation<'_> {
+        let bytes = (bytes as u64).min(self.limit);
+        let mut state = self.state.lock().unwrap();
+        if HELD.with(Cell::get) == 0 {
+            let ticket = state.next_ticket;
+            state.next_ticket += 1;
+            while state.serving != ticket || state.in_use + bytes > self.limit {
+                state = self.freed.wait(state).unwrap();
+            }
+            state.serving += 1;
+            // The next in line may fit too.
+            self.freed.notify_all();
+        }
+        state.in_use += bytes;
+        HELD.with(|held| held.set(held.get() + 1));
+        Reservation {
+            budget: self,
+            bytes,
+            _thread: PhantomData,
+        }
+    }
+
+    /// Bytes reserved right now.
+    pub fn in_use(&self) -> u64 {
+        self.state.lock().unwrap().in_use
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        HELD.with(|held| held.set(held.get() - 1));
+        self.budget.state.lock().unwrap().in_use -= self.bytes;
+        self.budget.freed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_buffers_add_up_to_the_peak() {
+        let profile = MemoryProfile::new(100);
+        let path = Path::new("file");
+        {
+            let _outer = profile.track("write", path, 60);
+            let _inner = profile.track("encrypt", path, 70);
+            assert_eq!(profile.current(), 130);
+        }
+        let _small = profile.track("read", path, 10);
+        assert_eq!((profile.current(), profile.peak()), (10, 130));
+        assert_eq!(profile.spikes(), 0);
+    }
+
+    #[test]
+    fn nested_reservation_does_not_wait_on_its_own_thread() {
+        let budget = MemoryBudget::new(100);
+        let outer = budget.reserve(80);
+        let inner = budget.reserve(50);
+        assert_eq!(budget.in_use(), 130);
+        drop(inner);
+        drop(outer);
+        let whole = budget.reserve(1000);
+        assert_eq!(budget.in_use(), 100);
+        drop(whole);
+        assert_eq!(budget.in_use(), 0);
+    }
+}
@@ -0,0 +1,436 @@
+//! Authenticated per-file metadata.
+//!
+//! Each backing file may have a sidecar next to it holding metadata that
+//! isn't part of the file contents (writer, cipher, ...). The sidecar is sealed with
+//! AES-256-GCM using the file's path relative to the vault root as AAD, so it
+//! can neither be edited nor swapped onto another file without the key.
+//!
+//! Sidecar layout on disk:
+//!   <dir>/.cmmeta.<name>  →  [ 12-byte nonce ][ "key=value\n"... + 16-byte GCM tag ]
+//!
+//! A sidecar can still be deleted, or replaced by an older copy of itself,
+//! so a file's expiry lives in its header instead (see `format`), where it
+//! is sealed along with the file's mtime. Sidecars written before that keep
+//! their expiry until the file is next rewritten.
+
+use crate::crypto::{self, Cipher};
+use crate::dedup::Store;
+use crate::logging::detail;
+use crate::{format, layout, walk};
+use anyhow::{anyhow, Result};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Prefix marking a backing entry as a metadata sidecar.
+pub const SIDECAR_PREFIX: &str = ".cmmeta.";
+
+/// Extended attribute used to set or read a file's time-to-live in seconds.
+/// Setting it to `0` removes the expiry.
+pub const TTL_XATTR: &str = "user.ciphermount.ttl";
+
+/// Read-only extended attribute holding the uid that last wrote the file,
+/// when the mount records writers.
+pub const WRITER_XATTR: &str = "user.ciphermount.writer";
+
+/// Extended attribute naming the cipher a file is encrypted with. Setting
+/// it requests that the file be re-encrypted with that cipher on its next
+/// write.
+pub const CIPHER_XATTR: &str = "user.ciphermount.cipher";
+
+/// Extended attribute marking a file immutable (`1`) or not (`0`), like
+/// `chattr +i`: an immutable file can't be written, truncated, have its
+/// attributes changed, or be renamed, linked or deleted until it is cleared.
+pub const IMMUTABLE_XATTR: &str = "user.ciphermount.immutable";
+
+/// Extended attribute on a directory naming the cipher that files created
+/// anywhere below it are encrypted with, unless a nearer directory names
+/// another. Setting it to `inherit` removes the directory's own choice.
+pub const DEFAULT_CIPHER_XATTR: &str = "user.ciphermount.default_cipher";
+
+/// Extended attribute holding a file's content type hint (`image/png`), set
+/// by hand or sniffed from its contents. Setting it to an empty value
+/// removes the hint.
+pub const MIME_XATTR: &str = "user.ciphermount.mime";
+
+/// Metadata stored in a file's sidecar.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FileMeta {
+    /// Unix time (seconds) after which the file is treated as deleted, for
+    /// files whose header has no room for it (written before expiries moved
+    /// there). Superseded by the header's once it has one.
+    pub expires_at: Option<u64>,
+    /// uid of the last user to write the file through the mount.
+    pub last_writer_uid: Option<u32>,
+    /// The file was being written when the mount stopped: its contents
+    /// are whatever reached the vault before the interruption.
+    pub incomplete: bool,
+    /// Cipher requested for the file's next write, until it is applied.
+    pub cipher: Option<Cipher>,
+    /// Changes to the file are refused until this is cleared.
+    pub immutable: bool,
+    /// For a directory: cipher requested for new files created below it.
+    pub default_cipher: Option<Cipher>,
+    /// Content type hint, such as `image/png`.
+    pub mime: Option<String>,
+    /// The hint was sniffed from the contents rather than set by hand, so
+    /// it follows them when the file is rewritten.
+    pub mime_sniffed: bool,
+}
+
+/// True if `name` is a sidecar rather than a user-visible entry.
+pub fn is_sidecar(name: &str) -> bool {
+    name.starts_with(SIDECAR_PREFIX)
+}
+
+/// Sidecar path for the backing file at `path`.
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}{}", SIDECAR_PREFIX, name))
+}
+
+/// Current Unix time in seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn aad(root: &Path, path: &Path) -> Vec<u8> {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+        .into_bytes()
+}
+
+impl FileMeta {
+    fn encode(&self) -> String {
+        let mut out = String::new();
+        if let Some(t) = self.expires_at {
+            out.push_str(&format!("expires_at={}\n", t));
+        }
+        if let Some(uid) = self.last_writer_uid {
+            out.push_str(&format!("writer_uid={}\n", uid));
+        }
+        if self.incomplete {
+            out.push_str("incomplete=1\n");
+        }
+        if let Some(cipher) = self.cipher {
+            out.push_str(&format!("cipher={}\n", cipher.name()));
+        }
+        if self.immutable {
+            out.push_str("immutable=1\n");
+        }
+        if let Some(cipher) = self.default_cipher {
+            out.push_str(&format!("default_cipher={}\n", cipher.name()));
+        }
+        if let Some(mime) = &self.mime {
+            out.push_str(&format!("mime={}\n", mime));
+        }
+        if self.mime_sniffed {
+            out.push_str("mime_sniffed=1\n");
+        }
+        out
+    }
+
+    fn decode(text: &str) -> Result<Self> {
+        let mut meta = FileMeta::default();
+        for line in text.lines() {
+            let (k, v) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Malformed metadata line"))?;
+            // Unknown keys are ignored so older builds can read newer sidecars.
+            match k {
+                "expires_at" => meta.expires_at = Some(v.parse()?),
+                "writer_uid" => meta.last_writer_uid = Some(v.parse()?),
+                "incomplete" => meta.incomplete = v == "1",
+                "cipher" => meta.cipher = Some(v.parse()?),
+                "immutable" => meta.immutable = v == "1",
+                "default_cipher" => meta.default_cipher = Some(v.parse()?),
+                "mime" => meta.mime = Some(v.to_string()),
+                "mime_sniffed" => meta.mime_sniffed = v == "1",
+                _ => {}
+            }
+        }
+        Ok(meta)
+    }
+
+    /// Load and authenticate the sidecar for `path`. Returns `Ok(None)` if the
+    /// file has no sidecar, and an error if the sidecar fails authentication.
+    pub fn load(key: &[u8; 32], root: &Path, path: &Path) -> Result<Option<Self>> {
+        let raw = match fs::read(sidecar_path(path)) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let plaintext = crypto::decrypt_with_aad(key, &raw, &aad(root, path))
+            .map_err(|_| anyhow!("Metadata failed authentication"))?;
+        Ok(Some(Self::decode(std::str::from_utf8(&plaintext)?)?))
+    }
+
+    /// Seal and write the sidecar for `path`.
+    pub fn store(&self, key: &[u8; 32], root: &Path, path: &Path) -> Result<()> {
+        let sealed = crypto::encrypt_with_aad(key, self.encode().as_bytes(), &aad(root, path))?;
+        fs::write(sidecar_path(path), sealed)?;
+        Ok(())
+    }
+
+    /// True if the file has expired at Unix time `now`.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+}
+
+/// Remove the sidecar for `path`, if any.
+pub fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(sidecar_path(path)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        other => other,
+    }
+}
+
+/// Delete the regular file at `path` with everything stored for it: the
+/// backing file, its sidecar and its blocks, releasing those it shares
+/// through `store`.
+pub fn delete_file(store: &Store, path: &Path) -> io::Result<()> {
+    fs::remove_file(path)?;
+    remove(path)?;
+    store.remove(path)
+}
+
+/// Unix time at which the file at `path` expires, if ever.
+///
+/// A file whose header has room for an expiry uses that. Otherwise a file
+/// with a sidecar uses the sidecar's expiry, and a file without one falls
+/// back to `default_ttl` counted from its backing mtime, so deleting a
+/// sidecar can't make a file immortal under a default TTL. A header or
+/// sidecar that fails authentication fails closed: the file is treated as
+/// expired.
+pub fn expires_at(
+    key: &[u8; 32],
+    root: &Path,
+    path: &Path,
+    default_ttl: Option<Duration>,
+) -> Option<u64> {
+    match expiry(key, root, path, default_ttl) {
+        Ok(expires_at) => expires_at,
+        Err(e) => {
+            log::warn!(
+                "Metadata of {}: {}; treating file as expired",
+                detail(path),
+                e
+            );
+            Some(0)
+        }
+    }
+}
+
+/// `expires_at`, with a header or sidecar that can't be read or fails
+/// authentication an error rather than an expiry. Hiding such a file is
+/// safe; deleting it is not, so only an expiry from here may remove a file.
+pub fn expiry(
+    key: &[u8; 32],
+    root: &Path,
+    path: &Path,
+    default_ttl: Option<Duration>,
+) -> Result<Option<u64>> {
+    if let Some(header) = header_with_expiry(path)? {
+        return header.open_expiry(key);
+    }
+    if let Some(meta) = FileMeta::load(key, root, path)? {
+        return Ok(meta.expires_at);
+    }
+    let Some(ttl) = default_ttl else {
+        return Ok(None);
+    };
+    let mtime = fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok(Some((mtime + ttl).as_secs()))
+}
+
+/// The header of the backing file at `path`, if it has room for an expiry.
+fn header_with_expiry(path: &Path) -> io::Result<Option<format::Header>> {
+    let len = match fs::metadata(path) {
+        Ok(m) => m.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut prefix = vec![];
+    fs::File::open(path)?
+        .take(format::MAX_HEADER_SIZE as u64)
+        .read_to_end(&mut prefix)?;
+    // A legacy file's nonce may look like a header by chance.
+    let header = format::Header::decode(&prefix).filter(|h| {
+        if h.separate_blocks() || h.dedup_blocks() {
+            layout::block_dir(path).is_dir()
+        } else {
+            format::fits(h, len)
+        }
+    });
+    Ok(header.filter(|h| h.has_expiry()))
+}
+
+/// Walk the vault under `root` and pass every regular file that has
+/// expired at Unix time `now` to `delete`, which removes it along with its
+/// sidecar and says whether it did. Files whose expiry can't be trusted are
+/// left alone. Returns the number of files removed.
+pub fn collect_expired(
+    key: &[u8; 32],
+    root: &Path,
+    now: u64,
+    default_ttl: Option<Duration>,
+    mut delete: impl FnMut(&Path) -> io::Result<bool>,
+) -> Result<usize> {
+    let mut removed = 0;
+    walk::walk(root, walk::Options::default(), |entry| {
+        if !entry.file_type.is_file() {
+            return Ok(());
+        }
+        match expiry(key, root, &entry.path, default_ttl) {
+            Ok(Some(t)) if t <= now => {
+                if delete(&entry.path)? {
+                    removed += 1;
+                }
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!(
+                "Metadata of {}: {}; leaving the file in place",
+                detail(&entry.path),
+                e
+            ),
+        }
+        Ok(())
+    })?;
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::RandomNonces;
+    use crate::vault;
+
+    const KEY: [u8; 32] = [0x42u8; 32];
+
+    #[test]
+    fn store_load_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f");
+        fs::write(&path, b"").unwrap();
+
+        assert_eq!(FileMeta::load(&KEY, dir.path(), &path).unwrap(), None);
+        let meta = FileMeta {
+            expires_at: Some(1234),
+            last_writer_uid: Some(1000),
+            incomplete: true,
+            cipher: Some(Cipher::ChaCha20Poly1305),
+            immutable: true,
+            default_cipher: Some(Cipher::ChaCha20Poly1305),
+            mime: Some("image/png".into()),
+            mime_sniffed: true,
+        };
+        meta.store(&KEY, dir.path(), &path).unwrap();
+        assert_eq!(FileMeta::load(&KEY, dir.path(), &path).unwrap(), Some(meta));
+    }
+
+    #[test]
+    fn swapped_sidecar_fails_authentication() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        FileMeta::default().store(&KEY, dir.path(), &a).unwrap();
+        fs::copy(sidecar_path(&a), sidecar_path(&b)).unwrap();
+
+        assert!(FileMeta::load(&KEY, dir.path(), &b).is_err());
+        assert_eq!(expires_at(&KEY, dir.path(), &b, None), Some(0));
+    }
+
+    #[test]
+    fn collect_expired_removes_only_expired_files() {
+        let dir = tempfile::tempdir().unwrap();
+        vault::init(dir.path(), &KEY).unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let old = dir.path().join("sub/old");
+        let fresh = dir.path().join("fresh");
+        fs::write(&old, b"x").unwrap();
+        fs::write(&fresh, b"y").unwrap();
+        // Blocks stored separately go with the file.
+        fs::create_dir(layout::block_dir(&old)).unwrap();
+        fs::write(layout::block_file(&old, 0), b"block").unwrap();
+        FileMeta {
+            expires_at: Some(100),
+            ..FileMeta::default()
+        }
+        .store(&KEY, dir.path(), &old)
+        .unwrap();
+        FileMeta {
+            expires_at: Some(300),
+            ..FileMeta::default()
+        }
+        .store(&KEY, dir.path(), &fresh)
+        .unwrap();
+
+        let store = Store::new(dir.path());
+        let delete = |path: &Path| delete_file(&store, path).map(|_| true);
+        assert_eq!(
+            collect_expired(&KEY, dir.path(), 200, None, delete).unwrap(),
+            1
+        );
+        assert!(!old.exists() && !sidecar_path(&old).exists());
+        assert!(!layout::block_dir(&old).exists());
+        assert!(fresh.exists());
+        assert!(dir.path().join(vault::CANARY_FILE).exists());
+    }
+
+    #[test]
+    fn untrusted_sidecar_hides_but_never_deletes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f");
+        fs::write(&path, b"data").unwrap();
+        FileMeta::default().store(&KEY, dir.path(), &path).unwrap();
+        let mut sealed = fs::read(sidecar_path(&path)).unwrap();
+        *sealed.last_mut().unwrap() ^= 1;
+        fs::write(sidecar_path(&path), &sealed).unwrap();
+
+        assert_eq!(expires_at(&KEY, dir.path(), &path, None), Some(0));
+        assert!(expiry(&KEY, dir.path(), &path, None).is_err());
+        let delete = |path: &Path| fs::remove_file(path).map(|_| true);
+        assert_eq!(
+            collect_expired(&KEY, dir.path(), 200, None, delete).unwrap(),
+            0
+        );
+        assert!(path.exists() && sidecar_path(&path).exists());
+    }
+
+    #[test]
+    fn header_expiry_outlives_its_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f");
+        let flags = format::FLAG_EXPIRY;
+        let mut raw =
+            format::encrypt_file_with_flags(&KEY, &RandomNonces, b"data", 64, flags, 0).unwrap();
+        format::set_expiry(&KEY, &RandomNonces, &mut raw, Some(100)).unwrap();
+        fs::write(&path, &raw).unwrap();
+        // A sidecar saying otherwise, deleted or replayed, changes nothing.
+        assert_eq!(expiry(&KEY, dir.path(), &path, None).unwrap(), Some(100));
+        FileMeta::default().store(&KEY, dir.path(), &path).unwrap();
+        assert_eq!(expiry(&KEY, dir.path(), &path, None).unwrap(), Some(100));
+        let ttl = Some(Duration::from_secs(1));
+        format::set_expiry(&KEY, &RandomNonces, &mut raw, None).unwrap();
+        fs::write(&path, &raw).unwrap();
+        assert_eq!(expiry(&KEY, dir.path(), &path, ttl).unwrap(), None);
+
+        // Nor can the field be edited, or cut from the header without
+        // breaking the blocks, which are sealed to the flags.
+        raw[format::HEADER_SIZE + 15] ^= 1;
+        fs::write(&path, &raw).unwrap();
+        assert!(expiry(&KEY, dir.path(), &path, None).is_err());
+        assert_eq!(expires_at(&KEY, dir.path(), &path, None), Some(0));
+        raw[5] &= !format::FLAG_EXPIRY;
+        assert!(format::decrypt_file(&KEY, &raw).is_err());
+    }
+}
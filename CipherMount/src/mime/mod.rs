@@ -0,0 +1,84 @@
+//! Content-type hints for files (`user.ciphermount.mime`).
+//!
+//! Previewers and indexers choose how to handle a file by its content type,
+//! usually guessed from the extension, which an encrypted name hides. A
+//! file's sidecar can carry a MIME type instead: set by hand through the
+//! xattr, or, with `--sniff-content-type`, recognised from the first bytes
+//! written to the file. Kept in the sidecar, the hint is authenticated like
+//! the rest of the file's metadata, so it can't be changed to have a tool
+//! parse the file as something it isn't.
+
+/// Types recognised by their leading bytes.
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (b"SQLite format 3\0", "application/vnd.sqlite3"),
+    (b"\x7fELF", "application/x-executable"),
+    (b"OggS", "audio/ogg"),
+    (b"fLaC", "audio/flac"),
+    (b"ID3", "audio/mpeg"),
+];
+
+/// Longest type accepted as a hint.
+const MAX_LEN: usize = 127;
+
+/// Content type `data`, the start of a file, appears to have.
+pub fn sniff(data: &[u8]) -> Option<&'static str> {
+    if let Some((_, mime)) = MAGIC.iter().find(|(magic, _)| data.starts_with(magic)) {
+        return Some(mime);
+    }
+    if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        return Some("video/mp4");
+    }
+    if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    // Text, allowing for a character cut in half where the write ends.
+    let utf8 = match std::str::from_utf8(data) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    let controls = data
+        .iter()
+        .any(|&b| (b < 0x20 && !b"\t\n\r\x0c".contains(&b)) || b == 0x7f);
+    (!data.is_empty() && utf8 && !controls).then_some("text/plain")
+}
+
+/// Whether `mime` is a well-formed `type/subtype`.
+pub fn is_valid(mime: &str) -> bool {
+    let token = |s: &str| {
+        !s.is_empty()
+            && s.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$&^_.+-".contains(&b))
+    };
+    mime.len() <= MAX_LEN
+        && mime
+            .split_once('/')
+            .is_some_and(|(t, s)| token(t) && token(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognises_common_types() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some("image/png"));
+        assert_eq!(sniff(b"%PDF-1.7\n"), Some("application/pdf"));
+        assert_eq!(sniff(b"\0\0\0\x18ftypmp42"), Some("video/mp4"));
+        assert_eq!(sniff("caf\u{e9}\n".as_bytes()), Some("text/plain"));
+        assert_eq!(sniff(b"\0\x01\x02binary"), None);
+        assert_eq!(sniff(b""), None);
+
+        assert!(is_valid("application/vnd.sqlite3"));
+        for bad in ["text", "text/", "text/plain\nexpires_at=1", "a/b/c"] {
+            assert!(!is_valid(bad), "{bad:?}");
+        }
+    }
+}
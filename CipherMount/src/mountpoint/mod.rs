@@ -0,0 +1,207 @@
+//! Mountpoint validation, so common mistakes fail with a clear message
+//! before the kernel gets involved, and supervision of a running mount.
+//!
+//! On removable media or network shares the vault source, or the directory
+//! holding the mountpoint, can disappear or lose its permissions while
+//! mounted, after which operations fail in confusing ways. `supervise`
+//! checks both periodically and applies a `LossPolicy`: keep serving what
+//! is cached, or unmount cleanly. The checks never touch the mount itself,
+//! so a wedged mount can't hang them.
+
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// State of a usable mountpoint.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Mountpoint {
+    /// An empty directory.
+    Empty,
+    /// A directory with this many entries, which the mount will hide.
+    NonEmpty(usize),
+}
+
+/// Check that `path` is a directory, creating it (and its parents) first if
+/// it is missing and `create` is set. Anything else is an error.
+pub fn check(path: &Path, create: bool) -> Result<Mountpoint> {
+    let meta = match fs::metadata(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound && create => {
+            fs::create_dir_all(path)
+                .with_context(|| format!("Cannot create mountpoint {:?}", path))?;
+            return Ok(Mountpoint::Empty);
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            bail!(
+                "Mountpoint {:?} does not exist (pass --create-mountpoint to create it)",
+                path
+            )
+        }
+        other => other.with_context(|| format!("Cannot access mountpoint {:?}", path))?,
+    };
+    if !meta.is_dir() {
+        bail!("Mountpoint {:?} is not a directory", path);
+    }
+    let entries = fs::read_dir(path)
+        .with_context(|| format!("Cannot list mountpoint {:?}", path))?
+        .count();
+    Ok(match entries {
+        0 => Mountpoint::Empty,
+        n => Mountpoint::NonEmpty(n),
+    })
+}
+
+/// What to do when a watched path becomes unreachable while mounted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LossPolicy {
+    /// Log it and keep serving; reads of cached blocks still succeed.
+    Continue,
+    /// Log it and unmount.
+    Unmount,
+}
+
+/// A running mount, as far as `supervise` is concerned.
+pub trait Session {
+    /// Whether the mount has ended on its own (unmounted from outside).
+    fn is_finished(&self) -> bool;
+    /// Unmount.
+    fn unmount(self);
+}
+
+impl Session for fuser::BackgroundSession {
+    fn is_finished(&self) -> bool {
+        self.guard.is_finished()
+    }
+
+    fn unmount(self) {
+        // Dropping the session unmounts it.
+        drop(self);
+    }
+}
+
+/// How a supervised mount ended.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Ended {
+    /// Unmounted from outside.
+    Unmounted,
+    /// Unmounted by `LossPolicy::Unmount` after this path became
+    /// unreachable.
+    Lost(PathBuf),
+}
+
+/// Whether the directory at `path` can still be listed.
+fn reachable(path: &Path) -> bool {
+    fs::read_dir(path).is_ok()
+}
+
+/// Wait for `session` to end, checking every `interval` that each of
+/// `watched` can still be listed, and applying `policy` when one can't.
+pub fn supervise(
+    session: impl Session,
+    watched: &[PathBuf],
+    interval: Duration,
+    policy: LossPolicy,
+) -> Ended {
+    let mut lost: Option<PathBuf> = None;
+    loop {
+        if session.is_finished() {
+            return Ended::Unmounted;
+        }
+        match (watched.iter().find(|p| !reachable(p)), &lost) {
+            (Some(path), _) if policy == LossPolicy::Unmount => {
+                log::error!("{:?} is no longer reachable; unmounting", path);
+                session.unmount();
+                return Ended::Lost(path.clone());
+            }
+            (Some(path), None) => {
+                log::warn!(
+                    "{:?} is no longer reachable; serving what is cached until it returns",
+                    path
+                );
+                lost = Some(path.clone());
+            }
+            (None, Some(path)) => {
+                log::info!("{:?} is reachable again", path);
+                lost = None;
+            }
+            _ => {}
+        }
+        thread::sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Default, Clone)]
+    struct MockSession {
+        finished: Arc<AtomicBool>,
+        unmounted: Arc<AtomicBool>,
+    }
+
+    impl Session for MockSession {
+        fn is_finished(&self) -> bool {
+            self.finished.load(Ordering::SeqCst)
+        }
+
+        fn unmount(self) {
+            self.unmounted.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn losing_a_watched_path_applies_the_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("media");
+        fs::create_dir(&source).unwrap();
+        fs::remove_dir(&source).unwrap();
+        let interval = Duration::from_millis(5);
+
+        let session = MockSession::default();
+        let ended = supervise(
+            session.clone(),
+            std::slice::from_ref(&source),
+            interval,
+            LossPolicy::Unmount,
+        );
+        assert_eq!(ended, Ended::Lost(source.clone()));
+        assert!(session.unmounted.load(Ordering::SeqCst));
+
+        // Continuing keeps the mount up until it is unmounted from outside.
+        let session = MockSession::default();
+        let finished = Arc::clone(&session.finished);
+        let outside = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            finished.store(true, Ordering::SeqCst);
+        });
+        let ended = supervise(session.clone(), &[source], interval, LossPolicy::Continue);
+        outside.join().unwrap();
+        assert_eq!(ended, Ended::Unmounted);
+        assert!(!session.unmounted.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn rejects_files_and_flags_non_empty_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, b"x").unwrap();
+
+        assert!(check(&file, true).is_err());
+        assert_eq!(check(dir.path(), false).unwrap(), Mountpoint::NonEmpty(1));
+    }
+
+    #[test]
+    fn missing_directory_is_created_only_on_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let mnt = dir.path().join("a/mnt");
+
+        assert!(check(&mnt, false).is_err());
+        assert_eq!(check(&mnt, true).unwrap(), Mountpoint::Empty);
+        assert!(mnt.is_dir());
+    }
+}
@@ -0,0 +1,222 @@
+//! Nonce generation for file contents (`--nonce-strategy`).
+//!
+//! `random` draws every nonce from the RNG, which is safe for any number of
+//! writers but leaves uniqueness to chance: fine for far more writes than a
+//! vault will see, yet never a guarantee. `counter` numbers them instead,
+//! from a per-vault counter persisted in `.ciphermount-nonce`, so no two
+//! writes by the one writer ever share a nonce.
+//!
+//! Counter nonces are only unique while a single writer uses the counter,
+//! and a reused nonce gives away both plaintexts. The mount therefore holds
+//! an exclusive lock on the counter file for as long as it runs and refuses
+//! to start if another process holds it. Because locks may not be honoured
+//! across machines (network filesystems), the counter is also checked each
+//! time it is advanced: if the file no longer holds what this writer last
+//! stored, someone else is using it and no further nonce is handed out.
+//!
+//! The file holds, sealed under the vault key, the first value not yet
+//! reserved. Values are reserved `RESERVE` at a time, and a reservation is
+//! on disk before any of its values are used, so a crash skips values but
+//! never repeats them. A torn or tampered counter fails authentication and
+//! the mount refuses to start rather than guess.
+//!
+//! Only file contents and symlink targets written through the mount use
+//! counter nonces; other sealed data (sidecars, the canary) and the offline
+//! commands keep using random ones.
+
+use crate::crypto::{self, NonceSource};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Counter file name, relative to the vault root.
+pub const COUNTER_FILE: &str = ".ciphermount-nonce";
+
+/// Counter values reserved per update of the counter file.
+pub const RESERVE: u64 = 1 << 16;
+
+/// AAD sealing the counter to its purpose.
+const COUNTER_AAD: &[u8] = b"ciphermount nonce counter v1";
+
+/// How file contents get their nonces.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum NonceStrategy {
+    /// A fresh random nonce for every seal.
+    #[default]
+    Random,
+    /// Nonces numbered from a persisted per-vault counter. Single writer
+    /// only: the mount refuses to start while another holds the counter.
+    Counter,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Next value to hand out.
+    next: u64,
+    /// First value not covered by the stored reservation.
+    reserved: u64,
+    /// What this writer last stored, to notice anyone else storing.
+    stored: Vec<u8>,
+}
+
+/// A vault's nonce counter, held exclusively until dropped.
+pub struct Counter {
+    file: fs::File,
+    key: [u8; 32],
+    reserve: u64,
+    state: Mutex<State>,
+}
+
+// Leaves out the key.
+impl fmt::Debug for Counter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Counter")
+            .field("reserve", &self.reserve)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Counter {
+    /// Take the nonce counter of the vault at `source`, creating it if the
+    /// vault has none. Fails if another writer holds it.
+    pub fn open(source: &Path, key: &[u8; 32]) -> Result<Self> {
+        let path = source.join(COUNTER_FILE);
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| format!("Opening {:?}", path))?;
+        // SAFETY: `file` is an open descriptor for the duration of the call.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                bail!(
+                    "Another writer holds the nonce counter of {:?}; counter nonces \
+                     need a single writer (use --nonce-strategy random)",
+                    source
+                );
+            }
+            return Err(anyhow!(e).context(format!("Locking {:?}", path)));
+        }
+
+        let stored = fs::read(&path)?;
+        let next = if stored.is_empty() {
+            0
+        } else {
+            let value = crypto::decrypt_with_aad(key, &stored, COUNTER_AAD).map_err(|_| {
+                anyhow!(
+                    "Nonce counter {:?} failed authentication (torn or tampered)",
+                    path
+                )
+            })?;
+            u64::from_le_bytes(
+                value
+                    .try_into()
+                    .map_err(|_| anyhow!("Nonce counter {:?} is malformed", path))?,
+            )
+        };
+        Ok(Self {
+            file,
+            key: *key,
+            reserve: RESERVE,
+            state: Mutex::new(State {
+                next,
+                reserved: next,
+                stored,
+            }),
+        })
+    }
+
+    /// Store a reservation up to `reserved`, after checking that the file
+    /// still holds this writer's last reservation.
+    fn store(&self, state: &mut State, reserved: u64) -> Result<()> {
+        let mut current = vec![0u8; state.stored.len() + 1];
+        let len = self.file.read_at(&mut current, 0)?;
+        current.truncate(len);
+        ensure!(
+            current == state.stored,
+            "The nonce counter changed behind this mount: another writer is using it"
+        );
+        let sealed = crypto::encrypt_with_aad(&self.key, &reserved.to_le_bytes(), COUNTER_AAD)?;
+        self.file.write_all_at(&sealed, 0)?;
+        self.file.set_len(sealed.len() as u64)?;
+        self.file.sync_data()?;
+        state.reserved = reserved;
+        state.stored = sealed;
+        Ok(())
+    }
+}
+
+impl NonceSource for Counter {
+    fn next_nonce(&self) -> Result<[u8; crypto::HEADER_LEN]> {
+        let mut state = self.state.lock().unwrap();
+        if state.next == state.reserved {
+            let reserved = state
+                .next
+                .checked_add(self.reserve)
+                .ok_or_else(|| anyhow!("Nonce counter exhausted"))?;
+            self.store(&mut state, reserved)?;
+        }
+        let mut nonce = [0u8; crypto::HEADER_LEN];
+        nonce[4..].copy_from_slice(&state.next.to_be_bytes());
+        state.next += 1;
+        Ok(nonce)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [0x42u8; 32];
+
+    #[test]
+    fn counter_nonces_increase_across_reservations_and_remounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut seen = vec![];
+        for _ in 0..2 {
+            let mut counter = Counter::open(dir.path(), &KEY).unwrap();
+            counter.reserve = 3;
+            for _ in 0..5 {
+                seen.push(counter.next_nonce().unwrap());
+            }
+        }
+        // Strictly increasing, so unique; the remount skips the rest of the
+        // first mount's reservation.
+        assert!(seen.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(u64::from_be_bytes(seen[5][4..].try_into().unwrap()), 6);
+
+        // Sealed under the key: a forged or torn counter fails.
+        fs::write(dir.path().join(COUNTER_FILE), b"rolled back").unwrap();
+        assert!(Counter::open(dir.path(), &KEY).is_err());
+    }
+
+    #[test]
+    fn concurrent_writers_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut first = Counter::open(dir.path(), &KEY).unwrap();
+        first.reserve = 2;
+        first.next_nonce().unwrap();
+        let err = Counter::open(dir.path(), &KEY).unwrap_err();
+        assert!(err.to_string().contains("Another writer"));
+
+        // A writer the lock didn't stop (another host) is noticed when the
+        // counter is next advanced, and no nonce is handed out.
+        let path = dir.path().join(COUNTER_FILE);
+        let other = crypto::encrypt_with_aad(&KEY, &9u64.to_le_bytes(), COUNTER_AAD).unwrap();
+        fs::write(&path, other).unwrap();
+        first.next_nonce().unwrap();
+        assert!(first.next_nonce().is_err());
+
+        drop(first);
+        assert!(Counter::open(dir.path(), &KEY).is_ok());
+    }
+}
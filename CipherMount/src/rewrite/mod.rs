@@ -0,0 +1,644 @@
+//! Offline bulk rewrites of every file in a vault.
+//!
+//! These run against an unmounted vault. Each file is replaced atomically —
+//! written to a temporary sibling, synced, then renamed over the original —
+//! and keeps its access and modification times, so a rewrite doesn't look
+//! like a content change to backup tools or reset default-TTL expiry. The
+//! logical mtime sealed in each header is carried over as well.
+//!
+//! A file with blocks stored apart from it can't be replaced by one rename.
+//! Its new blocks and header are staged in a directory of their own, which
+//! is swapped with the block directory in one `renameat2` exchange before
+//! the header is renamed into place. Until the staged header is complete
+//! the old file is untouched; after that, `finish_replace` completes the
+//! swap, so a rewrite cut short by a crash is finished (or, if it hadn't
+//! got that far, forgotten) the next time the file is rewritten.
+//!
+//! `reblock` runs to the end or the first error. Embedders that show
+//! progress, or need to stop part way, drive a `Reblock` instead: an
+//! iterator of per-file events that checkpoints as it goes.
+
+use crate::crypto::RandomNonces;
+use crate::dedup::{self, Store};
+use crate::{crypto, format, layout, walk};
+use anyhow::{Context, Result};
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+/// Name, in the vault root, of the checkpoint of an unfinished `Reblock`.
+pub const CHECKPOINT_FILE: &str = ".ciphermount-rewrite";
+
+/// The new header in a staging directory. Written last, so the staged
+/// blocks are complete once it is there.
+const STAGED_HEADER: &str = "header";
+
+/// Progress of a bulk rewrite, reported once per file.
+#[derive(Debug)]
+pub struct Progress<'a> {
+    /// Files handled so far, including this one.
+    pub done: usize,
+    pub total: usize,
+    pub path: &'a Path,
+    /// The file already had the wanted form and was left alone.
+    pub skipped: bool,
+}
+
+/// Outcome of a bulk rewrite.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub rewritten: usize,
+    pub skipped: usize,
+}
+
+/// Every user file in the vault under `root`, in a stable order. Sidecars
+/// and vault files are not included.
+pub fn files(root: &Path, options: walk::Options) -> Result<Vec<PathBuf>> {
+    let mut out = vec![];
+    walk::walk(root, options, |entry| {
+        if entry.file_type.is_file() {
+            out.push(entry.path.clone());
+        }
+        Ok(())
+    })?;
+    Ok(out)
+}
+
+/// Atomically replace the file at `path` with `data`, keeping its
+/// permissions, access time and modification time.
+pub fn replace_preserving_times(path: &Path, data: &[u8]) -> io::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!(".{}.cmtmp", name));
+    let result = write_like(path, &tmp, data).and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Write `data` to a new file at `to`, synced, with the permissions,
+/// access time and modification time of the file at `like`.
+fn write_like(like: &Path, to: &Path, data: &[u8]) -> io::Result<()> {
+    let old = fs::metadata(like)?;
+    let mut file = fs::File::create(to)?;
+    file.write_all(data)?;
+    file.set_permissions(old.permissions())?;
+    file.set_times(
+        fs::FileTimes::new()
+            .set_accessed(old.accessed()?)
+            .set_modified(old.modified()?),
+    )?;
+    file.sync_all()
+}
+
+/// Stand-in for the file at `path` whose blocks are the staged ones:
+/// `layout::block_dir` of it is the staging directory. It is named like a
+/// block directory, which no file can be, so its blocks are never a file's
+/// and are hidden from the mount and the walk like every block directory.
+fn staged(path: &Path) -> PathBuf {
+    layout::block_dir(path)
+}
+
+/// Replace the file at `path` with the packed stream `raw` like
+/// `replace_preserving_times`. A file whose header stores its blocks
+/// apart is staged, then swapped in by `finish_replace`. Blocks of the old
+/// contents are dropped from `store`.
+fn replace_blocks(store: &Store, path: &Path, raw: &[u8]) -> io::Result<()> {
+    let header = format::Header::decode(raw);
+    if !header.is_some_and(|h| h.separate_blocks() || h.dedup_blocks()) {
+        replace_preserving_times(path, raw)?;
+        return store.remove(path);
+    }
+    stage(path, raw)?;
+    finish_replace(store, path)
+}
+
+/// Stage the packed stream `raw` as the new contents of the file at
+/// `path`, whose header stores its blocks apart: its blocks (a
+/// deduplicated file's are already linked there from the store), then its
+/// header, with the times of the file it replaces.
+fn stage(path: &Path, raw: &[u8]) -> io::Result<()> {
+    let staging = layout::block_dir(&staged(path));
+    let backing = match format::Header::decode(raw).filter(|h| h.separate_blocks()) {
+        Some(header) => {
+            layout::write_blocks(&staged(path), raw, &header)?;
+            for index in 0..header.block_count() {
+                fs::File::open(layout::block_file(&staged(path), index))?.sync_all()?;
+            }
+            &raw[..header.size()]
+        }
+        None => {
+            fs::create_dir_all(&staging)?;
+            raw
+        }
+    };
+    let tmp = staging.join(format!("{}.tmp", STAGED_HEADER));
+    write_like(path, &tmp, backing)?;
+    fs::rename(&tmp, staging.join(STAGED_HEADER))
+}
+
+/// Finish a replacement of the file at `path` that `replace_blocks` staged
+/// but may not have swapped in, as after a crash, or drop what was staged
+/// if its header never got written. Does nothing if nothing is staged.
+pub fn finish_replace(store: &Store, path: &Path) -> io::Result<()> {
+    let (blocks, staging) = (layout::block_dir(path), layout::block_dir(&staged(path)));
+    if staging.join(STAGED_HEADER).exists() {
+        if blocks.exists() {
+            exchange(&staging, &blocks)?;
+        } else {
+            fs::rename(&staging, &blocks)?;
+        }
+    }
+    // Swapped in: only the header is left to move.
+    let header = blocks.join(STAGED_HEADER);
+    if header.exists() {
+        fs::rename(&header, path)?;
+    }
+    // Either the old blocks, or new ones whose header never got written.
+    store.remove(&staged(path))
+}
+
+/// Atomically swap the directories `a` and `b`.
+fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    let cstr = |p: &Path| {
+        CString::new(p.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let (a, b) = (cstr(a)?, cstr(b)?);
+    // SAFETY: both are valid NUL-terminated paths.
+    let rc = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Logical mtime of the backing file at `path` with contents `raw`: the one
+/// sealed in its header, or the backing mtime for files that have none.
+pub fn logical_mtime(key: &[u8; 32], path: &Path, raw: &[u8]) -> Result<u64> {
+    if let Some(ns) = format::Header::decode(raw).and_then(|h| h.open_mtime(key).ok().flatten()) {
+        return Ok(ns);
+    }
+    let mtime = fs::metadata(path)?.modified()?;
+    Ok(mtime
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64)
+}
+
+/// Rewrite every file in the vault at `root` into the block format with
+/// `block_size`. Files already at that block size are skipped; legacy files
+/// are converted. Deduplicated files stay deduplicated, in blocks of the
+/// new size.
+pub fn reblock(
+    root: &Path,
+    key: &[u8; 32],
+    block_size: u32,
+    options: walk::Options,
+    mut progress: impl FnMut(&Progress),
+) -> Result<Summary> {
+    let files = files(root, options)?;
+    let store = Store::new(root);
+    let mut summary = Summary::default();
+    for (i, path) in files.iter().enumerate() {
+        let skipped = !reblock_file(&store, key, path, block_size)?;
+        if skipped {
+            summary.skipped += 1;
+        } else {
+            summary.rewritten += 1;
+        }
+        progress(&Progress {
+            done: i + 1,
+            total: files.len(),
+            path,
+            skipped,
+        });
+    }
+    Ok(summary)
+}
+
+/// Rewrite the file at `path` with `block_size`, unless it already has it.
+/// Returns whether it was rewritten.
+fn reblock_file(store: &Store, key: &[u8; 32], path: &Path, block_size: u32) -> Result<bool> {
+    finish_replace(store, path).with_context(|| format!("Finishing a rewrite of {:?}", path))?;
+    let raw = layout::read(path).with_context(|| format!("Reading {:?}", path))?;
+    let header = format::Header::decode(&raw).filter(|h| h.file_len() <= raw.len() as u128);
+    // Rewriting drops trailing bytes, so files with any aren't done yet.
+    let current = header
+        .filter(|h| h.file_len() == raw.len() as u128)
+        .map(|h| h.block_size);
+    // Files too short to hold ciphertext are empty and have no blocks.
+    if current == Some(block_size) || raw.len() < crypto::HEADER_LEN + 16 {
+        return Ok(false);
+    }
+    reseal(store, key, path, &raw, header, block_size)?;
+    Ok(true)
+}
+
+/// Reseal the file at `path` in the current format if it is stored in an
+/// older one: the legacy whole-file format, or a version 1 header without
+/// a sealed mtime. The file keeps its block size and layout; legacy files
+/// get the default block size. Returns whether it was rewritten.
+pub fn upgrade_file(store: &Store, key: &[u8; 32], path: &Path) -> Result<bool> {
+    finish_replace(store, path).with_context(|| format!("Finishing a rewrite of {:?}", path))?;
+    let raw = layout::read(path).with_context(|| format!("Reading {:?}", path))?;
+    let header = format::Header::decode(&raw).filter(|h| h.file_len() <= raw.len() as u128);
+    if header.is_some_and(|h| h.version == format::VERSION) || raw.len() < crypto::HEADER_LEN + 16 {
+        return Ok(false);
+    }
+    let block_size = header.map_or(format::DEFAULT_BLOCK_SIZE, |h| h.block_size);
+    reseal(store, key, path, &raw, header, block_size)?;
+    Ok(true)
+}
+
+/// Replace the file at `path`, currently `raw` with `header`, by its
+/// contents resealed in the current format with `block_size`. Nothing may
+/// be staged for it (see `finish_replace`).
+fn reseal(
+    store: &Store,
+    key: &[u8; 32],
+    path: &Path,
+    raw: &[u8],
+    header: Option<format::Header>,
+    block_size: u32,
+) -> Result<()> {
+    let plaintext =
+        dedup::decrypt_file(key, path, raw).with_context(|| format!("Decrypting {:?}", path))?;
+    let mtime = logical_mtime(key, path, raw)?;
+    // Files keep their block layout.
+    let mut rewritten = match header {
+        Some(h) if h.dedup_blocks() => dedup::encrypt_file(
+            store,
+            key,
+            &RandomNonces,
+            &staged(path),
+            &plaintext,
+            block_size,
+            h.flags,
+            mtime,
+        )?,
+        h => format::encrypt_file_with_flags(
+            key,
+            &RandomNonces,
+            &plaintext,
+            block_size,
+            h.map_or(0, |h| h.flags),
+            mtime,
+        )?,
+    };
+    // And their expiry.
+    if let Some(h) = header.filter(|h| h.has_expiry()) {
+        format::set_expiry(key, &RandomNonces, &mut rewritten, h.open_expiry(key)?)?;
+    }
+    replace_blocks(store, path, &rewritten).with_context(|| format!("Replacing {:?}", path))
+}
+
+/// What happened to one file of a `Reblock`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The file is about to be rewritten.
+    Started(PathBuf),
+    /// The file was rewritten.
+    Completed(PathBuf),
+    /// The file already had the block size and was left alone.
+    Skipped(PathBuf),
+    /// The file could not be rewritten and was left as it was.
+    Failed(PathBuf, String),
+}
+
+/// `reblock` driven by its caller, a file at a time.
+///
+/// Each file yields `Started` and then its outcome; a failed file doesn't
+/// stop the rest. Not asking for the next event pauses the job, which holds
+/// no open files in between. After every file a checkpoint is saved in the
+/// vault root, so a job that is cancelled (or whose process dies) is picked
+/// up after the last file it handled by the next `Reblock` for the same
+/// block size. The checkpoint is removed once every file is done.
+pub struct Reblock {
+    root: PathBuf,
+    key: [u8; 32],
+    block_size: u32,
+    store: Store,
+    files: std::vec::IntoIter<PathBuf>,
+    /// File announced by the last `Started`, not yet handled.
+    current: Option<PathBuf>,
+    /// Least time spent on each file.
+    throttle: Duration,
+    last_started: Option<Instant>,
+}
+
+impl Reblock {
+    /// Prepare to rewrite every file of the vault at `root` with
+    /// `block_size`, resuming from a checkpoint left for the same size.
+    pub fn new(
+        root: &Path,
+        key: &[u8; 32],
+        block_size: u32,
+        options: walk::Options,
+    ) -> Result<Self> {
+        let after = load_checkpoint(root, block_size)?;
+        let files: Vec<PathBuf> = files(root, options)?
+            .into_iter()
+            // The walk is in path order, so this is everything not reached.
+            .filter(|path| {
+                after
+                    .as_deref()
+                    .is_none_or(|after| relative(root, path) > after)
+            })
+            .collect();
+        Ok(Self {
+            root: root.to_path_buf(),
+            key: *key,
+            block_size,
+            store: Store::new(root),
+            files: files.into_iter(),
+            current: None,
+            throttle: Duration::ZERO,
+            last_started: None,
+        })
+    }
+
+    /// Spend at least `per_file` on each file, so the rewrite leaves the
+    /// disk to others.
+    pub fn with_throttle(mut self, per_file: Duration) -> Self {
+        self.throttle = per_file;
+        self
+    }
+
+    /// Files not yet handled.
+    pub fn remaining(&self) -> usize {
+        self.files.len() + usize::from(self.current.is_some())
+    }
+
+    /// Stop for good, leaving the checkpoint for a later job to resume
+    /// from. Dropping the job does the same.
+    pub fn cancel(self) {}
+}
+
+impl Iterator for Reblock {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        if let Some(path) = self.current.take() {
+            let event = match reblock_file(&self.store, &self.key, &path, self.block_size) {
+                Ok(true) => Event::Completed(path.clone()),
+                Ok(false) => Event::Skipped(path.clone()),
+                Err(e) => Event::Failed(path.clone(), format!("{:#}", e)),
+            };
+            if let Err(e) =
+                save_checkpoint(&self.root, self.block_size, relative(&self.root, &path))
+            {
+                log::warn!("Could not save the rewrite checkpoint: {}", e);
+            }
+            return Some(event);
+        }
+        let Some(path) = self.files.next() else {
+            let _ = fs::remove_file(self.root.join(CHECKPOINT_FILE));
+            return None;
+        };
+        if let Some(wait) = self
+            .last_started
+            .and_then(|started| self.throttle.checked_sub(started.elapsed()))
+        {
+            std::thread::sleep(wait);
+        }
+        self.last_started = Some(Instant::now());
+        self.current = Some(path.clone());
+        Some(Event::Started(path))
+    }
+}
+
+fn relative<'a>(root: &Path, path: &'a Path) -> &'a Path {
+    path.strip_prefix(root).unwrap_or(path)
+}
+
+/// Record that a rewrite to `block_size` has handled every file up to
+/// `after`, relative to `root`.
+fn save_checkpoint(root: &Path, block_size: u32, after: &Path) -> io::Result<()> {
+    let path = root.join(CHECKPOINT_FILE);
+    let tmp = root.join(format!("{}.tmp", CHECKPOINT_FILE));
+    let text = format!(
+        "block_size={}\nafter={}\n",
+        block_size,
+        hex::encode(after.as_os_str().as_bytes())
+    );
+    fs::write(&tmp, text)?;
+    fs::rename(&tmp, path)
+}
+
+/// Last file handled by an unfinished rewrite to `block_size`, if any.
+/// A checkpoint left by a rewrite to another size is ignored.
+fn load_checkpoint(root: &Path, block_size: u32) -> Result<Option<PathBuf>> {
+    let text = match fs::read_to_string(root.join(CHECKPOINT_FILE)) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let value = |name: &str| {
+        text.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+    };
+    if value("block_size") != Some(block_size.to_string().as_str()) {
+        return Ok(None);
+    }
+    let Some(after) = value("after") else {
+        return Ok(None);
+    };
+    let bytes = hex::decode(after).context("Malformed rewrite checkpoint")?;
+    Ok(Some(PathBuf::from(std::ffi::OsStr::from_bytes(&bytes))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::Layout;
+    use crate::{meta, vault};
+
+    const KEY: [u8; 32] = [0x42u8; 32];
+
+    #[test]
+    fn reblock_rewrites_to_the_new_block_size() {
+        let dir = tempfile::tempdir().unwrap();
+        vault::init(dir.path(), &KEY).unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let files: Vec<(PathBuf, Vec<u8>, Vec<u8>)> = vec![
+            (
+                "a",
+                vec![1u8; 5000],
+                format::encrypt_file(&KEY, &[1u8; 5000], 4096),
+            ),
+            (
+                "sub/b",
+                vec![2u8; 300],
+                format::encrypt_file(&KEY, &[2u8; 300], 256),
+            ),
+            (
+                "sub/c",
+                b"legacy".to_vec(),
+                crypto::encrypt(&KEY, b"legacy"),
+            ),
+        ]
+        .into_iter()
+        .map(|(name, pt, raw)| (dir.path().join(name), pt, raw.unwrap()))
+        .collect();
+        let old_mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        for (path, _, raw) in &files {
+            fs::write(path, raw).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(old_mtime)
+                .unwrap();
+        }
+        meta::FileMeta::default()
+            .store(&KEY, dir.path(), &files[0].0)
+            .unwrap();
+
+        let mut seen = vec![];
+        let summary = reblock(dir.path(), &KEY, 256, walk::Options::default(), |p| {
+            seen.push((p.done, p.total, p.skipped))
+        })
+        .unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                rewritten: 2,
+                skipped: 1
+            }
+        );
+        assert_eq!(seen, [(1, 3, false), (2, 3, true), (3, 3, false)]);
+
+        for (path, plaintext, _) in &files {
+            let raw = fs::read(path).unwrap();
+            assert_eq!(format::Header::decode(&raw).unwrap().block_size, 256);
+            assert_eq!(&format::decrypt_file(&KEY, &raw).unwrap(), plaintext);
+            assert_eq!(fs::metadata(path).unwrap().modified().unwrap(), old_mtime);
+        }
+        assert_eq!(
+            vault::verify_canary(dir.path(), &KEY).unwrap(),
+            vault::Canary::Valid
+        );
+
+        // Running again has nothing left to do.
+        let again = reblock(dir.path(), &KEY, 256, walk::Options::default(), |_| {}).unwrap();
+        assert_eq!(
+            again,
+            Summary {
+                rewritten: 0,
+                skipped: 3
+            }
+        );
+    }
+
+    #[test]
+    fn rewrite_cut_short_leaves_the_old_or_the_new_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(dir.path());
+        let path = dir.path().join("f");
+        let contents = || dedup::decrypt_file(&KEY, &path, &layout::read(&path).unwrap());
+        for layout in [Layout::Separate, Layout::Dedup] {
+            let seal = |at: &Path, plaintext: &[u8], block_size| match layout {
+                Layout::Dedup => dedup::encrypt_file(
+                    &store,
+                    &KEY,
+                    &RandomNonces,
+                    at,
+                    plaintext,
+                    block_size,
+                    0,
+                    0,
+                ),
+                _ => format::encrypt_file_with_flags(
+                    &KEY,
+                    &RandomNonces,
+                    plaintext,
+                    block_size,
+                    layout.flags(),
+                    0,
+                ),
+            };
+            for crash in ["while staging", "before the swap", "after the swap"] {
+                let old = seal(&path, &[1u8; 300], 64).unwrap();
+                // A deduplicated file's blocks are already linked in.
+                match layout {
+                    Layout::Dedup => fs::write(&path, &old).unwrap(),
+                    _ => layout::write(&path, &old).unwrap(),
+                }
+                let new = seal(&staged(&path), &[2u8; 300], 128).unwrap();
+                stage(&path, &new).unwrap();
+                let staging = layout::block_dir(&staged(&path));
+                match crash {
+                    "while staging" => fs::remove_file(staging.join(STAGED_HEADER)).unwrap(),
+                    "before the swap" => {}
+                    _ => exchange(&staging, &layout::block_dir(&path)).unwrap(),
+                }
+                if crash != "after the swap" {
+                    assert_eq!(contents().unwrap(), [1u8; 300], "{layout:?} {crash}");
+                }
+
+                finish_replace(&store, &path).unwrap();
+                let expected = if crash == "while staging" { 1 } else { 2 };
+                assert_eq!(contents().unwrap(), [expected; 300], "{layout:?} {crash}");
+                assert!(!staging.exists());
+                // Whatever the file no longer uses was released.
+                assert_eq!(store.collect_garbage().unwrap(), 0);
+                store.remove(&path).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn cancelled_reblock_resumes_from_its_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        vault::init(dir.path(), &KEY).unwrap();
+        for (name, block_size) in [("a", 4096), ("b", 4096), ("c", 256)] {
+            let raw = format::encrypt_file(&KEY, &[7u8; 1000], block_size).unwrap();
+            fs::write(dir.path().join(name), raw).unwrap();
+        }
+        let file = |name: &str| dir.path().join(name);
+
+        let mut job = Reblock::new(dir.path(), &KEY, 256, walk::Options::default()).unwrap();
+        assert_eq!(job.remaining(), 3);
+        let events: Vec<Event> = job.by_ref().take(3).collect();
+        assert_eq!(
+            events,
+            [
+                Event::Started(file("a")),
+                Event::Completed(file("a")),
+                Event::Started(file("b"))
+            ]
+        );
+        job.cancel();
+        assert!(dir.path().join(CHECKPOINT_FILE).exists());
+
+        // `b` was announced but never rewritten, so it comes up again.
+        let job = Reblock::new(dir.path(), &KEY, 256, walk::Options::default()).unwrap();
+        assert_eq!(
+            job.collect::<Vec<_>>(),
+            [
+                Event::Started(file("b")),
+                Event::Completed(file("b")),
+                Event::Started(file("c")),
+                Event::Skipped(file("c"))
+            ]
+        );
+        assert!(!dir.path().join(CHECKPOINT_FILE).exists());
+        for name in ["a", "b", "c"] {
+            let raw = fs::read(file(name)).unwrap();
+            assert_eq!(format::Header::decode(&raw).unwrap().block_size, 256);
+        }
+    }
+}
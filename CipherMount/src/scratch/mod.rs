@@ -0,0 +1,88 @@
+//! Ephemeral vaults (`--ephemeral`): encrypted scratch space that lives in
+//! RAM and is gone after unmount.
+//!
+//! The backing directory is created on a memory filesystem (tmpfs or ramfs)
+//! and removed again when the `Scratch` is dropped. Its contents are still
+//! ciphertext under a key that only ever exists in the mounting process, so
+//! reading the tmpfs — or a leftover directory after a crash — yields
+//! nothing usable. Plaintext only exists in the block cache, which `--mlock`
+//! keeps out of swap.
+
+use anyhow::{bail, Context, Result};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+/// Where scratch vaults are created.
+pub const DEFAULT_ROOT: &str = "/dev/shm";
+
+/// `f_type` of a ramfs mount; libc only defines the tmpfs one.
+const RAMFS_MAGIC: i64 = 0x8584_58f6;
+
+/// A private, initially empty directory on a memory filesystem, removed
+/// with everything in it on drop.
+#[derive(Debug)]
+pub struct Scratch {
+    path: PathBuf,
+}
+
+impl Scratch {
+    /// Create a scratch directory under `root`, which must be on a memory
+    /// filesystem.
+    pub fn create(root: &Path) -> Result<Self> {
+        if !in_memory(root).with_context(|| format!("Cannot inspect {:?}", root))? {
+            bail!("{:?} is not a memory filesystem (tmpfs or ramfs)", root);
+        }
+        let id: [u8; 8] = rand::random();
+        let path = root.join(format!("ciphermount-{}", hex::encode(id)));
+        fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&path)
+            .with_context(|| format!("Cannot create {:?}", path))?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            log::warn!("Cannot remove scratch vault {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Whether `path` is on a filesystem that keeps its data in memory.
+pub fn in_memory(path: &Path) -> io::Result<bool> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: an all-zero statfs is a valid value to be overwritten.
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is NUL-terminated and `stat` a valid out-pointer.
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let kind = stat.f_type as i64;
+    Ok(kind == libc::TMPFS_MAGIC || kind == RAMFS_MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disk_roots_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        if in_memory(dir.path()).unwrap() {
+            return; // The temp dir is itself on tmpfs here.
+        }
+        assert!(Scratch::create(dir.path()).is_err());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
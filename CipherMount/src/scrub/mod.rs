@@ -0,0 +1,459 @@
+//! Scrubbing: re-authenticating every file in a vault to catch bit rot and
+//! tampering before the data is needed.
+//!
+//! A full scrub of a large archive is expensive, so scrubs are incremental:
+//! each verified file's mtime, size and verification time are recorded, and
+//! a file is only checked again once it has changed or its last check is
+//! older than the re-verification window. The record is sealed with the
+//! vault key and kept in `.ciphermount-scrub`; if it is missing, damaged or
+//! tampered with, the next scrub simply verifies everything.
+//!
+//! Files whose sidecar marks them incomplete (a write through the mount was
+//! cut short) are reported as such rather than as failures: a torn last
+//! block is expected there, not a sign of tampering.
+//!
+//! With `repair`, a file that verifies but is stored in an outdated format
+//! (legacy whole-file, or a version 1 header) is resealed in the current
+//! one as it is checked (read-repair), so a vault moves to the current
+//! format over ordinary scrubs. Each file is replaced atomically, so it
+//! reads the same throughout. Repair rewrites files under whoever else has
+//! them open, so it is only for vaults that aren't mounted.
+//!
+//! Record layout (before sealing): count u32 LE, then per file
+//!   path hash u64 | mtime (ns) i64 | size u64 | verified at (Unix s) u64
+
+use crate::dedup::Store;
+use crate::logging::detail;
+use crate::meta::FileMeta;
+use crate::{crypto, dedup, layout, rewrite, walk};
+use anyhow::{ensure, Result};
+use ring::digest;
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// Scrub record file name, relative to the vault root.
+pub const STATE_FILE: &str = ".ciphermount-scrub";
+
+/// How long a verification stays valid unless configured otherwise.
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(7 * 24 * 3600);
+
+const STATE_AAD: &[u8] = b"ciphermount scrub state v1";
+const RECORD_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Record {
+    mtime_ns: i64,
+    size: u64,
+    verified_at: u64,
+}
+
+/// Files verified so far, keyed by a hash of their path in the vault.
+#[derive(Debug, Default)]
+pub struct State {
+    records: HashMap<u64, Record>,
+}
+
+fn path_hash(root: &Path, path: &Path) -> u64 {
+    let rel = path.strip_prefix(root).unwrap_or(path);
+    let digest = digest::digest(&digest::SHA256, rel.as_os_str().as_encoded_bytes());
+    u64::from_le_bytes(digest.as_ref()[..8].try_into().unwrap())
+}
+
+impl State {
+    /// Load the record for the vault at `root`, or start afresh if there is
+    /// none or it can't be trusted.
+    pub fn load(root: &Path, key: &[u8; 32]) -> Self {
+        let Ok(sealed) = fs::read(root.join(STATE_FILE)) else {
+            return Self::default();
+        };
+        match crypto::decrypt_with_aad(key, &sealed, STATE_AAD)
+            .ok()
+            .and_then(|raw| Self::decode(&raw))
+        {
+            Some(state) => state,
+            None => {
+                log::warn!("Scrub record is unreadable; verifying every file");
+                Self::default()
+            }
+        }
+    }
+
+    /// Seal and write the record.
+    pub fn store(&self, root: &Path, key: &[u8; 32]) -> Result<()> {
+        let sealed = crypto::encrypt_with_aad(key, &self.encode(), STATE_AAD)?;
+        let tmp = root.join(format!("{}.tmp", STATE_FILE));
+        fs::write(&tmp, sealed)?;
+        fs::rename(tmp, root.join(STATE_FILE))?;
+        Ok(())
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(4 + self.records.len() * RECORD_LEN);
+        out.extend_from_slice(&(self.records.len() as u32).to_le_bytes());
+        for (hash, r) in &self.records {
+            out.extend_from_slice(&hash.to_le_bytes());
+            out.extend_from_slice(&r.mtime_ns.to_le_bytes());
+            out.extend_from_slice(&r.size.to_le_bytes());
+            out.extend_from_slice(&r.verified_at.to_le_bytes());
+        }
+        out
+    }
+
+    fn decode(raw: &[u8]) -> Option<Self> {
+        let count = u32::from_le_bytes(raw.get(..4)?.try_into().ok()?) as usize;
+        let body = &raw[4..];
+        if body.len() != count.checked_mul(RECORD_LEN)? {
+            return None;
+        }
+        let field = |rec: &[u8], i: usize| rec[i * 8..i * 8 + 8].try_into().unwrap();
+        let records = body
+            .chunks(RECORD_LEN)
+            .map(|rec| {
+                (
+                    u64::from_le_bytes(field(rec, 0)),
+                    Record {
+                        mtime_ns: i64::from_le_bytes(field(rec, 1)),
+                        size: u64::from_le_bytes(field(rec, 2)),
+                        verified_at: u64::from_le_bytes(field(rec, 3)),
+                    },
+                )
+            })
+            .collect();
+        Some(Self { records })
+    }
+}
+
+/// What to scrub.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// Verify every file, ignoring the record.
+    pub full: bool,
+    /// Re-verify unchanged files once their last check is this old.
+    pub window: Duration,
+    /// Reseal verified files stored in an outdated format.
+    pub repair: bool,
+    /// Pause after each repaired file, to spare the backing store.
+    pub throttle: Duration,
+    pub walk: walk::Options,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            full: false,
+            window: DEFAULT_WINDOW,
+            repair: false,
+            throttle: Duration::ZERO,
+            walk: walk::Options::default(),
+        }
+    }
+}
+
+/// Outcome of a scrub.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub verified: usize,
+    pub skipped: usize,
+    /// Files that failed authentication, with the reason.
+    pub failed: Vec<(PathBuf, String)>,
+    /// Files left incomplete by an interrupted write.
+    pub incomplete: Vec<PathBuf>,
+    /// Files resealed in the current format.
+    pub repaired: Vec<PathBuf>,
+    /// Stopped before the end; the files not reached keep their record.
+    pub paused: bool,
+}
+
+/// Authenticate every block of the backing file at `path`.
+pub fn verify(key: &[u8; 32], path: &Path) -> Result<()> {
+    let raw = layout::read(path)?;
+    // Only a file never written is empty; anything else too short to hold
+    // ciphertext was cut short.
+    if raw.is_empty() {
+        return Ok(());
+    }
+    ensure!(
+        raw.len() >= crypto::HEADER_LEN + 16,
+        "Truncated to {} bytes",
+        raw.len()
+    );
+    dedup::decrypt_file(key, path, &raw)?;
+    Ok(())
+}
+
+/// Scrub the vault at `root` at Unix time `now`, updating `state`. Failed
+/// and incomplete files lose their record so they are checked again next
+/// time.
+pub fn scrub(
+    root: &Path,
+    key: &[u8; 32],
+    state: &mut State,
+    now: u64,
+    options: Options,
+) -> Result<Report> {
+    scrub_while(root, key, state, now, options, || true)
+}
+
+/// `scrub`, stopping before the next file once `keep_going` returns false.
+/// The files verified up to then are recorded, so storing `state` and
+/// scrubbing again later carries on where this one stopped.
+pub fn scrub_while(
+    root: &Path,
+    key: &[u8; 32],
+    state: &mut State,
+    now: u64,
+    options: Options,
+    keep_going: impl Fn() -> bool,
+) -> Result<Report> {
+    let mut report = Report::default();
+    let mut seen = HashMap::new();
+    let store = Store::new(root);
+    walk::walk(root, options.walk, |entry| {
+        if !entry.file_type.is_file() || report.paused {
+            return Ok(());
+        }
+        if !keep_going() {
+            report.paused = true;
+            return Ok(());
+        }
+        let hash = path_hash(root, &entry.path);
+        let marked =
+            FileMeta::load(key, root, &entry.path).is_ok_and(|m| m.is_some_and(|m| m.incomplete));
+        if marked {
+            state.records.remove(&hash);
+            report.incomplete.push(entry.path.clone());
+            return Ok(());
+        }
+        let record = |path: &Path| -> Result<Record> {
+            let meta = fs::metadata(path)?;
+            Ok(Record {
+                mtime_ns: meta.mtime() * 1_000_000_000 + meta.mtime_nsec(),
+                size: meta.len(),
+                verified_at: now,
+            })
+        };
+        let mut current = record(&entry.path)?;
+        let fresh = state.records.get(&hash).is_some_and(|r| {
+            r.mtime_ns == current.mtime_ns
+                && r.size == current.size
+                && now.saturating_sub(r.verified_at) < options.window.as_secs()
+        });
+        if fresh && !options.full {
+            report.skipped += 1;
+            seen.insert(hash, state.records[&hash]);
+            return Ok(());
+        }
+        match verify(key, &entry.path) {
+            Ok(()) => {
+                report.verified += 1;
+                if options.repair {
+                    match rewrite::upgrade_file(&store, key, &entry.path) {
+                        Ok(true) => {
+                            report.repaired.push(entry.path.clone());
+                            current = record(&entry.path)?;
+                            thread::sleep(options.throttle);
+                        }
+                        Ok(false) => {}
+                        Err(e) => {
+                            log::warn!("Scrub: repairing {} failed: {:#}", detail(&entry.path), e)
+                        }
+                    }
+                }
+                seen.insert(hash, current);
+            }
+            Err(e) => {
+                state.records.remove(&hash);
+                report.failed.push((entry.path.clone(), e.to_string()));
+            }
+        }
+        Ok(())
+    })?;
+    if report.paused {
+        // Files not reached this time may still exist.
+        state.records.extend(seen);
+    } else {
+        // Records of deleted or failed files are dropped.
+        state.records = seen;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format;
+
+    const KEY: [u8; 32] = [0x42u8; 32];
+
+    fn write(path: &Path, plaintext: &[u8], mtime_secs: u64) {
+        fs::write(path, format::encrypt_file(&KEY, plaintext, 64).unwrap()).unwrap();
+        let mtime = std::time::UNIX_EPOCH + Duration::from_secs(mtime_secs);
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    }
+
+    fn run(dir: &Path, now: u64) -> Report {
+        let mut state = State::load(dir, &KEY);
+        let report = scrub(dir, &KEY, &mut state, now, Options::default()).unwrap();
+        state.store(dir, &KEY).unwrap();
+        report
+    }
+
+    #[test]
+    fn paused_scrub_resumes_where_it_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a", "b", "c", "d"] {
+            write(&dir.path().join(name), name.as_bytes(), 1000);
+        }
+        let budget = std::cell::Cell::new(2u32);
+        let mut state = State::load(dir.path(), &KEY);
+        let report = scrub_while(
+            dir.path(),
+            &KEY,
+            &mut state,
+            2000,
+            Options::default(),
+            || budget.replace(budget.get().saturating_sub(1)) > 0,
+        )
+        .unwrap();
+        assert!(report.paused);
+        assert_eq!(report.verified, 2);
+        state.store(dir.path(), &KEY).unwrap();
+
+        let report = run(dir.path(), 2001);
+        assert!(!report.paused);
+        assert_eq!((report.verified, report.skipped), (2, 2));
+    }
+
+    #[test]
+    fn unchanged_files_are_skipped_until_modified_or_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        write(&a, b"first", 1000);
+        write(&b, b"second", 1000);
+
+        let first = run(dir.path(), 10_000);
+        assert_eq!((first.verified, first.skipped), (2, 0));
+
+        let second = run(dir.path(), 10_100);
+        assert_eq!((second.verified, second.skipped), (0, 2));
+
+        write(&b, b"second, edited", 2000);
+        let third = run(dir.path(), 10_200);
+        assert_eq!((third.verified, third.skipped), (1, 1));
+
+        // Past the window everything is checked again.
+        let stale = run(dir.path(), 10_000 + DEFAULT_WINDOW.as_secs());
+        assert_eq!((stale.verified, stale.skipped), (1, 1));
+        assert!(stale.failed.is_empty());
+    }
+
+    #[test]
+    fn lost_or_tampered_record_falls_back_to_full_scrub() {
+        let dir = tempfile::tempdir().unwrap();
+        write(&dir.path().join("a"), b"data", 1000);
+        run(dir.path(), 5000);
+
+        let state_path = dir.path().join(STATE_FILE);
+        let mut sealed = fs::read(&state_path).unwrap();
+        *sealed.last_mut().unwrap() ^= 1;
+        fs::write(&state_path, &sealed).unwrap();
+        assert_eq!(run(dir.path(), 5001).verified, 1);
+
+        fs::remove_file(&state_path).unwrap();
+        assert_eq!(run(dir.path(), 5002).verified, 1);
+    }
+
+    #[test]
+    fn repair_reseals_outdated_files_while_they_stay_readable() {
+        use crate::crypto::RandomNonces;
+        use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+
+        let dir = tempfile::tempdir().unwrap();
+        let plaintext: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let mut v1 = format::Header::new(64, plaintext.len() as u64);
+        v1.version = 1;
+        v1.mtime = None;
+        let mut raw = v1.encode();
+        for (index, chunk) in plaintext.chunks(64).enumerate() {
+            let block = format::seal_block(&KEY, &RandomNonces, &v1, index as u64, chunk);
+            raw.extend_from_slice(&block.unwrap());
+        }
+        let old = dir.path().join("old");
+        fs::write(&old, &raw).unwrap();
+        let legacy = dir.path().join("legacy");
+        fs::write(&legacy, crypto::encrypt(&KEY, b"legacy").unwrap()).unwrap();
+        write(&dir.path().join("current"), b"current", 1000);
+
+        let done = AtomicBool::new(false);
+        let options = Options {
+            repair: true,
+            ..Default::default()
+        };
+        let report = thread::scope(|s| {
+            s.spawn(|| {
+                while !done.load(SeqCst) {
+                    let raw = fs::read(&old).unwrap();
+                    assert_eq!(format::decrypt_file(&KEY, &raw).unwrap(), plaintext);
+                }
+            });
+            let mut state = State::default();
+            let report = scrub(dir.path(), &KEY, &mut state, 5000, options);
+            done.store(true, SeqCst);
+            report.unwrap()
+        });
+        assert_eq!(report.verified, 3);
+        assert_eq!(report.repaired, vec![legacy.clone(), old.clone()]);
+
+        let raw = fs::read(&old).unwrap();
+        let header = format::Header::decode(&raw).unwrap();
+        assert_eq!((header.version, header.block_size), (format::VERSION, 64));
+        assert_eq!(format::decrypt_file(&KEY, &raw).unwrap(), plaintext);
+        let raw = fs::read(&legacy).unwrap();
+        let upgraded = format::Header::decode(&raw).unwrap();
+        assert_eq!(upgraded.block_size, format::DEFAULT_BLOCK_SIZE);
+        assert_eq!(format::decrypt_file(&KEY, &raw).unwrap(), b"legacy");
+
+        let mut state = State::default();
+        let again = scrub(dir.path(), &KEY, &mut state, 5001, options).unwrap();
+        assert!(again.repaired.is_empty() && again.failed.is_empty());
+    }
+
+    #[test]
+    fn damaged_file_is_reported_and_rechecked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        write(&path, &[7u8; 200], 1000);
+        run(dir.path(), 5000);
+
+        let mut raw = fs::read(&path).unwrap();
+        raw[format::HEADER_SIZE + 20] ^= 1;
+        fs::write(&path, raw).unwrap();
+        let report = run(dir.path(), 5001);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(run(dir.path(), 5002).failed.len(), 1);
+    }
+
+    #[test]
+    fn truncated_header_is_reported_but_empty_file_is_not() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        write(&path, &[7u8; 200], 1000);
+        fs::write(dir.path().join("empty"), b"").unwrap();
+        let raw = fs::read(&path).unwrap();
+        fs::write(&path, &raw[..20]).unwrap();
+
+        let report = run(dir.path(), 5000);
+        assert_eq!(report.failed.len(), 1, "{:?}", report.failed);
+        assert_eq!(report.failed[0].0, path);
+        assert!(verify(&KEY, &dir.path().join("empty")).is_ok());
+    }
+}
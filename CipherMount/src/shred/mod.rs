@@ -0,0 +1,149 @@
+//! Destroying deleted files (`--secure-delete`).
+//!
+//! Unlinking a file frees its ciphertext, which stays on the medium until
+//! the space is reused. With `overwrite`, the mount first overwrites the
+//! backing file, its sidecar and its separately stored blocks with zeros
+//! and syncs them, so the ciphertext is gone even if the vault key later
+//! leaks. Anything with another link is left alone: a hard link made
+//! through the mount still needs the data, and so do other files sharing a
+//! block in the dedup store. A dedup store entry only this file links to
+//! is overwritten, since it goes with the file.
+//!
+//! With `crypto-erase`, every file the mount writes gets a key of its own,
+//! wrapped in its header (see `format`), and deleting it zeroes just the
+//! wrapped key. However many copies of its blocks survive, nothing can
+//! decrypt them any more. Files without a key of their own (written before
+//! the mode was on, or legacy) and sidecars are overwritten instead.
+//!
+//! This only reaches what the backing filesystem overwrites in place.
+//! Copy-on-write and log-structured filesystems, snapshots and SSD wear
+//! levelling can all keep older copies that no write from above can touch;
+//! with `crypto-erase` that is only true of the small header.
+
+use crate::dedup::Store;
+use crate::{format, layout, meta};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::Path;
+
+/// Zeros written at once.
+const CHUNK: usize = 64 * 1024;
+
+/// What happens to a file's backing data when it is deleted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SecureDelete {
+    /// Unlink only.
+    #[default]
+    None,
+    /// Overwrite with zeros, then unlink.
+    Overwrite,
+    /// Destroy the file's own key, then unlink.
+    CryptoErase,
+}
+
+/// Destroy what is stored for the entry at `path`, about to be removed, as
+/// `mode` says.
+pub fn shred(store: &Store, path: &Path, mode: SecureDelete) -> io::Result<()> {
+    match mode {
+        SecureDelete::None => Ok(()),
+        SecureDelete::Overwrite => overwrite_file(store, path),
+        SecureDelete::CryptoErase if erase_key(path)? => overwrite(&meta::sidecar_path(path)),
+        SecureDelete::CryptoErase => overwrite_file(store, path),
+    }
+}
+
+/// Zero the wrapped key in the header of the file at `path` and sync it,
+/// unless it has other links. Returns whether there was a key to erase.
+pub fn erase_key(path: &Path) -> io::Result<bool> {
+    let meta = match fs::symlink_metadata(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        other => other?,
+    };
+    if !meta.is_file() || meta.nlink() > 1 {
+        return Ok(false);
+    }
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut prefix = vec![0u8; format::MAX_HEADER_SIZE.min(meta.len() as usize)];
+    file.read_exact_at(&mut prefix, 0)?;
+    if !format::Header::decode(&prefix).is_some_and(|h| h.has_file_key()) {
+        return Ok(false);
+    }
+    // The wrapped key follows the fixed fields.
+    let at = format::HEADER_SIZE as u64;
+    file.write_all_at(&[0u8; format::WRAPPED_KEY_LEN], at)?;
+    file.sync_data()?;
+    Ok(true)
+}
+
+/// Overwrite the file at `path` with zeros in place and sync it, unless it
+/// has other links. Anything but a regular file, or nothing at all, is
+/// left as it is.
+pub fn overwrite(path: &Path) -> io::Result<()> {
+    overwrite_with_links(path, 1)
+}
+
+/// Like `overwrite`, but for a file with up to `links` links.
+fn overwrite_with_links(path: &Path, links: u64) -> io::Result<()> {
+    let meta = match fs::symlink_metadata(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        other => other?,
+    };
+    if !meta.is_file() || meta.nlink() > links {
+        return Ok(());
+    }
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let zeros = vec![0u8; CHUNK];
+    let mut left = meta.len();
+    while left > 0 {
+        let n = left.min(CHUNK as u64) as usize;
+        file.write_all(&zeros[..n])?;
+        left -= n as u64;
+    }
+    file.sync_data()
+}
+
+/// Overwrite everything stored for the entry at `path`: a file's backing
+/// file and the blocks only it holds, separately or in `store`, and the
+/// sidecar of either a file or a directory.
+pub fn overwrite_file(store: &Store, path: &Path) -> io::Result<()> {
+    overwrite(path)?;
+    overwrite(&meta::sidecar_path(path))?;
+    let blocks = match fs::read_dir(layout::block_dir(path)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        other => other?,
+    };
+    for entry in blocks {
+        let block = entry?.path();
+        // The store's own link is the other one.
+        if store.is_only_link(&block)? {
+            overwrite_with_links(&block, 2)?;
+        } else {
+            overwrite(&block)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overwrites_only_unshared_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f");
+        fs::write(&path, [0xa5u8; 100_000]).unwrap();
+        let linked = dir.path().join("g");
+        fs::write(&linked, b"kept").unwrap();
+        fs::hard_link(&linked, dir.path().join("g2")).unwrap();
+
+        let store = Store::new(dir.path());
+        overwrite_file(&store, &path).unwrap();
+        overwrite_file(&store, &linked).unwrap();
+        overwrite_file(&store, &dir.path().join("missing")).unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), vec![0u8; 100_000]);
+        assert_eq!(fs::read(&linked).unwrap(), b"kept");
+    }
+}
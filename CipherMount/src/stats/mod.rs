@@ -0,0 +1,182 @@
+//! What a vault holds, in aggregate (`stats`).
+//!
+//! Only each file's header is read, never its blocks, so a summary of a
+//! large vault is quick: how many files there are, their logical size and
+//! the space they take on disk, and how they are stored (format version,
+//! cipher, block layout, integrity-only sealing). With the key, headers
+//! whose sealed mtime fails to authenticate are counted too — most often
+//! files from another vault, or sealed under another key.
+
+use crate::crypto::Cipher;
+use crate::{format, layout, meta, rewrite, walk};
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Aggregates over a vault's files.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub files: u64,
+    /// Plaintext bytes.
+    pub logical: u64,
+    /// Bytes on disk: backing files, sidecars and blocks, with blocks
+    /// shared through the dedup store counted once.
+    pub physical: u64,
+    /// Files by format: `legacy`, or `v<version>` for the block format.
+    pub formats: BTreeMap<String, u64>,
+    /// Files by cipher name.
+    pub ciphers: BTreeMap<String, u64>,
+    /// Block-format files by layout: `packed`, `separate` or `dedup`.
+    pub layouts: BTreeMap<String, u64>,
+    /// Files stored authenticated but not encrypted.
+    pub integrity_only: u64,
+    /// Files whose header failed to authenticate under the key.
+    pub unauthenticated: u64,
+}
+
+impl Stats {
+    /// The stats as a JSON object.
+    pub fn to_json(&self) -> String {
+        let object = |map: &BTreeMap<String, u64>| {
+            let fields: Vec<String> = map
+                .iter()
+                .map(|(k, v)| format!("\"{}\":{}", k, v))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        };
+        format!(
+            "{{\"files\":{},\"logical_bytes\":{},\"physical_bytes\":{},\
+             \"formats\":{},\"ciphers\":{},\"layouts\":{},\
+             \"integrity_only\":{},\"unauthenticated\":{}}}",
+            self.files,
+            self.logical,
+            self.physical,
+            object(&self.formats),
+            object(&self.ciphers),
+            object(&self.layouts),
+            self.integrity_only,
+            self.unauthenticated
+        )
+    }
+}
+
+/// Summarise the files of the vault at `root`, checking their headers
+/// against `key`.
+pub fn stats(root: &Path, key: &[u8; 32], options: walk::Options) -> Result<Stats> {
+    let mut stats = Stats::default();
+    // Inodes already counted towards `physical`.
+    let mut seen = HashSet::new();
+    let mut count = |path: &Path, stats: &mut Stats| {
+        if let Ok(m) = fs::symlink_metadata(path) {
+            if seen.insert((m.dev(), m.ino())) {
+                stats.physical += m.len();
+            }
+        }
+    };
+    for path in rewrite::files(root, options)? {
+        let len = fs::metadata(&path)
+            .with_context(|| format!("Reading {:?}", path))?
+            .len();
+        let mut prefix = vec![];
+        fs::File::open(&path)
+            .and_then(|f| {
+                f.take(format::MAX_HEADER_SIZE as u64)
+                    .read_to_end(&mut prefix)
+            })
+            .with_context(|| format!("Reading {:?}", path))?;
+        stats.files += 1;
+        count(&path, &mut stats);
+        count(&meta::sidecar_path(&path), &mut stats);
+
+        // A legacy file's nonce may look like a header by chance.
+        let blocks = layout::block_dir(&path);
+        let header = format::Header::decode(&prefix).filter(|h| {
+            if h.separate_blocks() || h.dedup_blocks() {
+                blocks.is_dir()
+            } else {
+                format::fits(h, len)
+            }
+        });
+        let Some(header) = header else {
+            stats.logical += len.saturating_sub(format::BLOCK_OVERHEAD as u64);
+            *stats.formats.entry("legacy".into()).or_default() += 1;
+            // Legacy files predate cipher choice.
+            *stats
+                .ciphers
+                .entry(Cipher::default().name().into())
+                .or_default() += 1;
+            continue;
+        };
+        stats.logical += header.plaintext_len;
+        *stats
+            .formats
+            .entry(format!("v{}", header.version))
+            .or_default() += 1;
+        *stats
+            .ciphers
+            .entry(header.cipher().name().into())
+            .or_default() += 1;
+        let layout = if header.dedup_blocks() {
+            "dedup"
+        } else if header.separate_blocks() {
+            "separate"
+        } else {
+            "packed"
+        };
+        *stats.layouts.entry(layout.into()).or_default() += 1;
+        if header.integrity_only() {
+            stats.integrity_only += 1;
+        }
+        if header.open_mtime(key).is_err() {
+            stats.unauthenticated += 1;
+        }
+        if let Ok(entries) = fs::read_dir(&blocks) {
+            for entry in entries {
+                count(&entry?.path(), &mut stats);
+            }
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{crypto, vault};
+
+    const KEY: [u8; 32] = [0x42u8; 32];
+
+    #[test]
+    fn counts_files_and_sizes_by_format() {
+        let dir = tempfile::tempdir().unwrap();
+        vault::init(dir.path(), &KEY).unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let block = |data: &[u8]| format::encrypt_file(&KEY, data, 256).unwrap();
+        fs::write(dir.path().join("a"), block(&[1u8; 1000])).unwrap();
+        fs::write(dir.path().join("sub/b"), block(&[2u8; 24])).unwrap();
+        let legacy = crypto::encrypt(&KEY, b"legacy").unwrap();
+        fs::write(dir.path().join("sub/c"), &legacy).unwrap();
+        let foreign = format::encrypt_file(&[0x24u8; 32], b"elsewhere", 256).unwrap();
+        fs::write(dir.path().join("foreign"), &foreign).unwrap();
+
+        let stats = stats(dir.path(), &KEY, walk::Options::default()).unwrap();
+        assert_eq!(stats.files, 4);
+        assert_eq!(stats.logical, 1000 + 24 + 6 + 9);
+        assert_eq!(stats.formats["legacy"], 1);
+        assert_eq!(stats.formats[&format!("v{}", format::VERSION)], 3);
+        assert_eq!(stats.ciphers["aes-256-gcm"], 4);
+        assert_eq!(stats.layouts["packed"], 3);
+        assert_eq!(stats.unauthenticated, 1);
+        let on_disk: u64 = ["a", "sub/b", "sub/c", "foreign"]
+            .iter()
+            .map(|name| fs::metadata(dir.path().join(name)).unwrap().len())
+            .sum();
+        assert_eq!(stats.physical, on_disk);
+        assert!(stats
+            .to_json()
+            .starts_with("{\"files\":4,\"logical_bytes\":1039,"));
+    }
+}
@@ -0,0 +1,139 @@
+//! Streaming one file's plaintext out of an unmounted vault (`stream`).
+//!
+//! `stream` decrypts a file block by block and writes each block out as soon
+//! as it is authenticated, so it holds one block in memory whatever the size
+//! of the file, and a pipeline (`ciphermount stream ... | ffmpeg -i - ...`)
+//! starts on the first block rather than the whole file. A block that fails
+//! authentication stops the stream with an error; everything written before
+//! it was authenticated. Legacy whole-file ciphertext can only be opened
+//! whole, so it is refused; `reblock` rewrites it in the block format.
+
+use crate::{dedup, format, layout};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path};
+
+/// Decrypt the file at `path`, relative to the vault root `source`, to
+/// `out`. Returns the plaintext bytes written.
+pub fn stream(key: &[u8; 32], source: &Path, path: &Path, out: &mut impl Write) -> Result<u64> {
+    ensure!(
+        path.components().all(|c| matches!(c, Component::Normal(_))),
+        "{:?} is not a path inside the vault",
+        path
+    );
+    let full = source.join(path);
+    let file = fs::File::open(&full).with_context(|| format!("Opening {:?}", full))?;
+    let file_len = file.metadata()?.len();
+    let mut prefix = vec![0u8; format::MAX_HEADER_SIZE.min(file_len as usize)];
+    file.read_exact_at(&mut prefix, 0)?;
+    let Some(header) = format::Header::decode(&prefix) else {
+        bail!(
+            "{:?} is in the legacy whole-file format, which can't be streamed; \
+             `reblock` rewrites it in the block format",
+            path
+        );
+    };
+    ensure!(
+        format::fits(&header, file_len),
+        "Backing file is {} bytes, header implies {}",
+        file_len,
+        header.stored_len()
+    );
+
+    for index in 0..header.block_count() {
+        let sealed = if header.separate_blocks() {
+            fs::read(layout::block_file(&full, index))?
+        } else {
+            let (start, len) = header.block_span(index);
+            let mut sealed = vec![0u8; len];
+            file.read_exact_at(&mut sealed, start)?;
+            sealed
+        };
+        let payload = format::open_sealed_block(key, &header, index, &sealed)?;
+        let block = if header.dedup_blocks() {
+            let stored = fs::read(layout::block_file(&full, index))
+                .map_err(|e| anyhow!("Stored block {} is unreadable: {}", index, e))?;
+            dedup::open_block(&payload, &header, index, &stored)?
+        } else {
+            payload
+        };
+        out.write_all(&block)?;
+    }
+    out.flush()?;
+    Ok(header.plaintext_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto;
+    use std::io;
+
+    const KEY: [u8; 32] = [0x42u8; 32];
+
+    /// Sink that remembers what it was given and the largest single write.
+    #[derive(Default)]
+    struct Sink {
+        data: Vec<u8>,
+        largest_write: usize,
+    }
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.largest_write = self.largest_write.max(buf.len());
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn streams_a_large_file_one_block_at_a_time() {
+        let vault = tempfile::tempdir().unwrap();
+        let plaintext: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+        fs::create_dir(vault.path().join("media")).unwrap();
+        let raw = format::encrypt_file(&KEY, &plaintext, 4096).unwrap();
+        fs::write(vault.path().join("media/clip"), raw).unwrap();
+
+        let mut sink = Sink::default();
+        let written = stream(&KEY, vault.path(), Path::new("media/clip"), &mut sink).unwrap();
+        assert_eq!(written, plaintext.len() as u64);
+        assert!(sink.data == plaintext);
+        assert!(sink.largest_write <= 4096, "{}", sink.largest_write);
+    }
+
+    #[test]
+    fn ignores_trailing_bytes_like_the_mount() {
+        let vault = tempfile::tempdir().unwrap();
+        let mut raw = format::encrypt_file(&KEY, b"short clip", 4096).unwrap();
+        raw.extend_from_slice(&[0u8; 100]);
+        fs::write(vault.path().join("clip"), &raw).unwrap();
+        raw.truncate(raw.len() - 120);
+        fs::write(vault.path().join("cut"), &raw).unwrap();
+
+        let mut sink = Sink::default();
+        stream(&KEY, vault.path(), Path::new("clip"), &mut sink).unwrap();
+        assert_eq!(sink.data, b"short clip");
+        let mut sink = Sink::default();
+        assert!(stream(&KEY, vault.path(), Path::new("cut"), &mut sink).is_err());
+    }
+
+    #[test]
+    fn refuses_legacy_files_and_paths_outside_the_vault() {
+        let vault = tempfile::tempdir().unwrap();
+        fs::write(
+            vault.path().join("old"),
+            crypto::encrypt(&KEY, b"legacy").unwrap(),
+        )
+        .unwrap();
+        let mut sink = Sink::default();
+        assert!(stream(&KEY, vault.path(), Path::new("old"), &mut sink).is_err());
+        assert!(stream(&KEY, vault.path(), Path::new("../old"), &mut sink).is_err());
+        assert!(sink.data.is_empty());
+    }
+}
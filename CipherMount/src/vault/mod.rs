@@ -0,0 +1,305 @@
+//! Vault-level files stored alongside the encrypted data in the backing
+//! directory.
+//!
+//! `init` writes a canary: a fixed, known plaintext encrypted like any other
+//! file. Decrypting it at mount time proves the key is right and the vault
+//! hasn't been tampered with before any real file is touched.
+//!
+//! `init` also records the key's fingerprint in `vault.meta`, a plaintext
+//! file that can be compared against a key without any decryption, so a
+//! wrong key is reported immediately and clearly.
+//!
+//! `vault.meta` can also hold a zstd dictionary trained on the vault's
+//! files, used when compressing them (see `image::pack`). Unlike the
+//! fingerprint it is sealed under the key, so it cannot be swapped for one
+//! crafted to blow up decompression.
+
+use crate::{crypto, dedup, layout, walk};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::hmac;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Canary file name, relative to the vault root.
+pub const CANARY_FILE: &str = ".ciphermount-canary";
+
+/// Known plaintext sealed into the canary.
+pub const CANARY_PLAINTEXT: &[u8] = b"CipherMount canary v1";
+
+/// Vault metadata file name, relative to the vault root.
+pub const VAULT_META_FILE: &str = ".ciphermount-vault.meta";
+
+/// Name prefix, in the vault root, of files unlinked while still open.
+/// They are removed when the last handle closes, or at the next mount.
+pub const ORPHAN_PREFIX: &str = ".ciphermount-orphan-";
+
+/// Names in the vault root that belong to CipherMount itself and are hidden
+/// from the mounted view.
+pub fn is_reserved(name: &str) -> bool {
+    name == CANARY_FILE
+        || name == VAULT_META_FILE
+        || name == dedup::STORE_DIR
+        || name == crate::nonce::COUNTER_FILE
+        || name.starts_with(crate::scrub::STATE_FILE)
+        || name.starts_with(ORPHAN_PREFIX)
+        || name.starts_with(crate::doctor::PROBE_FILE)
+        || name.starts_with(crate::rewrite::CHECKPOINT_FILE)
+}
+
+/// Directory `relative` of the vault at `source`, to be served as a
+/// mount's root (`--subpath`). It must be a plain path down the vault to a
+/// directory of the mounted view: no `..`, no absolute path, and none of
+/// CipherMount's own entries or symlinks on the way.
+pub fn subtree(source: &Path, relative: &Path) -> Result<PathBuf> {
+    let mut path = source.to_path_buf();
+    for (i, component) in relative.components().enumerate() {
+        let Component::Normal(name) = component else {
+            bail!("Subpath {:?} must stay inside the vault", relative);
+        };
+        let hidden = name.to_str().is_some_and(|name| {
+            (i == 0 && is_reserved(name))
+                || crate::meta::is_sidecar(name)
+                || layout::is_block_dir(name)
+        });
+        if hidden {
+            bail!("Subpath {:?} names CipherMount's own files", relative);
+        }
+        path.push(name);
+        let meta = fs::symlink_metadata(&path)
+            .with_context(|| format!("Subpath {:?} not found in the vault", relative))?;
+        if !meta.is_dir() {
+            bail!("Subpath {:?} is not a directory", relative);
+        }
+    }
+    if path == source {
+        bail!("Subpath must name a directory below the vault root");
+    }
+    Ok(path)
+}
+
+/// Non-secret identifier of `key`: a truncated HMAC-SHA256 of a fixed
+/// label under the key. It is one-way, so publishing it reveals nothing
+/// about the key.
+pub fn fingerprint(key: &[u8; 32]) -> String {
+    let tag = hmac::sign(
+        &hmac::Key::new(hmac::HMAC_SHA256, key),
+        b"CipherMount key fingerprint v1",
+    );
+    hex::encode(&tag.as_ref()[..16])
+}
+
+/// Fingerprint recorded in the vault's metadata, if any.
+pub fn stored_fingerprint(source: &Path) -> Result<Option<String>> {
+    meta_value(source, "fingerprint")
+}
+
+/// Fail fast if the vault records a fingerprint that isn't `key`'s. Only
+/// `vault.meta` is read. Returns false if the vault has no fingerprint.
+pub fn check_fingerprint(source: &Path, key: &[u8; 32]) -> Result<bool> {
+    match stored_fingerprint(source)? {
+        None => Ok(false),
+        Some(stored) if stored == fingerprint(key) => Ok(true),
+        Some(stored) => bail!(
+            "This key does not match this vault (key fingerprint {}, vault expects {})",
+            fingerprint(key),
+            stored
+        ),
+    }
+}
+
+/// AAD sealing the compression dictionary to its purpose.
+const DICTIONARY_AAD: &[u8] = b"ciphermount dictionary v1";
+
+fn meta_value(source: &Path, name: &str) -> Result<Option<String>> {
+    let text = match fs::read_to_string(source.join(VAULT_META_FILE)) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let prefix = format!("{}=", name);
+    Ok(text
+        .lines()
+        .find_map(|line| line.strip_prefix(&prefix))
+        .map(|v| v.trim().to_string()))
+}
+
+/// Set (or replace) one `name=value` line of `vault.meta`.
+fn set_meta_value(source: &Path, name: &str, value: &str) -> Result<()> {
+    let path = source.join(VAULT_META_FILE);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let prefix = format!("{}=", name);
+    let mut out: String = text
+        .lines()
+        .filter(|line| !line.starts_with(&prefix))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    out.push_str(&format!("{}{}\n", prefix, value));
+    fs::write(path, out)?;
+    Ok(())
+}
+
+/// Train a zstd dictionary of at most `max_size` bytes on the decrypted
+/// contents of the vault's files and store it, sealed, in `vault.meta`.
+/// Returns the dictionary's size.
+pub fn train_dictionary(
+    source: &Path,
+    key: &[u8; 32],
+    max_size: usize,
+    options: walk::Options,
+) -> Result<usize> {
+    let mut samples = vec![];
+    walk::walk(source, options, |entry| {
+        if entry.file_type.is_file() {
+            let raw = layout::read(&entry.path)?;
+            if raw.len() >= crypto::HEADER_LEN + 16 {
+                samples.push(
+                    dedup::decrypt_file(key, &entry.path, &raw)
+                        .with_context(|| format!("Decrypting {:?}", entry.path))?,
+                );
+            }
+        }
+        Ok(())
+    })?;
+    let dictionary = zstd::dict::from_samples(&samples, max_size)
+        .map_err(|e| anyhow!("Cannot train a dictionary on {:?}: {}", source, e))?;
+    let sealed = crypto::encrypt_with_aad(key, &dictionary, DICTIONARY_AAD)?;
+    set_meta_value(source, "dictionary", &BASE64.encode(sealed))?;
+    Ok(dictionary.len())
+}
+
+/// The vault's compression dictionary, if one has been trained. A
+/// dictionary that fails authentication is an error.
+pub fn dictionary(source: &Path, key: &[u8; 32]) -> Result<Option<Vec<u8>>> {
+    let Some(encoded) = meta_value(source, "dictionary")? else {
+        return Ok(None);
+    };
+    let sealed = BASE64
+        .decode(encoded)
+        .map_err(|_| anyhow!("Compression dictionary in vault.meta is malformed"))?;
+    crypto::decrypt_with_aad(key, &sealed, DICTIONARY_AAD)
+        .map(Some)
+        .map_err(|_| anyhow!("Compression dictionary in vault.meta failed authentication"))
+}
+
+/// Prepare `source` as a vault: create the directory if needed and write the
+/// canary. Refuses to overwrite an existing canary.
+pub fn init(source: &Path, key: &[u8; 32]) -> Result<()> {
+    fs::create_dir_all(source)?;
+    let canary = source.join(CANARY_FILE);
+    if canary.exists() {
+        bail!("{:?} is already initialised (canary exists)", source);
+    }
+    fs::write(&canary, crypto::encrypt(key, CANARY_PLAINTEXT)?)?;
+    fs::write(
+        source.join(VAULT_META_FILE),
+        format!("fingerprint={}\n", fingerprint(key)),
+    )?;
+    Ok(())
+}
+
+/// Result of checking a vault's canary.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Canary {
+    /// The canary decrypted to the expected plaintext.
+    Valid,
+    /// The vault has no canary (created before `init` existed).
+    Missing,
+}
+
+/// Decrypt and check the canary in `source`. A canary that fails to decrypt
+/// or holds the wrong plaintext is an error: the key is wrong or the vault
+/// has been tampered with.
+pub fn verify_canary(source: &Path, key: &[u8; 32]) -> Result<Canary> {
+    let raw = match fs::read(source.join(CANARY_FILE)) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Canary::Missing),
+        Err(e) => return Err(e.into()),
+    };
+    let plaintext = crypto::decrypt(key, &raw)
+        .map_err(|_| anyhow!("Canary check failed: wrong key or tampered vault"))?;
+    if plaintext != CANARY_PLAINTEXT {
+        bail!("Canary check failed: unexpected canary contents");
+    }
+    Ok(Canary::Valid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canary_accepts_right_key_and_rejects_wrong_key() {
+        let dir = tempfile::tempdir().unwrap();
+        init(dir.path(), &[0x11u8; 32]).unwrap();
+
+        assert_eq!(
+            verify_canary(dir.path(), &[0x11u8; 32]).unwrap(),
+            Canary::Valid
+        );
+        assert!(verify_canary(dir.path(), &[0x22u8; 32]).is_err());
+    }
+
+    #[test]
+    fn mismatched_key_is_rejected_by_fingerprint() {
+        let dir = tempfile::tempdir().unwrap();
+        init(dir.path(), &[0x11u8; 32]).unwrap();
+        // Without the canary only vault.meta is left to go on.
+        fs::remove_file(dir.path().join(CANARY_FILE)).unwrap();
+
+        assert!(check_fingerprint(dir.path(), &[0x11u8; 32]).unwrap());
+        let err = check_fingerprint(dir.path(), &[0x22u8; 32]).unwrap_err();
+        assert!(err.to_string().contains("does not match this vault"));
+        assert_ne!(fingerprint(&[0x11u8; 32]), fingerprint(&[0x22u8; 32]));
+
+        let bare = tempfile::tempdir().unwrap();
+        assert!(!check_fingerprint(bare.path(), &[0x22u8; 32]).unwrap());
+    }
+
+    #[test]
+    fn tampered_dictionary_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = [0x11u8; 32];
+        init(dir.path(), &key).unwrap();
+        assert_eq!(dictionary(dir.path(), &key).unwrap(), None);
+
+        let sealed = crypto::encrypt_with_aad(&key, b"dictionary", DICTIONARY_AAD).unwrap();
+        set_meta_value(dir.path(), "dictionary", &BASE64.encode(&sealed)).unwrap();
+        assert_eq!(
+            dictionary(dir.path(), &key).unwrap().as_deref(),
+            Some(&b"dictionary"[..])
+        );
+        assert!(check_fingerprint(dir.path(), &key).unwrap());
+
+        let mut forged = sealed;
+        *forged.last_mut().unwrap() ^= 1;
+        set_meta_value(dir.path(), "dictionary", &BASE64.encode(&forged)).unwrap();
+        assert!(dictionary(dir.path(), &key).is_err());
+    }
+
+    #[test]
+    fn canary_with_wrong_plaintext_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = [0x11u8; 32];
+        fs::write(
+            dir.path().join(CANARY_FILE),
+            crypto::encrypt(&key, b"nope").unwrap(),
+        )
+        .unwrap();
+        assert!(verify_canary(dir.path(), &key).is_err());
+    }
+
+    #[test]
+    fn missing_canary_is_reported_and_init_refuses_to_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = [0x11u8; 32];
+        assert_eq!(verify_canary(dir.path(), &key).unwrap(), Canary::Missing);
+        init(dir.path(), &key).unwrap();
+        assert!(init(dir.path(), &key).is_err());
+    }
+}
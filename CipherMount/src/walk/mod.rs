@@ -0,0 +1,172 @@
+//! The vault tree walk shared by bulk commands and the expiry sweep.
+//!
+//! Entries are visited depth-first in name order, directories before their
+//! contents. Metadata sidecars and vault files are skipped. The walk refuses
+//! to go deeper than `max_depth` levels and, when following symlinks,
+//! detects a directory that contains itself instead of recursing forever.
+
+use crate::{layout, meta, vault};
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Depth limit unless the user sets `--max-depth`.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    /// Deepest level an entry may be at; entries directly in the root are
+    /// at level 1.
+    pub max_depth: usize,
+    /// Descend into symlinked directories (as opposed to reporting the
+    /// symlink itself).
+    pub follow_links: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            max_depth: DEFAULT_MAX_DEPTH,
+            follow_links: false,
+        }
+    }
+}
+
+/// An entry found by the walk.
+#[derive(Debug)]
+pub struct Entry {
+    pub path: PathBuf,
+    /// Type of the entry itself, or of its target for a followed symlink.
+    pub file_type: fs::FileType,
+    /// 1 for entries directly in the root.
+    pub depth: usize,
+}
+
+/// Walk the vault under `root`, calling `visit` for every entry. An error
+/// from `visit` stops the walk.
+pub fn walk(
+    root: &Path,
+    options: Options,
+    mut visit: impl FnMut(&Entry) -> Result<()>,
+) -> Result<()> {
+    let root_meta = fs::metadata(root).with_context(|| format!("Reading {:?}", root))?;
+    let mut ancestors = vec![(root_meta.dev(), root_meta.ino())];
+    walk_dir(root, root, 1, options, &mut ancestors, &mut visit)
+}
+
+fn walk_dir(
+    root: &Path,
+    dir: &Path,
+    depth: usize,
+    options: Options,
+    ancestors: &mut Vec<(u64, u64)>,
+    visit: &mut dyn FnMut(&Entry) -> Result<()>,
+) -> Result<()> {
+    let mut children = fs::read_dir(dir)
+        .with_context(|| format!("Reading {:?}", dir))?
+        .collect::<std::io::Result<Vec<_>>>()?;
+    children.sort_by_key(|e| e.file_name());
+    for child in children {
+        let name = child.file_name();
+        let name = name.to_string_lossy();
+        if meta::is_sidecar(&name)
+            || layout::is_block_dir(&name)
+            || (dir == root && vault::is_reserved(&name))
+        {
+            continue;
+        }
+        let path = child.path();
+        if depth > options.max_depth {
+            bail!(
+                "{:?} is nested more than {} levels deep (see --max-depth)",
+                path,
+                options.max_depth
+            );
+        }
+        let mut file_type = child.file_type()?;
+        if file_type.is_symlink() && options.follow_links {
+            // A dangling link is reported as the link itself.
+            if let Ok(target) = fs::metadata(&path) {
+                file_type = target.file_type();
+            }
+        }
+        let entry = Entry {
+            path,
+            file_type,
+            depth,
+        };
+        visit(&entry)?;
+        if !entry.file_type.is_dir() {
+            continue;
+        }
+
+        let dir_meta = fs::metadata(&entry.path)?;
+        let id = (dir_meta.dev(), dir_meta.ino());
+        if ancestors.contains(&id) {
+            bail!(
+                "Directory cycle: {:?} leads back to one of its parents",
+                entry.path
+            );
+        }
+        ancestors.push(id);
+        walk_dir(root, &entry.path, depth + 1, options, ancestors, visit)?;
+        ancestors.pop();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn names(root: &Path, options: Options) -> Result<Vec<String>> {
+        let mut seen = vec![];
+        walk(root, options, |e| {
+            seen.push(e.path.strip_prefix(root)?.to_string_lossy().into_owned());
+            Ok(())
+        })?;
+        Ok(seen)
+    }
+
+    #[test]
+    fn symlink_cycle_is_an_error_not_a_loop() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        fs::write(dir.path().join("a/b/file"), b"").unwrap();
+        symlink("../..", dir.path().join("a/b/up")).unwrap();
+
+        // Not following links, the cycle is just a symlink entry.
+        let plain = names(dir.path(), Options::default()).unwrap();
+        assert_eq!(plain, ["a", "a/b", "a/b/file", "a/b/up"]);
+
+        let following = Options {
+            follow_links: true,
+            ..Options::default()
+        };
+        let err = names(dir.path(), following).unwrap_err();
+        assert!(err.to_string().contains("cycle"), "{err}");
+    }
+
+    #[test]
+    fn depth_limit_is_enforced() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("1/2/3/4")).unwrap();
+        vault::init(dir.path(), &[0x42u8; 32]).unwrap();
+
+        let shallow = Options {
+            max_depth: 3,
+            ..Options::default()
+        };
+        assert!(names(dir.path(), shallow).is_err());
+        let deep = Options {
+            max_depth: 4,
+            ..Options::default()
+        };
+        assert_eq!(
+            names(dir.path(), deep).unwrap(),
+            ["1", "1/2", "1/2/3", "1/2/3/4"]
+        );
+    }
+}
@@ -0,0 +1,80 @@
+//! A fixed set of threads running queued jobs.
+//!
+//! `fuser` hands every request to the filesystem on the single session
+//! thread, so anything slow there (decrypting a large read, resealing a
+//! write, waiting on a network source) holds up every other request. The
+//! mount passes the requests that spend their time on file contents to a
+//! pool instead, and replies from whichever worker ran them; the per-inode
+//! content locks keep requests for the same file in order.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Worker threads sharing one queue. Dropping the pool lets the workers
+/// finish what is queued and exit.
+pub struct Pool {
+    queue: Mutex<mpsc::Sender<Job>>,
+}
+
+impl Pool {
+    /// Start `size` workers (at least one).
+    pub fn new(size: usize) -> Self {
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..size.max(1) {
+            let rx = Arc::clone(&rx);
+            thread::Builder::new()
+                .name(format!("worker-{i}"))
+                .spawn(move || loop {
+                    // Released before the job runs, so the others can take
+                    // the next one.
+                    let job = rx.lock().unwrap().recv();
+                    let Ok(job) = job else { break };
+                    // A job that panics drops its reply, which answers EIO;
+                    // the worker carries on with the next.
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        log::error!("A request panicked on worker {}", i);
+                    }
+                })
+                .expect("spawning a worker thread");
+        }
+        Self {
+            queue: Mutex::new(tx),
+        }
+    }
+
+    /// Queue `job` for the next free worker.
+    pub fn run(&self, job: impl FnOnce() + Send + 'static) {
+        // The workers only exit once the pool is dropped.
+        let _ = self.queue.lock().unwrap().send(Box::new(job));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use std::time::Duration;
+
+    #[test]
+    fn jobs_run_at_once_and_survive_a_panic() {
+        let pool = Pool::new(4);
+        pool.run(|| panic!("one bad request"));
+        // Only completes if all four are running together.
+        let barrier = Arc::new(Barrier::new(4));
+        let (done, finished) = mpsc::channel();
+        for _ in 0..4 {
+            let (barrier, done) = (Arc::clone(&barrier), done.clone());
+            pool.run(move || {
+                barrier.wait();
+                done.send(()).unwrap();
+            });
+        }
+        for _ in 0..4 {
+            finished.recv_timeout(Duration::from_secs(10)).unwrap();
+        }
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::empty_line_after_doc_comments)]
+
 /// Integration tests for the crypto layer.
 /// FUSE mount tests require root/fuse permissions and are run manually.
 